@@ -0,0 +1,50 @@
+//! Measures how many instructions per second the runtime retires on a tight
+//! loop. Run with `cargo bench`.
+
+use std::time::Instant;
+
+use micah::assembler::assemble;
+use micah::mips_parser::read_str_to_state;
+use micah::runtime::Runtime;
+
+const ITERATIONS: u32 = 5_000_000;
+
+const LOOP: &str = "
+        .data
+buffer: .space 64
+        .text
+main:   li $t0, 0
+        li $t1, ITERATIONS
+        la $t2, buffer
+loop:   andi $t3, $t0, 15
+        sll $t3, $t3, 2
+        addu $t3, $t3, $t2
+        sw $t0, 0($t3)
+        lw $t4, 0($t3)
+        addu $t5, $t5, $t4
+        addiu $t0, $t0, 1
+        bne $t0, $t1, loop
+        li $v0, 10
+        syscall
+";
+
+fn main() {
+    let source = LOOP.replace("ITERATIONS", &ITERATIONS.to_string());
+    let (components, _) = read_str_to_state(&source, "loop.s");
+    let program = assemble(&components).expect("benchmark program should assemble");
+
+    let mut runtime = Runtime::with_undo_capacity(0);
+    runtime.load_program(program).expect("benchmark program should load");
+
+    let start = Instant::now();
+    runtime.run().expect("benchmark program should run");
+    let elapsed = start.elapsed();
+
+    let count = runtime.instruction_count();
+    println!(
+        "{} instructions in {:.3}s: {:.1}M instructions/s",
+        count,
+        elapsed.as_secs_f64(),
+        count as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
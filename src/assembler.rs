@@ -0,0 +1,1576 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::code::{FloatFormat, Instruction, FLOAT_CONDITIONS};
+use super::mips_parser::{MIPSComponent, MIPSDirective, MIPSInstruction, MIPSLocation};
+use super::runtime::{FloatRegister, RegisterCodes};
+use super::runtime::RegisterCodes::*;
+use super::runtime::Registers;
+
+pub const TEXT_BASE: u32 = 0x0040_0000;
+pub const DATA_BASE: u32 = 0x1001_0000;
+pub const GLOBAL_POINTER: u32 = 0x1000_8000;
+pub const STACK_TOP: u32 = 0x7fff_fffc;
+/// Where `.ktext` is placed: the exception vector, as in SPIM.
+pub const KTEXT_BASE: u32 = 0x8000_0180;
+pub const KDATA_BASE: u32 = 0x9000_0000;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssemblerError {
+    pub message: String,
+    pub location: Option<MIPSLocation>,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}\n    {}", location, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Bytes of the data segment emitted by one directive.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DataSpan {
+    pub addr: u32,
+    pub len: usize,
+    pub kind: DataKind,
+    pub location: MIPSLocation,
+}
+
+/// The directive that emitted a `DataSpan`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DataKind {
+    /// Padding from `.align`.
+    Align,
+    Byte,
+    Half,
+    Word,
+    Float,
+    Double,
+    Space,
+    Ascii,
+    Asciiz,
+}
+
+impl DataKind {
+    /// The kind of data a directive such as `word` emits, if any.
+    fn from_directive(directive_type: &str) -> Option<DataKind> {
+        match directive_type.to_ascii_lowercase().as_str() {
+            "align" => Some(DataKind::Align),
+            "byte" => Some(DataKind::Byte),
+            "half" => Some(DataKind::Half),
+            "word" => Some(DataKind::Word),
+            "float" => Some(DataKind::Float),
+            "double" => Some(DataKind::Double),
+            "space" => Some(DataKind::Space),
+            "ascii" => Some(DataKind::Ascii),
+            "asciiz" => Some(DataKind::Asciiz),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RelocationKind {
+    /// A `.word` holding the address.
+    Word32,
+    /// The target field of `j` or `jal`.
+    Jump26,
+    /// The upper half of the address, adjusted for a signed lower half, in
+    /// a `lui`.
+    Hi16,
+    /// The lower half of the address, as a signed offset.
+    Lo16,
+}
+
+/// A place in the text or data segment that holds the address of `symbol`
+/// plus `addend`, and must be patched if the symbol moves.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Relocation {
+    pub addr: u32,
+    pub kind: RelocationKind,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+/// An assembled program: instructions from `TEXT_BASE` and initialised data
+/// from `DATA_BASE`, plus any exception handler in `.ktext` and `.kdata`.
+pub struct Program {
+    /// The instruction at `TEXT_BASE + 4 * i`.
+    pub text: Vec<Instruction>,
+    /// The machine code for each instruction in `text`.
+    pub words: Vec<u32>,
+    /// Where each instruction in `text` came from; `None` for the startup code.
+    pub locations: Vec<Option<MIPSLocation>>,
+    pub data: Vec<u8>,
+    /// Which directive each run of bytes in `data` came from, in order.
+    pub data_spans: Vec<DataSpan>,
+    /// The largest alignment the data segment needs.
+    pub data_alignment: usize,
+    /// The instruction at `KTEXT_BASE + 4 * i`.
+    pub kernel_text: Vec<Instruction>,
+    pub kernel_words: Vec<u32>,
+    pub kernel_locations: Vec<Option<MIPSLocation>>,
+    pub kernel_data: Vec<u8>,
+    pub symbols: HashMap<String, u32>,
+    /// Symbols declared with `.globl`.
+    pub globals: HashSet<String>,
+    /// Every use of a label's address.
+    pub relocations: Vec<Relocation>,
+    pub entry: u32,
+    labels: HashMap<u32, String>,
+}
+
+fn text_index(addr: u32, base: u32, len: usize) -> Option<usize> {
+    if addr < base || !addr.is_multiple_of(4) {
+        return None
+    }
+    let index = ((addr - base) / 4) as usize;
+    if index < len {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+impl Program {
+    pub fn instruction_at(&self, addr: u32) -> Option<&Instruction> {
+        match text_index(addr, TEXT_BASE, self.text.len()) {
+            Some(index) => Some(&self.text[index]),
+            None => text_index(addr, KTEXT_BASE, self.kernel_text.len()).map(|index| &self.kernel_text[index])
+        }
+    }
+
+    pub fn location_at(&self, addr: u32) -> Option<&MIPSLocation> {
+        match text_index(addr, TEXT_BASE, self.text.len()) {
+            Some(index) => self.locations[index].as_ref(),
+            None => text_index(addr, KTEXT_BASE, self.kernel_text.len())
+                .and_then(|index| self.kernel_locations[index].as_ref())
+        }
+    }
+
+    /// A program with no text or data of its own, whose symbols name code
+    /// already loaded into memory some other way.
+    pub fn from_symbols(symbols: HashMap<String, u32>, entry: u32) -> Program {
+        Program {
+            text: Vec::new(),
+            words: Vec::new(),
+            locations: Vec::new(),
+            data: Vec::new(),
+            data_spans: Vec::new(),
+            data_alignment: 1,
+            kernel_text: Vec::new(),
+            kernel_words: Vec::new(),
+            kernel_locations: Vec::new(),
+            kernel_data: Vec::new(),
+            globals: HashSet::new(),
+            relocations: Vec::new(),
+            labels: label_map(&symbols),
+            symbols,
+            entry,
+        }
+    }
+
+    /// The first instruction assembled from `line` of `file`, or of any file
+    /// if `file` is `None`. Lines count from one, as editors do.
+    pub fn address_of_line(&self, file: Option<&str>, line: usize) -> Option<u32> {
+        let segments = [(TEXT_BASE, &self.locations), (KTEXT_BASE, &self.kernel_locations)];
+        segments.iter().find_map(|(base, locations)| {
+            locations.iter()
+                .position(|location| location.as_ref().is_some_and(|location| {
+                    location.line_num + 1 == line && file.is_none_or(|file| location.file == file)
+                }))
+                .map(|index| base + 4 * index as u32)
+        })
+    }
+
+    /// The first label defined at `addr`, if any.
+    pub fn label_at(&self, addr: u32) -> Option<&str> {
+        self.labels.get(&addr).map(|label| label.as_str())
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Segment {
+    Text,
+    Data,
+}
+
+/// Builds an I-type instruction from an immediate, or gives `None` if the
+/// immediate doesn't fit.
+type ImmediateBuilder = fn(RegisterCodes, RegisterCodes, i64) -> Option<Instruction>;
+
+/// A use of a label, plus the offset added to it.
+#[derive(Clone)]
+struct SymbolRef {
+    name: String,
+    addend: i64,
+}
+
+/// A memory operand: an address from a literal or a symbol, plus an
+/// optional base register.
+struct Address {
+    value: i64,
+    symbol: Option<SymbolRef>,
+    base: Option<RegisterCodes>,
+}
+
+/// One pass over the source. The first pass runs without a symbol table,
+/// treating every label as address 0 just to lay the program out; every
+/// expansion whose size depends on a value only does so for literals, so
+/// both passes place everything at the same addresses.
+struct Assembler<'a> {
+    symbols: Option<&'a HashMap<String, u32>>,
+    /// Whether labels may be left undefined for a linker to resolve.
+    object: bool,
+    segment: Segment,
+    /// Whether `text` and `data` are the kernel's.
+    kernel: bool,
+    text: Vec<Instruction>,
+    locations: Vec<Option<MIPSLocation>>,
+    data: Vec<u8>,
+    /// The text and data not being assembled into, swapped with `text` and
+    /// `data` when moving between user and kernel segments.
+    other_text: Vec<Instruction>,
+    other_locations: Vec<Option<MIPSLocation>>,
+    other_data: Vec<u8>,
+    data_spans: Vec<DataSpan>,
+    /// Where the item being emitted starts, after any alignment.
+    item_start: u32,
+    data_alignment: usize,
+    relocations: Vec<Relocation>,
+    /// Relocations for the instruction being expanded, by index into its
+    /// expansion.
+    pending_relocations: RefCell<Vec<(usize, RelocationKind, SymbolRef)>>,
+    globals: HashSet<String>,
+    pending_labels: Vec<String>,
+    defined: HashMap<String, u32>,
+    location: Option<MIPSLocation>,
+}
+
+fn fits_i16(value: i64) -> bool {
+    value >= i16::MIN as i64 && value <= i16::MAX as i64
+}
+
+fn fits_u16(value: i64) -> bool {
+    value >= 0 && value <= u16::MAX as i64
+}
+
+fn is_symbol(arg: &str) -> bool {
+    let mut chars = arg.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => (),
+        _ => return false
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+}
+
+fn parse_escape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<u8> {
+    let byte = match chars.next()? {
+        'n' => b'\n',
+        't' => b'\t',
+        'r' => b'\r',
+        'a' => 0x07,
+        'b' => 0x08,
+        'f' => 0x0c,
+        'v' => 0x0b,
+        'x' => {
+            let mut value: u32 = 0;
+            while let Some(digit) = chars.peek().and_then(|c| c.to_digit(16)) {
+                value = value * 16 + digit;
+                chars.next();
+            }
+            value as u8
+        }
+        c @ '0'..='7' => {
+            let mut value = c.to_digit(8)?;
+            for _ in 0..2 {
+                match chars.peek().and_then(|c| c.to_digit(8)) {
+                    Some(digit) => {
+                        value = value * 8 + digit;
+                        chars.next();
+                    }
+                    None => break
+                }
+            }
+            value as u8
+        }
+        c if c.is_ascii() => c as u8,
+        _ => return None
+    };
+    Some(byte)
+}
+
+/// Parses the inside of a quoted string or character literal.
+fn parse_quoted(text: &str, quote: char) -> Option<Vec<u8>> {
+    if text.len() < 2 || !text.starts_with(quote) || !text.ends_with(quote) {
+        return None
+    }
+    let mut bytes = Vec::new();
+    let mut chars = text[1..text.len() - 1].chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            bytes.push(parse_escape(&mut chars)?);
+        } else {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+    }
+    Some(bytes)
+}
+
+/// Parses a decimal, hexadecimal or character literal.
+fn parse_int(arg: &str) -> Option<i64> {
+    if let Some(rest) = arg.strip_prefix('-') {
+        return parse_int(rest).map(|value| -value)
+    }
+    if let Some(rest) = arg.strip_prefix('+') {
+        return parse_int(rest)
+    }
+    if arg.starts_with('\'') {
+        let bytes = parse_quoted(arg, '\'')?;
+        return if bytes.len() == 1 { Some(bytes[0] as i64) } else { None }
+    }
+    if let Some(hex) = arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        return i64::from_str_radix(hex, 16).ok()
+    }
+    arg.parse().ok()
+}
+
+impl<'a> Assembler<'a> {
+    fn new(symbols: Option<&'a HashMap<String, u32>>, object: bool) -> Assembler<'a> {
+        Assembler {
+            symbols,
+            object,
+            segment: Segment::Text,
+            kernel: false,
+            text: Vec::new(),
+            locations: Vec::new(),
+            data: Vec::new(),
+            other_text: Vec::new(),
+            other_locations: Vec::new(),
+            other_data: Vec::new(),
+            data_spans: Vec::new(),
+            item_start: TEXT_BASE,
+            data_alignment: 1,
+            relocations: Vec::new(),
+            pending_relocations: RefCell::new(Vec::new()),
+            globals: HashSet::new(),
+            pending_labels: Vec::new(),
+            defined: HashMap::new(),
+            location: None,
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, AssemblerError> {
+        Err(AssemblerError {
+            message,
+            location: self.location.clone(),
+        })
+    }
+
+    fn run(&mut self, components: &[MIPSComponent]) -> Result<(), AssemblerError> {
+        for component in components {
+            match component {
+                MIPSComponent::Label(label) => self.pending_labels.push(label.name().to_owned()),
+                MIPSComponent::Directive(directive) => {
+                    self.location = Some(directive.directive_location.clone());
+                    let data_len = self.data.len();
+                    self.directive(directive)?;
+                    let kind = DataKind::from_directive(&directive.directive_type);
+                    if let Some(kind) = kind.filter(|_| self.data.len() > data_len) {
+                        // `.align` pads without starting an item.
+                        let addr = self.item_start.max(self.data_base() + data_len as u32);
+                        self.data_spans.push(DataSpan {
+                            addr,
+                            len: (self.data_base() + self.data.len() as u32 - addr) as usize,
+                            kind,
+                            location: directive.directive_location.clone(),
+                        });
+                    }
+                }
+                MIPSComponent::Instruction(instruction) => {
+                    self.location = Some(instruction.instr_location.clone());
+                    self.instruction(instruction)?;
+                }
+            }
+        }
+        self.bind_labels()?;
+        self.set_kernel(false);
+        Ok(())
+    }
+
+    fn text_base(&self) -> u32 {
+        if self.kernel { KTEXT_BASE } else { TEXT_BASE }
+    }
+
+    fn data_base(&self) -> u32 {
+        if self.kernel { KDATA_BASE } else { DATA_BASE }
+    }
+
+    fn set_kernel(&mut self, kernel: bool) {
+        if kernel != self.kernel {
+            std::mem::swap(&mut self.text, &mut self.other_text);
+            std::mem::swap(&mut self.locations, &mut self.other_locations);
+            std::mem::swap(&mut self.data, &mut self.other_data);
+            self.kernel = kernel;
+        }
+    }
+
+    fn current_addr(&self) -> u32 {
+        match self.segment {
+            Segment::Text => self.text_base() + 4 * self.text.len() as u32,
+            Segment::Data => self.data_base() + self.data.len() as u32,
+        }
+    }
+
+    /// Gives every label seen since the last thing emitted the current address.
+    fn bind_labels(&mut self) -> Result<(), AssemblerError> {
+        let addr = self.current_addr();
+        self.item_start = addr;
+        for label in std::mem::take(&mut self.pending_labels) {
+            if self.defined.insert(label.clone(), addr).is_some() {
+                return self.error(format!("Label {} is defined more than once", label))
+            }
+        }
+        Ok(())
+    }
+
+    fn align_data(&mut self, alignment: usize) {
+        self.data_alignment = self.data_alignment.max(alignment);
+        while !self.data.len().is_multiple_of(alignment) {
+            self.data.push(0);
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Result<u32, AssemblerError> {
+        match self.symbols {
+            None => Ok(0),
+            Some(symbols) => match symbols.get(name) {
+                Some(addr) => Ok(*addr),
+                // Left for the linker, which is told by a relocation.
+                None if self.object => Ok(0),
+                None => self.error(format!("Label {} is not defined", name))
+            }
+        }
+    }
+
+    fn is_undefined(&self, name: &str) -> bool {
+        self.symbols.is_some_and(|symbols| !symbols.contains_key(name))
+    }
+
+    /// Records that the instruction at `index` in the current expansion
+    /// uses `symbol`.
+    fn relocate(&self, index: usize, kind: RelocationKind, symbol: &Option<SymbolRef>) {
+        if let Some(symbol) = symbol {
+            self.pending_relocations.borrow_mut().push((index, kind, symbol.clone()));
+        }
+    }
+
+    /// Parses a literal, `label`, or `label+N`/`label-N`, returning its value
+    /// and the label it depended on.
+    fn value(&self, arg: &str) -> Result<(i64, Option<SymbolRef>), AssemblerError> {
+        if let Some(value) = parse_int(arg) {
+            return Ok((value, None))
+        }
+        let (name, offset) = match arg.rfind(['+', '-']) {
+            Some(pos) if pos > 0 => match parse_int(&arg[pos..]) {
+                Some(offset) => (&arg[..pos], offset),
+                None => (arg, 0)
+            },
+            _ => (arg, 0)
+        };
+        if !is_symbol(name) {
+            return self.error(format!("Expected a number or label, found {}", arg))
+        }
+        let symbol = SymbolRef {name: name.to_owned(), addend: offset};
+        Ok((self.symbol(name)? as i64 + offset, Some(symbol)))
+    }
+
+    fn literal(&self, arg: &str) -> Result<i64, AssemblerError> {
+        match self.value(arg)? {
+            (value, None) => Ok(value),
+            (_, Some(_)) => self.error(format!("Expected a number, found {}", arg))
+        }
+    }
+
+    fn register(&self, arg: &str) -> Result<RegisterCodes, AssemblerError> {
+        let reg = arg.strip_prefix('$').and_then(Registers::code_to_register);
+        match reg {
+            Some(reg) => Ok(reg),
+            None => self.error(format!("Expected a register, found {}", arg))
+        }
+    }
+
+    fn float_register(&self, arg: &str) -> Result<FloatRegister, AssemblerError> {
+        match arg.strip_prefix('$').and_then(FloatRegister::from_name) {
+            Some(reg) => Ok(reg),
+            None => self.error(format!("Expected a floating-point register, found {}", arg))
+        }
+    }
+
+    /// Coprocessor 0 registers are only ever named by number, e.g. `$13`.
+    fn coprocessor0_register(&self, arg: &str) -> Result<u8, AssemblerError> {
+        match arg.strip_prefix('$').and_then(|number| number.parse::<u8>().ok()) {
+            Some(reg) if reg < 32 => Ok(reg),
+            _ => self.error(format!("Expected a coprocessor 0 register, found {}", arg))
+        }
+    }
+
+    fn float_literal(&self, arg: &str) -> Result<f64, AssemblerError> {
+        match arg.parse::<f64>() {
+            Ok(value) => Ok(value),
+            Err(_) => self.error(format!("Expected a floating-point number, found {}", arg))
+        }
+    }
+
+    fn is_register(arg: &str) -> bool {
+        arg.starts_with('$')
+    }
+
+    fn address(&self, arg: &str) -> Result<Address, AssemblerError> {
+        let (offset, base) = match arg.find('(') {
+            Some(pos) => {
+                if !arg.ends_with(')') {
+                    return self.error(format!("Expected an address, found {}", arg))
+                }
+                (&arg[..pos], Some(self.register(&arg[pos + 1..arg.len() - 1])?))
+            }
+            None => (arg, None)
+        };
+        let (value, symbol) = if offset.is_empty() {
+            (0, None)
+        } else {
+            self.value(offset)?
+        };
+        Ok(Address {value, symbol, base})
+    }
+
+    /// Branches are relative to the pc, so they need no relocation within a
+    /// file, but can't reach labels in other files.
+    fn branch_target(&self, arg: &str) -> Result<u32, AssemblerError> {
+        match self.value(arg)? {
+            (_, Some(symbol)) if self.is_undefined(&symbol.name) => {
+                self.error(format!("Cannot branch to undefined label {}, use j or jal", symbol.name))
+            }
+            (value, _) => Ok(value as u32)
+        }
+    }
+
+    /// The target of a jump that will be at `index` in the current expansion.
+    fn jump_target(&self, index: usize, arg: &str) -> Result<u32, AssemblerError> {
+        let (value, symbol) = self.value(arg)?;
+        self.relocate(index, RelocationKind::Jump26, &symbol);
+        Ok(value as u32)
+    }
+
+    fn expect_args<'b>(&self, instr: &'b MIPSInstruction, counts: &[usize]) -> Result<&'b [String], AssemblerError> {
+        let args = &instr.instr_args[..];
+        if !counts.contains(&args.len()) {
+            let expected: Vec<String> = counts.iter().map(|count| count.to_string()).collect();
+            return self.error(format!(
+                "{} expects {} arguments, found {}",
+                instr.instr_type, expected.join(" or "), args.len()
+            ))
+        }
+        Ok(args)
+    }
+
+    /// Emits the shortest sequence loading `value` into `rt`. Addresses of
+    /// labels are always loaded as a `%hi`/`%lo` pair so they can be relocated.
+    fn load_immediate(
+        &self, out: &mut Vec<Instruction>, rt: RegisterCodes, value: i64, symbol: Option<SymbolRef>
+    ) -> Result<(), AssemblerError> {
+        if value < i32::MIN as i64 || value > u32::MAX as i64 {
+            return self.error(format!("{} does not fit in 32 bits", value))
+        }
+        let value32 = value as u32;
+        if symbol.is_some() {
+            self.relocate(out.len(), RelocationKind::Hi16, &symbol);
+            out.push(Instruction::Lui {rt: Rat, imm: (value32.wrapping_add(0x8000) >> 16) as u16});
+            self.relocate(out.len(), RelocationKind::Lo16, &symbol);
+            out.push(Instruction::Addiu {rt, rs: Rat, imm: value32 as u16 as i16});
+        } else if fits_i16(value) {
+            out.push(Instruction::Addiu {rt, rs: Rzero, imm: value as i16});
+        } else if fits_u16(value) {
+            out.push(Instruction::Ori {rt, rs: Rzero, imm: value as u16});
+        } else {
+            out.push(Instruction::Lui {rt: Rat, imm: (value32 >> 16) as u16});
+            out.push(Instruction::Ori {rt, rs: Rat, imm: value32 as u16});
+        }
+        Ok(())
+    }
+
+    /// Emits a load or store of `rt` at `arg`, going through `$at` for labels
+    /// and offsets that don't fit in 16 bits.
+    fn memory_access(
+        &self, out: &mut Vec<Instruction>, arg: &str, make: &dyn Fn(RegisterCodes, i16) -> Instruction
+    ) -> Result<(), AssemblerError> {
+        let address = self.address(arg)?;
+        let base = address.base.unwrap_or(Rzero);
+        if address.symbol.is_none() && fits_i16(address.value) {
+            out.push(make(base, address.value as i16));
+            return Ok(())
+        }
+        let value = address.value as u32;
+        let high = (value.wrapping_add(0x8000) >> 16) as u16;
+        self.relocate(out.len(), RelocationKind::Hi16, &address.symbol);
+        out.push(Instruction::Lui {rt: Rat, imm: high});
+        if base != Rzero {
+            out.push(Instruction::Addu {rd: Rat, rs: Rat, rt: base});
+        }
+        self.relocate(out.len(), RelocationKind::Lo16, &address.symbol);
+        out.push(make(Rat, value as u16 as i16));
+        Ok(())
+    }
+
+    /// Handles `op rd, rs, rt`, `op rd, rt` (as `op rd, rd, rt`) and, where
+    /// `immediate` is given, `op rd, rs, imm`.
+    fn three_register(
+        &self, out: &mut Vec<Instruction>, instr: &MIPSInstruction,
+        make: fn(RegisterCodes, RegisterCodes, RegisterCodes) -> Instruction,
+        immediate: Option<ImmediateBuilder>
+    ) -> Result<(), AssemblerError> {
+        let args = self.expect_args(instr, &[2, 3])?;
+        let rd = self.register(&args[0])?;
+        let (rs, last) = if args.len() == 3 {
+            (self.register(&args[1])?, &args[2])
+        } else {
+            (rd, &args[1])
+        };
+        if Assembler::is_register(last) {
+            out.push(make(rd, rs, self.register(last)?));
+            return Ok(())
+        }
+        let immediate = match immediate {
+            Some(immediate) => immediate,
+            None => return self.error(format!("{} expects a register, found {}", instr.instr_type, last))
+        };
+        let (value, symbol) = self.value(last)?;
+        match immediate(rd, rs, value) {
+            Some(instruction) if symbol.is_none() => out.push(instruction),
+            _ => {
+                self.load_immediate(out, Rat, value, symbol)?;
+                out.push(make(rd, rs, Rat));
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles `op rt, rs, imm` and `op rt, imm`, loading immediates that
+    /// don't fit through `$at` and using `fallback` on them.
+    fn immediate(
+        &self, out: &mut Vec<Instruction>, instr: &MIPSInstruction,
+        make: ImmediateBuilder,
+        fallback: fn(RegisterCodes, RegisterCodes, RegisterCodes) -> Instruction
+    ) -> Result<(), AssemblerError> {
+        let args = self.expect_args(instr, &[2, 3])?;
+        let rt = self.register(&args[0])?;
+        let (rs, last) = if args.len() == 3 {
+            (self.register(&args[1])?, &args[2])
+        } else {
+            (rt, &args[1])
+        };
+        if Assembler::is_register(last) {
+            return self.error(format!("{} expects an immediate, found {}", instr.instr_type, last))
+        }
+        let (value, symbol) = self.value(last)?;
+        match make(rt, rs, value) {
+            Some(instruction) if symbol.is_none() => out.push(instruction),
+            _ => {
+                self.load_immediate(out, Rat, value, symbol)?;
+                out.push(fallback(rt, rs, Rat));
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles `b<cond> rs, rt, label` for conditions built from `slt`.
+    /// `swap` compares `rt < rs` instead of `rs < rt`, and `taken_if_set`
+    /// branches when the comparison holds rather than when it fails.
+    fn compare_branch(
+        &self, out: &mut Vec<Instruction>, instr: &MIPSInstruction,
+        unsigned: bool, swap: bool, taken_if_set: bool
+    ) -> Result<(), AssemblerError> {
+        let args = self.expect_args(instr, &[3])?;
+        let rs = self.register(&args[0])?;
+        let rt = if Assembler::is_register(&args[1]) {
+            self.register(&args[1])?
+        } else {
+            let (value, symbol) = self.value(&args[1])?;
+            self.load_immediate(out, Rat, value, symbol)?;
+            Rat
+        };
+        let target = self.branch_target(&args[2])?;
+        let (rs, rt) = if swap { (rt, rs) } else { (rs, rt) };
+        if unsigned {
+            out.push(Instruction::Sltu {rd: Rat, rs, rt});
+        } else {
+            out.push(Instruction::Slt {rd: Rat, rs, rt});
+        }
+        if taken_if_set {
+            out.push(Instruction::Bne {rs: Rat, rt: Rzero, target});
+        } else {
+            out.push(Instruction::Beq {rs: Rat, rt: Rzero, target});
+        }
+        Ok(())
+    }
+
+    /// Handles `beq`/`bne`, whose second operand may be an immediate.
+    fn equality_branch(
+        &self, out: &mut Vec<Instruction>, instr: &MIPSInstruction,
+        make: fn(RegisterCodes, RegisterCodes, u32) -> Instruction
+    ) -> Result<(), AssemblerError> {
+        let args = self.expect_args(instr, &[3])?;
+        let rs = self.register(&args[0])?;
+        let rt = if Assembler::is_register(&args[1]) {
+            self.register(&args[1])?
+        } else {
+            let (value, symbol) = self.value(&args[1])?;
+            self.load_immediate(out, Rat, value, symbol)?;
+            Rat
+        };
+        out.push(make(rs, rt, self.branch_target(&args[2])?));
+        Ok(())
+    }
+
+    fn zero_branch(
+        &self, out: &mut Vec<Instruction>, instr: &MIPSInstruction,
+        make: fn(RegisterCodes, u32) -> Instruction
+    ) -> Result<(), AssemblerError> {
+        let args = self.expect_args(instr, &[2])?;
+        out.push(make(self.register(&args[0])?, self.branch_target(&args[1])?));
+        Ok(())
+    }
+
+    fn shift(
+        &self, out: &mut Vec<Instruction>, instr: &MIPSInstruction,
+        make: fn(RegisterCodes, RegisterCodes, u8) -> Instruction,
+        variable: fn(RegisterCodes, RegisterCodes, RegisterCodes) -> Instruction
+    ) -> Result<(), AssemblerError> {
+        let args = self.expect_args(instr, &[3])?;
+        let rd = self.register(&args[0])?;
+        let rt = self.register(&args[1])?;
+        if Assembler::is_register(&args[2]) {
+            out.push(variable(rd, rt, self.register(&args[2])?));
+            return Ok(())
+        }
+        let shamt = self.literal(&args[2])?;
+        if !(0..32).contains(&shamt) {
+            return self.error(format!("Shift amount {} is not between 0 and 31", shamt))
+        }
+        out.push(make(rd, rt, shamt as u8));
+        Ok(())
+    }
+
+    /// Handles `div rs, rt`, and `div rd, rs, rt` which also moves the
+    /// result out of `lo` (or `hi` for `rem`).
+    fn divide(
+        &self, out: &mut Vec<Instruction>, instr: &MIPSInstruction,
+        make: fn(RegisterCodes, RegisterCodes) -> Instruction, remainder: bool
+    ) -> Result<(), AssemblerError> {
+        let counts: &[usize] = if remainder { &[3] } else { &[2, 3] };
+        let args = self.expect_args(instr, counts)?;
+        if args.len() == 2 {
+            out.push(make(self.register(&args[0])?, self.register(&args[1])?));
+            return Ok(())
+        }
+        let rd = self.register(&args[0])?;
+        let rs = self.register(&args[1])?;
+        let rt = if Assembler::is_register(&args[2]) {
+            self.register(&args[2])?
+        } else {
+            let (value, symbol) = self.value(&args[2])?;
+            self.load_immediate(out, Rat, value, symbol)?;
+            Rat
+        };
+        out.push(make(rs, rt));
+        if remainder {
+            out.push(Instruction::Mfhi {rd});
+        } else {
+            out.push(Instruction::Mflo {rd});
+        }
+        Ok(())
+    }
+
+    fn expand(&self, instr: &MIPSInstruction) -> Result<Vec<Instruction>, AssemblerError> {
+        use Instruction as I;
+        let mut out = Vec::new();
+        let out_ref = &mut out;
+        match instr.instr_type.to_ascii_lowercase().as_str() {
+            "add" => self.three_register(out_ref, instr, |rd, rs, rt| I::Add {rd, rs, rt},
+                Some(|rt, rs, imm| if fits_i16(imm) { Some(I::Addi {rt, rs, imm: imm as i16}) } else { None }))?,
+            "addu" => self.three_register(out_ref, instr, |rd, rs, rt| I::Addu {rd, rs, rt},
+                Some(|rt, rs, imm| if fits_i16(imm) { Some(I::Addiu {rt, rs, imm: imm as i16}) } else { None }))?,
+            "sub" => self.three_register(out_ref, instr, |rd, rs, rt| I::Sub {rd, rs, rt}, None)?,
+            "subu" => self.three_register(out_ref, instr, |rd, rs, rt| I::Subu {rd, rs, rt}, None)?,
+            "and" => self.three_register(out_ref, instr, |rd, rs, rt| I::And {rd, rs, rt},
+                Some(|rt, rs, imm| if fits_u16(imm) { Some(I::Andi {rt, rs, imm: imm as u16}) } else { None }))?,
+            "or" => self.three_register(out_ref, instr, |rd, rs, rt| I::Or {rd, rs, rt},
+                Some(|rt, rs, imm| if fits_u16(imm) { Some(I::Ori {rt, rs, imm: imm as u16}) } else { None }))?,
+            "xor" => self.three_register(out_ref, instr, |rd, rs, rt| I::Xor {rd, rs, rt},
+                Some(|rt, rs, imm| if fits_u16(imm) { Some(I::Xori {rt, rs, imm: imm as u16}) } else { None }))?,
+            "nor" => self.three_register(out_ref, instr, |rd, rs, rt| I::Nor {rd, rs, rt}, None)?,
+            "slt" => self.three_register(out_ref, instr, |rd, rs, rt| I::Slt {rd, rs, rt},
+                Some(|rt, rs, imm| if fits_i16(imm) { Some(I::Slti {rt, rs, imm: imm as i16}) } else { None }))?,
+            "sltu" => self.three_register(out_ref, instr, |rd, rs, rt| I::Sltu {rd, rs, rt},
+                Some(|rt, rs, imm| if fits_i16(imm) { Some(I::Sltiu {rt, rs, imm: imm as i16}) } else { None }))?,
+            "sgt" => self.three_register(out_ref, instr, |rd, rs, rt| I::Slt {rd, rs: rt, rt: rs}, None)?,
+            "sgtu" => self.three_register(out_ref, instr, |rd, rs, rt| I::Sltu {rd, rs: rt, rt: rs}, None)?,
+            "mul" => self.three_register(out_ref, instr, |rd, rs, rt| I::Mul {rd, rs, rt}, Some(|_, _, _| None))?,
+            "sllv" => self.three_register(out_ref, instr, |rd, rt, rs| I::Sllv {rd, rt, rs}, None)?,
+            "srlv" => self.three_register(out_ref, instr, |rd, rt, rs| I::Srlv {rd, rt, rs}, None)?,
+            "srav" => self.three_register(out_ref, instr, |rd, rt, rs| I::Srav {rd, rt, rs}, None)?,
+            "sll" => self.shift(out_ref, instr, |rd, rt, shamt| I::Sll {rd, rt, shamt}, |rd, rt, rs| I::Sllv {rd, rt, rs})?,
+            "srl" => self.shift(out_ref, instr, |rd, rt, shamt| I::Srl {rd, rt, shamt}, |rd, rt, rs| I::Srlv {rd, rt, rs})?,
+            "sra" => self.shift(out_ref, instr, |rd, rt, shamt| I::Sra {rd, rt, shamt}, |rd, rt, rs| I::Srav {rd, rt, rs})?,
+
+            "addi" => self.immediate(out_ref, instr,
+                |rt, rs, imm| if fits_i16(imm) { Some(I::Addi {rt, rs, imm: imm as i16}) } else { None },
+                |rd, rs, rt| I::Add {rd, rs, rt})?,
+            "addiu" => self.immediate(out_ref, instr,
+                |rt, rs, imm| if fits_i16(imm) { Some(I::Addiu {rt, rs, imm: imm as i16}) } else { None },
+                |rd, rs, rt| I::Addu {rd, rs, rt})?,
+            "slti" => self.immediate(out_ref, instr,
+                |rt, rs, imm| if fits_i16(imm) { Some(I::Slti {rt, rs, imm: imm as i16}) } else { None },
+                |rd, rs, rt| I::Slt {rd, rs, rt})?,
+            "sltiu" => self.immediate(out_ref, instr,
+                |rt, rs, imm| if fits_i16(imm) { Some(I::Sltiu {rt, rs, imm: imm as i16}) } else { None },
+                |rd, rs, rt| I::Sltu {rd, rs, rt})?,
+            "andi" => self.immediate(out_ref, instr,
+                |rt, rs, imm| if fits_u16(imm) { Some(I::Andi {rt, rs, imm: imm as u16}) } else { None },
+                |rd, rs, rt| I::And {rd, rs, rt})?,
+            "ori" => self.immediate(out_ref, instr,
+                |rt, rs, imm| if fits_u16(imm) { Some(I::Ori {rt, rs, imm: imm as u16}) } else { None },
+                |rd, rs, rt| I::Or {rd, rs, rt})?,
+            "xori" => self.immediate(out_ref, instr,
+                |rt, rs, imm| if fits_u16(imm) { Some(I::Xori {rt, rs, imm: imm as u16}) } else { None },
+                |rd, rs, rt| I::Xor {rd, rs, rt})?,
+            "lui" => {
+                let args = self.expect_args(instr, &[2])?;
+                let imm = self.literal(&args[1])?;
+                if !fits_u16(imm) && !fits_i16(imm) {
+                    return self.error(format!("{} does not fit in 16 bits", imm))
+                }
+                out.push(I::Lui {rt: self.register(&args[0])?, imm: imm as u16});
+            }
+
+            "mult" | "multu" => {
+                let args = self.expect_args(instr, &[2])?;
+                let rs = self.register(&args[0])?;
+                let rt = self.register(&args[1])?;
+                out.push(if instr.instr_type.eq_ignore_ascii_case("mult") { I::Mult {rs, rt} } else { I::Multu {rs, rt} });
+            }
+            "div" => self.divide(out_ref, instr, |rs, rt| I::Div {rs, rt}, false)?,
+            "divu" => self.divide(out_ref, instr, |rs, rt| I::Divu {rs, rt}, false)?,
+            "rem" => self.divide(out_ref, instr, |rs, rt| I::Div {rs, rt}, true)?,
+            "remu" => self.divide(out_ref, instr, |rs, rt| I::Divu {rs, rt}, true)?,
+            "mfhi" | "mflo" | "mthi" | "mtlo" => {
+                let args = self.expect_args(instr, &[1])?;
+                let reg = self.register(&args[0])?;
+                out.push(match instr.instr_type.to_ascii_lowercase().as_str() {
+                    "mfhi" => I::Mfhi {rd: reg},
+                    "mflo" => I::Mflo {rd: reg},
+                    "mthi" => I::Mthi {rs: reg},
+                    _ => I::Mtlo {rs: reg},
+                });
+            }
+
+            "lb" | "lbu" | "lh" | "lhu" | "lw" | "sb" | "sh" | "sw" => {
+                let args = self.expect_args(instr, &[2])?;
+                let rt = self.register(&args[0])?;
+                let make: fn(RegisterCodes, RegisterCodes, i16) -> Instruction =
+                    match instr.instr_type.to_ascii_lowercase().as_str() {
+                        "lb" => |rt, base, offset| I::Lb {rt, base, offset},
+                        "lbu" => |rt, base, offset| I::Lbu {rt, base, offset},
+                        "lh" => |rt, base, offset| I::Lh {rt, base, offset},
+                        "lhu" => |rt, base, offset| I::Lhu {rt, base, offset},
+                        "lw" => |rt, base, offset| I::Lw {rt, base, offset},
+                        "sb" => |rt, base, offset| I::Sb {rt, base, offset},
+                        "sh" => |rt, base, offset| I::Sh {rt, base, offset},
+                        _ => |rt, base, offset| I::Sw {rt, base, offset},
+                    };
+                self.memory_access(out_ref, &args[1], &|base, offset| make(rt, base, offset))?;
+            }
+
+            "beq" => self.equality_branch(out_ref, instr, |rs, rt, target| I::Beq {rs, rt, target})?,
+            "bne" => self.equality_branch(out_ref, instr, |rs, rt, target| I::Bne {rs, rt, target})?,
+            "beqz" => self.zero_branch(out_ref, instr, |rs, target| I::Beq {rs, rt: Rzero, target})?,
+            "bnez" => self.zero_branch(out_ref, instr, |rs, target| I::Bne {rs, rt: Rzero, target})?,
+            "blez" => self.zero_branch(out_ref, instr, |rs, target| I::Blez {rs, target})?,
+            "bgtz" => self.zero_branch(out_ref, instr, |rs, target| I::Bgtz {rs, target})?,
+            "bltz" => self.zero_branch(out_ref, instr, |rs, target| I::Bltz {rs, target})?,
+            "bgez" => self.zero_branch(out_ref, instr, |rs, target| I::Bgez {rs, target})?,
+            "blt" => self.compare_branch(out_ref, instr, false, false, true)?,
+            "bltu" => self.compare_branch(out_ref, instr, true, false, true)?,
+            "bgt" => self.compare_branch(out_ref, instr, false, true, true)?,
+            "bgtu" => self.compare_branch(out_ref, instr, true, true, true)?,
+            "ble" => self.compare_branch(out_ref, instr, false, true, false)?,
+            "bleu" => self.compare_branch(out_ref, instr, true, true, false)?,
+            "bge" => self.compare_branch(out_ref, instr, false, false, false)?,
+            "bgeu" => self.compare_branch(out_ref, instr, true, false, false)?,
+            "b" => {
+                let args = self.expect_args(instr, &[1])?;
+                out.push(I::Beq {rs: Rzero, rt: Rzero, target: self.branch_target(&args[0])?});
+            }
+
+            "j" | "jal" => {
+                let args = self.expect_args(instr, &[1])?;
+                let target = self.jump_target(out.len(), &args[0])?;
+                out.push(if instr.instr_type.eq_ignore_ascii_case("j") { I::J {target} } else { I::Jal {target} });
+            }
+            "jr" => {
+                let args = self.expect_args(instr, &[1])?;
+                out.push(I::Jr {rs: self.register(&args[0])?});
+            }
+            "jalr" => {
+                let args = self.expect_args(instr, &[1, 2])?;
+                if args.len() == 1 {
+                    out.push(I::Jalr {rd: Rra, rs: self.register(&args[0])?});
+                } else {
+                    out.push(I::Jalr {rd: self.register(&args[0])?, rs: self.register(&args[1])?});
+                }
+            }
+            "syscall" => {
+                self.expect_args(instr, &[0])?;
+                out.push(I::Syscall);
+            }
+            "nop" => {
+                self.expect_args(instr, &[0])?;
+                out.push(I::Sll {rd: Rzero, rt: Rzero, shamt: 0});
+            }
+            "break" => {
+                let args = self.expect_args(instr, &[0, 1])?;
+                let code = match args.first() {
+                    Some(arg) => self.literal(arg)?,
+                    None => 0
+                };
+                if !(0..1 << 20).contains(&code) {
+                    return self.error(format!("{} does not fit in 20 bits", code))
+                }
+                out.push(I::Break {code: code as u32});
+            }
+            "eret" => {
+                self.expect_args(instr, &[0])?;
+                out.push(I::Eret);
+            }
+            "mfc0" | "mtc0" => {
+                let args = self.expect_args(instr, &[2])?;
+                let rt = self.register(&args[0])?;
+                let rd = self.coprocessor0_register(&args[1])?;
+                out.push(if instr.instr_type.eq_ignore_ascii_case("mfc0") { I::Mfc0 {rt, rd} } else { I::Mtc0 {rt, rd} });
+            }
+
+            "move" => {
+                let args = self.expect_args(instr, &[2])?;
+                out.push(I::Addu {rd: self.register(&args[0])?, rs: Rzero, rt: self.register(&args[1])?});
+            }
+            "neg" | "negu" => {
+                let args = self.expect_args(instr, &[2])?;
+                let rd = self.register(&args[0])?;
+                let rt = self.register(&args[1])?;
+                out.push(if instr.instr_type.eq_ignore_ascii_case("neg") {
+                    I::Sub {rd, rs: Rzero, rt}
+                } else {
+                    I::Subu {rd, rs: Rzero, rt}
+                });
+            }
+            "not" => {
+                let args = self.expect_args(instr, &[2])?;
+                out.push(I::Nor {rd: self.register(&args[0])?, rs: self.register(&args[1])?, rt: Rzero});
+            }
+            "abs" => {
+                let args = self.expect_args(instr, &[2])?;
+                let rd = self.register(&args[0])?;
+                let rs = self.register(&args[1])?;
+                out.push(I::Sra {rd: Rat, rt: rs, shamt: 31});
+                out.push(I::Xor {rd, rs, rt: Rat});
+                out.push(I::Subu {rd, rs: rd, rt: Rat});
+            }
+            "li" => {
+                let args = self.expect_args(instr, &[2])?;
+                let rt = self.register(&args[0])?;
+                let (value, symbol) = self.value(&args[1])?;
+                self.load_immediate(out_ref, rt, value, symbol)?;
+            }
+            "la" => {
+                let args = self.expect_args(instr, &[2])?;
+                let rt = self.register(&args[0])?;
+                let address = self.address(&args[1])?;
+                match address.base {
+                    Some(base) if address.symbol.is_none() && fits_i16(address.value) => {
+                        out.push(I::Addiu {rt, rs: base, imm: address.value as i16});
+                    }
+                    Some(base) => {
+                        self.load_immediate(out_ref, Rat, address.value, address.symbol)?;
+                        out.push(I::Addu {rd: rt, rs: Rat, rt: base});
+                    }
+                    None => self.load_immediate(out_ref, rt, address.value, address.symbol)?,
+                }
+            }
+            "mfc1" | "mtc1" => {
+                let args = self.expect_args(instr, &[2])?;
+                let rt = self.register(&args[0])?;
+                let fs = self.float_register(&args[1])?;
+                out.push(if instr.instr_type.eq_ignore_ascii_case("mfc1") { I::Mfc1 {rt, fs} } else { I::Mtc1 {rt, fs} });
+            }
+            "lwc1" | "l.s" | "ldc1" | "l.d" | "swc1" | "s.s" | "sdc1" | "s.d" => {
+                let args = self.expect_args(instr, &[2])?;
+                let ft = self.float_register(&args[0])?;
+                let make: fn(FloatRegister, RegisterCodes, i16) -> Instruction =
+                    match instr.instr_type.to_ascii_lowercase().as_str() {
+                        "lwc1" | "l.s" => |ft, base, offset| I::Lwc1 {ft, base, offset},
+                        "ldc1" | "l.d" => |ft, base, offset| I::Ldc1 {ft, base, offset},
+                        "swc1" | "s.s" => |ft, base, offset| I::Swc1 {ft, base, offset},
+                        _ => |ft, base, offset| I::Sdc1 {ft, base, offset},
+                    };
+                self.memory_access(out_ref, &args[1], &|base, offset| make(ft, base, offset))?;
+            }
+            "bc1t" | "bc1f" => {
+                let args = self.expect_args(instr, &[1, 2])?;
+                let cc = if args.len() == 2 { self.condition_flag(&args[0])? } else { 0 };
+                let target = self.branch_target(args.last().unwrap())?;
+                out.push(if instr.instr_type.eq_ignore_ascii_case("bc1t") { I::Bc1t {cc, target} } else { I::Bc1f {cc, target} });
+            }
+            "li.s" | "li.d" => {
+                let args = self.expect_args(instr, &[2])?;
+                let fd = self.float_register(&args[0])?;
+                let value = self.float_literal(&args[1])?;
+                if instr.instr_type.eq_ignore_ascii_case("li.s") {
+                    self.load_immediate(out_ref, Rat, (value as f32).to_bits() as i64, None)?;
+                    out_ref.push(I::Mtc1 {rt: Rat, fs: fd});
+                } else {
+                    let bits = value.to_bits();
+                    self.load_immediate(out_ref, Rat, bits as u32 as i64, None)?;
+                    out_ref.push(I::Mtc1 {rt: Rat, fs: fd});
+                    self.load_immediate(out_ref, Rat, (bits >> 32) as i64, None)?;
+                    out_ref.push(I::Mtc1 {rt: Rat, fs: FloatRegister(fd.0 + 1)});
+                }
+            }
+            name if name.contains('.') => self.float_instruction(out_ref, instr, name)?,
+            _ => return self.error(format!("Unknown instruction {}", instr.instr_type))
+        }
+        if !out.iter().all(|instruction| instruction.doubles_are_even()) {
+            return self.error(format!("{} needs even-numbered registers for doubles", instr.instr_type))
+        }
+        Ok(out)
+    }
+
+    fn condition_flag(&self, arg: &str) -> Result<u8, AssemblerError> {
+        match self.literal(arg)? {
+            cc @ 0..=7 => Ok(cc as u8),
+            cc => self.error(format!("Condition flag {} is not between 0 and 7", cc))
+        }
+    }
+
+    /// Handles floating-point instructions named `op.fmt`, `cvt.to.from`,
+    /// `round.w.fmt` and the like, and `c.cond.fmt`.
+    fn float_instruction(&self, out: &mut Vec<Instruction>, instr: &MIPSInstruction, name: &str) -> Result<(), AssemblerError> {
+        use Instruction as I;
+        let unknown = || AssemblerError {
+            message: format!("Unknown instruction {}", instr.instr_type),
+            location: self.location.clone(),
+        };
+        let parts: Vec<&str> = name.split('.').collect();
+        let format = |suffix: &str| FloatFormat::from_suffix(suffix).ok_or_else(unknown);
+        match parts[..] {
+            ["add", fmt] | ["sub", fmt] | ["mul", fmt] | ["div", fmt] => {
+                let fmt = format(fmt)?;
+                if fmt == FloatFormat::Word {
+                    return Err(unknown())
+                }
+                let args = self.expect_args(instr, &[3])?;
+                let fd = self.float_register(&args[0])?;
+                let fs = self.float_register(&args[1])?;
+                let ft = self.float_register(&args[2])?;
+                out.push(match parts[0] {
+                    "add" => I::AddFmt {fmt, fd, fs, ft},
+                    "sub" => I::SubFmt {fmt, fd, fs, ft},
+                    "mul" => I::MulFmt {fmt, fd, fs, ft},
+                    _ => I::DivFmt {fmt, fd, fs, ft},
+                });
+            }
+            ["sqrt", fmt] | ["abs", fmt] | ["mov", fmt] | ["neg", fmt]
+            | ["round", "w", fmt] | ["trunc", "w", fmt] | ["ceil", "w", fmt] | ["floor", "w", fmt] => {
+                let fmt = format(fmt)?;
+                if fmt == FloatFormat::Word {
+                    return Err(unknown())
+                }
+                let args = self.expect_args(instr, &[2])?;
+                let fd = self.float_register(&args[0])?;
+                let fs = self.float_register(&args[1])?;
+                out.push(match parts[0] {
+                    "sqrt" => I::SqrtFmt {fmt, fd, fs},
+                    "abs" => I::AbsFmt {fmt, fd, fs},
+                    "mov" => I::MovFmt {fmt, fd, fs},
+                    "neg" => I::NegFmt {fmt, fd, fs},
+                    "round" => I::RoundW {fmt, fd, fs},
+                    "trunc" => I::TruncW {fmt, fd, fs},
+                    "ceil" => I::CeilW {fmt, fd, fs},
+                    _ => I::FloorW {fmt, fd, fs},
+                });
+            }
+            ["cvt", to, from] => {
+                let (to, from) = (format(to)?, format(from)?);
+                if to == from {
+                    return Err(unknown())
+                }
+                let args = self.expect_args(instr, &[2])?;
+                out.push(I::Cvt {to, from, fd: self.float_register(&args[0])?, fs: self.float_register(&args[1])?});
+            }
+            ["c", cond, fmt] => {
+                let fmt = format(fmt)?;
+                let cond = match FLOAT_CONDITIONS.iter().position(|name| *name == cond) {
+                    Some(cond) if fmt != FloatFormat::Word => cond as u8,
+                    _ => return Err(unknown())
+                };
+                let args = self.expect_args(instr, &[2, 3])?;
+                let cc = if args.len() == 3 { self.condition_flag(&args[0])? } else { 0 };
+                let fs = self.float_register(&args[args.len() - 2])?;
+                let ft = self.float_register(&args[args.len() - 1])?;
+                out.push(I::CompareFmt {cond, fmt, cc, fs, ft});
+            }
+            _ => return Err(unknown())
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, instr: &MIPSInstruction) -> Result<(), AssemblerError> {
+        if self.segment != Segment::Text {
+            return self.error(format!("Instruction {} is outside the text segment", instr.instr_type))
+        }
+        self.bind_labels()?;
+        let expansion = self.expand(instr)?;
+        for (index, kind, symbol) in self.pending_relocations.borrow_mut().drain(..) {
+            self.relocations.push(Relocation {
+                addr: self.text_base() + 4 * (self.text.len() + index) as u32,
+                kind,
+                symbol: symbol.name,
+                addend: symbol.addend,
+            });
+        }
+        for instruction in expansion {
+            self.text.push(instruction);
+            self.locations.push(Some(instr.instr_location.clone()));
+        }
+        Ok(())
+    }
+
+    fn data_values(&mut self, directive: &MIPSDirective, size: usize) -> Result<(), AssemblerError> {
+        self.align_data(size);
+        self.bind_labels()?;
+        for arg in &directive.directive_value {
+            let (value, symbol) = self.value(arg)?;
+            let bits = 8 * size as u32;
+            if let Some(symbol) = symbol {
+                if size != 4 {
+                    return self.error(format!("Labels can only be stored with .word, found {}", arg))
+                }
+                self.relocations.push(Relocation {
+                    addr: self.data_base() + self.data.len() as u32,
+                    kind: RelocationKind::Word32,
+                    symbol: symbol.name,
+                    addend: symbol.addend,
+                });
+            }
+            if value < -(1i64 << (bits - 1)) || value >= 1i64 << bits {
+                return self.error(format!("{} does not fit in {} bits", value, bits))
+            }
+            let bytes = (value as u32).to_be_bytes();
+            self.data.extend_from_slice(&bytes[4 - size..]);
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, directive: &MIPSDirective) -> Result<(), AssemblerError> {
+        let directive_type = directive.directive_type.to_ascii_lowercase();
+        match directive_type.as_str() {
+            "text" | "data" => {
+                self.bind_labels()?;
+                self.segment = if directive_type == "text" { Segment::Text } else { Segment::Data };
+                self.set_kernel(false);
+                return Ok(())
+            }
+            "ktext" | "kdata" => {
+                if self.object {
+                    return self.error(format!(".{} cannot be used in an object", directive_type))
+                }
+                self.bind_labels()?;
+                let (segment, base) = if directive_type == "ktext" {
+                    (Segment::Text, KTEXT_BASE)
+                } else {
+                    (Segment::Data, KDATA_BASE)
+                };
+                if let Some(arg) = directive.directive_value.first() {
+                    if self.literal(arg)? != base as i64 {
+                        return self.error(format!(".{} can only be placed at 0x{:08x}", directive_type, base))
+                    }
+                }
+                self.segment = segment;
+                self.set_kernel(true);
+                return Ok(())
+            }
+            "globl" | "global" => {
+                self.globals.extend(directive.directive_value.iter().cloned());
+                return Ok(())
+            }
+            "extern" => return Ok(()),
+            "align" if self.segment == Segment::Text => return Ok(()),
+            _ => ()
+        }
+        if self.segment != Segment::Data {
+            return self.error(format!("Directive .{} is outside the data segment", directive.directive_type))
+        }
+        match directive_type.as_str() {
+            "align" => {
+                let power = self.single_literal(directive)?;
+                if !(0..=16).contains(&power) {
+                    return self.error(format!("Cannot align to 2^{} bytes", power))
+                }
+                self.align_data(1 << power);
+            }
+            "float" | "double" => {
+                let size = if directive_type == "float" { 4 } else { 8 };
+                self.align_data(size);
+                self.bind_labels()?;
+                for arg in &directive.directive_value {
+                    let value = self.float_literal(arg)?;
+                    if size == 4 {
+                        self.data.extend_from_slice(&(value as f32).to_bits().to_be_bytes());
+                    } else {
+                        self.data.extend_from_slice(&value.to_bits().to_be_bytes());
+                    }
+                }
+            }
+            "byte" => self.data_values(directive, 1)?,
+            "half" => self.data_values(directive, 2)?,
+            "word" => self.data_values(directive, 4)?,
+            "space" => {
+                let size = self.single_literal(directive)?;
+                if size < 0 {
+                    return self.error(format!("Cannot reserve {} bytes", size))
+                }
+                self.bind_labels()?;
+                self.data.resize(self.data.len() + size as usize, 0);
+            }
+            "ascii" | "asciiz" => {
+                self.bind_labels()?;
+                for arg in &directive.directive_value {
+                    match parse_quoted(arg, '"') {
+                        Some(bytes) => self.data.extend_from_slice(&bytes),
+                        None => return self.error(format!("Expected a quoted string, found {}", arg))
+                    }
+                    if directive_type == "asciiz" {
+                        self.data.push(0);
+                    }
+                }
+            }
+            _ => return self.error(format!("Unknown directive .{}", directive.directive_type))
+        }
+        Ok(())
+    }
+
+    fn single_literal(&self, directive: &MIPSDirective) -> Result<i64, AssemblerError> {
+        match &directive.directive_value[..] {
+            [arg] => self.literal(arg),
+            _ => self.error(format!(".{} expects one argument", directive.directive_type))
+        }
+    }
+}
+
+/// The first label, alphabetically, at each address.
+fn label_map(symbols: &HashMap<String, u32>) -> HashMap<u32, String> {
+    let mut labels = HashMap::new();
+    for (label, addr) in symbols {
+        let existing = labels.entry(*addr).or_insert_with(|| label.clone());
+        if label < existing {
+            *existing = label.clone();
+        }
+    }
+    labels
+}
+
+/// The startup routine that calls `main` and then exits, as SPIM's does.
+pub fn startup_code(main: u32) -> [Instruction; 3] {
+    [
+        Instruction::Jal {target: main},
+        Instruction::Addiu {rt: Rv0, rs: Rzero, imm: 10},
+        Instruction::Syscall,
+    ]
+}
+
+/// Assembles parsed components into a program. If `main` is defined, the
+/// entry point is the startup code, placed after the program's own text.
+pub fn assemble(components: &[MIPSComponent]) -> Result<Program, AssemblerError> {
+    assemble_with(components, false)
+}
+
+/// Assembles one file of a program to be linked with others. Labels it
+/// doesn't define are left as zero, to be filled in from its relocations,
+/// and there is no startup code.
+pub fn assemble_object(components: &[MIPSComponent]) -> Result<Program, AssemblerError> {
+    assemble_with(components, true)
+}
+
+fn encode(text: &[Instruction], locations: &[Option<MIPSLocation>], base: u32) -> Result<Vec<u32>, AssemblerError> {
+    let mut words = Vec::with_capacity(text.len());
+    for (i, instruction) in text.iter().enumerate() {
+        match instruction.encode(base + 4 * i as u32) {
+            Some(word) => words.push(word),
+            None => return Err(AssemblerError {
+                message: "Branch or jump target is out of range".to_string(),
+                location: locations[i].clone(),
+            })
+        }
+    }
+    Ok(words)
+}
+
+fn assemble_with(components: &[MIPSComponent], object: bool) -> Result<Program, AssemblerError> {
+    let mut layout = Assembler::new(None, object);
+    layout.run(components)?;
+    let symbols = layout.defined;
+
+    let mut assembler = Assembler::new(Some(&symbols), object);
+    assembler.run(components)?;
+
+    let mut text = assembler.text;
+    let mut locations = assembler.locations;
+    let kernel_text = assembler.other_text;
+    let kernel_locations = assembler.other_locations;
+    let entry = match symbols.get("main") {
+        Some(main) if !object => {
+            let entry = TEXT_BASE + 4 * text.len() as u32;
+            text.extend_from_slice(&startup_code(*main));
+            locations.resize(text.len(), None);
+            entry
+        }
+        _ => TEXT_BASE
+    };
+
+    let words = encode(&text, &locations, TEXT_BASE)?;
+    let kernel_words = encode(&kernel_text, &kernel_locations, KTEXT_BASE)?;
+
+    let labels = label_map(&symbols);
+    let globals = assembler.globals;
+    let relocations = assembler.relocations;
+    let data_alignment = assembler.data_alignment;
+    Ok(Program {
+        text,
+        words,
+        locations,
+        data: assembler.data,
+        data_spans: assembler.data_spans,
+        data_alignment,
+        kernel_text,
+        kernel_words,
+        kernel_locations,
+        kernel_data: assembler.other_data,
+        symbols,
+        globals,
+        relocations,
+        entry,
+        labels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mips_parser::read_str_to_state;
+
+    fn assemble_str(source: &str) -> Result<Program, AssemblerError> {
+        let (components, _) = read_str_to_state(source, "test.s");
+        assemble(&components)
+    }
+
+    #[test]
+    fn test_parse_int() {
+        assert_eq!(parse_int("42"), Some(42));
+        assert_eq!(parse_int("-0x10"), Some(-16));
+        assert_eq!(parse_int("'a'"), Some(97));
+        assert_eq!(parse_int("-'A'"), Some(-65));
+        assert_eq!(parse_int("'\\n'"), Some(10));
+        assert_eq!(parse_int("label"), None);
+        assert_eq!(parse_quoted("\"\\033[H\"", '"'), Some(vec![0o33, b'[', b'H']));
+    }
+
+    #[test]
+    fn test_layout() {
+        let program = assemble_str("
+            .data
+        value:  .byte 1
+        word:   .word value, 7
+        str:    .asciiz \"hi\"
+            .text
+        main:   li $t0, 5
+                la $t1, word
+                jr $ra
+        ").unwrap();
+        assert_eq!(program.symbols["value"], DATA_BASE);
+        assert_eq!(program.symbols["word"], DATA_BASE + 4);
+        assert_eq!(program.symbols["str"], DATA_BASE + 12);
+        assert_eq!(program.symbols["main"], TEXT_BASE);
+        assert_eq!(program.data, vec![1, 0, 0, 0, 0x10, 0x01, 0, 0, 0, 0, 0, 7, b'h', b'i', 0]);
+        let spans: Vec<(u32, usize, DataKind, usize)> = program.data_spans.iter()
+            .map(|span| (span.addr, span.len, span.kind, span.location.line_num))
+            .collect();
+        assert_eq!(spans, vec![
+            (DATA_BASE, 1, DataKind::Byte, 2),
+            (DATA_BASE + 4, 8, DataKind::Word, 3),
+            (DATA_BASE + 12, 3, DataKind::Asciiz, 4),
+        ]);
+        assert_eq!(program.text[..4], [
+            Instruction::Addiu {rt: Rt0, rs: Rzero, imm: 5},
+            Instruction::Lui {rt: Rat, imm: 0x1001},
+            Instruction::Addiu {rt: Rt1, rs: Rat, imm: 4},
+            Instruction::Jr {rs: Rra},
+        ]);
+        assert_eq!(program.entry, TEXT_BASE + 16);
+        assert_eq!(program.instruction_at(program.entry), Some(&Instruction::Jal {target: TEXT_BASE}));
+        assert_eq!(program.label_at(TEXT_BASE), Some("main"));
+        assert_eq!(program.location_at(TEXT_BASE + 4).unwrap().line_num, 7);
+        assert_eq!(program.location_at(program.entry), None);
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let program = assemble_str("
+        loop:   blt $t0, $t1, loop
+                bgt $t0, 3, loop
+                lw $t2, label+4
+                lw $t2, -4($sp)
+                addi $s0, -'A'
+                li $t3, 0x12345678
+        label:  nop
+        ").unwrap();
+        assert_eq!(program.text, vec![
+            Instruction::Slt {rd: Rat, rs: Rt0, rt: Rt1},
+            Instruction::Bne {rs: Rat, rt: Rzero, target: TEXT_BASE},
+            Instruction::Addiu {rt: Rat, rs: Rzero, imm: 3},
+            Instruction::Slt {rd: Rat, rs: Rat, rt: Rt0},
+            Instruction::Bne {rs: Rat, rt: Rzero, target: TEXT_BASE},
+            Instruction::Lui {rt: Rat, imm: 0x0040},
+            Instruction::Lw {rt: Rt2, base: Rat, offset: 0x30},
+            Instruction::Lw {rt: Rt2, base: Rsp, offset: -4},
+            Instruction::Addi {rt: Rs0, rs: Rs0, imm: -65},
+            Instruction::Lui {rt: Rat, imm: 0x1234},
+            Instruction::Ori {rt: Rt3, rs: Rat, imm: 0x5678},
+            Instruction::Sll {rd: Rzero, rt: Rzero, shamt: 0},
+        ]);
+    }
+
+    #[test]
+    fn test_floating_point() {
+        let program = assemble_str("
+                .data
+                .byte 1
+        one:    .float 1.0, -2.5
+        two:    .double 2
+                .text
+                l.d $f2, two
+                c.le.s 1, $f0, $f1
+                bc1f 1, end
+                li.s $f4, 1.5
+                cvt.d.w $f6, $f4
+        end:    swc1 $f4, 4($sp)
+        ").unwrap();
+        assert_eq!(program.symbols["one"], DATA_BASE + 4);
+        assert_eq!(program.symbols["two"], DATA_BASE + 16);
+        assert_eq!(&program.data[4..12], &[0x3f, 0x80, 0, 0, 0xc0, 0x20, 0, 0]);
+        assert_eq!(&program.data[16..24], &[0x40, 0, 0, 0, 0, 0, 0, 0]);
+        let (f, fmt) = (FloatRegister, FloatFormat::Single);
+        assert_eq!(program.text, vec![
+            Instruction::Lui {rt: Rat, imm: 0x1001},
+            Instruction::Ldc1 {ft: f(2), base: Rat, offset: 16},
+            Instruction::CompareFmt {cond: 14, fmt, cc: 1, fs: f(0), ft: f(1)},
+            Instruction::Bc1f {cc: 1, target: TEXT_BASE + 4 * 8},
+            Instruction::Lui {rt: Rat, imm: 0x3fc0},
+            Instruction::Ori {rt: Rat, rs: Rat, imm: 0},
+            Instruction::Mtc1 {rt: Rat, fs: f(4)},
+            Instruction::Cvt {to: FloatFormat::Double, from: FloatFormat::Word, fd: f(6), fs: f(4)},
+            Instruction::Swc1 {ft: f(4), base: Rsp, offset: 4},
+        ]);
+
+        let error = |source| assemble_str(source).err().unwrap().message;
+        assert_eq!(error("add.d $f0, $f1, $f2"), "add.d needs even-numbered registers for doubles");
+        assert_eq!(error("add.w $f0, $f1, $f2"), "Unknown instruction add.w");
+        assert_eq!(error("cvt.s.s $f0, $f1"), "Unknown instruction cvt.s.s");
+        assert_eq!(error("c.foo.s $f0, $f1"), "Unknown instruction c.foo.s");
+        assert_eq!(error("mov.s $f0, $t0"), "Expected a floating-point register, found $t0");
+        assert_eq!(error("bc1t 8, end\nend: nop"), "Condition flag 8 is not between 0 and 7");
+        assert_eq!(error(".data\n.float one"), "Expected a floating-point number, found one");
+    }
+
+    #[test]
+    fn test_kernel_segments() {
+        let program = assemble_str("
+                .kdata
+        saved:  .word 0
+                .text
+        main:   jr $ra
+                .ktext 0x80000180
+        handler:
+                sw $at, saved
+                mfc0 $k0, $14
+                eret
+                .data
+        value:  .word 1
+        ").unwrap();
+        assert_eq!(program.symbols["saved"], KDATA_BASE);
+        assert_eq!(program.symbols["handler"], KTEXT_BASE);
+        assert_eq!(program.symbols["value"], DATA_BASE);
+        assert_eq!(program.data, vec![0, 0, 0, 1]);
+        assert_eq!(program.kernel_data, vec![0; 4]);
+        assert_eq!(program.text.len(), 1 + 3);
+        assert_eq!(program.kernel_text, vec![
+            Instruction::Lui {rt: Rat, imm: 0x9000},
+            Instruction::Sw {rt: Rat, base: Rat, offset: 0},
+            Instruction::Mfc0 {rt: Rk0, rd: 14},
+            Instruction::Eret,
+        ]);
+        assert_eq!(program.instruction_at(KTEXT_BASE + 12), Some(&Instruction::Eret));
+        assert_eq!(program.location_at(KTEXT_BASE + 8).unwrap().line_num, 8);
+
+        let error = |source| assemble_str(source).err().unwrap().message;
+        assert_eq!(error(".ktext 0x80000000\neret"), ".ktext can only be placed at 0x80000180");
+        assert_eq!(error("mtc0 $t0, $32"), "Expected a coprocessor 0 register, found $32");
+        assert_eq!(error("break 0x100000"), "1048576 does not fit in 20 bits");
+        let (components, _) = read_str_to_state(".kdata\n.word 0", "test.s");
+        assert_eq!(assemble_object(&components).err().unwrap().message, ".kdata cannot be used in an object");
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = assemble_str("main: j missing").err().unwrap();
+        assert_eq!(error.message, "Label missing is not defined");
+        assert_eq!(error.location.unwrap().line_num, 0);
+        assert_eq!(assemble_str("addi $t0, $t1, $t2").err().unwrap().message, "addi expects an immediate, found $t2");
+        assert_eq!(assemble_str("frob $t0").err().unwrap().message, "Unknown instruction frob");
+        assert_eq!(assemble_str("a: nop\na: nop").err().unwrap().message, "Label a is defined more than once");
+        assert!(assemble_str(".data\nnop").is_err());
+        let error = assemble_str(".data\nfar: .word 0\n.text\nnop\nbeq $t0, $t1, far").err().unwrap();
+        assert_eq!(error.message, "Branch or jump target is out of range");
+        assert_eq!(error.location.unwrap().line_num, 4);
+    }
+
+    #[test]
+    fn test_object() {
+        let (components, _) = read_str_to_state(".globl main\nmain: la $a0, msg+2\nj exit\n.data\nmsg: .word exit", "test.s");
+        let program = assemble_object(&components).unwrap();
+        assert_eq!(program.entry, TEXT_BASE);
+        assert_eq!(program.text.len(), 3);
+        assert!(program.globals.contains("main"));
+        assert_eq!(program.relocations, vec![
+            Relocation {addr: TEXT_BASE, kind: RelocationKind::Hi16, symbol: "msg".to_string(), addend: 2},
+            Relocation {addr: TEXT_BASE + 4, kind: RelocationKind::Lo16, symbol: "msg".to_string(), addend: 2},
+            Relocation {addr: TEXT_BASE + 8, kind: RelocationKind::Jump26, symbol: "exit".to_string(), addend: 0},
+            Relocation {addr: DATA_BASE, kind: RelocationKind::Word32, symbol: "exit".to_string(), addend: 0},
+        ]);
+        let (components, _) = read_str_to_state("beq $t0, $t1, exit", "test.s");
+        assert_eq!(assemble_object(&components).err().unwrap().message, "Cannot branch to undefined label exit, use j or jal");
+    }
+}
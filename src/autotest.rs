@@ -0,0 +1,514 @@
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::assembler::{assemble, assemble_object};
+use super::console::Console;
+use super::exceptions::add_default_handler;
+use super::linker::link;
+use super::mips_parser::{read_str_to_state, MIPSComponent};
+use super::object::Object;
+use super::runtime::Runtime;
+use super::utils::json::json_string;
+use super::utils::xml::xml_escape;
+use super::watchdog::Watchdog;
+
+/// The step limit for a case that doesn't set its own, so one that never
+/// finishes fails rather than holding up the rest.
+pub const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+
+/// Lines of unchanged output shown around each change in a diff.
+const CONTEXT: usize = 3;
+
+/// Beyond this many lines expected times lines printed, a diff doesn't look
+/// for the lines in common and shows all of one replaced by the other.
+const MAX_DIFF_CELLS: usize = 1 << 22;
+
+/// One program to run and what it should do.
+///
+/// In a directory of tests, a case is a file `<name>.s`, or a directory
+/// `<name>/` whose `.s` files are linked together. Beside it, optionally:
+/// `<name>.args` holds flags for running it (`--exceptions`,
+/// `--max-steps <n>`, `--timeout <seconds>` and `--detect-stalls`), then
+/// any arguments for the program, after a `--` if the first starts with
+/// `-`. The program gets them as SPIM's argc and argv, after the case's
+/// name. `<name>.stdin` holds its input, `<name>.stdout` the output it
+/// should print and `<name>.exit` the status it should exit with,
+/// otherwise 0.
+#[derive(Debug, Default, PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    /// Each source file's name and contents.
+    pub sources: Vec<(String, String)>,
+    pub args: Vec<String>,
+    pub stdin: Vec<u8>,
+    /// The output to expect, or `None` not to check it.
+    pub expected_stdout: Option<Vec<u8>>,
+    pub expected_exit: i32,
+}
+
+/// What running a case found.
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    /// Every way the case failed; none if it passed.
+    pub failures: Vec<String>,
+    /// A unified diff from the expected output to what was printed, if they
+    /// differ.
+    pub diff: Option<String>,
+    pub time: Duration,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+fn read_optional(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+fn read_sources(paths: &[std::path::PathBuf]) -> io::Result<Vec<(String, String)>> {
+    paths.iter().map(|path| Ok((path.display().to_string(), fs::read_to_string(path)?))).collect()
+}
+
+/// Finds the test cases in `dir`, in order of name.
+pub fn discover(dir: &Path) -> io::Result<Vec<TestCase>> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<_>>()?;
+    entries.sort();
+    let mut cases = Vec::new();
+    for path in entries {
+        let (name, sources) = if path.is_dir() {
+            let mut files: Vec<_> = fs::read_dir(&path)?.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<_>>()?;
+            files.retain(|file| file.extension().is_some_and(|extension| extension == "s"));
+            files.sort();
+            if files.is_empty() {
+                continue
+            }
+            (path.file_name(), read_sources(&files)?)
+        } else if path.extension().is_some_and(|extension| extension == "s") {
+            (path.file_stem(), read_sources(std::slice::from_ref(&path))?)
+        } else {
+            continue
+        };
+        let name = name.unwrap_or_default().to_string_lossy().into_owned();
+        let sidecar = |extension| path.with_file_name(format!("{}.{}", name, extension));
+        let args = read_optional(&sidecar("args"))?.unwrap_or_default();
+        let expected_exit = match read_optional(&sidecar("exit"))? {
+            Some(exit) => String::from_utf8_lossy(&exit).trim().parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{} is not an exit status", sidecar("exit").display()))
+            })?,
+            None => 0,
+        };
+        cases.push(TestCase {
+            args: String::from_utf8_lossy(&args).split_whitespace().map(|arg| arg.to_string()).collect(),
+            stdin: read_optional(&sidecar("stdin"))?.unwrap_or_default(),
+            expected_stdout: read_optional(&sidecar("stdout"))?,
+            expected_exit,
+            name,
+            sources,
+        });
+    }
+    Ok(cases)
+}
+
+/// How to run a case, from its args.
+struct Options {
+    default_handler: bool,
+    detect_stalls: bool,
+    watchdog: Watchdog,
+    /// The program's own arguments, after the runner's flags.
+    arguments: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options { default_handler: false, detect_stalls: false, watchdog: Watchdog::new(), arguments: Vec::new() };
+    let mut max_steps = DEFAULT_MAX_STEPS;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => options.arguments.extend(args.by_ref().cloned()),
+            arg if !arg.starts_with('-') => {
+                options.arguments.push(arg.to_string());
+                options.arguments.extend(args.by_ref().cloned());
+            }
+            "--exceptions" => options.default_handler = true,
+            "--detect-stalls" => options.detect_stalls = true,
+            "--max-steps" => match args.next().and_then(|steps| steps.parse().ok()) {
+                Some(steps) => max_steps = steps,
+                None => return Err("--max-steps requires a number of instructions".to_string()),
+            },
+            "--timeout" => match args.next().and_then(|seconds| Duration::try_from_secs_f64(seconds.parse().ok()?).ok()) {
+                Some(timeout) => options.watchdog.set_timeout(timeout),
+                None => return Err("--timeout requires a number of seconds".to_string()),
+            },
+            arg => return Err(format!("unknown flag {}", arg)),
+        }
+    }
+    options.watchdog.set_max_steps(max_steps);
+    Ok(options)
+}
+
+/// Assembles a case's sources, linking them if there are several, and
+/// loads them into `runtime`.
+fn load(case: &TestCase, default_handler: bool, runtime: &mut Runtime) -> Result<(), String> {
+    let mut files: Vec<Vec<MIPSComponent>> = case.sources.iter()
+        .map(|(file_name, source)| read_str_to_state(source, file_name).0)
+        .collect();
+    match files.first_mut() {
+        Some(components) if default_handler => add_default_handler(components),
+        Some(_) => {}
+        None => return Err("no source files".to_string()),
+    }
+    if files.len() == 1 {
+        let program = assemble(&files[0]).map_err(|error| error.to_string())?;
+        return runtime.load_program(program).map_err(|error| error.to_string())
+    }
+    let mut objects = Vec::new();
+    for components in &files {
+        let program = assemble_object(components).map_err(|error| error.to_string())?;
+        objects.push(Object::from_program(&program));
+    }
+    let elf = link(&objects).map_err(|error| error.to_string())?;
+    runtime.load_elf(&elf).map_err(|error| error.to_string())
+}
+
+/// Runs a case in a fresh runtime, checking its exit status and output.
+pub fn run_case(case: &TestCase) -> TestResult {
+    let start = Instant::now();
+    let mut result = TestResult { name: case.name.clone(), failures: Vec::new(), diff: None, time: Duration::ZERO };
+    let mut options = match parse_args(&case.args) {
+        Ok(options) => options,
+        Err(error) => {
+            result.failures.push(error);
+            return result
+        }
+    };
+    let mut runtime = Runtime::with_undo_capacity(0);
+    runtime.set_console(Console::scripted(&case.stdin));
+    let mut arguments = vec![case.name.clone()];
+    arguments.append(&mut options.arguments);
+    let loaded = load(case, options.default_handler, &mut runtime)
+        .and_then(|()| runtime.set_arguments(&arguments).map_err(|error| error.to_string()));
+    if let Err(error) = loaded {
+        result.failures.push(error);
+        result.time = start.elapsed();
+        return result
+    }
+    if options.detect_stalls {
+        options.watchdog.detect_stalls(&mut runtime);
+    }
+    match options.watchdog.run(&mut runtime) {
+        Ok(code) if code != case.expected_exit => {
+            result.failures.push(format!("exited with {}, expected {}", code, case.expected_exit));
+        }
+        Ok(_) => {}
+        Err(error) => match runtime.current_location() {
+            Some(location) => result.failures.push(format!("{} at {}", error, location)),
+            None => result.failures.push(error.to_string()),
+        },
+    }
+    if let Some(expected) = &case.expected_stdout {
+        let printed = runtime.console().captured_output().unwrap_or_default();
+        if printed != expected.as_slice() {
+            result.failures.push("printed the wrong output".to_string());
+            result.diff = Some(unified_diff(&String::from_utf8_lossy(expected), &String::from_utf8_lossy(printed)));
+        }
+    }
+    result.time = start.elapsed();
+    result
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Edit {
+    Same,
+    Removed,
+    Added,
+}
+
+/// The shortest edit from `old` to `new`, by the longest run of lines
+/// they have in common.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Edit, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+    let mut edits: Vec<(Edit, &str)> = old[..prefix].iter().map(|line| (Edit::Same, *line)).collect();
+    let (n, m) = (old_middle.len(), new_middle.len());
+    if n * m > MAX_DIFF_CELLS {
+        edits.extend(old_middle.iter().map(|line| (Edit::Removed, *line)));
+        edits.extend(new_middle.iter().map(|line| (Edit::Added, *line)));
+    } else {
+        // common[i * (m + 1) + j] is the most lines old_middle[i..] and
+        // new_middle[j..] have in common.
+        let mut common = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                common[i * (m + 1) + j] = if old_middle[i] == new_middle[j] {
+                    common[(i + 1) * (m + 1) + j + 1] + 1
+                } else {
+                    common[(i + 1) * (m + 1) + j].max(common[i * (m + 1) + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old_middle[i] == new_middle[j] {
+                edits.push((Edit::Same, old_middle[i]));
+                i += 1;
+                j += 1;
+            } else if i < n && (j == m || common[(i + 1) * (m + 1) + j] >= common[i * (m + 1) + j + 1]) {
+                edits.push((Edit::Removed, old_middle[i]));
+                i += 1;
+            } else {
+                edits.push((Edit::Added, new_middle[j]));
+                j += 1;
+            }
+        }
+    }
+    edits.extend(old[old.len() - suffix..].iter().map(|line| (Edit::Same, *line)));
+    edits
+}
+
+/// A unified diff from `expected` to `actual`, or nothing if they are the
+/// same.
+pub fn unified_diff(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.split_inclusive('\n').collect();
+    let new: Vec<&str> = actual.split_inclusive('\n').collect();
+    let edits = diff_lines(&old, &new);
+    let changes: Vec<usize> = (0..edits.len()).filter(|&i| edits[i].0 != Edit::Same).collect();
+    if changes.is_empty() {
+        return String::new()
+    }
+    // The line of each side that each edit starts at.
+    let mut lines = Vec::with_capacity(edits.len() + 1);
+    let (mut old_line, mut new_line) = (0, 0);
+    for (edit, _) in &edits {
+        lines.push((old_line, new_line));
+        old_line += (*edit != Edit::Added) as usize;
+        new_line += (*edit != Edit::Removed) as usize;
+    }
+    lines.push((old_line, new_line));
+
+    let mut diff = "--- expected\n+++ actual\n".to_string();
+    let mut k = 0;
+    while k < changes.len() {
+        let start = changes[k].saturating_sub(CONTEXT);
+        let mut end = changes[k] + 1;
+        k += 1;
+        while k < changes.len() && changes[k] - end <= 2 * CONTEXT {
+            end = changes[k] + 1;
+            k += 1;
+        }
+        let end = (end + CONTEXT).min(edits.len());
+        let old_count = lines[end].0 - lines[start].0;
+        let new_count = lines[end].1 - lines[start].1;
+        // An empty side starts at the line before, by convention.
+        let old_start = lines[start].0 + (old_count > 0) as usize;
+        let new_start = lines[start].1 + (new_count > 0) as usize;
+        writeln!(diff, "@@ -{},{} +{},{} @@", old_start, old_count, new_start, new_count).unwrap();
+        for (edit, line) in &edits[start..end] {
+            let mark = match edit {
+                Edit::Same => ' ',
+                Edit::Removed => '-',
+                Edit::Added => '+',
+            };
+            match line.strip_suffix('\n') {
+                Some(line) => writeln!(diff, "{}{}", mark, line).unwrap(),
+                None => writeln!(diff, "{}{}\n\\ No newline at end of file", mark, line).unwrap(),
+            }
+        }
+    }
+    diff
+}
+
+/// A JUnit XML report of `results`, for CI servers.
+pub fn junit(results: &[TestResult]) -> String {
+    let failures = results.iter().filter(|result| !result.passed()).count();
+    let time: Duration = results.iter().map(|result| result.time).sum();
+    let mut xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_string();
+    writeln!(xml, "<testsuite name=\"micah\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+        results.len(), failures, time.as_secs_f64()).unwrap();
+    for result in results {
+        write!(xml, "  <testcase name=\"{}\" classname=\"micah\" time=\"{:.3}\"",
+            xml_escape(&result.name), result.time.as_secs_f64()).unwrap();
+        if result.passed() {
+            xml.push_str("/>\n");
+            continue
+        }
+        writeln!(xml, ">\n    <failure message=\"{}\">{}</failure>\n  </testcase>",
+            xml_escape(&result.failures.join("; ")), xml_escape(result.diff.as_deref().unwrap_or_default())).unwrap();
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+/// A JSON report of `results`, for other tools.
+pub fn json(results: &[TestResult]) -> String {
+    let passed = results.iter().filter(|result| result.passed()).count();
+    let cases: Vec<String> = results.iter().map(|result| {
+        let failures: Vec<String> = result.failures.iter().map(|failure| json_string(failure)).collect();
+        let diff = result.diff.as_deref().map_or("null".to_string(), json_string);
+        format!("{{\"name\":{},\"passed\":{},\"time\":{:.3},\"failures\":[{}],\"diff\":{}}}",
+            json_string(&result.name), result.passed(), result.time.as_secs_f64(), failures.join(","), diff)
+    }).collect();
+    format!("{{\"tests\":{},\"passed\":{},\"failed\":{},\"cases\":[{}]}}\n",
+        results.len(), passed, results.len() - passed, cases.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn case(name: &str, source: &str) -> TestCase {
+        TestCase {
+            name: name.to_string(),
+            sources: vec![(format!("{}.s", name), source.to_string())],
+            ..TestCase::default()
+        }
+    }
+
+    #[test]
+    fn test_run_case() {
+        let echo = "
+        main:   li $v0, 5
+                syscall
+                move $a0, $v0
+                li $v0, 1
+                syscall
+                li $v0, 17
+                li $a0, 3
+                syscall
+        ";
+        let mut passing = case("echo", echo);
+        passing.stdin = b"42\n".to_vec();
+        passing.expected_stdout = Some(b"42".to_vec());
+        passing.expected_exit = 3;
+        let result = run_case(&passing);
+        assert!(result.passed(), "{:?}", result.failures);
+
+        let mut failing = case("echo", echo);
+        failing.stdin = b"41\n".to_vec();
+        failing.expected_stdout = Some(b"42".to_vec());
+        let result = run_case(&failing);
+        assert_eq!(result.failures, vec!["exited with 3, expected 0", "printed the wrong output"]);
+        assert_eq!(result.diff.unwrap(), concat!(
+            "--- expected\n+++ actual\n@@ -1,1 +1,1 @@\n",
+            "-42\n\\ No newline at end of file\n+41\n\\ No newline at end of file\n",
+        ));
+
+        let mut spinning = case("spin", "main: j main\n");
+        spinning.args = vec!["--max-steps".to_string(), "50".to_string()];
+        let result = run_case(&spinning);
+        assert_eq!(result.failures, vec!["stopped after the step limit of 50 instructions at spin.s:1: main: j main"]);
+
+        let result = run_case(&case("broken", "main: frob $t0\n"));
+        assert!(!result.passed());
+
+        // The program's arguments follow the runner's flags.
+        let mut counting = case("count", "main: move $a1, $a0\nli $v0, 17\nmove $a0, $a1\nsyscall\n");
+        counting.args = vec!["--max-steps".to_string(), "50".to_string(), "a".to_string(), "--max-steps".to_string()];
+        counting.expected_exit = 3;
+        let result = run_case(&counting);
+        assert!(result.passed(), "{:?}", result.failures);
+        counting.args = vec!["--".to_string(), "-n".to_string()];
+        counting.expected_exit = 2;
+        let result = run_case(&counting);
+        assert!(result.passed(), "{:?}", result.failures);
+        counting.args = vec!["--frob".to_string()];
+        assert_eq!(run_case(&counting).failures, vec!["unknown flag --frob"]);
+    }
+
+    #[test]
+    fn test_linked_case() {
+        let mut linked = TestCase {
+            name: "linked".to_string(),
+            sources: vec![
+                ("main.s".to_string(), ".globl main\nmain: li $a0, 4\njal square\nmove $a0, $v0\nli $v0, 1\nsyscall\nli $v0, 10\nsyscall\n".to_string()),
+                ("square.s".to_string(), ".globl square\nsquare: mul $v0, $a0, $a0\njr $ra\n".to_string()),
+            ],
+            ..TestCase::default()
+        };
+        linked.expected_stdout = Some(b"16".to_vec());
+        let result = run_case(&linked);
+        assert!(result.passed(), "{:?}", result.failures);
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let expected = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let actual = "1\n2\n3\nfour\n5\n6\n7\n8\n9\n10\n11\n12\n13\n";
+        assert_eq!(unified_diff(expected, actual), concat!(
+            "--- expected\n+++ actual\n",
+            "@@ -1,7 +1,7 @@\n 1\n 2\n 3\n-4\n+four\n 5\n 6\n 7\n",
+            "@@ -10,3 +10,4 @@\n 10\n 11\n 12\n+13\n",
+        ));
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n"), "");
+        assert_eq!(unified_diff("", "x\n"), "--- expected\n+++ actual\n@@ -0,0 +1,1 @@\n+x\n");
+    }
+
+    #[test]
+    fn test_discover() {
+        let dir = env::temp_dir().join(format!("micah-autotest-{}", process::id()));
+        fs::create_dir_all(dir.join("pair")).unwrap();
+        fs::write(dir.join("hello.s"), "main: jr $ra\n").unwrap();
+        fs::write(dir.join("hello.stdout"), "hi\n").unwrap();
+        fs::write(dir.join("hello.exit"), "2\n").unwrap();
+        fs::write(dir.join("hello.args"), "--max-steps 10\n").unwrap();
+        fs::write(dir.join("pair").join("b.s"), "b: jr $ra\n").unwrap();
+        fs::write(dir.join("pair").join("a.s"), "main: jr $ra\n").unwrap();
+        fs::write(dir.join("pair.stdin"), "1 2\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not a test\n").unwrap();
+        let cases = discover(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let cases = cases.unwrap();
+        assert_eq!(cases.len(), 2);
+        assert_eq!(cases[0].name, "hello");
+        assert_eq!(cases[0].args, vec!["--max-steps", "10"]);
+        assert_eq!(cases[0].expected_stdout, Some(b"hi\n".to_vec()));
+        assert_eq!(cases[0].expected_exit, 2);
+        assert_eq!(cases[1].name, "pair");
+        assert!(cases[1].sources[0].0.ends_with("a.s") && cases[1].sources[1].0.ends_with("b.s"));
+        assert_eq!(cases[1].stdin, b"1 2\n");
+        assert_eq!(cases[1].expected_stdout, None);
+    }
+
+    #[test]
+    fn test_reports() {
+        let results = vec![
+            TestResult { name: "good".to_string(), failures: Vec::new(), diff: None, time: Duration::from_millis(5) },
+            TestResult {
+                name: "<bad>".to_string(),
+                failures: vec!["exited with 1, expected 0".to_string()],
+                diff: Some("-a\n+b\n".to_string()),
+                time: Duration::from_millis(10),
+            },
+        ];
+        assert_eq!(junit(&results), concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<testsuite name=\"micah\" tests=\"2\" failures=\"1\" time=\"0.015\">\n",
+            "  <testcase name=\"good\" classname=\"micah\" time=\"0.005\"/>\n",
+            "  <testcase name=\"&lt;bad&gt;\" classname=\"micah\" time=\"0.010\">\n",
+            "    <failure message=\"exited with 1, expected 0\">-a\n+b\n</failure>\n",
+            "  </testcase>\n",
+            "</testsuite>\n",
+        ));
+        assert_eq!(json(&results), concat!(
+            "{\"tests\":2,\"passed\":1,\"failed\":1,\"cases\":[",
+            "{\"name\":\"good\",\"passed\":true,\"time\":0.005,\"failures\":[],\"diff\":null},",
+            "{\"name\":\"<bad>\",\"passed\":false,\"time\":0.010,\"failures\":[\"exited with 1, expected 0\"],\"diff\":\"-a\\n+b\\n\"}",
+            "]}\n",
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt;
+
+use super::mips_parser::MIPSLocation;
+use super::runtime::RegisterCodes;
+use super::runtime::RegisterCodes::*;
+
+/// The registers a callee must restore before `jr $ra`.
+pub const CALLEE_SAVED: [RegisterCodes; 9] = [Rs0, Rs1, Rs2, Rs3, Rs4, Rs5, Rs6, Rs7, Rsp];
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CallFrame {
+    /// The label that was jumped to, if the call named one (`jalr` does not).
+    pub function: Option<String>,
+    pub target: u32,
+    pub return_addr: u32,
+    /// Where the `jal`/`jalr` that created this frame was written.
+    pub call_site: Option<MIPSLocation>,
+    /// Values of `CALLEE_SAVED` at the call, if calling conventions are checked.
+    pub saved_registers: Option<[u32; 9]>,
+}
+
+/// A callee-saved register that held a different value on return than on call.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConventionViolation {
+    pub function: Option<String>,
+    pub register: RegisterCodes,
+    pub expected: u32,
+    pub found: u32,
+    /// The last instruction that wrote the register, if known.
+    pub clobbered_at: Option<MIPSLocation>,
+}
+
+impl fmt::Display for ConventionViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(function) => write!(f, "{}", function)?,
+            None => write!(f, "function")?,
+        }
+        write!(
+            f, " did not restore {} (was 0x{:08x}, now 0x{:08x})",
+            self.register, self.expected, self.found
+        )?;
+        if let Some(location) = &self.clobbered_at {
+            write!(f, ", last written at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+/// A shadow of the program's call stack, kept by watching `jal`/`jalr` and
+/// `jr $ra` rather than by trusting the contents of `$sp`.
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack { frames: Vec::new() }
+    }
+
+    pub fn push(&mut self, frame: CallFrame) {
+        self.frames.push(frame);
+    }
+
+    /// Handles a jump to `addr` through `$ra`. Frames are popped up to and
+    /// including the newest one returning to `addr`, so a function that skips
+    /// its callers' returns still leaves the stack consistent. Returns the
+    /// popped frames, newest first; a jump matching no frame pops nothing.
+    pub fn pop_to(&mut self, addr: u32) -> Vec<CallFrame> {
+        match self.frames.iter().rposition(|frame| frame.return_addr == addr) {
+            Some(index) => {
+                let mut popped = self.frames.split_off(index);
+                popped.reverse();
+                popped
+            }
+            None => Vec::new()
+        }
+    }
+
+    /// Discards the newest frame.
+    pub fn pop(&mut self) -> Option<CallFrame> {
+        self.frames.pop()
+    }
+
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Formats the stack newest frame first, one frame per line.
+    pub fn backtrace(&self) -> String {
+        let mut trace = String::new();
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            let name = match &frame.function {
+                Some(function) => function.clone(),
+                None => format!("0x{:08x}", frame.target),
+            };
+            trace.push_str(&format!("#{} {}", i, name));
+            if let Some(call_site) = &frame.call_site {
+                trace.push_str(&format!(" called from {}", call_site));
+            }
+            trace.push('\n');
+        }
+        trace
+    }
+}
+
+impl Default for CallStack {
+    fn default() -> CallStack {
+        CallStack::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(function: &str, return_addr: u32, line_num: usize) -> CallFrame {
+        CallFrame {
+            function: Some(function.to_string()),
+            target: 0,
+            return_addr,
+            call_site: Some(MIPSLocation {
+                file: "test.s".to_string(),
+                line_num,
+                line_text: format!("\tjal\t{}", function)
+            }),
+            saved_registers: None
+        }
+    }
+
+    #[test]
+    fn test_push_pop(){
+        let mut stack = CallStack::new();
+        stack.push(frame("outer", 0x10, 1));
+        stack.push(frame("inner", 0x20, 5));
+        assert_eq!(stack.pop_to(0x20), vec![frame("inner", 0x20, 5)]);
+        assert_eq!(stack.depth(), 1);
+        assert_eq!(stack.pop_to(0x99), vec![]);
+        assert_eq!(stack.depth(), 1);
+    }
+
+    #[test]
+    fn test_pop_skipped_frames(){
+        let mut stack = CallStack::new();
+        stack.push(frame("outer", 0x10, 1));
+        stack.push(frame("inner", 0x20, 5));
+        assert_eq!(stack.pop_to(0x10), vec![frame("inner", 0x20, 5), frame("outer", 0x10, 1)]);
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn test_backtrace(){
+        let mut stack = CallStack::new();
+        stack.push(frame("outer", 0x10, 1));
+        stack.push(frame("inner", 0x20, 5));
+        assert_eq!(
+            stack.backtrace(),
+            "#0 inner called from test.s:6: jal\tinner\n#1 outer called from test.s:2: jal\touter\n"
+        );
+    }
+}
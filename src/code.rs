@@ -1,9 +1,544 @@
-enum MIPSArguments {
-    MemoryAddr(),
-    Register(MIPSRegister)
+use std::fmt;
+
+use super::runtime::{FloatRegister, RegisterCodes};
+
+type Reg = RegisterCodes;
+type FReg = FloatRegister;
+
+/// The format a floating-point instruction works in: the `.s`, `.d` or `.w`
+/// in its name.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FloatFormat {
+    Single,
+    Double,
+    /// A 32-bit integer held in a floating-point register.
+    Word,
+}
+
+impl FloatFormat {
+    fn field(&self) -> u32 {
+        match self {
+            FloatFormat::Single => 0x10,
+            FloatFormat::Double => 0x11,
+            FloatFormat::Word => 0x14,
+        }
+    }
+
+    fn from_field(field: u32) -> Option<FloatFormat> {
+        match field {
+            0x10 => Some(FloatFormat::Single),
+            0x11 => Some(FloatFormat::Double),
+            0x14 => Some(FloatFormat::Word),
+            _ => None
+        }
+    }
+
+    pub fn from_suffix(suffix: &str) -> Option<FloatFormat> {
+        match suffix {
+            "s" => Some(FloatFormat::Single),
+            "d" => Some(FloatFormat::Double),
+            "w" => Some(FloatFormat::Word),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for FloatFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let suffix = match self {
+            FloatFormat::Single => "s",
+            FloatFormat::Double => "d",
+            FloatFormat::Word => "w",
+        };
+        write!(f, "{}", suffix)
+    }
+}
+
+/// The names of the 16 conditions `c.cond.fmt` can test, by the `cond`
+/// field. Bit 0 holds if the operands are unordered, bit 1 if they are
+/// equal and bit 2 if the first is less; bit 3 only selects whether a NaN
+/// signals, which micah doesn't trap on.
+pub const FLOAT_CONDITIONS: [&str; 16] = [
+    "f", "un", "eq", "ueq", "olt", "ult", "ole", "ule",
+    "sf", "ngle", "seq", "ngl", "lt", "nge", "le", "ngt",
+];
+
+/// A machine instruction. The assembler encodes these into MIPS32 machine
+/// words in the text segment, and the runtime decodes each word it fetches
+/// back into one so it can dispatch with a single `match`.
+///
+/// Pseudo-instructions such as `li` and `blt` are expanded by the assembler
+/// and never appear here. Branch and jump targets are absolute addresses.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Instruction {
+    Add { rd: Reg, rs: Reg, rt: Reg },
+    Addu { rd: Reg, rs: Reg, rt: Reg },
+    Sub { rd: Reg, rs: Reg, rt: Reg },
+    Subu { rd: Reg, rs: Reg, rt: Reg },
+    And { rd: Reg, rs: Reg, rt: Reg },
+    Or { rd: Reg, rs: Reg, rt: Reg },
+    Xor { rd: Reg, rs: Reg, rt: Reg },
+    Nor { rd: Reg, rs: Reg, rt: Reg },
+    Slt { rd: Reg, rs: Reg, rt: Reg },
+    Sltu { rd: Reg, rs: Reg, rt: Reg },
+    Sllv { rd: Reg, rt: Reg, rs: Reg },
+    Srlv { rd: Reg, rt: Reg, rs: Reg },
+    Srav { rd: Reg, rt: Reg, rs: Reg },
+    Sll { rd: Reg, rt: Reg, shamt: u8 },
+    Srl { rd: Reg, rt: Reg, shamt: u8 },
+    Sra { rd: Reg, rt: Reg, shamt: u8 },
+    Mul { rd: Reg, rs: Reg, rt: Reg },
+    Mult { rs: Reg, rt: Reg },
+    Multu { rs: Reg, rt: Reg },
+    Div { rs: Reg, rt: Reg },
+    Divu { rs: Reg, rt: Reg },
+    Mfhi { rd: Reg },
+    Mflo { rd: Reg },
+    Mthi { rs: Reg },
+    Mtlo { rs: Reg },
+    Jr { rs: Reg },
+    Jalr { rd: Reg, rs: Reg },
+    Syscall,
+    /// Raises a breakpoint exception; `code` is only there for the handler
+    /// to read.
+    Break { code: u32 },
+
+    Addi { rt: Reg, rs: Reg, imm: i16 },
+    Addiu { rt: Reg, rs: Reg, imm: i16 },
+    Slti { rt: Reg, rs: Reg, imm: i16 },
+    Sltiu { rt: Reg, rs: Reg, imm: i16 },
+    Andi { rt: Reg, rs: Reg, imm: u16 },
+    Ori { rt: Reg, rs: Reg, imm: u16 },
+    Xori { rt: Reg, rs: Reg, imm: u16 },
+    Lui { rt: Reg, imm: u16 },
+    Lb { rt: Reg, base: Reg, offset: i16 },
+    Lbu { rt: Reg, base: Reg, offset: i16 },
+    Lh { rt: Reg, base: Reg, offset: i16 },
+    Lhu { rt: Reg, base: Reg, offset: i16 },
+    Lw { rt: Reg, base: Reg, offset: i16 },
+    Sb { rt: Reg, base: Reg, offset: i16 },
+    Sh { rt: Reg, base: Reg, offset: i16 },
+    Sw { rt: Reg, base: Reg, offset: i16 },
+    Beq { rs: Reg, rt: Reg, target: u32 },
+    Bne { rs: Reg, rt: Reg, target: u32 },
+    Blez { rs: Reg, target: u32 },
+    Bgtz { rs: Reg, target: u32 },
+    Bltz { rs: Reg, target: u32 },
+    Bgez { rs: Reg, target: u32 },
+
+    J { target: u32 },
+    Jal { target: u32 },
+
+    AddFmt { fmt: FloatFormat, fd: FReg, fs: FReg, ft: FReg },
+    SubFmt { fmt: FloatFormat, fd: FReg, fs: FReg, ft: FReg },
+    MulFmt { fmt: FloatFormat, fd: FReg, fs: FReg, ft: FReg },
+    DivFmt { fmt: FloatFormat, fd: FReg, fs: FReg, ft: FReg },
+    SqrtFmt { fmt: FloatFormat, fd: FReg, fs: FReg },
+    AbsFmt { fmt: FloatFormat, fd: FReg, fs: FReg },
+    MovFmt { fmt: FloatFormat, fd: FReg, fs: FReg },
+    NegFmt { fmt: FloatFormat, fd: FReg, fs: FReg },
+    /// Converts `fs`, in format `from`, to format `to`.
+    Cvt { to: FloatFormat, from: FloatFormat, fd: FReg, fs: FReg },
+    RoundW { fmt: FloatFormat, fd: FReg, fs: FReg },
+    TruncW { fmt: FloatFormat, fd: FReg, fs: FReg },
+    CeilW { fmt: FloatFormat, fd: FReg, fs: FReg },
+    FloorW { fmt: FloatFormat, fd: FReg, fs: FReg },
+    /// Sets condition flag `cc` to whether `fs` and `ft` meet `cond`, an
+    /// index into `FLOAT_CONDITIONS`.
+    CompareFmt { cond: u8, fmt: FloatFormat, cc: u8, fs: FReg, ft: FReg },
+    Bc1t { cc: u8, target: u32 },
+    Bc1f { cc: u8, target: u32 },
+    Mfc1 { rt: Reg, fs: FReg },
+    Mtc1 { rt: Reg, fs: FReg },
+    Lwc1 { ft: FReg, base: Reg, offset: i16 },
+    Ldc1 { ft: FReg, base: Reg, offset: i16 },
+    Swc1 { ft: FReg, base: Reg, offset: i16 },
+    Sdc1 { ft: FReg, base: Reg, offset: i16 },
+
+    /// Reads coprocessor 0 register `rd`.
+    Mfc0 { rt: Reg, rd: u8 },
+    Mtc0 { rt: Reg, rd: u8 },
+    /// Returns from an exception handler to the EPC.
+    Eret,
+}
+
+const SPECIAL: u32 = 0x00;
+const REGIMM: u32 = 0x01;
+const SPECIAL2: u32 = 0x1c;
+const COP0: u32 = 0x10;
+const COP1: u32 = 0x11;
+/// The `fmt` field values of `mfc1`, `mtc1` and `bc1t`/`bc1f`.
+const MF: u32 = 0x00;
+const MT: u32 = 0x04;
+const BC: u32 = 0x08;
+
+fn r_type(op: u32, rs: Reg, rt: Reg, rd: Reg, shamt: u8, funct: u32) -> u32 {
+    op << 26 | rs.number() << 21 | rt.number() << 16 | rd.number() << 11 | (shamt as u32 & 31) << 6 | funct
+}
+
+/// A `COP1` instruction with a format: `fmt ft fs fd funct`.
+fn f_type(fmt: FloatFormat, ft: FReg, fs: FReg, fd: FReg, funct: u32) -> u32 {
+    COP1 << 26 | fmt.field() << 21 | ft.number() << 16 | fs.number() << 11 | fd.number() << 6 | funct
+}
+
+fn f_unary(fmt: FloatFormat, fd: FReg, fs: FReg, funct: u32) -> u32 {
+    f_type(fmt, FloatRegister(0), fs, fd, funct)
+}
+
+fn i_type(op: u32, rs: Reg, rt: Reg, imm: u16) -> u32 {
+    op << 26 | rs.number() << 21 | rt.number() << 16 | imm as u32
 }
 
-pub struct MIPSCodeInstruction {
-    args: Vec<MIPSArguments>,
-    run_func: fn(Vec<MIPSArguments>, MIPSRuntime) -> MIPSRuntime
+/// The 16-bit word offset from the instruction after `addr` to `target`.
+fn branch_offset(addr: u32, target: u32) -> Option<u16> {
+    let offset = (target as i64 - (addr as i64 + 4)) / 4;
+    if !target.is_multiple_of(4) || offset < i16::MIN as i64 || offset > i16::MAX as i64 {
+        return None
+    }
+    Some(offset as i16 as u16)
+}
+
+fn branch_target(addr: u32, offset: u16) -> u32 {
+    addr.wrapping_add(4).wrapping_add((offset as i16 as i32 as u32) << 2)
+}
+
+/// The 26-bit field of a jump, which keeps the top 4 bits of the pc.
+fn jump_index(addr: u32, target: u32) -> Option<u32> {
+    if !target.is_multiple_of(4) || (addr.wrapping_add(4) ^ target) & 0xf000_0000 != 0 {
+        return None
+    }
+    Some(target >> 2 & 0x03ff_ffff)
+}
+
+impl Instruction {
+    /// Whether this is a conditional branch, which may or may not be taken.
+    pub fn is_branch(&self) -> bool {
+        use Instruction::*;
+        matches!(self, Beq {..} | Bne {..} | Blez {..} | Bgtz {..} | Bltz {..} | Bgez {..} | Bc1t {..} | Bc1f {..})
+    }
+
+    /// Encodes the instruction as a MIPS32 machine word, as though it were
+    /// stored at `addr`. Returns `None` if a branch or jump target can't be
+    /// reached from `addr`.
+    pub fn encode(&self, addr: u32) -> Option<u32> {
+        use Instruction::*;
+        use RegisterCodes::Rzero;
+
+        Some(match *self {
+            Sll {rd, rt, shamt} => r_type(SPECIAL, Rzero, rt, rd, shamt, 0x00),
+            Srl {rd, rt, shamt} => r_type(SPECIAL, Rzero, rt, rd, shamt, 0x02),
+            Sra {rd, rt, shamt} => r_type(SPECIAL, Rzero, rt, rd, shamt, 0x03),
+            Sllv {rd, rt, rs} => r_type(SPECIAL, rs, rt, rd, 0, 0x04),
+            Srlv {rd, rt, rs} => r_type(SPECIAL, rs, rt, rd, 0, 0x06),
+            Srav {rd, rt, rs} => r_type(SPECIAL, rs, rt, rd, 0, 0x07),
+            Jr {rs} => r_type(SPECIAL, rs, Rzero, Rzero, 0, 0x08),
+            Jalr {rd, rs} => r_type(SPECIAL, rs, Rzero, rd, 0, 0x09),
+            Syscall => r_type(SPECIAL, Rzero, Rzero, Rzero, 0, 0x0c),
+            Break {code} => SPECIAL << 26 | (code & 0xf_ffff) << 6 | 0x0d,
+            Mfhi {rd} => r_type(SPECIAL, Rzero, Rzero, rd, 0, 0x10),
+            Mthi {rs} => r_type(SPECIAL, rs, Rzero, Rzero, 0, 0x11),
+            Mflo {rd} => r_type(SPECIAL, Rzero, Rzero, rd, 0, 0x12),
+            Mtlo {rs} => r_type(SPECIAL, rs, Rzero, Rzero, 0, 0x13),
+            Mult {rs, rt} => r_type(SPECIAL, rs, rt, Rzero, 0, 0x18),
+            Multu {rs, rt} => r_type(SPECIAL, rs, rt, Rzero, 0, 0x19),
+            Div {rs, rt} => r_type(SPECIAL, rs, rt, Rzero, 0, 0x1a),
+            Divu {rs, rt} => r_type(SPECIAL, rs, rt, Rzero, 0, 0x1b),
+            Add {rd, rs, rt} => r_type(SPECIAL, rs, rt, rd, 0, 0x20),
+            Addu {rd, rs, rt} => r_type(SPECIAL, rs, rt, rd, 0, 0x21),
+            Sub {rd, rs, rt} => r_type(SPECIAL, rs, rt, rd, 0, 0x22),
+            Subu {rd, rs, rt} => r_type(SPECIAL, rs, rt, rd, 0, 0x23),
+            And {rd, rs, rt} => r_type(SPECIAL, rs, rt, rd, 0, 0x24),
+            Or {rd, rs, rt} => r_type(SPECIAL, rs, rt, rd, 0, 0x25),
+            Xor {rd, rs, rt} => r_type(SPECIAL, rs, rt, rd, 0, 0x26),
+            Nor {rd, rs, rt} => r_type(SPECIAL, rs, rt, rd, 0, 0x27),
+            Slt {rd, rs, rt} => r_type(SPECIAL, rs, rt, rd, 0, 0x2a),
+            Sltu {rd, rs, rt} => r_type(SPECIAL, rs, rt, rd, 0, 0x2b),
+            Mul {rd, rs, rt} => r_type(SPECIAL2, rs, rt, rd, 0, 0x02),
+
+            Bltz {rs, target} => i_type(REGIMM, rs, Rzero, branch_offset(addr, target)?),
+            Bgez {rs, target} => i_type(REGIMM, rs, RegisterCodes::Rat, branch_offset(addr, target)?),
+            J {target} => 0x02 << 26 | jump_index(addr, target)?,
+            Jal {target} => 0x03 << 26 | jump_index(addr, target)?,
+            Beq {rs, rt, target} => i_type(0x04, rs, rt, branch_offset(addr, target)?),
+            Bne {rs, rt, target} => i_type(0x05, rs, rt, branch_offset(addr, target)?),
+            Blez {rs, target} => i_type(0x06, rs, Rzero, branch_offset(addr, target)?),
+            Bgtz {rs, target} => i_type(0x07, rs, Rzero, branch_offset(addr, target)?),
+            Addi {rt, rs, imm} => i_type(0x08, rs, rt, imm as u16),
+            Addiu {rt, rs, imm} => i_type(0x09, rs, rt, imm as u16),
+            Slti {rt, rs, imm} => i_type(0x0a, rs, rt, imm as u16),
+            Sltiu {rt, rs, imm} => i_type(0x0b, rs, rt, imm as u16),
+            Andi {rt, rs, imm} => i_type(0x0c, rs, rt, imm),
+            Ori {rt, rs, imm} => i_type(0x0d, rs, rt, imm),
+            Xori {rt, rs, imm} => i_type(0x0e, rs, rt, imm),
+            Lui {rt, imm} => i_type(0x0f, Rzero, rt, imm),
+            Lb {rt, base, offset} => i_type(0x20, base, rt, offset as u16),
+            Lh {rt, base, offset} => i_type(0x21, base, rt, offset as u16),
+            Lw {rt, base, offset} => i_type(0x23, base, rt, offset as u16),
+            Lbu {rt, base, offset} => i_type(0x24, base, rt, offset as u16),
+            Lhu {rt, base, offset} => i_type(0x25, base, rt, offset as u16),
+            Sb {rt, base, offset} => i_type(0x28, base, rt, offset as u16),
+            Sh {rt, base, offset} => i_type(0x29, base, rt, offset as u16),
+            Sw {rt, base, offset} => i_type(0x2b, base, rt, offset as u16),
+
+            AddFmt {fmt, fd, fs, ft} => f_type(fmt, ft, fs, fd, 0x00),
+            SubFmt {fmt, fd, fs, ft} => f_type(fmt, ft, fs, fd, 0x01),
+            MulFmt {fmt, fd, fs, ft} => f_type(fmt, ft, fs, fd, 0x02),
+            DivFmt {fmt, fd, fs, ft} => f_type(fmt, ft, fs, fd, 0x03),
+            SqrtFmt {fmt, fd, fs} => f_unary(fmt, fd, fs, 0x04),
+            AbsFmt {fmt, fd, fs} => f_unary(fmt, fd, fs, 0x05),
+            MovFmt {fmt, fd, fs} => f_unary(fmt, fd, fs, 0x06),
+            NegFmt {fmt, fd, fs} => f_unary(fmt, fd, fs, 0x07),
+            RoundW {fmt, fd, fs} => f_unary(fmt, fd, fs, 0x0c),
+            TruncW {fmt, fd, fs} => f_unary(fmt, fd, fs, 0x0d),
+            CeilW {fmt, fd, fs} => f_unary(fmt, fd, fs, 0x0e),
+            FloorW {fmt, fd, fs} => f_unary(fmt, fd, fs, 0x0f),
+            Cvt {to: FloatFormat::Single, from, fd, fs} => f_unary(from, fd, fs, 0x20),
+            Cvt {to: FloatFormat::Double, from, fd, fs} => f_unary(from, fd, fs, 0x21),
+            Cvt {to: FloatFormat::Word, from, fd, fs} => f_unary(from, fd, fs, 0x24),
+            CompareFmt {cond, fmt, cc, fs, ft} => {
+                f_type(fmt, ft, fs, FloatRegister(0), 0x30 | (cond as u32 & 15)) | (cc as u32 & 7) << 8
+            }
+            Bc1f {cc, target} => COP1 << 26 | BC << 21 | (cc as u32 & 7) << 18 | branch_offset(addr, target)? as u32,
+            Bc1t {cc, target} => COP1 << 26 | BC << 21 | (cc as u32 & 7) << 18 | 1 << 16 | branch_offset(addr, target)? as u32,
+            Mfc1 {rt, fs} => COP1 << 26 | MF << 21 | rt.number() << 16 | fs.number() << 11,
+            Mtc1 {rt, fs} => COP1 << 26 | MT << 21 | rt.number() << 16 | fs.number() << 11,
+            Lwc1 {ft, base, offset} => 0x31 << 26 | base.number() << 21 | ft.number() << 16 | offset as u16 as u32,
+            Ldc1 {ft, base, offset} => 0x35 << 26 | base.number() << 21 | ft.number() << 16 | offset as u16 as u32,
+            Swc1 {ft, base, offset} => 0x39 << 26 | base.number() << 21 | ft.number() << 16 | offset as u16 as u32,
+            Sdc1 {ft, base, offset} => 0x3d << 26 | base.number() << 21 | ft.number() << 16 | offset as u16 as u32,
+            Mfc0 {rt, rd} => COP0 << 26 | MF << 21 | rt.number() << 16 | (rd as u32 & 31) << 11,
+            Mtc0 {rt, rd} => COP0 << 26 | MT << 21 | rt.number() << 16 | (rd as u32 & 31) << 11,
+            Eret => COP0 << 26 | 1 << 25 | 0x18,
+        })
+    }
+
+    /// Decodes a MIPS32 machine word fetched from `addr`, or `None` if it
+    /// isn't an instruction micah can run.
+    pub fn decode(word: u32, addr: u32) -> Option<Instruction> {
+        use Instruction::*;
+
+        let op = word >> 26;
+        let rs = RegisterCodes::from_number(word >> 21);
+        let rt = RegisterCodes::from_number(word >> 16);
+        let rd = RegisterCodes::from_number(word >> 11);
+        let shamt = (word >> 6 & 31) as u8;
+        let funct = word & 0x3f;
+        let imm = word as u16;
+        let offset = imm as i16;
+        let target = branch_target(addr, imm);
+
+        Some(match op {
+            SPECIAL => match funct {
+                0x00 => Sll {rd, rt, shamt},
+                0x02 => Srl {rd, rt, shamt},
+                0x03 => Sra {rd, rt, shamt},
+                0x04 => Sllv {rd, rt, rs},
+                0x06 => Srlv {rd, rt, rs},
+                0x07 => Srav {rd, rt, rs},
+                0x08 => Jr {rs},
+                0x09 => Jalr {rd, rs},
+                0x0c => Syscall,
+                0x0d => Break {code: word >> 6 & 0xf_ffff},
+                0x10 => Mfhi {rd},
+                0x11 => Mthi {rs},
+                0x12 => Mflo {rd},
+                0x13 => Mtlo {rs},
+                0x18 => Mult {rs, rt},
+                0x19 => Multu {rs, rt},
+                0x1a => Div {rs, rt},
+                0x1b => Divu {rs, rt},
+                0x20 => Add {rd, rs, rt},
+                0x21 => Addu {rd, rs, rt},
+                0x22 => Sub {rd, rs, rt},
+                0x23 => Subu {rd, rs, rt},
+                0x24 => And {rd, rs, rt},
+                0x25 => Or {rd, rs, rt},
+                0x26 => Xor {rd, rs, rt},
+                0x27 => Nor {rd, rs, rt},
+                0x2a => Slt {rd, rs, rt},
+                0x2b => Sltu {rd, rs, rt},
+                _ => return None
+            },
+            REGIMM => match word >> 16 & 31 {
+                0x00 => Bltz {rs, target},
+                0x01 => Bgez {rs, target},
+                _ => return None
+            },
+            SPECIAL2 if funct == 0x02 => Mul {rd, rs, rt},
+            0x02 => J {target: addr.wrapping_add(4) & 0xf000_0000 | (word & 0x03ff_ffff) << 2},
+            0x03 => Jal {target: addr.wrapping_add(4) & 0xf000_0000 | (word & 0x03ff_ffff) << 2},
+            0x04 => Beq {rs, rt, target},
+            0x05 => Bne {rs, rt, target},
+            0x06 => Blez {rs, target},
+            0x07 => Bgtz {rs, target},
+            0x08 => Addi {rt, rs, imm: offset},
+            0x09 => Addiu {rt, rs, imm: offset},
+            0x0a => Slti {rt, rs, imm: offset},
+            0x0b => Sltiu {rt, rs, imm: offset},
+            0x0c => Andi {rt, rs, imm},
+            0x0d => Ori {rt, rs, imm},
+            0x0e => Xori {rt, rs, imm},
+            0x0f => Lui {rt, imm},
+            0x20 => Lb {rt, base: rs, offset},
+            0x21 => Lh {rt, base: rs, offset},
+            0x23 => Lw {rt, base: rs, offset},
+            0x24 => Lbu {rt, base: rs, offset},
+            0x25 => Lhu {rt, base: rs, offset},
+            0x28 => Sb {rt, base: rs, offset},
+            0x29 => Sh {rt, base: rs, offset},
+            0x2b => Sw {rt, base: rs, offset},
+            COP0 => match word >> 21 & 31 {
+                MF if word & 0x7ff == 0 => Mfc0 {rt, rd: (word >> 11 & 31) as u8},
+                MT if word & 0x7ff == 0 => Mtc0 {rt, rd: (word >> 11 & 31) as u8},
+                _ if word == COP0 << 26 | 1 << 25 | 0x18 => Eret,
+                _ => return None
+            },
+            COP1 => return decode_cop1(word, addr),
+            0x31 => Lwc1 {ft: FloatRegister::from_number(word >> 16), base: rs, offset},
+            0x35 => Ldc1 {ft: FloatRegister::from_number(word >> 16), base: rs, offset},
+            0x39 => Swc1 {ft: FloatRegister::from_number(word >> 16), base: rs, offset},
+            0x3d => Sdc1 {ft: FloatRegister::from_number(word >> 16), base: rs, offset},
+            _ => return None
+        }).filter(|instruction| instruction.doubles_are_even())
+    }
+
+    /// Whether every register the instruction reads or writes a double
+    /// through is even, since a double occupies an even-odd pair.
+    pub fn doubles_are_even(&self) -> bool {
+        use Instruction::*;
+        use FloatFormat::Double;
+
+        let even = |reg: &FReg| reg.0.is_multiple_of(2);
+        match self {
+            AddFmt {fmt: Double, fd, fs, ft} | SubFmt {fmt: Double, fd, fs, ft}
+            | MulFmt {fmt: Double, fd, fs, ft} | DivFmt {fmt: Double, fd, fs, ft} => even(fd) && even(fs) && even(ft),
+            SqrtFmt {fmt: Double, fd, fs} | AbsFmt {fmt: Double, fd, fs}
+            | MovFmt {fmt: Double, fd, fs} | NegFmt {fmt: Double, fd, fs} => even(fd) && even(fs),
+            RoundW {fmt: Double, fs, ..} | TruncW {fmt: Double, fs, ..}
+            | CeilW {fmt: Double, fs, ..} | FloorW {fmt: Double, fs, ..} => even(fs),
+            Cvt {to, from, fd, fs} => (*to != Double || even(fd)) && (*from != Double || even(fs)),
+            CompareFmt {fmt: Double, fs, ft, ..} => even(fs) && even(ft),
+            Ldc1 {ft, ..} | Sdc1 {ft, ..} => even(ft),
+            _ => true
+        }
+    }
+}
+
+fn decode_cop1(word: u32, addr: u32) -> Option<Instruction> {
+    use Instruction::*;
+
+    let ft = FloatRegister::from_number(word >> 16);
+    let fs = FloatRegister::from_number(word >> 11);
+    let fd = FloatRegister::from_number(word >> 6);
+    let funct = word & 0x3f;
+    match word >> 21 & 31 {
+        MF => return Some(Mfc1 {rt: RegisterCodes::from_number(word >> 16), fs}),
+        MT => return Some(Mtc1 {rt: RegisterCodes::from_number(word >> 16), fs}),
+        BC => {
+            let cc = (word >> 18 & 7) as u8;
+            let target = branch_target(addr, word as u16);
+            return Some(if word >> 16 & 1 == 1 { Bc1t {cc, target} } else { Bc1f {cc, target} })
+        }
+        _ => ()
+    }
+    let fmt = FloatFormat::from_field(word >> 21 & 31)?;
+    let arithmetic = fmt != FloatFormat::Word;
+    let instruction = match funct {
+        0x00 if arithmetic => AddFmt {fmt, fd, fs, ft},
+        0x01 if arithmetic => SubFmt {fmt, fd, fs, ft},
+        0x02 if arithmetic => MulFmt {fmt, fd, fs, ft},
+        0x03 if arithmetic => DivFmt {fmt, fd, fs, ft},
+        0x04 if arithmetic => SqrtFmt {fmt, fd, fs},
+        0x05 if arithmetic => AbsFmt {fmt, fd, fs},
+        0x06 if arithmetic => MovFmt {fmt, fd, fs},
+        0x07 if arithmetic => NegFmt {fmt, fd, fs},
+        0x0c if arithmetic => RoundW {fmt, fd, fs},
+        0x0d if arithmetic => TruncW {fmt, fd, fs},
+        0x0e if arithmetic => CeilW {fmt, fd, fs},
+        0x0f if arithmetic => FloorW {fmt, fd, fs},
+        0x20 if fmt != FloatFormat::Single => Cvt {to: FloatFormat::Single, from: fmt, fd, fs},
+        0x21 if fmt != FloatFormat::Double => Cvt {to: FloatFormat::Double, from: fmt, fd, fs},
+        0x24 if arithmetic => Cvt {to: FloatFormat::Word, from: fmt, fd, fs},
+        0x30..=0x3f if arithmetic => CompareFmt {cond: (funct & 15) as u8, fmt, cc: (word >> 8 & 7) as u8, fs, ft},
+        _ => return None
+    };
+    if instruction.doubles_are_even() { Some(instruction) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Instruction::*;
+    use super::super::runtime::RegisterCodes::*;
+
+    const ADDR: u32 = 0x0040_0010;
+
+    #[test]
+    fn test_known_encodings(){
+        // Checked against the encodings in the MIPS32 manual.
+        let cases = [
+            (Add {rd: Rt0, rs: Rt1, rt: Rt2}, 0x012a_4020),
+            (Addiu {rt: Rsp, rs: Rsp, imm: -8}, 0x27bd_fff8),
+            (Lw {rt: Rra, base: Rsp, offset: 4}, 0x8fbf_0004),
+            (Sll {rd: Rzero, rt: Rzero, shamt: 0}, 0x0000_0000),
+            (Jr {rs: Rra}, 0x03e0_0008),
+            (Syscall, 0x0000_000c),
+            (Lui {rt: Rat, imm: 0x1001}, 0x3c01_1001),
+            (Mul {rd: Rv0, rs: Rv0, rt: Rs0}, 0x7050_1002),
+            (Beq {rs: Rt0, rt: Rzero, target: ADDR}, 0x1100_ffff),
+            (Jal {target: 0x0040_0000}, 0x0c10_0000),
+            (AddFmt {fmt: FloatFormat::Single, fd: FloatRegister(0), fs: FloatRegister(1), ft: FloatRegister(2)}, 0x4602_0800),
+            (MulFmt {fmt: FloatFormat::Double, fd: FloatRegister(12), fs: FloatRegister(2), ft: FloatRegister(4)}, 0x4624_1302),
+            (Lwc1 {ft: FloatRegister(0), base: Rat, offset: 0}, 0xc420_0000),
+            (Mtc1 {rt: Rat, fs: FloatRegister(1)}, 0x4481_0800),
+            (CompareFmt {cond: 12, fmt: FloatFormat::Single, cc: 0, fs: FloatRegister(0), ft: FloatRegister(1)}, 0x4601_003c),
+            (Bc1t {cc: 0, target: ADDR}, 0x4501_ffff),
+            (Mfc0 {rt: Rk0, rd: 13}, 0x401a_6800),
+            (Mtc0 {rt: Rk0, rd: 14}, 0x409a_7000),
+            (Eret, 0x4200_0018),
+            (Break {code: 0}, 0x0000_000d),
+        ];
+        for (instruction, word) in cases.iter() {
+            assert_eq!(instruction.encode(ADDR), Some(*word), "encoding {:?}", instruction);
+            assert_eq!(Instruction::decode(*word, ADDR), Some(*instruction), "decoding {:08x}", word);
+        }
+    }
+
+    #[test]
+    fn test_round_trip(){
+        let instructions = [
+            Sub {rd: Rs0, rs: Rs1, rt: Rs2}, Sltu {rd: Rt9, rs: Ra0, rt: Ra1},
+            Srav {rd: Rv1, rt: Rt3, rs: Rt4}, Sra {rd: Rk0, rt: Rk1, shamt: 31},
+            Mult {rs: Rt0, rt: Rt1}, Divu {rs: Ra2, rt: Ra3}, Mfhi {rd: Rt5}, Mtlo {rs: Rt6},
+            Jalr {rd: Rra, rs: Rt7}, Slti {rt: Rt0, rs: Rt1, imm: -32768},
+            Xori {rt: Rt0, rs: Rt1, imm: 0xffff}, Sb {rt: Rt0, base: Rgp, offset: -1},
+            Lhu {rt: Rfp, base: Rsp, offset: 32767},
+            Bltz {rs: Rt0, target: ADDR + 4 + 4 * 32767}, Bgez {rs: Rt0, target: ADDR + 4 - 4 * 32768},
+            Blez {rs: Ra0, target: 0x0040_0000}, Bgtz {rs: Ra0, target: 0x0040_0100},
+            Bne {rs: Rt0, rt: Rt1, target: ADDR + 4}, J {target: 0x0fff_fffc},
+            SqrtFmt {fmt: FloatFormat::Double, fd: FloatRegister(30), fs: FloatRegister(2)},
+            Cvt {to: FloatFormat::Single, from: FloatFormat::Word, fd: FloatRegister(1), fs: FloatRegister(31)},
+            FloorW {fmt: FloatFormat::Single, fd: FloatRegister(3), fs: FloatRegister(5)},
+            CompareFmt {cond: 2, fmt: FloatFormat::Double, cc: 7, fs: FloatRegister(4), ft: FloatRegister(6)},
+            Bc1f {cc: 3, target: ADDR - 8}, Mfc1 {rt: Rv0, fs: FloatRegister(17)},
+            Sdc1 {ft: FloatRegister(10), base: Rsp, offset: -8},
+            Break {code: 0xf_ffff}, Mtc0 {rt: Rzero, rd: 12},
+        ];
+        for instruction in instructions.iter() {
+            let word = instruction.encode(ADDR).unwrap();
+            assert_eq!(Instruction::decode(word, ADDR), Some(*instruction));
+        }
+    }
+
+    #[test]
+    fn test_unreachable_targets(){
+        assert_eq!(Beq {rs: Rt0, rt: Rt1, target: ADDR + 4 + 4 * 32768}.encode(ADDR), None);
+        assert_eq!(Bne {rs: Rt0, rt: Rt1, target: ADDR + 2}.encode(ADDR), None);
+        assert_eq!(J {target: 0x1000_0000}.encode(ADDR), None);
+        assert_eq!(Instruction::decode(0x6666_6666, ADDR), None);
+        assert_eq!(Instruction::decode(0xffff_ffff, ADDR), None);
+        // add.d with an odd register, and add.w, don't exist.
+        assert_eq!(Instruction::decode(0x4622_0840, ADDR), None);
+        assert_eq!(Instruction::decode(0x4682_0800, ADDR), None);
+    }
 }
@@ -0,0 +1,469 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+
+use super::device::Device;
+
+#[derive(Debug, PartialEq)]
+pub enum ConsoleError {
+    EndOfInput,
+    InputDisabled,
+    Io(io::ErrorKind),
+}
+
+impl From<io::Error> for ConsoleError {
+    fn from(error: io::Error) -> ConsoleError {
+        ConsoleError::Io(error.kind())
+    }
+}
+
+pub enum ConsoleInput {
+    Stdin,
+    /// No input; reading is an error. Used while the mapped console's
+    /// keyboard owns stdin, so syscalls can't race it for bytes.
+    Disabled,
+    /// Bytes fed to the program in order, and how many have been read.
+    Scripted(Vec<u8>, usize),
+}
+
+pub enum ConsoleOutput {
+    Stdout,
+    Stderr,
+    Captured(Vec<u8>),
+}
+
+impl ConsoleOutput {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), ConsoleError> {
+        match self {
+            ConsoleOutput::Stdout => {
+                let mut stdout = io::stdout();
+                stdout.write_all(bytes)?;
+                stdout.flush()?;
+            }
+            ConsoleOutput::Stderr => io::stderr().write_all(bytes)?,
+            ConsoleOutput::Captured(captured) => captured.extend_from_slice(bytes),
+        }
+        Ok(())
+    }
+
+    fn captured(&self) -> Option<&[u8]> {
+        match self {
+            ConsoleOutput::Captured(captured) => Some(captured),
+            _ => None
+        }
+    }
+}
+
+/// The program's view of stdin, stdout and stderr.
+pub struct Console {
+    input: ConsoleInput,
+    output: ConsoleOutput,
+    error: ConsoleOutput,
+}
+
+impl Console {
+    /// A console attached to the real stdin, stdout and stderr.
+    pub fn new() -> Console {
+        Console {
+            input: ConsoleInput::Stdin,
+            output: ConsoleOutput::Stdout,
+            error: ConsoleOutput::Stderr,
+        }
+    }
+
+    /// A console that reads `input` and captures everything written to it.
+    pub fn scripted(input: &[u8]) -> Console {
+        Console {
+            input: ConsoleInput::Scripted(input.to_vec(), 0),
+            output: ConsoleOutput::Captured(Vec::new()),
+            error: ConsoleOutput::Captured(Vec::new()),
+        }
+    }
+
+    pub fn set_input(&mut self, input: ConsoleInput) {
+        self.input = input;
+    }
+
+    pub fn set_output(&mut self, output: ConsoleOutput) {
+        self.output = output;
+    }
+
+    pub fn set_error(&mut self, error: ConsoleOutput) {
+        self.error = error;
+    }
+
+    /// Everything written to stdout so far, if it is being captured.
+    pub fn captured_output(&self) -> Option<&[u8]> {
+        self.output.captured()
+    }
+
+    /// Everything written to stderr so far, if it is being captured.
+    pub fn captured_error(&self) -> Option<&[u8]> {
+        self.error.captured()
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, ConsoleError> {
+        match &mut self.input {
+            ConsoleInput::Stdin => {
+                let mut byte = [0];
+                match io::stdin().read(&mut byte)? {
+                    0 => Ok(None),
+                    _ => Ok(Some(byte[0]))
+                }
+            }
+            ConsoleInput::Disabled => Err(ConsoleError::InputDisabled),
+            ConsoleInput::Scripted(bytes, pos) => {
+                let byte = bytes.get(*pos).cloned();
+                if byte.is_some() {
+                    *pos += 1;
+                }
+                Ok(byte)
+            }
+        }
+    }
+
+    pub fn read_char(&mut self) -> Result<u8, ConsoleError> {
+        self.read_byte()?.ok_or(ConsoleError::EndOfInput)
+    }
+
+    /// Reads like SPIM's `read_string`: up to `max_len - 1` bytes, stopping
+    /// after a newline. Reading nothing because input has ended is an error.
+    pub fn read_line(&mut self, max_len: usize) -> Result<Vec<u8>, ConsoleError> {
+        let mut line = Vec::new();
+        while line.len() + 1 < max_len {
+            match self.read_byte()? {
+                Some(byte) => {
+                    line.push(byte);
+                    if byte == b'\n' {
+                        break
+                    }
+                }
+                None if line.is_empty() => return Err(ConsoleError::EndOfInput),
+                None => break
+            }
+        }
+        Ok(line)
+    }
+
+    /// Reads a line and parses the integer at its start, giving 0 if there
+    /// is none, as SPIM does.
+    pub fn read_int(&mut self) -> Result<i32, ConsoleError> {
+        let line = self.read_line(usize::MAX)?;
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_start();
+        let (negative, digits) = match line.as_bytes().first() {
+            Some(b'-') => (true, &line[1..]),
+            Some(b'+') => (false, &line[1..]),
+            _ => (false, line)
+        };
+        let mut value: i32 = 0;
+        for digit in digits.bytes().take_while(|c| c.is_ascii_digit()) {
+            value = value.wrapping_mul(10).wrapping_add((digit - b'0') as i32);
+        }
+        Ok(if negative { value.wrapping_neg() } else { value })
+    }
+
+    /// Reads a line and parses the number at its start like C's `atof`,
+    /// giving 0 if there is none, as SPIM does.
+    pub fn read_double(&mut self) -> Result<f64, ConsoleError> {
+        let line = self.read_line(usize::MAX)?;
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_start();
+        let candidate: String = line.chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || ['+', '-', '.'].contains(c))
+            .collect();
+        // The longest prefix that is a number, e.g. `2.5` out of `2.5e`.
+        let value = (1..=candidate.len()).rev()
+            .filter_map(|len| candidate[..len].parse::<f64>().ok())
+            .next();
+        Ok(value.unwrap_or(0.0))
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), ConsoleError> {
+        self.output.write(bytes)
+    }
+
+    pub fn write_error(&mut self, bytes: &[u8]) -> Result<(), ConsoleError> {
+        self.error.write(bytes)
+    }
+}
+
+/// Offsets of SPIM's memory-mapped console registers from where it is
+/// mapped, normally 0xffff0000.
+pub const RECEIVER_CONTROL: u32 = 0x0;
+pub const RECEIVER_DATA: u32 = 0x4;
+pub const TRANSMITTER_CONTROL: u32 = 0x8;
+pub const TRANSMITTER_DATA: u32 = 0xc;
+/// The bytes the mapped console takes up.
+pub const MAPPED_CONSOLE_LEN: usize = 0x10;
+
+/// Control register bits.
+pub const READY: u32 = 1 << 0;
+pub const INTERRUPT_ENABLE: u32 = 1 << 1;
+
+/// The Cause bits for the keyboard's and display's interrupts: hardware
+/// levels 1 and 0, as in SPIM.
+pub const RECEIVER_INTERRUPT: u32 = 1 << 11;
+pub const TRANSMITTER_INTERRUPT: u32 = 1 << 10;
+
+/// How many cycles the display takes to print a character before it is
+/// ready for another.
+pub const TRANSMIT_DELAY: u32 = 100;
+
+enum Keyboard {
+    /// Bytes from stdin, read on another thread so that polling the
+    /// receiver never blocks. The thread starts on the first tick.
+    Stdin(Option<mpsc::Receiver<u8>>),
+    Scripted(VecDeque<u8>),
+}
+
+/// SPIM's memory-mapped terminal: a keyboard behind the receiver registers
+/// and a display behind the transmitter registers. Each register is a word,
+/// and reading the receiver's data takes the waiting byte.
+pub struct MappedConsole {
+    keyboard: Keyboard,
+    display: ConsoleOutput,
+    /// The byte typed but not yet read, if any.
+    received: Option<u8>,
+    last_received: u8,
+    receiver_interrupts: bool,
+    /// Cycles until the display is ready again.
+    transmit_delay: u32,
+    transmitter_interrupts: bool,
+}
+
+impl MappedConsole {
+    /// A mapped console attached to the real stdin and stdout. Its keyboard
+    /// reads stdin on another thread, so syscalls shouldn't read it too.
+    pub fn new() -> MappedConsole {
+        MappedConsole::with(Keyboard::Stdin(None), ConsoleOutput::Stdout)
+    }
+
+    /// A mapped console that types `input` one byte at a time, each as soon
+    /// as the last has been read, and captures everything displayed.
+    pub fn scripted(input: &[u8]) -> MappedConsole {
+        MappedConsole::with(Keyboard::Scripted(input.iter().cloned().collect()), ConsoleOutput::Captured(Vec::new()))
+    }
+
+    fn with(keyboard: Keyboard, display: ConsoleOutput) -> MappedConsole {
+        MappedConsole {
+            keyboard,
+            display,
+            received: None,
+            last_received: 0,
+            receiver_interrupts: false,
+            transmit_delay: 0,
+            transmitter_interrupts: false,
+        }
+    }
+
+    /// Everything displayed so far, if it is being captured.
+    pub fn captured_output(&self) -> Option<&[u8]> {
+        self.display.captured()
+    }
+}
+
+impl Device for MappedConsole {
+    fn read(&mut self, offset: u32) -> u32 {
+        let control = |ready: bool, interrupts: bool| {
+            (if ready { READY } else { 0 }) | if interrupts { INTERRUPT_ENABLE } else { 0 }
+        };
+        match offset & !3 {
+            RECEIVER_CONTROL => control(self.received.is_some(), self.receiver_interrupts),
+            RECEIVER_DATA => {
+                if let Some(byte) = self.received.take() {
+                    self.last_received = byte;
+                }
+                self.last_received as u32
+            }
+            TRANSMITTER_CONTROL => control(self.transmit_delay == 0, self.transmitter_interrupts),
+            _ => 0
+        }
+    }
+
+    /// Only the interrupt enable bits and the transmitter's data can be
+    /// written.
+    fn write(&mut self, offset: u32, val: u32) {
+        match offset & !3 {
+            RECEIVER_CONTROL => self.receiver_interrupts = val & INTERRUPT_ENABLE != 0,
+            TRANSMITTER_CONTROL => self.transmitter_interrupts = val & INTERRUPT_ENABLE != 0,
+            TRANSMITTER_DATA => {
+                // Like a real terminal, the program can't tell if this fails.
+                let _ = self.display.write(&[val as u8]);
+                self.transmit_delay = TRANSMIT_DELAY;
+            }
+            _ => ()
+        }
+    }
+
+    /// Advances the console by one cycle: the display gets closer to being
+    /// ready, and the next byte is typed if the last has been read.
+    fn tick(&mut self) {
+        self.transmit_delay = self.transmit_delay.saturating_sub(1);
+        if self.received.is_some() {
+            return
+        }
+        self.received = match &mut self.keyboard {
+            Keyboard::Scripted(bytes) => bytes.pop_front(),
+            Keyboard::Stdin(receiver) => receiver.get_or_insert_with(spawn_stdin_reader).try_recv().ok(),
+        };
+    }
+
+    fn interrupts(&self) -> u32 {
+        let mut interrupts = 0;
+        if self.receiver_interrupts && self.received.is_some() {
+            interrupts |= RECEIVER_INTERRUPT;
+        }
+        if self.transmitter_interrupts && self.transmit_delay == 0 {
+            interrupts |= TRANSMITTER_INTERRUPT;
+        }
+        interrupts
+    }
+}
+
+impl Default for MappedConsole {
+    fn default() -> MappedConsole {
+        MappedConsole::new()
+    }
+}
+
+fn spawn_stdin_reader() -> mpsc::Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = [0; 256];
+        while let Ok(len @ 1..) = io::stdin().read(&mut buffer) {
+            if buffer[..len].iter().any(|byte| sender.send(*byte).is_err()) {
+                break
+            }
+        }
+    });
+    receiver
+}
+
+/// C's spelling of infinities and NaNs, which Rust spells differently.
+fn format_special(value: f64) -> Option<String> {
+    let sign = if value.is_sign_negative() { "-" } else { "" };
+    if value.is_nan() {
+        Some(format!("{}nan", sign))
+    } else if value.is_infinite() {
+        Some(format!("{}inf", sign))
+    } else {
+        None
+    }
+}
+
+/// Formats a float as SPIM's `print_float` does, with C's `%.8f`.
+pub fn format_float(value: f32) -> String {
+    let value = value as f64;
+    format_special(value).unwrap_or_else(|| format!("{:.8}", value))
+}
+
+/// Formats a double as SPIM's `print_double` does, with C's `%.18g`.
+pub fn format_double(value: f64) -> String {
+    const PRECISION: i32 = 18;
+    if let Some(special) = format_special(value) {
+        return special
+    }
+    let scientific = format!("{:.*e}", PRECISION as usize - 1, value);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    let trim = |number: &str| {
+        if number.contains('.') {
+            number.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            number.to_string()
+        }
+    };
+    if !(-4..PRECISION).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa), sign, exponent.abs())
+    } else {
+        trim(&format!("{:.*}", (PRECISION - 1 - exponent) as usize, value))
+    }
+}
+
+impl Default for Console {
+    fn default() -> Console {
+        Console::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripted_reads(){
+        let mut console = Console::scripted(b"  -42 apples\nhello\nxy");
+        assert_eq!(console.read_int(), Ok(-42));
+        assert_eq!(console.read_line(100), Ok(b"hello\n".to_vec()));
+        assert_eq!(console.read_line(2), Ok(b"x".to_vec()));
+        assert_eq!(console.read_char(), Ok(b'y'));
+        assert_eq!(console.read_char(), Err(ConsoleError::EndOfInput));
+        assert_eq!(console.read_int(), Err(ConsoleError::EndOfInput));
+
+        console.set_input(ConsoleInput::Disabled);
+        assert_eq!(console.read_char(), Err(ConsoleError::InputDisabled));
+    }
+
+    #[test]
+    fn test_read_double(){
+        let mut console = Console::scripted(b" -2.5e3x\n.5\nfoo\n1e\n");
+        assert_eq!(console.read_double(), Ok(-2500.0));
+        assert_eq!(console.read_double(), Ok(0.5));
+        assert_eq!(console.read_double(), Ok(0.0));
+        assert_eq!(console.read_double(), Ok(1.0));
+        assert_eq!(console.read_double(), Err(ConsoleError::EndOfInput));
+    }
+
+    #[test]
+    fn test_format_floats(){
+        assert_eq!(format_float(1.5), "1.50000000");
+        assert_eq!(format_float(0.1), "0.10000000");
+        assert_eq!(format_float(-1.0 / 0.0), "-inf");
+        assert_eq!(format_double(0.1), "0.100000000000000006");
+        assert_eq!(format_double(2.5), "2.5");
+        assert_eq!(format_double(-0.0), "-0");
+        assert_eq!(format_double(1e100), "1.00000000000000002e+100");
+        assert_eq!(format_double(1.5e-7), "1.49999999999999993e-07");
+        assert_eq!(format_double(123456.0), "123456");
+        assert_eq!(format_double(f64::NAN), "nan");
+    }
+
+    #[test]
+    fn test_captured_output(){
+        let mut console = Console::scripted(b"");
+        console.write(b"out").unwrap();
+        console.write_error(b"err").unwrap();
+        console.write(b"put").unwrap();
+        assert_eq!(console.captured_output(), Some(&b"output"[..]));
+        assert_eq!(console.captured_error(), Some(&b"err"[..]));
+        assert_eq!(Console::new().captured_output(), None);
+    }
+
+    #[test]
+    fn test_mapped_console(){
+        let mut console = MappedConsole::scripted(b"a");
+        console.write(RECEIVER_CONTROL, INTERRUPT_ENABLE);
+        assert_eq!(console.read(TRANSMITTER_CONTROL), READY);
+        assert_eq!(console.interrupts(), 0);
+        console.tick();
+        assert_eq!(console.read(RECEIVER_CONTROL), READY | INTERRUPT_ENABLE);
+        assert_eq!(console.interrupts(), RECEIVER_INTERRUPT);
+        assert_eq!(console.read(RECEIVER_DATA), b'a' as u32);
+        assert_eq!(console.read(RECEIVER_CONTROL), INTERRUPT_ENABLE);
+        assert_eq!(console.read(RECEIVER_DATA), b'a' as u32);
+        assert_eq!(console.interrupts(), 0);
+
+        console.write(TRANSMITTER_CONTROL, INTERRUPT_ENABLE);
+        console.write(TRANSMITTER_DATA, b'b' as u32);
+        assert_eq!(console.read(TRANSMITTER_CONTROL), INTERRUPT_ENABLE);
+        for _ in 0..TRANSMIT_DELAY {
+            console.tick();
+        }
+        assert_eq!(console.read(TRANSMITTER_CONTROL), READY | INTERRUPT_ENABLE);
+        assert_eq!(console.interrupts(), TRANSMITTER_INTERRUPT);
+        assert_eq!(console.captured_output(), Some(&b"b"[..]));
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use super::assembler::{Program, KTEXT_BASE, TEXT_BASE};
+use super::mips_parser::MIPSLocation;
+use super::observer::Observer;
+
+/// How often one conditional branch went each way.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BranchCount {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// A branch instruction and the source line it came from.
+struct Branch {
+    file: String,
+    line: usize,
+    count: BranchCount,
+}
+
+/// Records which source lines a runtime executes and which way each
+/// conditional branch goes, for coverage reports.
+///
+/// Lines count from one, as editors and coverage viewers do.
+pub struct Coverage {
+    /// How many instructions were executed from each line of each file.
+    lines: BTreeMap<String, BTreeMap<usize, u64>>,
+    branches: HashMap<u32, Branch>,
+}
+
+impl Coverage {
+    /// Covers `program`: every line it has code for, and every branch, starts
+    /// out never executed.
+    pub fn new(program: &Program) -> Coverage {
+        let mut coverage = Coverage { lines: BTreeMap::new(), branches: HashMap::new() };
+        let segments = [
+            (TEXT_BASE, &program.text, &program.locations),
+            (KTEXT_BASE, &program.kernel_text, &program.kernel_locations),
+        ];
+        for (base, text, locations) in segments {
+            for (i, (instruction, location)) in text.iter().zip(locations).enumerate() {
+                let location = match location {
+                    Some(location) => location,
+                    None => continue
+                };
+                coverage.lines.entry(location.file.clone()).or_default().entry(location.line_num + 1).or_insert(0);
+                if instruction.is_branch() {
+                    coverage.branches.insert(base + 4 * i as u32, Branch {
+                        file: location.file.clone(),
+                        line: location.line_num + 1,
+                        count: BranchCount::default(),
+                    });
+                }
+            }
+        }
+        coverage
+    }
+
+    /// How many instructions were executed from a line, or `None` if it has
+    /// no code.
+    pub fn line(&self, file: &str, line: usize) -> Option<u64> {
+        self.lines.get(file)?.get(&line).cloned()
+    }
+
+    /// How often the branch at `addr` went each way.
+    pub fn branch(&self, addr: u32) -> Option<BranchCount> {
+        self.branches.get(&addr).map(|branch| branch.count)
+    }
+
+    /// The coverage as an LCOV tracefile, one record per source file.
+    ///
+    /// Each branch instruction on a line is its own block, numbered in
+    /// address order, with branch 0 taken and branch 1 not taken.
+    pub fn lcov(&self) -> String {
+        let mut branches: Vec<(&u32, &Branch)> = self.branches.iter().collect();
+        branches.sort_by_key(|(addr, _)| **addr);
+        let mut lcov = String::new();
+        for (file, lines) in &self.lines {
+            writeln!(lcov, "TN:\nSF:{}", file).unwrap();
+            let mut found = 0;
+            let mut hit = 0;
+            let mut blocks: BTreeMap<usize, usize> = BTreeMap::new();
+            for (_, branch) in branches.iter().filter(|(_, branch)| &branch.file == file) {
+                let block = blocks.entry(branch.line).or_insert(0);
+                // A branch never reached wasn't taken or not taken either.
+                let reached = branch.count.taken + branch.count.not_taken > 0;
+                for (i, &count) in [branch.count.taken, branch.count.not_taken].iter().enumerate() {
+                    if reached {
+                        writeln!(lcov, "BRDA:{},{},{},{}", branch.line, block, i, count).unwrap();
+                    } else {
+                        writeln!(lcov, "BRDA:{},{},{},-", branch.line, block, i).unwrap();
+                    }
+                    found += 1;
+                    hit += (count > 0) as usize;
+                }
+                *block += 1;
+            }
+            writeln!(lcov, "BRF:{}\nBRH:{}", found, hit).unwrap();
+            for (line, count) in lines {
+                writeln!(lcov, "DA:{},{}", line, count).unwrap();
+            }
+            let hit = lines.values().filter(|count| **count > 0).count();
+            writeln!(lcov, "LF:{}\nLH:{}\nend_of_record", lines.len(), hit).unwrap();
+        }
+        lcov
+    }
+}
+
+impl Observer for Coverage {
+    fn instruction_retired(&mut self, _addr: u32, location: Option<&MIPSLocation>) {
+        if let Some(location) = location {
+            *self.lines.entry(location.file.clone()).or_default().entry(location.line_num + 1).or_insert(0) += 1;
+        }
+    }
+    fn branch(&mut self, addr: u32, _target: u32, taken: bool) {
+        if let Some(branch) = self.branches.get_mut(&addr) {
+            if taken {
+                branch.count.taken += 1;
+            } else {
+                branch.count.not_taken += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+    use super::super::runtime::Runtime;
+
+    #[test]
+    fn test_coverage() {
+        let source = "main:   li $t0, 2
+        loop:   addi $t0, $t0, -1
+                bgtz $t0, loop
+                beq $t0, $t0, done
+                li $t1, 1
+        done:   li $v0, 10
+                syscall
+        ";
+        let (components, _) = read_str_to_state(source, "test.s");
+        let program = assemble(&components).unwrap();
+        let coverage = Rc::new(RefCell::new(Coverage::new(&program)));
+        let mut runtime = Runtime::new();
+        runtime.load_program(program).unwrap();
+        runtime.add_observer(Box::new(coverage.clone()));
+        runtime.run().unwrap();
+        let coverage = coverage.borrow();
+
+        assert_eq!(coverage.line("test.s", 2), Some(2));
+        assert_eq!(coverage.line("test.s", 5), Some(0));
+        assert_eq!(coverage.line("test.s", 8), None);
+        assert_eq!(coverage.branch(TEXT_BASE + 8), Some(BranchCount { taken: 1, not_taken: 1 }));
+        assert_eq!(coverage.lcov(), concat!(
+            "TN:\nSF:test.s\n",
+            "BRDA:3,0,0,1\nBRDA:3,0,1,1\nBRDA:4,0,0,1\nBRDA:4,0,1,0\nBRF:4\nBRH:3\n",
+            "DA:1,1\nDA:2,2\nDA:3,2\nDA:4,1\nDA:5,0\nDA:6,1\nDA:7,1\n",
+            "LF:7\nLH:6\nend_of_record\n",
+        ));
+    }
+}
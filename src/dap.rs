@@ -0,0 +1,638 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use super::assembler::{DataKind, Program, DATA_BASE, KDATA_BASE};
+use super::debugger::{Debugger, Stop};
+use super::mips_parser::MIPSLocation;
+use super::runtime::{FloatRegister, RegisterCodes, Runtime};
+use super::utils::json::Json;
+
+/// The only thread a program has.
+const THREAD_ID: i64 = 1;
+
+/// The `variablesReference` of each scope. None of the variables in them
+/// have children.
+const REGISTERS: i64 = 1;
+const FLOAT_REGISTERS: i64 = 2;
+const DATA: i64 = 3;
+
+/// The most of a string label's bytes shown as its value.
+const MAX_STRING: u32 = 256;
+
+/// Serves the Debug Adapter Protocol to one client, such as an editor, so it
+/// can debug the program in `debugger`.
+///
+/// Breakpoints are set by source line, and the call stack is the runtime's
+/// shadow of `jal` and `jr $ra`. The registers and the labels in the data
+/// segments are shown as variables. What the program prints has to be
+/// captured by its console, since `writer` carries the protocol, and is sent
+/// on as output events.
+///
+/// `reader` is read on its own thread, so a pause request can stop a
+/// running program. Returns once the client disconnects.
+pub fn serve<R, W>(debugger: &mut Debugger, reader: R, writer: W) -> io::Result<()>
+where
+    R: BufRead + Send + 'static,
+    W: Write,
+{
+    let (sender, input) = mpsc::channel();
+    let pause = debugger.pause_handle();
+    thread::spawn(move || {
+        let mut reader = reader;
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if message.get("command").and_then(Json::as_str) == Some("pause") {
+                pause.store(true, Ordering::Relaxed);
+            }
+            if sender.send(message).is_err() {
+                break
+            }
+        }
+    });
+    let mut server = Server {
+        debugger,
+        writer,
+        input,
+        seq: 0,
+        stop_on_entry: false,
+        breakpoints: HashMap::new(),
+        paths: HashMap::new(),
+        output_sent: 0,
+        error_sent: 0,
+    };
+    server.run()
+}
+
+/// Reads one message, a JSON body after a `Content-Length` header. Returns
+/// `None` at the end of the input.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None)
+        }
+        let line = line.trim_end();
+        if line.is_empty() && len.is_some() {
+            break
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            len = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; len.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    match Json::parse(&String::from_utf8_lossy(&body)) {
+        Some(message) => Ok(Some(message)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "a message isn't valid JSON")),
+    }
+}
+
+fn object(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(members.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+}
+
+struct Server<'a, W> {
+    debugger: &'a mut Debugger,
+    writer: W,
+    input: Receiver<Json>,
+    seq: i64,
+    stop_on_entry: bool,
+    /// The breakpoints set in each source file, to replace when the client
+    /// sets that file's breakpoints again.
+    breakpoints: HashMap<String, Vec<u32>>,
+    /// The client's path for each source file it has named.
+    paths: HashMap<String, String>,
+    /// How much of the program's captured output has been sent on.
+    output_sent: usize,
+    error_sent: usize,
+}
+
+impl<W: Write> Server<'_, W> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Ok(request) = self.input.recv() {
+            if !self.handle(&request)? {
+                break
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut members: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        members.insert(0, ("seq", self.seq.into()));
+        let body = object(members).to_string();
+        let message = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        self.writer.write_all(message.as_bytes())?;
+        self.writer.flush()
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let request_seq = request.get("seq").cloned().unwrap_or(Json::Null);
+        let command = request.get("command").cloned().unwrap_or(Json::Null);
+        let mut members = vec![
+            ("type", "response".into()),
+            ("request_seq", request_seq),
+            ("success", result.is_ok().into()),
+            ("command", command),
+        ];
+        match result {
+            Ok(body) => members.push(("body", body)),
+            Err(message) => members.push(("message", message.into())),
+        }
+        self.send(members)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(vec![("type", "event".into()), ("event", event.into()), ("body", body)])
+    }
+
+    /// Answers a request, returning false once the client disconnects.
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let arguments = request.get("arguments").unwrap_or(&Json::Null);
+        match request.get("command").and_then(Json::as_str).unwrap_or_default() {
+            "initialize" => {
+                let capabilities = object(vec![("supportsConfigurationDoneRequest", true.into())]);
+                self.respond(request, Ok(capabilities))?;
+                self.event("initialized", object(vec![]))?;
+            }
+            // micah has already loaded the program, so launching only says
+            // how to start it.
+            "launch" | "attach" => {
+                self.stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+                self.respond(request, Ok(object(vec![])))?;
+            }
+            "setBreakpoints" => {
+                let result = self.set_breakpoints(arguments);
+                self.respond(request, result)?;
+            }
+            "setExceptionBreakpoints" => self.respond(request, Ok(object(vec![])))?,
+            "configurationDone" => {
+                self.respond(request, Ok(object(vec![])))?;
+                if self.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else {
+                    let stop = self.debugger.resume();
+                    self.report(stop)?;
+                }
+            }
+            "threads" => {
+                let thread = object(vec![("id", THREAD_ID.into()), ("name", "main".into())]);
+                self.respond(request, Ok(object(vec![("threads", Json::Array(vec![thread]))])))?;
+            }
+            "stackTrace" => {
+                let body = self.stack_trace(arguments);
+                self.respond(request, Ok(body))?;
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: i64| {
+                    object(vec![("name", name.into()), ("variablesReference", reference.into()), ("expensive", false.into())])
+                };
+                let scopes = vec![scope("Registers", REGISTERS), scope("Float Registers", FLOAT_REGISTERS), scope("Data", DATA)];
+                self.respond(request, Ok(object(vec![("scopes", Json::Array(scopes))])))?;
+            }
+            "variables" => {
+                let reference = arguments.get("variablesReference").and_then(Json::as_f64).unwrap_or(0.0) as i64;
+                let variables = variables(self.debugger.runtime(), reference);
+                self.respond(request, Ok(object(vec![("variables", Json::Array(variables))])))?;
+            }
+            "continue" => {
+                self.respond(request, Ok(object(vec![("allThreadsContinued", true.into())])))?;
+                let stop = self.debugger.resume();
+                self.report(stop)?;
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.respond(request, Ok(object(vec![])))?;
+                let stop = self.step(request.get("command").and_then(Json::as_str).unwrap_or_default());
+                self.report(stop)?;
+            }
+            // The reader has already asked a running program to stop. By now
+            // it has, so the request mustn't stop the next resume too.
+            "pause" => {
+                self.debugger.pause_handle().store(false, Ordering::Relaxed);
+                self.respond(request, Ok(object(vec![])))?
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(object(vec![])))?;
+                return Ok(false)
+            }
+            command => self.respond(request, Err(format!("micah doesn't support {}", command)))?,
+        }
+        Ok(true)
+    }
+
+    /// Steps by source line: into calls for `stepIn`, over them for `next`,
+    /// and out of the current function for `stepOut`.
+    fn step(&mut self, command: &str) -> Stop {
+        let runtime = self.debugger.runtime();
+        let line = runtime.current_location().map(|location| (location.file.clone(), location.line_num));
+        let depth = runtime.call_stack().depth();
+        let on_new_line = move |runtime: &Runtime| {
+            let location = runtime.current_location();
+            location.is_some() && location.map(|location| (&location.file, location.line_num)) != line.as_ref().map(|(file, line)| (file, *line))
+        };
+        match command {
+            "stepIn" => self.debugger.step_until(on_new_line),
+            "next" => self.debugger.step_until(|runtime| runtime.call_stack().depth() <= depth && on_new_line(runtime)),
+            _ => self.debugger.step_until(|runtime| runtime.call_stack().depth() < depth),
+        }
+    }
+
+    /// Sends what the program printed, then why it stopped.
+    fn report(&mut self, stop: Stop) -> io::Result<()> {
+        self.send_output()?;
+        match stop {
+            Stop::Stepped | Stop::HistoryStart => self.stopped("step", None),
+            Stop::Breakpoint(_) => self.stopped("breakpoint", None),
+            Stop::Watchpoint(_) => self.stopped("data breakpoint", None),
+            Stop::Paused => self.stopped("pause", None),
+            Stop::Error(error) => self.stopped("exception", Some(error.to_string())),
+            Stop::Exited(code) => {
+                self.event("exited", object(vec![("exitCode", (code as i64).into())]))?;
+                self.event("terminated", object(vec![]))
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(text) = text {
+            body.push(("text", text.into()));
+        }
+        self.event("stopped", object(body))
+    }
+
+    fn send_output(&mut self) -> io::Result<()> {
+        let console = self.debugger.runtime().console();
+        let output = console.captured_output().map(|output| output[self.output_sent..].to_vec()).unwrap_or_default();
+        let error = console.captured_error().map(|error| error[self.error_sent..].to_vec()).unwrap_or_default();
+        self.output_sent += output.len();
+        self.error_sent += error.len();
+        for (category, text) in [("stdout", output), ("stderr", error)] {
+            if !text.is_empty() {
+                let body = object(vec![("category", category.into()), ("output", String::from_utf8_lossy(&text).into_owned().into())]);
+                self.event("output", body)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces a source file's breakpoints with ones on the lines asked for.
+    /// A line with no code gets an unverified breakpoint.
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments.get("source").and_then(|source| source.get("path"))
+            .and_then(Json::as_str)
+            .ok_or("setBreakpoints needs a source path")?;
+        let program = self.debugger.runtime().program().ok_or("no program is loaded")?;
+        let file = source_file(program, path);
+        let lines: Vec<usize> = arguments.get("breakpoints").and_then(Json::as_array).unwrap_or_default().iter()
+            .filter_map(|breakpoint| breakpoint.get("line").and_then(Json::as_f64))
+            .map(|line| line as usize)
+            .collect();
+        let addrs: Vec<Option<u32>> = lines.iter()
+            .map(|&line| file.as_deref().and_then(|file| program.address_of_line(Some(file), line)))
+            .collect();
+        for addr in self.breakpoints.remove(path).unwrap_or_default() {
+            self.debugger.remove_breakpoint(addr);
+        }
+        let mut set = Vec::new();
+        let mut breakpoints = Vec::new();
+        for (line, addr) in lines.into_iter().zip(addrs) {
+            let mut breakpoint = vec![("verified", addr.is_some().into()), ("line", (line as i64).into())];
+            match addr {
+                Some(addr) => {
+                    self.debugger.add_breakpoint(addr);
+                    set.push(addr);
+                }
+                None => breakpoint.push(("message", "no code on this line".into())),
+            }
+            breakpoints.push(object(breakpoint));
+        }
+        self.breakpoints.insert(path.to_string(), set);
+        if let Some(file) = file {
+            self.paths.insert(file, path.to_string());
+        }
+        Ok(object(vec![("breakpoints", Json::Array(breakpoints))]))
+    }
+
+    /// The call stack, newest frame first. Each frame is at the pc or, below
+    /// the top, at the call it is waiting on.
+    fn stack_trace(&self, arguments: &Json) -> Json {
+        let runtime = self.debugger.runtime();
+        let calls = runtime.call_stack().frames();
+        let mut frames = Vec::new();
+        let top = runtime.current_location();
+        if calls.is_empty() {
+            frames.push(self.frame(0, "<startup>".to_string(), top, runtime.pc()));
+        }
+        for (i, call) in calls.iter().rev().enumerate() {
+            let name = call.function.clone().unwrap_or_else(|| format!("0x{:08x}", call.target));
+            let frame = match i {
+                0 => self.frame(i, name, top, runtime.pc()),
+                _ => {
+                    let callee = &calls[calls.len() - i];
+                    self.frame(i, name, callee.call_site.as_ref(), callee.return_addr.wrapping_sub(4))
+                }
+            };
+            frames.push(frame);
+        }
+        let total = frames.len();
+        let start = arguments.get("startFrame").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+        let levels = match arguments.get("levels").and_then(Json::as_f64) {
+            Some(levels) if levels > 0.0 => levels as usize,
+            _ => total,
+        };
+        let frames = frames.into_iter().skip(start).take(levels).collect();
+        object(vec![("stackFrames", Json::Array(frames)), ("totalFrames", (total as i64).into())])
+    }
+
+    fn frame(&self, id: usize, name: String, location: Option<&MIPSLocation>, addr: u32) -> Json {
+        let mut frame = vec![
+            ("id", (id as i64).into()),
+            ("name", name.into()),
+            ("instructionPointerReference", format!("0x{:08x}", addr).into()),
+        ];
+        match location {
+            Some(location) => {
+                let path = self.paths.get(&location.file).cloned().unwrap_or_else(|| {
+                    fs::canonicalize(&location.file).map_or(location.file.clone(), |path| path.display().to_string())
+                });
+                let name = Path::new(&location.file).file_name().map_or(location.file.clone(), |name| name.to_string_lossy().into_owned());
+                frame.push(("source", object(vec![("name", name.into()), ("path", path.into())])));
+                frame.push(("line", (location.line_num as i64 + 1).into()));
+                frame.push(("column", Json::Number(1.0)));
+            }
+            None => {
+                frame.push(("line", Json::Number(0.0)));
+                frame.push(("column", Json::Number(0.0)));
+            }
+        }
+        object(frame)
+    }
+}
+
+/// The file in `program` that the client's `path` names: the same path, or
+/// one `path` ends with, as an editor's absolute path ends with the relative
+/// one micah was given.
+fn source_file(program: &Program, path: &str) -> Option<String> {
+    program.locations.iter().chain(&program.kernel_locations).flatten()
+        .map(|location| &location.file)
+        .find(|file| *file == path || Path::new(path).ends_with(file))
+        .cloned()
+}
+
+fn variable(name: String, value: String) -> Json {
+    object(vec![("name", name.into()), ("value", value.into()), ("variablesReference", Json::Number(0.0))])
+}
+
+/// The variables in the scope with this reference.
+fn variables(runtime: &Runtime, reference: i64) -> Vec<Json> {
+    let word = |val: u32| format!("0x{:08x} ({})", val, val as i32);
+    match reference {
+        REGISTERS => {
+            let mut variables: Vec<Json> = (0..32).map(|number| {
+                let reg = RegisterCodes::from_number(number);
+                variable(reg.to_string(), word(runtime.get_register(&reg).unwrap_or(0)))
+            }).collect();
+            variables.push(variable("pc".to_string(), format!("0x{:08x}", runtime.pc())));
+            variables.push(variable("hi".to_string(), word(runtime.hi())));
+            variables.push(variable("lo".to_string(), word(runtime.lo())));
+            variables
+        }
+        FLOAT_REGISTERS => (0..32).map(|number| {
+            let reg = FloatRegister::from_number(number);
+            let bits = runtime.get_float_register(&reg);
+            variable(reg.to_string(), format!("{} (0x{:08x})", f32::from_bits(bits), bits))
+        }).collect(),
+        DATA => {
+            let program = match runtime.program() {
+                Some(program) => program,
+                None => return Vec::new(),
+            };
+            let segments = [(DATA_BASE, program.data.len()), (KDATA_BASE, program.kernel_data.len())];
+            let mut labels: Vec<(&String, u32)> = program.symbols.iter()
+                .map(|(label, &addr)| (label, addr))
+                .filter(|&(_, addr)| segments.iter().any(|&(base, len)| addr >= base && ((addr - base) as usize) < len))
+                .collect();
+            labels.sort_by_key(|&(label, addr)| (addr, label.clone()));
+            labels.into_iter().map(|(label, addr)| variable(label.clone(), data_value(runtime, program, addr))).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// What a data label holds: the string for `.ascii` and `.asciiz`, and
+/// otherwise the word there.
+fn data_value(runtime: &Runtime, program: &Program, addr: u32) -> String {
+    let is_string = program.data_spans.iter()
+        .find(|span| span.addr == addr)
+        .is_some_and(|span| matches!(span.kind, DataKind::Ascii | DataKind::Asciiz));
+    if is_string {
+        let bytes: Vec<u8> = (addr..addr.saturating_add(MAX_STRING))
+            .map_while(|addr| runtime.peek_byte(addr as usize).filter(|&byte| byte != 0))
+            .collect();
+        return format!("{:?}", String::from_utf8_lossy(&bytes))
+    }
+    let bytes: Option<Vec<u8>> = (0..4).map(|i| runtime.peek_byte(addr.wrapping_add(i) as usize)).collect();
+    match bytes {
+        Some(bytes) => {
+            let val = runtime.endian().word_from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            format!("0x{:08x} ({})", val, val as i32)
+        }
+        None => "??????????".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::console::{Console, ConsoleOutput};
+    use super::super::mips_parser::read_str_to_state;
+    use super::super::runtime::DEFAULT_UNDO_CAPACITY;
+
+    fn debugger(source: &str) -> Debugger {
+        let (components, _) = read_str_to_state(source, "test.s");
+        let mut runtime = Runtime::with_undo_capacity(DEFAULT_UNDO_CAPACITY);
+        runtime.load_program(assemble(&components).unwrap()).unwrap();
+        let mut console = Console::new();
+        console.set_output(ConsoleOutput::Captured(Vec::new()));
+        runtime.set_console(console);
+        Debugger::new(runtime)
+    }
+
+    fn request(seq: i64, command: &str, arguments: Json) -> Vec<u8> {
+        let body = object(vec![
+            ("seq", seq.into()),
+            ("type", "request".into()),
+            ("command", command.into()),
+            ("arguments", arguments),
+        ]).to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+    }
+
+    fn messages(output: &[u8]) -> Vec<Json> {
+        let mut reader = Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    /// The events a session sent, as `event` or `event:reason`.
+    fn events(messages: &[Json]) -> Vec<String> {
+        messages.iter()
+            .filter_map(|message| {
+                let event = message.get("event")?.as_str()?;
+                Some(match message.get("body").and_then(|body| body.get("reason")).and_then(Json::as_str) {
+                    Some(reason) => format!("{}:{}", event, reason),
+                    None => event.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    fn response<'a>(messages: &'a [Json], command: &str) -> Vec<&'a Json> {
+        messages.iter()
+            .filter(|message| message.get("type").and_then(Json::as_str) == Some("response"))
+            .filter(|message| message.get("command").and_then(Json::as_str) == Some(command))
+            .filter_map(|message| message.get("body"))
+            .collect()
+    }
+
+    fn names_and_values(variables: &Json) -> Vec<(String, String)> {
+        variables.get("variables").and_then(Json::as_array).unwrap().iter()
+            .map(|variable| {
+                let field = |name| variable.get(name).and_then(Json::as_str).unwrap().to_string();
+                (field("name"), field("value"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dap_session() {
+        let mut debugger = debugger("
+                .data
+        hello:  .asciiz \"hi\"
+        count:  .word 7 # not .ascii
+                .text
+        main:   li $a0, 1
+                jal twice
+                li $v0, 4
+                la $a0, hello
+                syscall
+                li $v0, 10
+                syscall
+        twice:  add $v0, $a0, $a0
+                jr $ra
+        ");
+        let breakpoints = |lines: &[i64]| {
+            let breakpoints = lines.iter().map(|&line| object(vec![("line", line.into())])).collect();
+            object(vec![
+                ("source", object(vec![("path", "/home/student/test.s".into())])),
+                ("breakpoints", Json::Array(breakpoints)),
+            ])
+        };
+        let mut input = Vec::new();
+        input.extend(request(1, "initialize", object(vec![])));
+        input.extend(request(2, "launch", object(vec![])));
+        input.extend(request(3, "setBreakpoints", breakpoints(&[13, 4])));
+        input.extend(request(4, "configurationDone", object(vec![])));
+        input.extend(request(5, "stackTrace", object(vec![("threadId", THREAD_ID.into())])));
+        input.extend(request(6, "variables", object(vec![("variablesReference", REGISTERS.into())])));
+        input.extend(request(7, "variables", object(vec![("variablesReference", DATA.into())])));
+        input.extend(request(8, "stepOut", object(vec![])));
+        input.extend(request(9, "stackTrace", object(vec![("threadId", THREAD_ID.into())])));
+        input.extend(request(10, "next", object(vec![])));
+        input.extend(request(11, "setBreakpoints", breakpoints(&[])));
+        input.extend(request(12, "continue", object(vec![])));
+        input.extend(request(13, "disconnect", object(vec![])));
+        let mut output = Vec::new();
+        serve(&mut debugger, Cursor::new(input), &mut output).unwrap();
+        let messages = messages(&output);
+
+        assert_eq!(events(&messages), vec![
+            "initialized", "stopped:breakpoint", "stopped:step", "stopped:step", "output", "exited", "terminated",
+        ]);
+        let set = response(&messages, "setBreakpoints");
+        assert_eq!(set[0].to_string(), concat!(
+            "{\"breakpoints\":[{\"verified\":true,\"line\":13},",
+            "{\"verified\":false,\"line\":4,\"message\":\"no code on this line\"}]}"
+        ));
+
+        // Stopped in twice, called from main.
+        let traces = response(&messages, "stackTrace");
+        let frames = traces[0].get("stackFrames").and_then(Json::as_array).unwrap();
+        let frame = |frame: &Json| {
+            let path = frame.get("source").and_then(|source| source.get("path")).and_then(Json::as_str);
+            (frame.get("name").and_then(Json::as_str).unwrap().to_string(), frame.get("line").and_then(Json::as_f64).unwrap(), path.map(str::to_string))
+        };
+        assert_eq!(frames.iter().map(frame).collect::<Vec<_>>(), vec![
+            ("twice".to_string(), 13.0, Some("/home/student/test.s".to_string())),
+            ("main".to_string(), 7.0, Some("/home/student/test.s".to_string())),
+        ]);
+        // Stepping out returns to the line after the call.
+        let frames = traces[1].get("stackFrames").and_then(Json::as_array).unwrap();
+        assert_eq!(frames.iter().map(frame).collect::<Vec<_>>(), vec![
+            ("main".to_string(), 8.0, Some("/home/student/test.s".to_string())),
+        ]);
+
+        let variables = response(&messages, "variables");
+        let registers = names_and_values(variables[0]);
+        assert_eq!(registers[4], ("$a0".to_string(), "0x00000001 (1)".to_string()));
+        assert_eq!(registers[32], ("pc".to_string(), "0x00400020".to_string()));
+        assert_eq!(names_and_values(variables[1]), vec![
+            ("hello".to_string(), "\"hi\"".to_string()),
+            ("count".to_string(), "0x00000007 (7)".to_string()),
+        ]);
+
+        let printed = messages.iter().find(|message| message.get("event").and_then(Json::as_str) == Some("output")).unwrap();
+        assert_eq!(printed.get("body").and_then(|body| body.get("output")).and_then(Json::as_str), Some("hi"));
+        assert_eq!(debugger.exit_code(), Some(0));
+    }
+
+    #[test]
+    fn test_dap_pause() {
+        let (reader, mut writer) = io::pipe().unwrap();
+        let (sender, output) = mpsc::channel();
+        let server = thread::spawn(move || {
+            /// A writer that sends each message it is given on.
+            struct Sender(mpsc::Sender<Vec<u8>>);
+            impl Write for Sender {
+                fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                    let _ = self.0.send(buf.to_vec());
+                    Ok(buf.len())
+                }
+                fn flush(&mut self) -> io::Result<()> {
+                    Ok(())
+                }
+            }
+            let mut debugger = debugger("main: j main\n");
+            serve(&mut debugger, BufReader::new(reader), Sender(sender)).unwrap();
+        });
+        let mut received = Vec::new();
+        let mut wait_for = |event: &str| loop {
+            received.extend(output.recv().unwrap());
+            if events(&messages(&received)).iter().any(|received| received == event) {
+                break
+            }
+        };
+        writer.write_all(&request(1, "initialize", object(vec![]))).unwrap();
+        writer.write_all(&request(2, "configurationDone", object(vec![]))).unwrap();
+        writer.write_all(&request(3, "pause", object(vec![("threadId", THREAD_ID.into())]))).unwrap();
+        wait_for("stopped:pause");
+        writer.write_all(&request(4, "disconnect", object(vec![]))).unwrap();
+        server.join().unwrap();
+    }
+}
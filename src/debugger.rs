@@ -0,0 +1,294 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::runtime::{Runtime, RuntimeError, StepResult};
+
+/// Why the debugger stopped the program.
+#[derive(Debug)]
+pub enum Stop {
+    /// A single step, forwards or backwards, finished.
+    Stepped,
+    /// The pc reached a breakpoint; the instruction there hasn't run.
+    Breakpoint(u32),
+    /// The last step changed the bytes watched from this address.
+    Watchpoint(u32),
+    /// The program exited with this code. Stepping forwards does nothing
+    /// more, but stepping backwards can leave the exit.
+    Exited(i32),
+    Error(RuntimeError),
+    /// Stepping backwards ran out of undo log.
+    HistoryStart,
+    /// The pause handle was set while the program ran.
+    Paused,
+}
+
+/// A range of memory to stop on when its contents change, as a gdb `watch`
+/// does, in either direction.
+struct Watchpoint {
+    addr: u32,
+    len: u32,
+}
+
+/// Runs a program under a debugger's control: forwards or, as far as the
+/// runtime's undo log reaches, backwards, stopping at breakpoints and
+/// watchpoints.
+pub struct Debugger {
+    runtime: Runtime,
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    exit_code: Option<i32>,
+    pause: Arc<AtomicBool>,
+}
+
+impl Debugger {
+    /// Debugs the program loaded into `runtime`. Its undo log's capacity
+    /// limits how far back the program can be stepped.
+    pub fn new(runtime: Runtime) -> Debugger {
+        Debugger {
+            runtime,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            exit_code: None,
+            pause: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    pub fn runtime_mut(&mut self) -> &mut Runtime {
+        &mut self.runtime
+    }
+
+    pub fn into_runtime(self) -> Runtime {
+        self.runtime
+    }
+
+    /// The program's exit code, if it has exited.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Adds a breakpoint at `addr`, returning false if there already was one.
+    pub fn add_breakpoint(&mut self, addr: u32) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().cloned()
+    }
+
+    /// Watches the `len` bytes from `addr`, replacing any watchpoint already
+    /// there.
+    pub fn add_watchpoint(&mut self, addr: u32, len: u32) {
+        self.remove_watchpoint(addr);
+        self.watchpoints.push(Watchpoint { addr, len });
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u32) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| watchpoint.addr != addr);
+        self.watchpoints.len() != len
+    }
+
+    /// The start and length of each watchpoint.
+    pub fn watchpoints(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.watchpoints.iter().map(|watchpoint| (watchpoint.addr, watchpoint.len))
+    }
+
+    /// A flag that stops `resume` or `reverse_resume` with `Stop::Paused`
+    /// when set, e.g. from another thread reading a front end's requests.
+    /// It is cleared whenever the debugger reports a stop, so a pause set
+    /// just before a resume starts stops that resume straight away.
+    pub fn pause_handle(&self) -> Arc<AtomicBool> {
+        self.pause.clone()
+    }
+
+    /// Runs one instruction.
+    pub fn step(&mut self) -> Stop {
+        let stop = self.step_forward().unwrap_or(Stop::Stepped);
+        self.pause.store(false, Ordering::Relaxed);
+        stop
+    }
+
+    /// Undoes the last instruction.
+    pub fn reverse_step(&mut self) -> Stop {
+        let stop = self.step_backward().unwrap_or(Stop::Stepped);
+        self.pause.store(false, Ordering::Relaxed);
+        stop
+    }
+
+    /// Runs until a breakpoint, a watchpoint, the exit or an error.
+    pub fn resume(&mut self) -> Stop {
+        self.resume_with(Debugger::step_forward, |_| false)
+    }
+
+    /// Runs until `until` holds after a step, which stops with
+    /// `Stop::Stepped`, or until anything that stops `resume`. A front end
+    /// steps by source line this way.
+    pub fn step_until(&mut self, until: impl FnMut(&Runtime) -> bool) -> Stop {
+        self.resume_with(Debugger::step_forward, until)
+    }
+
+    /// Runs backwards until a breakpoint, a watchpoint or the start of the
+    /// undo log.
+    pub fn reverse_resume(&mut self) -> Stop {
+        self.resume_with(Debugger::step_backward, |_| false)
+    }
+
+    fn resume_with(&mut self, step: fn(&mut Debugger) -> Option<Stop>, until: impl FnMut(&Runtime) -> bool) -> Stop {
+        let stop = self.run_until_stop(step, until);
+        self.pause.store(false, Ordering::Relaxed);
+        stop
+    }
+
+    fn run_until_stop(&mut self, step: fn(&mut Debugger) -> Option<Stop>, mut until: impl FnMut(&Runtime) -> bool) -> Stop {
+        loop {
+            if self.pause.load(Ordering::Relaxed) {
+                return Stop::Paused
+            }
+            if let Some(stop) = step(self) {
+                return stop
+            }
+            let pc = self.runtime.pc();
+            if self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint(pc)
+            }
+            if until(&self.runtime) {
+                return Stop::Stepped
+            }
+        }
+    }
+
+    /// Steps forwards once, giving a reason to stop other than the step.
+    fn step_forward(&mut self) -> Option<Stop> {
+        if let Some(code) = self.exit_code {
+            return Some(Stop::Exited(code))
+        }
+        let before = self.watched_bytes();
+        match self.runtime.step() {
+            Ok(StepResult::Running) => self.changed_watchpoint(before),
+            Ok(StepResult::Exited(code)) => {
+                self.exit_code = Some(code);
+                Some(Stop::Exited(code))
+            }
+            Err(error) => Some(Stop::Error(error)),
+        }
+    }
+
+    fn step_backward(&mut self) -> Option<Stop> {
+        let before = self.watched_bytes();
+        if !self.runtime.step_back() {
+            return Some(Stop::HistoryStart)
+        }
+        self.exit_code = None;
+        self.changed_watchpoint(before)
+    }
+
+    fn watched_bytes(&self) -> Vec<Vec<Option<u8>>> {
+        self.watchpoints.iter()
+            .map(|watchpoint| {
+                (watchpoint.addr..watchpoint.addr.wrapping_add(watchpoint.len))
+                    .map(|addr| self.runtime.peek_byte(addr as usize))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn changed_watchpoint(&self, before: Vec<Vec<Option<u8>>>) -> Option<Stop> {
+        if self.watchpoints.is_empty() {
+            return None
+        }
+        self.watchpoints.iter().zip(before.iter().zip(self.watched_bytes()))
+            .find(|(_, (before, after))| **before != *after)
+            .map(|(watchpoint, _)| Stop::Watchpoint(watchpoint.addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+    use super::super::runtime::RegisterCodes::*;
+
+    fn debug_str(source: &str) -> Debugger {
+        let (components, _) = read_str_to_state(source, "test.s");
+        let mut runtime = Runtime::new();
+        runtime.load_program(assemble(&components).unwrap()).unwrap();
+        Debugger::new(runtime)
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints(){
+        let mut debugger = debug_str("
+                .data
+        count:  .word 0
+                .text
+        main:   li $s0, 3
+        loop:   lw $t0, count
+                addi $t0, $t0, 1
+                sw $t0, count
+                addi $s0, $s0, -1
+                bgtz $s0, loop
+                jr $ra
+        ");
+        let program = debugger.runtime().program().unwrap();
+        let (entry, loop_addr, count) = (program.entry, program.symbols["loop"], program.symbols["count"]);
+        // `sw $t0, count` is two instructions, the second doing the store.
+        let after_store = program.address_of_line(Some("test.s"), 9).unwrap();
+        assert_eq!(program.address_of_line(None, 8), Some(after_store - 8));
+
+        debugger.add_breakpoint(loop_addr);
+        assert!(matches!(debugger.resume(), Stop::Breakpoint(addr) if addr == loop_addr));
+        assert!(matches!(debugger.resume(), Stop::Breakpoint(addr) if addr == loop_addr));
+        assert_eq!(debugger.runtime().get_register(&Rs0), Ok(2));
+
+        debugger.add_watchpoint(count, 4);
+        assert!(matches!(debugger.resume(), Stop::Watchpoint(addr) if addr == count));
+        assert_eq!(debugger.runtime().pc(), after_store);
+        assert_eq!(debugger.runtime().peek_byte(count as usize + 3), Some(2));
+
+        // Backwards, the store that just ran is the first change.
+        assert!(matches!(debugger.reverse_resume(), Stop::Watchpoint(addr) if addr == count));
+        assert_eq!(debugger.runtime().pc(), after_store - 4);
+        assert!(matches!(debugger.reverse_resume(), Stop::Breakpoint(addr) if addr == loop_addr));
+        assert!(matches!(debugger.reverse_resume(), Stop::Watchpoint(addr) if addr == count));
+        assert_eq!(debugger.runtime().peek_byte(count as usize + 3), Some(0));
+
+        debugger.remove_watchpoint(count);
+        debugger.remove_breakpoint(loop_addr);
+        assert!(matches!(debugger.resume(), Stop::Exited(0)));
+        assert!(matches!(debugger.step(), Stop::Exited(0)));
+        assert!(matches!(debugger.reverse_step(), Stop::Stepped));
+        assert_eq!(debugger.exit_code(), None);
+        assert!(matches!(debugger.reverse_resume(), Stop::HistoryStart));
+        assert_eq!(debugger.runtime().pc(), entry);
+        assert_eq!(debugger.runtime().instruction_count(), 0);
+
+        // A pause set before a resume starts isn't lost, and is cleared
+        // once reported.
+        let pause = debugger.pause_handle();
+        pause.store(true, Ordering::Relaxed);
+        assert!(matches!(debugger.resume(), Stop::Paused));
+        assert_eq!(debugger.runtime().pc(), entry);
+        debugger.add_breakpoint(loop_addr);
+        assert!(matches!(debugger.resume(), Stop::Breakpoint(_)));
+    }
+
+    #[test]
+    fn test_pause_while_running(){
+        let mut debugger = debug_str("main: j main\n");
+        let pause = debugger.pause_handle();
+        let pauser = std::thread::spawn(move || pause.store(true, Ordering::Relaxed));
+        assert!(matches!(debugger.resume(), Stop::Paused));
+        pauser.join().unwrap();
+        assert!(!debugger.pause_handle().load(Ordering::Relaxed));
+    }
+}
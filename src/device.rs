@@ -0,0 +1,178 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A device mapped over a range of a runtime's memory.
+///
+/// Registers are read and written by their offset from the start of the
+/// range, a word at a time; byte and half accesses use the low bits of the
+/// register they fall in. The runtime ticks every device once per
+/// instruction, and a device raises interrupts by returning their Cause bits
+/// from `interrupts` for as long as they are pending.
+pub trait Device {
+    fn read(&mut self, offset: u32) -> u32;
+    fn write(&mut self, offset: u32, val: u32);
+    fn tick(&mut self) {}
+    fn interrupts(&self) -> u32 {
+        0
+    }
+}
+
+/// Lets a caller keep a handle on a device after mapping it, to inspect or
+/// drive it from outside the program.
+impl<T: Device> Device for Rc<RefCell<T>> {
+    fn read(&mut self, offset: u32) -> u32 {
+        self.borrow_mut().read(offset)
+    }
+    fn write(&mut self, offset: u32, val: u32) {
+        self.borrow_mut().write(offset, val)
+    }
+    fn tick(&mut self) {
+        self.borrow_mut().tick()
+    }
+    fn interrupts(&self) -> u32 {
+        self.borrow().interrupts()
+    }
+}
+
+/// Offsets of the timer's registers, and the bytes it takes up.
+pub const TIMER_COUNT: u32 = 0x0;
+pub const TIMER_COMPARE: u32 = 0x4;
+pub const TIMER_LEN: usize = 0x8;
+
+/// The Cause bit for the timer's interrupt: hardware level 5, as for the
+/// coprocessor 0 timer.
+pub const TIMER_INTERRUPT: u32 = 1 << 15;
+
+/// Counts cycles, like coprocessor 0's Count and Compare. When the count
+/// reaches the compare register the timer interrupts, until compare is
+/// written again.
+#[derive(Default)]
+pub struct Timer {
+    count: u32,
+    compare: u32,
+    pending: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer::default()
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, offset: u32) -> u32 {
+        match offset & !3 {
+            TIMER_COUNT => self.count,
+            TIMER_COMPARE => self.compare,
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: u32) {
+        match offset & !3 {
+            TIMER_COUNT => self.count = val,
+            TIMER_COMPARE => {
+                self.compare = val;
+                self.pending = false;
+            }
+            _ => ()
+        }
+    }
+
+    fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+        if self.count == self.compare {
+            self.pending = true;
+        }
+    }
+
+    fn interrupts(&self) -> u32 {
+        if self.pending { TIMER_INTERRUPT } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::memory::MMIO_BASE;
+    use super::super::mips_parser::read_str_to_state;
+    use super::super::runtime::Runtime;
+    use super::super::runtime::RegisterCodes::*;
+
+    #[test]
+    fn test_timer(){
+        let mut timer = Timer::new();
+        timer.write(TIMER_COMPARE, 2);
+        timer.tick();
+        assert_eq!(timer.interrupts(), 0);
+        timer.tick();
+        assert_eq!(timer.read(TIMER_COUNT), 2);
+        assert_eq!(timer.interrupts(), TIMER_INTERRUPT);
+        timer.tick();
+        assert_eq!(timer.interrupts(), TIMER_INTERRUPT);
+        timer.write(TIMER_COMPARE, 10);
+        assert_eq!(timer.interrupts(), 0);
+    }
+
+    /// A device that reads back the last value written to each register.
+    #[derive(Default)]
+    struct Latch {
+        registers: [u32; 2],
+        ticks: u32,
+    }
+
+    impl Device for Latch {
+        fn read(&mut self, offset: u32) -> u32 {
+            self.registers[offset as usize / 4]
+        }
+        fn write(&mut self, offset: u32, val: u32) {
+            self.registers[offset as usize / 4] = val;
+        }
+        fn tick(&mut self) {
+            self.ticks += 1;
+        }
+    }
+
+    #[test]
+    fn test_runtime_devices(){
+        // Counts timer interrupts in $s0, restarting the timer each time,
+        // while it reads and writes the latch.
+        let source = "
+        main:   li $t0, 0xffff0000
+                li $t1, 20
+                sw $t1, 0x104($t0)
+                li $t1, 0x1234
+                sh $t1, 0x204($t0)
+                lw $s1, 0x204($t0)
+                mfc0 $t1, $12
+                ori $t1, $t1, 1
+                mtc0 $t1, $12
+        wait:   blt $s0, 3, wait
+                jr $ra
+                .ktext
+                addi $s0, $s0, 1
+                lui $k0, 0xffff
+                sw $zero, 0x100($k0)
+                li $k1, 20
+                sw $k1, 0x104($k0)
+                eret
+        ";
+        let (components, _) = read_str_to_state(source, "test.s");
+        let mut runtime = Runtime::new();
+        runtime.load_program(assemble(&components).unwrap()).unwrap();
+        let timer = Rc::new(RefCell::new(Timer::new()));
+        let latch = Rc::new(RefCell::new(Latch::default()));
+        runtime.map_device(MMIO_BASE + 0x100, TIMER_LEN, Box::new(timer.clone())).unwrap();
+        runtime.map_device(MMIO_BASE + 0x200, 8, Box::new(latch.clone())).unwrap();
+        assert!(runtime.map_device(MMIO_BASE + 0x104, 4, Box::new(Timer::new())).is_err());
+
+        assert_eq!(runtime.run().unwrap(), 0);
+        assert_eq!(runtime.get_register(&Rs0).unwrap(), 3);
+        assert_eq!(runtime.get_register(&Rs1).unwrap(), 0x1234);
+        assert_eq!(latch.borrow().registers, [0, 0x1234]);
+        // Devices tick after every instruction but the one that exits.
+        assert_eq!(latch.borrow().ticks as u64 + 1, runtime.instruction_count());
+        assert!(timer.borrow().count < 20);
+    }
+}
@@ -0,0 +1,279 @@
+use std::fmt;
+
+use super::assembler::{Program, KTEXT_BASE, TEXT_BASE};
+use super::code::{Instruction, FLOAT_CONDITIONS};
+use super::memory::MemoryRep;
+use super::mips_parser::MIPSLocation;
+use super::runtime::RegisterCodes::*;
+
+/// One word of memory rendered as assembly.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DisassembledWord {
+    pub addr: u32,
+    pub word: u32,
+    /// The label defined at `addr`, if any.
+    pub label: Option<String>,
+    pub text: String,
+}
+
+impl fmt::Display for DisassembledWord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:08x}  {:08x}  {}", self.addr, self.word, self.text)
+    }
+}
+
+/// Renders `instruction` as assembly that assembles back to the same word.
+/// Branch and jump targets are named with `label` where it knows them.
+pub fn format_instruction(instruction: &Instruction, label: &dyn Fn(u32) -> Option<String>) -> String {
+    use Instruction::*;
+
+    let name = |addr: u32| label(addr).unwrap_or_else(|| format!("0x{:08x}", addr));
+    match *instruction {
+        Sll {rd: Rzero, rt: Rzero, shamt: 0} => "nop".to_string(),
+        Add {rd, rs, rt} => format!("add {}, {}, {}", rd, rs, rt),
+        Addu {rd, rs, rt} => format!("addu {}, {}, {}", rd, rs, rt),
+        Sub {rd, rs, rt} => format!("sub {}, {}, {}", rd, rs, rt),
+        Subu {rd, rs, rt} => format!("subu {}, {}, {}", rd, rs, rt),
+        And {rd, rs, rt} => format!("and {}, {}, {}", rd, rs, rt),
+        Or {rd, rs, rt} => format!("or {}, {}, {}", rd, rs, rt),
+        Xor {rd, rs, rt} => format!("xor {}, {}, {}", rd, rs, rt),
+        Nor {rd, rs, rt} => format!("nor {}, {}, {}", rd, rs, rt),
+        Slt {rd, rs, rt} => format!("slt {}, {}, {}", rd, rs, rt),
+        Sltu {rd, rs, rt} => format!("sltu {}, {}, {}", rd, rs, rt),
+        Mul {rd, rs, rt} => format!("mul {}, {}, {}", rd, rs, rt),
+        Sllv {rd, rt, rs} => format!("sllv {}, {}, {}", rd, rt, rs),
+        Srlv {rd, rt, rs} => format!("srlv {}, {}, {}", rd, rt, rs),
+        Srav {rd, rt, rs} => format!("srav {}, {}, {}", rd, rt, rs),
+        Sll {rd, rt, shamt} => format!("sll {}, {}, {}", rd, rt, shamt),
+        Srl {rd, rt, shamt} => format!("srl {}, {}, {}", rd, rt, shamt),
+        Sra {rd, rt, shamt} => format!("sra {}, {}, {}", rd, rt, shamt),
+        Mult {rs, rt} => format!("mult {}, {}", rs, rt),
+        Multu {rs, rt} => format!("multu {}, {}", rs, rt),
+        Div {rs, rt} => format!("div {}, {}", rs, rt),
+        Divu {rs, rt} => format!("divu {}, {}", rs, rt),
+        Mfhi {rd} => format!("mfhi {}", rd),
+        Mflo {rd} => format!("mflo {}", rd),
+        Mthi {rs} => format!("mthi {}", rs),
+        Mtlo {rs} => format!("mtlo {}", rs),
+        Jr {rs} => format!("jr {}", rs),
+        Jalr {rd: Rra, rs} => format!("jalr {}", rs),
+        Jalr {rd, rs} => format!("jalr {}, {}", rd, rs),
+        Syscall => "syscall".to_string(),
+        Break {code: 0} => "break".to_string(),
+        Break {code} => format!("break {}", code),
+
+        Addi {rt, rs, imm} => format!("addi {}, {}, {}", rt, rs, imm),
+        Addiu {rt, rs, imm} => format!("addiu {}, {}, {}", rt, rs, imm),
+        Slti {rt, rs, imm} => format!("slti {}, {}, {}", rt, rs, imm),
+        Sltiu {rt, rs, imm} => format!("sltiu {}, {}, {}", rt, rs, imm),
+        Andi {rt, rs, imm} => format!("andi {}, {}, 0x{:x}", rt, rs, imm),
+        Ori {rt, rs, imm} => format!("ori {}, {}, 0x{:x}", rt, rs, imm),
+        Xori {rt, rs, imm} => format!("xori {}, {}, 0x{:x}", rt, rs, imm),
+        Lui {rt, imm} => format!("lui {}, 0x{:x}", rt, imm),
+        Lb {rt, base, offset} => format!("lb {}, {}({})", rt, offset, base),
+        Lbu {rt, base, offset} => format!("lbu {}, {}({})", rt, offset, base),
+        Lh {rt, base, offset} => format!("lh {}, {}({})", rt, offset, base),
+        Lhu {rt, base, offset} => format!("lhu {}, {}({})", rt, offset, base),
+        Lw {rt, base, offset} => format!("lw {}, {}({})", rt, offset, base),
+        Sb {rt, base, offset} => format!("sb {}, {}({})", rt, offset, base),
+        Sh {rt, base, offset} => format!("sh {}, {}({})", rt, offset, base),
+        Sw {rt, base, offset} => format!("sw {}, {}({})", rt, offset, base),
+        Beq {rs, rt, target} => format!("beq {}, {}, {}", rs, rt, name(target)),
+        Bne {rs, rt, target} => format!("bne {}, {}, {}", rs, rt, name(target)),
+        Blez {rs, target} => format!("blez {}, {}", rs, name(target)),
+        Bgtz {rs, target} => format!("bgtz {}, {}", rs, name(target)),
+        Bltz {rs, target} => format!("bltz {}, {}", rs, name(target)),
+        Bgez {rs, target} => format!("bgez {}, {}", rs, name(target)),
+
+        J {target} => format!("j {}", name(target)),
+        Jal {target} => format!("jal {}", name(target)),
+
+        AddFmt {fmt, fd, fs, ft} => format!("add.{} {}, {}, {}", fmt, fd, fs, ft),
+        SubFmt {fmt, fd, fs, ft} => format!("sub.{} {}, {}, {}", fmt, fd, fs, ft),
+        MulFmt {fmt, fd, fs, ft} => format!("mul.{} {}, {}, {}", fmt, fd, fs, ft),
+        DivFmt {fmt, fd, fs, ft} => format!("div.{} {}, {}, {}", fmt, fd, fs, ft),
+        SqrtFmt {fmt, fd, fs} => format!("sqrt.{} {}, {}", fmt, fd, fs),
+        AbsFmt {fmt, fd, fs} => format!("abs.{} {}, {}", fmt, fd, fs),
+        MovFmt {fmt, fd, fs} => format!("mov.{} {}, {}", fmt, fd, fs),
+        NegFmt {fmt, fd, fs} => format!("neg.{} {}, {}", fmt, fd, fs),
+        Cvt {to, from, fd, fs} => format!("cvt.{}.{} {}, {}", to, from, fd, fs),
+        RoundW {fmt, fd, fs} => format!("round.w.{} {}, {}", fmt, fd, fs),
+        TruncW {fmt, fd, fs} => format!("trunc.w.{} {}, {}", fmt, fd, fs),
+        CeilW {fmt, fd, fs} => format!("ceil.w.{} {}, {}", fmt, fd, fs),
+        FloorW {fmt, fd, fs} => format!("floor.w.{} {}, {}", fmt, fd, fs),
+        CompareFmt {cond, fmt, cc: 0, fs, ft} => format!("c.{}.{} {}, {}", FLOAT_CONDITIONS[cond as usize], fmt, fs, ft),
+        CompareFmt {cond, fmt, cc, fs, ft} => {
+            format!("c.{}.{} {}, {}, {}", FLOAT_CONDITIONS[cond as usize], fmt, cc, fs, ft)
+        }
+        Bc1t {cc: 0, target} => format!("bc1t {}", name(target)),
+        Bc1t {cc, target} => format!("bc1t {}, {}", cc, name(target)),
+        Bc1f {cc: 0, target} => format!("bc1f {}", name(target)),
+        Bc1f {cc, target} => format!("bc1f {}, {}", cc, name(target)),
+        Mfc1 {rt, fs} => format!("mfc1 {}, {}", rt, fs),
+        Mtc1 {rt, fs} => format!("mtc1 {}, {}", rt, fs),
+        Lwc1 {ft, base, offset} => format!("lwc1 {}, {}({})", ft, offset, base),
+        Ldc1 {ft, base, offset} => format!("ldc1 {}, {}({})", ft, offset, base),
+        Swc1 {ft, base, offset} => format!("swc1 {}, {}({})", ft, offset, base),
+        Sdc1 {ft, base, offset} => format!("sdc1 {}, {}({})", ft, offset, base),
+        Mfc0 {rt, rd} => format!("mfc0 {}, ${}", rt, rd),
+        Mtc0 {rt, rd} => format!("mtc0 {}, ${}", rt, rd),
+        Eret => "eret".to_string(),
+    }
+}
+
+/// Renders the machine word `word` found at `addr`. Words that aren't
+/// instructions are shown as `.word` data.
+pub fn disassemble_word(word: u32, addr: u32, program: Option<&Program>) -> String {
+    let label = |target: u32| program.and_then(|program| program.label_at(target)).map(|label| label.to_string());
+    match Instruction::decode(word, addr) {
+        Some(instruction) => format_instruction(&instruction, &label),
+        None => format!(".word 0x{:08x}", word),
+    }
+}
+
+/// Disassembles `count` words of memory starting at `addr`, stopping early
+/// at a page that has never been written to.
+pub fn disassemble(memory: &mut MemoryRep, program: Option<&Program>, addr: u32, count: usize) -> Vec<DisassembledWord> {
+    let mut words = Vec::new();
+    for i in 0..count as u32 {
+        let addr = addr.wrapping_add(4 * i);
+        let word = match memory.read_word(addr as usize) {
+            Ok(word) => word,
+            Err(_) => break
+        };
+        words.push(DisassembledWord {
+            addr,
+            word,
+            label: program.and_then(|program| program.label_at(addr)).map(|label| label.to_string()),
+            text: disassemble_word(word, addr, program),
+        });
+    }
+    words
+}
+
+/// Renders the text a program was assembled from, including any `.ktext`,
+/// as source that assembles back to the same machine code. The startup code
+/// is left out, since the assembler adds it again.
+pub fn disassemble_program(program: &Program) -> String {
+    let mut source = String::from("\t.text\n");
+    disassemble_text(program, &program.words, &program.locations, TEXT_BASE, &mut source);
+    if !program.kernel_words.is_empty() {
+        source.push_str("\t.ktext\n");
+        disassemble_text(program, &program.kernel_words, &program.kernel_locations, KTEXT_BASE, &mut source);
+    }
+    source
+}
+
+fn disassemble_text(program: &Program, words: &[u32], locations: &[Option<MIPSLocation>], base: u32, source: &mut String) {
+    for (i, word) in words.iter().enumerate() {
+        if locations[i].is_none() {
+            continue
+        }
+        let addr = base + 4 * i as u32;
+        if let Some(label) = program.label_at(addr) {
+            source.push_str(&format!("{}:\n", label));
+        }
+        source.push_str(&format!("\t{}\t# 0x{:08x}: {:08x}\n", disassemble_word(*word, addr, Some(program)), addr, word));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+
+    fn assemble_str(source: &str) -> Program {
+        let (components, _) = read_str_to_state(source, "test.s");
+        assemble(&components).unwrap()
+    }
+
+    const PROGRAM: &str = "
+            .data
+    value:  .word 5
+            .text
+    main:   addi $sp, $sp, -4
+            sw $ra, 0($sp)
+            lw $a0, value
+            li $t0, 0x12345678
+            la $t1, value
+            blt $a0, 10, small
+            jal helper
+    small:  bgez $a0, done
+            abs $a0, $a0
+            rem $t2, $a0, $t0
+            sllv $t3, $t2, $a0
+            srl $t3, $t3, 3
+            xori $t4, $t3, 0xff00
+            jalr $t4
+            jalr $t5, $t4
+    done:   lw $ra, 0($sp)
+            addi $sp, $sp, 4
+            nop
+            jr $ra
+    helper: lbu $v0, -1($a0)
+            sltiu $v1, $v0, -3
+            mthi $v0
+            l.d $f2, 8($sp)
+            c.ule.d 2, $f2, $f4
+            bc1f 2, done
+            cvt.s.w $f0, $f1
+            b done
+            .ktext
+            mfc0 $k0, $14
+            addiu $k0, $k0, 4
+            mtc0 $k0, $14
+            break 3
+            eret
+    ";
+
+    #[test]
+    fn test_disassemble_word(){
+        let program = assemble_str(PROGRAM);
+        let text: Vec<String> = program.words.iter().enumerate()
+            .map(|(i, word)| disassemble_word(*word, TEXT_BASE + 4 * i as u32, Some(&program)))
+            .collect();
+        assert_eq!(text[0], "addi $sp, $sp, -4");
+        assert_eq!(text[1], "sw $ra, 0($sp)");
+        assert_eq!(text[2], "lui $at, 0x1001");
+        assert_eq!(text[3], "lw $a0, 0($at)");
+        assert!(text.contains(&"jal helper".to_string()));
+        assert!(text.contains(&"bgez $a0, done".to_string()));
+        assert!(text.contains(&"jalr $t4".to_string()));
+        assert!(text.contains(&"jalr $t5, $t4".to_string()));
+        assert!(text.contains(&"nop".to_string()));
+        assert!(text.contains(&"ldc1 $f2, 8($sp)".to_string()));
+        assert!(text.contains(&"c.ule.d 2, $f2, $f4".to_string()));
+        assert!(text.contains(&"bc1f 2, done".to_string()));
+        assert_eq!(disassemble_word(program.kernel_words[0], KTEXT_BASE, Some(&program)), "mfc0 $k0, $14");
+        assert_eq!(disassemble_word(0x4200_0018, KTEXT_BASE, None), "eret");
+        assert_eq!(text.last().unwrap(), "syscall");
+        assert_eq!(disassemble_word(0x0800_0000, TEXT_BASE, None), "j 0x00000000");
+        assert_eq!(disassemble_word(0xffff_ffff, TEXT_BASE, None), ".word 0xffffffff");
+    }
+
+    #[test]
+    fn test_disassemble_memory(){
+        let program = assemble_str("main: li $v0, 10\nsyscall");
+        let mut memory = MemoryRep::new();
+        for (i, word) in program.words.iter().enumerate() {
+            memory.store_word(TEXT_BASE as usize + 4 * i, *word).unwrap();
+        }
+        let words = disassemble(&mut memory, Some(&program), TEXT_BASE, 6);
+        assert_eq!(words.len(), 6);
+        assert_eq!(words[0].label, Some("main".to_string()));
+        assert_eq!(words[0].to_string(), "0x00400000  2402000a  addiu $v0, $zero, 10");
+        assert_eq!(words[2].text, "jal main");
+        assert_eq!(words[5].text, ".word 0x66666666");
+        assert!(disassemble(&mut memory, None, 0x0080_0000, 5).is_empty());
+    }
+
+    #[test]
+    fn test_round_trip(){
+        let program = assemble_str(PROGRAM);
+        let source = disassemble_program(&program);
+        let reassembled = assemble_str(&source);
+        assert_eq!(reassembled.words, program.words);
+        assert_eq!(disassemble_program(&reassembled), source);
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt::Write;
+
+use super::device::Device;
+
+/// Where MARS's bitmap display is mapped by default, the start of its heap.
+pub const DISPLAY_BASE: usize = 0x10040000;
+
+/// A bitmap display, as in MARS. Each pixel is a word `0x00rrggbb`, stored
+/// a row at a time from the top left.
+pub struct BitmapDisplay {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>,
+}
+
+impl BitmapDisplay {
+    /// A black display of `width` by `height` pixels.
+    pub fn new(width: usize, height: usize) -> BitmapDisplay {
+        BitmapDisplay { width, height, pixels: vec![0; width * height] }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The bytes the display takes up when mapped.
+    pub fn len(&self) -> usize {
+        self.pixels.len() * 4
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pixels.is_empty()
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.pixels[y * self.width + x]
+    }
+
+    fn rgb(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|pixel| rgb(*pixel).to_vec()).collect()
+    }
+
+    /// The display as a binary PPM image.
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        ppm.extend(self.rgb());
+        ppm
+    }
+
+    /// The display as a PNG image. Its pixels are stored uncompressed.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend(&(self.width as u32).to_be_bytes());
+        header.extend(&(self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGB, then the default compression, filtering
+        // and no interlacing.
+        header.extend(&[8, 2, 0, 0, 0]);
+
+        let mut scanlines = Vec::with_capacity(self.height * (self.width * 3 + 1));
+        for row in self.rgb().chunks(self.width * 3) {
+            scanlines.push(0);
+            scanlines.extend(row);
+        }
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &zlib_stored(&scanlines));
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// The display in ANSI colour, two rows to each line of text: the top
+    /// pixel is the foreground of a half block and the bottom its background.
+    pub fn to_ansi(&self) -> String {
+        let mut ansi = String::new();
+        for y in (0..self.height).step_by(2) {
+            for x in 0..self.width {
+                let [r, g, b] = rgb(self.pixel(x, y));
+                write!(ansi, "\x1b[38;2;{};{};{}m", r, g, b).unwrap();
+                if y + 1 < self.height {
+                    let [r, g, b] = rgb(self.pixel(x, y + 1));
+                    write!(ansi, "\x1b[48;2;{};{};{}m", r, g, b).unwrap();
+                }
+                ansi.push('\u{2580}');
+            }
+            ansi.push_str("\x1b[0m\n");
+        }
+        ansi
+    }
+}
+
+impl Device for BitmapDisplay {
+    fn read(&mut self, offset: u32) -> u32 {
+        self.pixels[offset as usize / 4]
+    }
+
+    fn write(&mut self, offset: u32, val: u32) {
+        self.pixels[offset as usize / 4] = val & 0xffffff;
+    }
+}
+
+fn rgb(pixel: u32) -> [u8; 3] {
+    let [_, r, g, b] = pixel.to_be_bytes();
+    [r, g, b]
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(kind);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(&crc.to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream of uncompressed blocks, which every
+/// decoder can read.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend(&(block.len() as u16).to_le_bytes());
+        zlib.extend(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend(block);
+    }
+    zlib.extend(&adler32(data).to_be_bytes());
+    zlib
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 != 0 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 }
+        })
+    })
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (a, b) = bytes.iter().fold((1, 0), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+    use super::super::runtime::Runtime;
+    use super::super::runtime::RegisterCodes::*;
+
+    #[test]
+    fn test_checksums(){
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        let zlib = zlib_stored(&[7; 0x10000]);
+        assert_eq!(&zlib[..7], &[0x78, 0x01, 0, 0xff, 0xff, 0, 0]);
+        assert_eq!(&zlib[0xffff + 7..0xffff + 13], &[1, 1, 0, 0xfe, 0xff, 7]);
+        assert_eq!(zlib.len(), 2 + 5 + 0xffff + 5 + 1 + 4);
+    }
+
+    #[test]
+    fn test_bitmap_display(){
+        // Draws red, green and blue pixels down the diagonal, and a white
+        // one in the top right.
+        let source = "
+        main:   li $t0, 0x10040000
+                li $t1, 0xff0000
+                sw $t1, 0($t0)
+                li $t1, 0x00ff00
+                sw $t1, 20($t0)
+                li $t1, 0x0000ff
+                sw $t1, 40($t0)
+                li $t1, -1
+                sw $t1, 12($t0)
+                lw $s0, 12($t0)
+                jr $ra
+        ";
+        let (components, _) = read_str_to_state(source, "test.s");
+        let mut runtime = Runtime::new();
+        runtime.load_program(assemble(&components).unwrap()).unwrap();
+        let display = Rc::new(RefCell::new(BitmapDisplay::new(4, 3)));
+        runtime.map_device(DISPLAY_BASE, display.borrow().len(), Box::new(display.clone())).unwrap();
+        assert_eq!(runtime.run().unwrap(), 0);
+        assert_eq!(runtime.get_register(&Rs0), Ok(0xffffff));
+
+        let display = display.borrow();
+        assert_eq!(display.pixel(1, 1), 0x00ff00);
+        assert_eq!(display.pixel(3, 2), 0);
+        let ppm = display.to_ppm();
+        assert_eq!(&ppm[..11], b"P6\n4 3\n255\n");
+        assert_eq!(ppm.len(), 11 + 4 * 3 * 3);
+        assert_eq!(&ppm[11..17], &[255, 0, 0, 0, 0, 0]);
+        assert_eq!(&ppm[11 + 15..11 + 18], &[0, 255, 0]);
+
+        let png = display.to_png();
+        assert_eq!(&png[..16], b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 4, 0, 0, 0, 3, 8, 2, 0, 0, 0]);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+
+        let black = "\x1b[38;2;0;0;0m";
+        assert_eq!(display.to_ansi(), [
+            "\x1b[38;2;255;0;0m\x1b[48;2;0;0;0m\u{2580}",
+            "\x1b[38;2;0;0;0m\x1b[48;2;0;255;0m\u{2580}",
+            "\x1b[38;2;0;0;0m\x1b[48;2;0;0;0m\u{2580}",
+            "\x1b[38;2;255;255;255m\x1b[48;2;0;0;0m\u{2580}\x1b[0m\n",
+            black, "\u{2580}", black, "\u{2580}",
+            "\x1b[38;2;0;0;255m\u{2580}", black, "\u{2580}\x1b[0m\n",
+        ].concat());
+    }
+}
@@ -0,0 +1,520 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::assembler::Program;
+use super::memory::Endian;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+pub const ET_REL: u16 = 1;
+pub const ET_EXEC: u16 = 2;
+const EM_MIPS: u16 = 8;
+/// MIPS32, o32 ABI.
+const EF_MIPS: u32 = 0x5000_1000;
+const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+pub(crate) const SHT_PROGBITS: u32 = 1;
+pub(crate) const SHT_SYMTAB: u32 = 2;
+pub(crate) const SHT_STRTAB: u32 = 3;
+pub(crate) const SHT_RELA: u32 = 4;
+pub(crate) const SHT_REL: u32 = 9;
+pub(crate) const SHF_WRITE: u32 = 1;
+pub(crate) const SHF_ALLOC: u32 = 2;
+pub(crate) const SHF_EXECINSTR: u32 = 4;
+pub(crate) const STB_LOCAL: u8 = 0;
+pub(crate) const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+pub(crate) const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    NotElf,
+    /// Only 32-bit ELF files are supported.
+    Not32Bit,
+    NotMips,
+    NotExecutable,
+    NotRelocatable,
+    UnknownEndianness(u8),
+    UnsupportedRelocation(u8),
+    /// A relocatable object has a section micah can't link, named here.
+    UnsupportedSection(String),
+    /// A header or table runs past the end of the file; the string names it.
+    Truncated(&'static str),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Not32Bit => write!(f, "not a 32-bit ELF file"),
+            ElfError::NotMips => write!(f, "not a MIPS ELF file"),
+            ElfError::NotExecutable => write!(f, "not an executable ELF file"),
+            ElfError::NotRelocatable => write!(f, "not a relocatable ELF object"),
+            ElfError::UnknownEndianness(data) => write!(f, "unknown ELF byte order {}", data),
+            ElfError::UnsupportedRelocation(kind) => write!(f, "unsupported relocation type {}", kind),
+            ElfError::UnsupportedSection(name) => write!(f, "unsupported ELF section {}", name),
+            ElfError::Truncated(part) => write!(f, "ELF {} runs past the end of the file", part),
+        }
+    }
+}
+
+/// A `PT_LOAD` segment: `data` goes at `vaddr`, followed by zeroes up to
+/// `mem_size` bytes.
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub vaddr: u32,
+    pub data: Vec<u8>,
+    pub mem_size: u32,
+    /// `PF_R`, `PF_W` and `PF_X` bits.
+    pub flags: u32,
+}
+
+/// A parsed MIPS32 ELF executable.
+#[derive(Debug, PartialEq)]
+pub struct Elf {
+    pub endian: Endian,
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    /// Defined function, object and untyped symbols.
+    pub symbols: HashMap<String, u32>,
+}
+
+/// Reads fields of the file in its byte order.
+pub(crate) struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub endian: Endian,
+}
+
+impl<'a> Reader<'a> {
+    pub fn slice(&self, offset: usize, len: usize, part: &'static str) -> Result<&'a [u8], ElfError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.bytes.len() => Ok(&self.bytes[offset..end]),
+            _ => Err(ElfError::Truncated(part))
+        }
+    }
+
+    pub fn half(&self, offset: usize, part: &'static str) -> Result<u16, ElfError> {
+        let bytes = self.slice(offset, 2, part)?;
+        Ok(self.endian.half_from_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn word(&self, offset: usize, part: &'static str) -> Result<u32, ElfError> {
+        let bytes = self.slice(offset, 4, part)?;
+        Ok(self.endian.word_from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(ELF_MAGIC)
+}
+
+/// Checks the identification and machine of a MIPS32 ELF file, returning a
+/// reader in its byte order and its type.
+pub(crate) fn read_header(bytes: &[u8]) -> Result<(Reader<'_>, u16), ElfError> {
+    if !is_elf(bytes) {
+        return Err(ElfError::NotElf)
+    }
+    if bytes.len() < 52 {
+        return Err(ElfError::Truncated("header"))
+    }
+    if bytes[4] != ELFCLASS32 {
+        return Err(ElfError::Not32Bit)
+    }
+    let endian = match bytes[5] {
+        ELFDATA2LSB => Endian::Little,
+        ELFDATA2MSB => Endian::Big,
+        data => return Err(ElfError::UnknownEndianness(data))
+    };
+    let reader = Reader {bytes, endian};
+    if reader.half(18, "header")? != EM_MIPS {
+        return Err(ElfError::NotMips)
+    }
+    let elf_type = reader.half(16, "header")?;
+    Ok((reader, elf_type))
+}
+
+/// A section header's fields.
+pub(crate) struct SectionHeader {
+    pub name: u32,
+    pub sh_type: u32,
+    pub offset: usize,
+    pub size: usize,
+    pub link: usize,
+    pub info: usize,
+    pub align: u32,
+    pub entsize: usize,
+}
+
+pub(crate) fn section_headers(reader: &Reader) -> Result<Vec<SectionHeader>, ElfError> {
+    let shoff = reader.word(32, "header")? as usize;
+    let shentsize = reader.half(46, "header")? as usize;
+    let shnum = reader.half(48, "header")? as usize;
+    let mut sections = Vec::new();
+    for i in 0..shnum {
+        let header = shoff + i * shentsize;
+        sections.push(SectionHeader {
+            name: reader.word(header, "section header")?,
+            sh_type: reader.word(header + 4, "section header")?,
+            offset: reader.word(header + 16, "section header")? as usize,
+            size: reader.word(header + 20, "section header")? as usize,
+            link: reader.word(header + 24, "section header")? as usize,
+            info: reader.word(header + 28, "section header")? as usize,
+            align: reader.word(header + 32, "section header")?,
+            entsize: reader.word(header + 36, "section header")? as usize,
+        });
+    }
+    Ok(sections)
+}
+
+/// Each section's name, from the section header string table.
+pub(crate) fn section_names(reader: &Reader, sections: &[SectionHeader]) -> Result<Vec<String>, ElfError> {
+    let shstrndx = reader.half(50, "header")? as usize;
+    let shstrtab = match sections.get(shstrndx) {
+        Some(shstrtab) => reader.slice(shstrtab.offset, shstrtab.size, "string table")?,
+        None => return Ok(vec![String::new(); sections.len()])
+    };
+    sections.iter().map(|section| string_at(shstrtab, section.name as usize)).collect()
+}
+
+/// The NUL-terminated string at `offset` in a string table.
+pub(crate) fn string_at(table: &[u8], offset: usize) -> Result<String, ElfError> {
+    match table.get(offset..) {
+        Some(rest) => {
+            let end = rest.iter().position(|byte| *byte == 0).unwrap_or(rest.len());
+            Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+        }
+        None => Err(ElfError::Truncated("string table"))
+    }
+}
+
+/// Parses a big- or little-endian MIPS32 ELF executable.
+pub fn parse_elf(bytes: &[u8]) -> Result<Elf, ElfError> {
+    let (reader, elf_type) = read_header(bytes)?;
+    let endian = reader.endian;
+    if elf_type != ET_EXEC {
+        return Err(ElfError::NotExecutable)
+    }
+    let entry = reader.word(24, "header")?;
+    let phoff = reader.word(28, "header")? as usize;
+    let phentsize = reader.half(42, "header")? as usize;
+    let phnum = reader.half(44, "header")? as usize;
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let header = phoff + i * phentsize;
+        if reader.word(header, "program header")? != PT_LOAD {
+            continue
+        }
+        let offset = reader.word(header + 4, "program header")? as usize;
+        let vaddr = reader.word(header + 8, "program header")?;
+        let file_size = reader.word(header + 16, "program header")? as usize;
+        let mem_size = reader.word(header + 20, "program header")?;
+        let flags = reader.word(header + 24, "program header")?;
+        segments.push(Segment {
+            vaddr,
+            data: reader.slice(offset, file_size, "segment")?.to_vec(),
+            mem_size,
+            flags,
+        });
+    }
+
+    let mut symbols = HashMap::new();
+    for symbol in read_symbols(&reader, &section_headers(&reader)?)? {
+        if symbol.shndx != SHN_UNDEF && !symbol.name.is_empty() {
+            symbols.insert(symbol.name, symbol.value);
+        }
+    }
+
+    Ok(Elf {endian, entry, segments, symbols})
+}
+
+/// An entry of a symbol table.
+pub(crate) struct SymbolEntry {
+    pub name: String,
+    pub value: u32,
+    pub binding: u8,
+    pub shndx: u16,
+}
+
+/// Reads the first symbol table, leaving out section and file symbols but
+/// keeping every other symbol's index.
+pub(crate) fn read_symbols(reader: &Reader, sections: &[SectionHeader]) -> Result<Vec<SymbolEntry>, ElfError> {
+    let symtab = match sections.iter().find(|section| section.sh_type == SHT_SYMTAB) {
+        Some(symtab) => symtab,
+        None => return Ok(Vec::new())
+    };
+    let strtab = match sections.get(symtab.link) {
+        Some(strtab) => reader.slice(strtab.offset, strtab.size, "string table")?,
+        None => return Err(ElfError::Truncated("section header"))
+    };
+    let entsize = symtab.entsize.max(16);
+    let mut symbols = Vec::new();
+    for i in 0..symtab.size / entsize {
+        let symbol = symtab.offset + i * entsize;
+        let name = reader.word(symbol, "symbol table")? as usize;
+        let info = reader.slice(symbol + 12, 1, "symbol table")?[0];
+        let skipped = [STT_SECTION, STT_FILE].contains(&(info & 0xf));
+        symbols.push(SymbolEntry {
+            name: if skipped { String::new() } else { string_at(strtab, name)? },
+            value: reader.word(symbol + 4, "symbol table")?,
+            binding: info >> 4,
+            shndx: if skipped { SHN_UNDEF } else { reader.half(symbol + 14, "symbol table")? },
+        });
+    }
+    Ok(symbols)
+}
+
+/// A section to be written by `ElfWriter`.
+pub(crate) struct Section {
+    pub name: &'static str,
+    pub sh_type: u32,
+    pub flags: u32,
+    pub addr: u32,
+    pub data: Vec<u8>,
+    pub link: u32,
+    pub info: u32,
+    pub align: u32,
+    pub entsize: u32,
+}
+
+/// Lays out an ELF file: the header, program headers, each section's data,
+/// then the section headers. Section 0 is the null section.
+pub(crate) struct ElfWriter {
+    pub endian: Endian,
+    sections: Vec<Section>,
+}
+
+impl ElfWriter {
+    pub fn new(endian: Endian) -> ElfWriter {
+        ElfWriter {endian, sections: Vec::new()}
+    }
+
+    /// Adds a section, returning its index.
+    pub fn add(&mut self, section: Section) -> u16 {
+        self.sections.push(section);
+        self.sections.len() as u16
+    }
+
+    pub fn half(&self, out: &mut Vec<u8>, half: u16) {
+        out.extend_from_slice(&self.endian.half_to_bytes(half));
+    }
+
+    pub fn word(&self, out: &mut Vec<u8>, word: u32) {
+        out.extend_from_slice(&self.endian.word_to_bytes(word));
+    }
+
+    /// A symbol table entry.
+    pub fn symbol(&self, out: &mut Vec<u8>, name: u32, value: u32, binding: u8, shndx: u16) {
+        self.word(out, name);
+        self.word(out, value);
+        self.word(out, 0);
+        out.push(binding << 4 | STT_NOTYPE);
+        out.push(0);
+        self.half(out, shndx);
+    }
+
+    /// Writes the file. Each `(section, flags)` in `segments` becomes a
+    /// `PT_LOAD` segment covering that section, extended to `mem_size` bytes.
+    pub fn finish(self, elf_type: u16, entry: u32, segments: &[(u16, u32, u32)]) -> Vec<u8> {
+        let phoff = 52;
+        let mut offset = phoff + 32 * segments.len();
+        let mut offsets = vec![0];
+        for section in &self.sections {
+            offset += (section.align as usize).max(1) - 1;
+            offset -= offset % (section.align as usize).max(1);
+            offsets.push(offset);
+            offset += section.data.len();
+        }
+        let shstrtab_offset = offset;
+        let mut shstrtab = vec![0];
+        let mut names = vec![0];
+        for section in &self.sections {
+            names.push(shstrtab.len() as u32);
+            shstrtab.extend_from_slice(section.name.as_bytes());
+            shstrtab.push(0);
+        }
+        let shstrtab_name = shstrtab.len() as u32;
+        shstrtab.extend_from_slice(b".shstrtab\0");
+        let shoff = (shstrtab_offset + shstrtab.len() + 3) & !3;
+        let shnum = self.sections.len() as u16 + 2;
+
+        let mut out = ELF_MAGIC.to_vec();
+        out.push(ELFCLASS32);
+        out.push(if self.endian == Endian::Big { ELFDATA2MSB } else { ELFDATA2LSB });
+        out.push(1);
+        out.resize(16, 0);
+        self.half(&mut out, elf_type);
+        self.half(&mut out, EM_MIPS);
+        self.word(&mut out, 1);
+        self.word(&mut out, entry);
+        self.word(&mut out, if segments.is_empty() { 0 } else { phoff as u32 });
+        self.word(&mut out, shoff as u32);
+        self.word(&mut out, EF_MIPS);
+        for half in &[52, 32, segments.len() as u16, 40, shnum, shnum - 1] {
+            self.half(&mut out, *half);
+        }
+
+        for (index, flags, mem_size) in segments {
+            let section = &self.sections[*index as usize - 1];
+            let file_size = section.data.len() as u32;
+            let fields = [
+                PT_LOAD, offsets[*index as usize] as u32, section.addr, section.addr,
+                file_size, (*mem_size).max(file_size), *flags, 0x1000,
+            ];
+            for field in &fields {
+                self.word(&mut out, *field);
+            }
+        }
+        for (section, offset) in self.sections.iter().zip(&offsets[1..]) {
+            out.resize(*offset, 0);
+            out.extend_from_slice(&section.data);
+        }
+        out.extend_from_slice(&shstrtab);
+        out.resize(shoff, 0);
+
+        out.extend_from_slice(&[0; 40]);
+        for (i, section) in self.sections.iter().enumerate() {
+            let fields = [
+                names[i + 1], section.sh_type, section.flags, section.addr, offsets[i + 1] as u32,
+                section.data.len() as u32, section.link, section.info, section.align, section.entsize,
+            ];
+            for field in &fields {
+                self.word(&mut out, *field);
+            }
+        }
+        let fields = [shstrtab_name, SHT_STRTAB, 0, 0, shstrtab_offset as u32, shstrtab.len() as u32, 0, 0, 1, 0];
+        for field in &fields {
+            self.word(&mut out, *field);
+        }
+        out
+    }
+}
+
+/// Writes an executable that `parse_elf` reads back as `elf`. Executable
+/// segments are named `.text` and the rest `.data`.
+pub fn write_executable(elf: &Elf) -> Vec<u8> {
+    let mut writer = ElfWriter::new(elf.endian);
+    let mut segments = Vec::new();
+    for segment in &elf.segments {
+        let executable = segment.flags & PF_X != 0;
+        let index = writer.add(Section {
+            name: if executable { ".text" } else { ".data" },
+            sh_type: SHT_PROGBITS,
+            flags: SHF_ALLOC | if executable { SHF_EXECINSTR } else { SHF_WRITE },
+            addr: segment.vaddr,
+            data: segment.data.clone(),
+            link: 0,
+            info: 0,
+            align: 4,
+            entsize: 0,
+        });
+        segments.push((index, segment.flags, segment.mem_size));
+    }
+
+    let mut names: Vec<(&String, &u32)> = elf.symbols.iter().collect();
+    names.sort();
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 16];
+    for (name, value) in names {
+        let shndx = segments.iter()
+            .find(|(index, _, _)| {
+                let segment = &elf.segments[*index as usize - 1];
+                let size = segment.mem_size.max(segment.data.len() as u32);
+                *value >= segment.vaddr && *value - segment.vaddr < size
+            })
+            .map(|(index, _, _)| *index)
+            .unwrap_or(SHN_ABS);
+        writer.symbol(&mut symtab, strtab.len() as u32, *value, STB_GLOBAL, shndx);
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+    }
+    let strtab_index = writer.sections.len() as u32 + 2;
+    writer.add(Section {
+        name: ".symtab", sh_type: SHT_SYMTAB, flags: 0, addr: 0, data: symtab,
+        link: strtab_index, info: 1, align: 4, entsize: 16,
+    });
+    writer.add(Section {
+        name: ".strtab", sh_type: SHT_STRTAB, flags: 0, addr: 0, data: strtab,
+        link: 0, info: 0, align: 1, entsize: 0,
+    });
+    writer.finish(ET_EXEC, elf.entry, &segments)
+}
+
+impl Elf {
+    /// The executable's symbols and entry point, for naming addresses once
+    /// its segments are in memory.
+    pub fn program(&self) -> Program {
+        Program::from_symbols(self.symbols.clone(), self.entry)
+    }
+}
+
+/// Builds small executables for tests, since there is no cross-compiler to
+/// make real ones.
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+
+    /// An executable with one segment per `(vaddr, bytes, mem_size)` and a
+    /// symbol table holding `symbols`.
+    pub fn executable(endian: Endian, entry: u32, segments: &[(u32, Vec<u8>, u32)], symbols: &[(&str, u32)]) -> Vec<u8> {
+        write_executable(&Elf {
+            endian,
+            entry,
+            segments: segments.iter()
+                .map(|(vaddr, data, mem_size)| Segment {vaddr: *vaddr, data: data.clone(), mem_size: *mem_size, flags: PF_R | PF_W | PF_X})
+                .collect(),
+            symbols: symbols.iter().map(|(name, value)| (name.to_string(), *value)).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_elf(){
+        for endian in [Endian::Big, Endian::Little].iter() {
+            let bytes = fixtures::executable(
+                *endian, 0x0040_0008,
+                &[(0x0040_0000, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12], 12), (0x1001_0000, vec![1], 16)],
+                &[("main", 0x0040_0008), ("_gp", 0x1001_8000)]
+            );
+            let elf = parse_elf(&bytes).unwrap();
+            assert_eq!(elf.endian, *endian);
+            assert_eq!(elf.entry, 0x0040_0008);
+            assert_eq!(elf.segments[0].vaddr, 0x0040_0000);
+            assert_eq!(elf.segments[0].data.len(), 12);
+            assert_eq!(elf.segments[1].data, vec![1]);
+            assert_eq!(elf.segments[1].mem_size, 16);
+            assert_eq!(elf.symbols["main"], 0x0040_0008);
+            assert_eq!(elf.symbols["_gp"], 0x1001_8000);
+            assert_eq!(elf.program().label_at(0x0040_0008), Some("main"));
+        }
+    }
+
+    #[test]
+    fn test_bad_elf(){
+        let bytes = fixtures::executable(Endian::Big, 0, &[(0x0040_0000, vec![0; 8], 8)], &[]);
+        assert_eq!(parse_elf(b"#!/bin/sh\n"), Err(ElfError::NotElf));
+        assert_eq!(parse_elf(&bytes[..40]), Err(ElfError::Truncated("header")));
+        assert_eq!(parse_elf(&bytes[..90]), Err(ElfError::Truncated("segment")));
+
+        let mut wrong_class = bytes.clone();
+        wrong_class[4] = 2;
+        assert_eq!(parse_elf(&wrong_class), Err(ElfError::Not32Bit));
+        let mut wrong_machine = bytes.clone();
+        wrong_machine[19] = 3;
+        assert_eq!(parse_elf(&wrong_machine), Err(ElfError::NotMips));
+        let mut relocatable = bytes;
+        relocatable[17] = 1;
+        assert_eq!(parse_elf(&relocatable), Err(ElfError::NotExecutable));
+    }
+}
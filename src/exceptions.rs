@@ -0,0 +1,150 @@
+use super::mips_parser::{read_str_to_state, MIPSComponent};
+
+/// The default exception handler, after SPIM's `exceptions.s`. It prints
+/// which exception happened and carries on after the instruction that
+/// raised it. A bad instruction address can't be skipped, so it exits, and
+/// interrupts it has no code for are disabled rather than reported.
+pub const DEFAULT_HANDLER: &str = r#"
+        .kdata
+__m1_:  .asciiz "  Exception "
+__m2_:  .asciiz " occurred and ignored\n"
+__e0_:  .asciiz "  [Interrupt] "
+__e1_:  .asciiz "  [TLB]"
+__e2_:  .asciiz "  [TLB]"
+__e3_:  .asciiz "  [TLB]"
+__e4_:  .asciiz "  [Address error in inst/data fetch] "
+__e5_:  .asciiz "  [Address error in store] "
+__e6_:  .asciiz "  [Bad instruction address] "
+__e7_:  .asciiz "  [Bad data address] "
+__e8_:  .asciiz "  [Error in syscall] "
+__e9_:  .asciiz "  [Breakpoint] "
+__e10_: .asciiz "  [Reserved instruction] "
+__e11_: .asciiz ""
+__e12_: .asciiz "  [Arithmetic overflow] "
+__e13_: .asciiz "  [Trap] "
+__e14_: .asciiz ""
+__e15_: .asciiz "  [Floating point] "
+        .align 2
+__excp: .word __e0_, __e1_, __e2_, __e3_, __e4_, __e5_, __e6_, __e7_
+        .word __e8_, __e9_, __e10_, __e11_, __e12_, __e13_, __e14_, __e15_
+# The handler can't trust $sp, so it saves what it uses here.
+__s1:   .word 0
+__s2:   .word 0
+
+        .ktext 0x80000180
+        move $k1, $at
+        sw $v0, __s1
+        sw $a0, __s2
+
+        mfc0 $k0, $13
+        srl $a0, $k0, 2
+        andi $a0, $a0, 0x1f
+        bne $a0, $zero, __report
+        mfc0 $k0, $12
+        li $at, -2
+        and $k0, $k0, $at
+        mtc0 $k0, $12
+        j __restore
+
+__report:
+        li $v0, 4
+        la $a0, __m1_
+        syscall
+        li $v0, 1
+        srl $a0, $k0, 2
+        andi $a0, $a0, 0x1f
+        syscall
+        li $v0, 4
+        andi $a0, $k0, 0x3c
+        lw $a0, __excp($a0)
+        syscall
+        srl $a0, $k0, 2
+        andi $a0, $a0, 0x1f
+        bne $a0, 6, __skip
+        li $v0, 10
+        syscall
+
+__skip:
+        li $v0, 4
+        la $a0, __m2_
+        syscall
+        mfc0 $k0, $14
+        addiu $k0, $k0, 4
+        mtc0 $k0, $14
+
+__restore:
+        lw $v0, __s1
+        lw $a0, __s2
+        move $at, $k1
+        mtc0 $zero, $13
+        eret
+"#;
+
+/// Whether a program brings its own exception handler.
+pub fn has_handler(components: &[MIPSComponent]) -> bool {
+    components.iter().any(|component| match component {
+        MIPSComponent::Directive(directive) => directive.directive_type.eq_ignore_ascii_case("ktext"),
+        _ => false
+    })
+}
+
+/// Adds `DEFAULT_HANDLER` to a program's components, unless the program has
+/// a handler of its own.
+pub fn add_default_handler(components: &mut Vec<MIPSComponent>) {
+    if !has_handler(components) {
+        let (handler, _) = read_str_to_state(DEFAULT_HANDLER, "exceptions.s");
+        components.extend(handler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::console::Console;
+    use super::super::runtime::Runtime;
+
+    fn run(source: &str) -> (i32, String) {
+        let (mut components, _) = read_str_to_state(source, "test.s");
+        add_default_handler(&mut components);
+        let mut runtime = Runtime::new();
+        runtime.load_program(assemble(&components).unwrap()).unwrap();
+        runtime.set_console(Console::scripted(b""));
+        let code = runtime.run().unwrap();
+        (code, String::from_utf8_lossy(runtime.console().captured_output().unwrap()).into_owned())
+    }
+
+    #[test]
+    fn test_default_handler(){
+        let (code, output) = run("
+        main:   li $t0, 0x7fffffff
+                addi $t0, $t0, 1
+                sh $t0, 1($sp)
+                li $v0, 1
+                li $a0, 5
+                syscall
+                jr $ra
+        ");
+        assert_eq!(code, 0);
+        assert_eq!(output, concat!(
+            "  Exception 12  [Arithmetic overflow]  occurred and ignored\n",
+            "  Exception 5  [Address error in store]  occurred and ignored\n",
+            "5",
+        ));
+
+        let (code, output) = run("main: jr $zero");
+        assert_eq!(code, 0);
+        assert_eq!(output, "  Exception 6  [Bad instruction address] ");
+    }
+
+    #[test]
+    fn test_own_handler(){
+        let source = "main: break\njr $ra\n.ktext\nmfc0 $k0, $14\naddiu $k0, $k0, 4\nmtc0 $k0, $14\neret";
+        let (mut components, _) = read_str_to_state(source, "test.s");
+        assert!(has_handler(&components));
+        let count = components.len();
+        add_default_handler(&mut components);
+        assert_eq!(components.len(), count);
+        assert_eq!(run(source), (0, String::new()));
+    }
+}
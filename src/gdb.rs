@@ -0,0 +1,648 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use super::debugger::{Debugger, Stop};
+use super::runtime::{FloatRegister, RegisterCodes, Runtime, RuntimeError};
+
+/// The largest packet the server accepts, which gdb also sizes its memory
+/// reads and writes by.
+const PACKET_SIZE: usize = 0x4000;
+
+/// The byte gdb sends, outside any packet, to interrupt a running program.
+const INTERRUPT: u8 = 0x03;
+
+/// gdb's numbering of the MIPS registers, as the target description sets out:
+/// the 32 general registers, then these, then the 32 floating-point
+/// registers, `fcsr` and `fir`.
+const STATUS: usize = 32;
+const LO: usize = 33;
+const HI: usize = 34;
+const BAD_VADDR: usize = 35;
+const CAUSE: usize = 36;
+const PC: usize = 37;
+const FLOAT_BASE: usize = 38;
+const FCSR: usize = 70;
+const FIR: usize = 71;
+const NUM_REGISTERS: usize = 72;
+
+/// micah doesn't model the FPU implementation register, so it reads as an
+/// FPU with single and double precision.
+const FIR_VALUE: u32 = 0x0003_0000;
+
+/// Signals gdb is told stopped the program.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGFPE: u8 = 8;
+const SIGBUS: u8 = 10;
+const SIGSEGV: u8 = 11;
+
+/// What to do after answering a packet.
+enum Outcome {
+    Reply(String),
+    /// Close the connection, sending this reply first if there is one.
+    Close(Option<String>),
+}
+
+/// Serves the GDB Remote Serial Protocol to one client, so a MIPS gdb can
+/// debug the program in `debugger` with `target remote`.
+///
+/// `reader` and `writer` are the two halves of the connection. The reader
+/// is read on its own thread, so that gdb can interrupt a running program.
+/// Returns once gdb detaches, kills the program or disconnects.
+pub fn serve<R, W>(debugger: &mut Debugger, reader: R, writer: W) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let (sender, input) = mpsc::channel();
+    let pause = debugger.pause_handle();
+    thread::spawn(move || {
+        let mut reader = reader;
+        let mut buf = [0; 4096];
+        loop {
+            let len = match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => len,
+            };
+            if buf[..len].contains(&INTERRUPT) {
+                pause.store(true, Ordering::Relaxed);
+            }
+            if sender.send(buf[..len].to_vec()).is_err() {
+                break
+            }
+        }
+    });
+    let mut server = Server {
+        debugger,
+        writer,
+        input,
+        pending: VecDeque::new(),
+        acks: true,
+    };
+    server.run()
+}
+
+struct Server<'a, W> {
+    debugger: &'a mut Debugger,
+    writer: W,
+    input: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+    /// Whether packets are acknowledged, until gdb turns that off.
+    acks: bool,
+}
+
+impl<W: Write> Server<'_, W> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet) {
+                Outcome::Reply(reply) => self.write_packet(reply.as_bytes())?,
+                Outcome::Close(reply) => {
+                    if let Some(reply) = reply {
+                        self.write_packet(reply.as_bytes())?;
+                    }
+                    break
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        while self.pending.is_empty() {
+            self.pending.extend(self.input.recv().ok()?);
+        }
+        self.pending.pop_front()
+    }
+
+    /// Reads the next packet, skipping acknowledgements and interrupts that
+    /// came too late to matter. Returns `None` once gdb disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.next_byte() {
+                Some(b'$') => {}
+                // The program is stopped, so the pause this asked for is
+                // stale and mustn't stop the next resume.
+                Some(INTERRUPT) => {
+                    self.debugger.pause_handle().store(false, Ordering::Relaxed);
+                    continue
+                }
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = Vec::new();
+            let mut sum = 0u8;
+            loop {
+                let byte = match self.next_byte() {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+                if byte == b'#' {
+                    break
+                }
+                sum = sum.wrapping_add(byte);
+                if byte == b'}' {
+                    let escaped = match self.next_byte() {
+                        Some(byte) => byte,
+                        None => return Ok(None),
+                    };
+                    sum = sum.wrapping_add(escaped);
+                    data.push(escaped ^ 0x20);
+                } else {
+                    data.push(byte);
+                }
+            }
+            let checksum = match (self.next_byte(), self.next_byte()) {
+                (Some(high), Some(low)) => parse_hex(&[high, low]),
+                _ => return Ok(None),
+            };
+            if self.acks {
+                let valid = checksum == Some(sum as u32);
+                self.writer.write_all(if valid { b"+" } else { b"-" })?;
+                self.writer.flush()?;
+                if !valid {
+                    continue
+                }
+            }
+            return Ok(Some(data))
+        }
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut packet = vec![b'$'];
+        for &byte in data {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                packet.extend_from_slice(&[b'}', byte ^ 0x20]);
+            } else {
+                packet.push(byte);
+            }
+        }
+        let sum = packet[1..].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        packet.extend_from_slice(format!("#{:02x}", sum).as_bytes());
+        self.writer.write_all(&packet)?;
+        self.writer.flush()
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> Outcome {
+        let packet = String::from_utf8_lossy(packet);
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.stop_reply(None),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') => self.set_point(&packet[1..], true),
+            Some(b'z') => self.set_point(&packet[1..], false),
+            Some(b'c') | Some(b'C') => self.resume(&packet, Debugger::resume),
+            Some(b's') | Some(b'S') => self.resume(&packet, Debugger::step),
+            Some(b'b') if &*packet == "bc" => {
+                let stop = self.debugger.reverse_resume();
+                self.stop_reply(Some(stop))
+            }
+            Some(b'b') if &*packet == "bs" => {
+                let stop = self.debugger.reverse_step();
+                self.stop_reply(Some(stop))
+            }
+            Some(b'H') | Some(b'T') => "OK".to_string(),
+            Some(b'D') => return Outcome::Close(Some("OK".to_string())),
+            Some(b'k') => return Outcome::Close(None),
+            _ => self.query(&packet),
+        };
+        Outcome::Reply(reply)
+    }
+
+    /// Answers the `q` and `Q` packets micah supports, and an empty reply,
+    /// meaning unsupported, to anything else.
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;\
+                ReverseStep+;ReverseContinue+", PACKET_SIZE)
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_pair(range, ',') {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = (offset as usize).min(xml.len());
+                    let end = start.saturating_add(len as usize).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    format!("{}{}", more, &xml[start..end])
+                }
+                None => "E01".to_string(),
+            }
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.acks = false;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Continues or steps, from the address in the packet if it gives one.
+    fn resume(&mut self, packet: &str, resume: fn(&mut Debugger) -> Stop) -> String {
+        // `C` and `S` give a signal to deliver first, which micah ignores.
+        let addr = match packet.split_once(';') {
+            Some((_, addr)) => Some(addr),
+            None if packet.starts_with(['c', 's']) && packet.len() > 1 => Some(&packet[1..]),
+            None => None,
+        };
+        if let Some(addr) = addr {
+            match parse_hex(addr.as_bytes()) {
+                Some(addr) => self.debugger.runtime_mut().set_pc(addr),
+                None => return "E01".to_string(),
+            }
+        }
+        let stop = resume(self.debugger);
+        self.stop_reply(Some(stop))
+    }
+
+    /// Tells gdb why the program stopped: because of `stop`, or, if there's
+    /// none, why it is stopped now.
+    fn stop_reply(&self, stop: Option<Stop>) -> String {
+        let stop = match (stop, self.debugger.exit_code()) {
+            (Some(stop), _) => stop,
+            (None, Some(code)) => Stop::Exited(code),
+            (None, None) => Stop::Stepped,
+        };
+        match stop {
+            Stop::Stepped => format!("S{:02x}", SIGTRAP),
+            Stop::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+            Stop::Watchpoint(addr) => format!("T{:02x}watch:{:x};", SIGTRAP, addr),
+            Stop::Exited(code) => format!("W{:02x}", code as u8),
+            Stop::Error(error) => format!("S{:02x}", signal(&error)),
+            Stop::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            Stop::Paused => format!("S{:02x}", SIGINT),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let runtime = self.debugger.runtime();
+        (0..NUM_REGISTERS).map(|reg| encode_word(runtime, register(runtime, reg))).collect()
+    }
+
+    /// Writes every register gdb sends, failing at the first one micah can't
+    /// change.
+    fn write_registers(&mut self, values: &str) -> String {
+        let endian = self.debugger.runtime().endian();
+        let mut vals = Vec::new();
+        for value in values.as_bytes().chunks(8).take(NUM_REGISTERS) {
+            match decode_bytes(value) {
+                Some(bytes) if bytes.len() == 4 => vals.push(endian.word_from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+                _ => return "E01".to_string(),
+            }
+        }
+        let runtime = self.edit();
+        for (reg, val) in vals.into_iter().enumerate() {
+            if !set_register(runtime, reg, val) {
+                return "E01".to_string()
+            }
+        }
+        "OK".to_string()
+    }
+
+    /// The runtime, for an edit gdb is about to make. The edit is its own
+    /// undo step, so stepping backwards undoes it on its own rather than
+    /// with the instruction before it.
+    fn edit(&mut self) -> &mut Runtime {
+        let runtime = self.debugger.runtime_mut();
+        runtime.begin_step();
+        runtime
+    }
+
+    fn read_register(&self, reg: &str) -> String {
+        match parse_hex(reg.as_bytes()) {
+            Some(reg) if (reg as usize) < NUM_REGISTERS => {
+                let runtime = self.debugger.runtime();
+                encode_word(runtime, register(runtime, reg as usize))
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, packet: &str) -> String {
+        let (reg, value) = match packet.split_once('=') {
+            Some((reg, value)) => (parse_hex(reg.as_bytes()), decode_bytes(value.as_bytes())),
+            None => return "E01".to_string(),
+        };
+        match (reg, value) {
+            (Some(reg), Some(bytes)) if (reg as usize) < NUM_REGISTERS && bytes.len() == 4 => {
+                let endian = self.debugger.runtime().endian();
+                let val = endian.word_from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                if set_register(self.edit(), reg as usize, val) {
+                    "OK".to_string()
+                } else {
+                    "E01".to_string()
+                }
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Reads as many of the requested bytes as are readable, or fails if
+    /// the first isn't.
+    fn read_memory(&self, range: &str) -> String {
+        let (addr, len) = match parse_pair(range, ',') {
+            Some(range) => range,
+            None => return "E01".to_string(),
+        };
+        let runtime = self.debugger.runtime();
+        let mut reply = String::new();
+        for i in 0..len.min(PACKET_SIZE as u32 / 2) {
+            match runtime.peek_byte(addr.wrapping_add(i) as usize) {
+                Some(byte) => write!(reply, "{:02x}", byte).unwrap(),
+                None => break,
+            }
+        }
+        if reply.is_empty() && len > 0 {
+            return "E14".to_string()
+        }
+        reply
+    }
+
+    fn write_memory(&mut self, packet: &str) -> String {
+        let (range, data) = match packet.split_once(':') {
+            Some(split) => split,
+            None => return "E01".to_string(),
+        };
+        let (addr, bytes) = match (parse_pair(range, ','), decode_bytes(data.as_bytes())) {
+            (Some((addr, len)), Some(bytes)) if bytes.len() == len as usize => (addr, bytes),
+            _ => return "E01".to_string(),
+        };
+        let runtime = self.edit();
+        for (i, byte) in bytes.into_iter().enumerate() {
+            if runtime.store_byte(addr.wrapping_add(i as u32) as usize, byte).is_err() {
+                return "E14".to_string()
+            }
+        }
+        "OK".to_string()
+    }
+
+    /// Adds or removes a breakpoint (types 0 and 1) or a write watchpoint
+    /// (type 2). Read and access watchpoints aren't supported.
+    fn set_point(&mut self, packet: &str, add: bool) -> String {
+        let mut fields = packet.split(',');
+        let (kind, addr, len) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(kind), Some(addr), Some(len)) => (kind, parse_hex(addr.as_bytes()), parse_hex(len.as_bytes())),
+            _ => return "E01".to_string(),
+        };
+        let (addr, len) = match (addr, len) {
+            (Some(addr), Some(len)) => (addr, len),
+            _ => return "E01".to_string(),
+        };
+        match (kind, add) {
+            ("0", true) | ("1", true) => {
+                self.debugger.add_breakpoint(addr);
+            }
+            ("0", false) | ("1", false) => {
+                self.debugger.remove_breakpoint(addr);
+            }
+            ("2", true) => self.debugger.add_watchpoint(addr, len),
+            ("2", false) => {
+                self.debugger.remove_watchpoint(addr);
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+}
+
+/// The value of register `reg` in gdb's numbering.
+fn register(runtime: &Runtime, reg: usize) -> u32 {
+    match reg {
+        0..=31 => runtime.get_register(&RegisterCodes::from_number(reg as u32)).unwrap_or(0),
+        STATUS => runtime.get_cp0_register(12),
+        LO => runtime.lo(),
+        HI => runtime.hi(),
+        BAD_VADDR => runtime.get_cp0_register(8),
+        CAUSE => runtime.get_cp0_register(13),
+        PC => runtime.pc(),
+        FCSR => runtime.fcsr(),
+        FIR => FIR_VALUE,
+        _ => runtime.get_float_register(&FloatRegister::from_number((reg - FLOAT_BASE) as u32)),
+    }
+}
+
+/// Sets register `reg` in gdb's numbering, returning false if micah can't
+/// change it.
+fn set_register(runtime: &mut Runtime, reg: usize, val: u32) -> bool {
+    match reg {
+        0 => return val == 0,
+        1..=31 => return runtime.set_register(&RegisterCodes::from_number(reg as u32), val).is_ok(),
+        STATUS => runtime.set_cp0_register(12, val),
+        LO => runtime.set_hi_lo(runtime.hi(), val),
+        HI => runtime.set_hi_lo(val, runtime.lo()),
+        BAD_VADDR => runtime.set_cp0_register(8, val),
+        CAUSE => runtime.set_cp0_register(13, val),
+        PC => runtime.set_pc(val),
+        FCSR | FIR => return val == register(runtime, reg),
+        _ => runtime.set_float_register(&FloatRegister::from_number((reg - FLOAT_BASE) as u32), val),
+    }
+    true
+}
+
+/// The signal gdb reports for a program that stopped with `error`.
+fn signal(error: &RuntimeError) -> u8 {
+    match error {
+        RuntimeError::UnalignedAccess(_) => SIGBUS,
+        RuntimeError::Memory(_) | RuntimeError::NoInstruction(_) => SIGSEGV,
+        RuntimeError::InvalidInstruction(..) => SIGILL,
+        RuntimeError::ArithmeticOverflow => SIGFPE,
+        RuntimeError::Break(_) => SIGTRAP,
+        _ => SIGABRT,
+    }
+}
+
+/// Describes micah's registers to gdb, numbered as `register` numbers them.
+fn target_xml() -> String {
+    let reg = |name: &str, regnum: usize, kind: &str| {
+        format!("<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" type=\"{}\"/>\n", name, regnum, kind)
+    };
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+        <target version=\"1.0\">\n<architecture>mips</architecture>\n<feature name=\"org.gnu.gdb.mips.cpu\">\n");
+    for i in 0..32 {
+        xml += &reg(&format!("r{}", i), i, "int");
+    }
+    xml += &reg("lo", LO, "int");
+    xml += &reg("hi", HI, "int");
+    xml += &reg("pc", PC, "code_ptr");
+    xml += "</feature>\n<feature name=\"org.gnu.gdb.mips.cp0\">\n";
+    xml += &reg("status", STATUS, "int");
+    xml += &reg("badvaddr", BAD_VADDR, "int");
+    xml += &reg("cause", CAUSE, "int");
+    xml += "</feature>\n<feature name=\"org.gnu.gdb.mips.fpu\">\n";
+    for i in 0..32 {
+        xml += &reg(&format!("f{}", i), FLOAT_BASE + i, "ieee_single");
+    }
+    xml += &reg("fcsr", FCSR, "int");
+    xml += &reg("fir", FIR, "int");
+    xml += "</feature>\n</target>\n";
+    xml
+}
+
+/// A word as hex, in the target's byte order as gdb expects.
+fn encode_word(runtime: &Runtime, word: u32) -> String {
+    runtime.endian().word_to_bytes(word).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_bytes(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None
+    }
+    hex.chunks(2).map(|pair| parse_hex(pair).map(|byte| byte as u8)).collect()
+}
+
+fn parse_hex(hex: &[u8]) -> Option<u32> {
+    u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+/// Two hex numbers separated by `separator`, like a packet's `addr,length`.
+fn parse_pair(text: &str, separator: char) -> Option<(u32, u32)> {
+    let (first, second) = text.split_once(separator)?;
+    Some((parse_hex(first.as_bytes())?, parse_hex(second.as_bytes())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+    use super::super::runtime::DEFAULT_UNDO_CAPACITY;
+
+    /// A scripted gdb: sends packets and reads the replies.
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send(&mut self, data: &str) -> String {
+            let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.stream, "${}#{:02x}", data, sum).unwrap();
+            assert_eq!(self.read_byte(), b'+');
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            assert_eq!(self.read_byte(), b'$');
+            let mut reply = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    b'}' => {
+                        let byte = self.read_byte();
+                        reply.push(byte ^ 0x20);
+                    }
+                    byte => reply.push(byte),
+                }
+            }
+            self.read_byte();
+            self.read_byte();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    /// Serves `source` on a local port, returning a client connected to it.
+    fn connect(source: &str) -> (Client, thread::JoinHandle<i32>) {
+        let (components, _) = read_str_to_state(source, "test.s");
+        let program = assemble(&components).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut runtime = Runtime::with_undo_capacity(DEFAULT_UNDO_CAPACITY);
+            runtime.load_program(program).unwrap();
+            let mut debugger = Debugger::new(runtime);
+            let (stream, _) = listener.accept().unwrap();
+            serve(&mut debugger, stream.try_clone().unwrap(), stream).unwrap();
+            debugger.exit_code().unwrap_or(-1)
+        });
+        (Client { stream: TcpStream::connect(addr).unwrap() }, server)
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let (mut gdb, server) = connect("
+                .data
+        count:  .word 0
+                .text
+        main:   li $t0, 5
+                sw $t0, count
+                addi $t0, $t0, 1
+                li $v0, 10
+                syscall
+        ");
+        assert!(gdb.send("qSupported:multiprocess+;swbreak+").contains("qXfer:features:read+"));
+        assert!(gdb.send("qXfer:features:read:target.xml:0,10000").contains("org.gnu.gdb.mips.fpu"));
+        assert_eq!(gdb.send("?"), "S05");
+        // The startup stub after the program's text calls main.
+        assert_eq!(gdb.send("p25"), "00400018");
+        assert_eq!(gdb.send("g").len(), NUM_REGISTERS * 8);
+
+        // Stop before the addi, once $t0 and count hold 5.
+        assert_eq!(gdb.send("Z0,40000c,4"), "OK");
+        assert_eq!(gdb.send("c"), "T05swbreak:;");
+        assert_eq!(gdb.send("p25"), "0040000c");
+        assert_eq!(gdb.send("p8"), "00000005");
+        assert_eq!(gdb.send("m10010000,4"), "00000005");
+        assert_eq!(gdb.send("z0,40000c,4"), "OK");
+
+        // Writes to registers and memory are seen by the program.
+        assert_eq!(gdb.send("P8=00000029"), "OK");
+        assert_eq!(gdb.send("M10010000,4:0000002a"), "OK");
+        assert_eq!(gdb.send("m10010000,4"), "0000002a");
+        assert_eq!(gdb.send("s"), "S05");
+        assert_eq!(gdb.send("p8"), "0000002a");
+
+        // Stepping backwards undoes the addi, then each edit on its own.
+        assert_eq!(gdb.send("bs"), "S05");
+        assert_eq!(gdb.send("p8"), "00000029");
+        assert_eq!(gdb.send("m10010000,4"), "0000002a");
+        assert_eq!(gdb.send("bs"), "S05");
+        assert_eq!(gdb.send("m10010000,4"), "00000005");
+        assert_eq!(gdb.send("bs"), "S05");
+        assert_eq!(gdb.send("p8"), "00000005");
+        assert_eq!(gdb.send("p25"), "0040000c");
+
+        // $zero can't be changed.
+        let mut values = gdb.send("g");
+        values.replace_range(..8, "00000001");
+        assert_eq!(gdb.send(&format!("G{}", values)), "E01");
+
+        assert_eq!(gdb.send("Z2,10010000,4"), "OK");
+        assert_eq!(gdb.send("bc"), "T05watch:10010000;");
+        assert_eq!(gdb.send("z2,10010000,4"), "OK");
+        assert_eq!(gdb.send("c"), "W00");
+        assert_eq!(gdb.send("D"), "OK");
+        assert_eq!(server.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_gdb_interrupt() {
+        let (mut gdb, server) = connect("main: j main\n");
+        assert_eq!(gdb.send("QStartNoAckMode"), "OK");
+        gdb.stream.write_all(b"$c#63").unwrap();
+        gdb.stream.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(gdb.reply(), "S02");
+        gdb.stream.write_all(b"$k#6b").unwrap();
+        assert_eq!(server.join().unwrap(), -1);
+    }
+}
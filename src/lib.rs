@@ -0,0 +1,79 @@
+//! MICAH, the MIPS Interpreted Controller And Helper.
+//!
+//! The crate is split along the stages a program goes through:
+//!
+//! - [`mips_parser`] turns source text into [`mips_parser::MIPSComponent`]s,
+//!   each tagged with the [`mips_parser::MIPSLocation`] it came from.
+//! - [`assembler`] lays those components out in memory, expanding
+//!   pseudo-instructions and resolving labels, and encodes each
+//!   [`code::Instruction`] as MIPS32 machine code.
+//! - [`disassembler`] renders machine code back into assembly, and
+//!   [`listing`] lays it out beside the source it came from.
+//! - [`elf`] reads and writes MIPS32 ELF executables, which a runtime can
+//!   load in place of an assembled program.
+//! - [`object`] holds one file assembled on its own as a relocatable ELF
+//!   object, and [`linker`] combines objects into an executable.
+//! - [`runtime`] holds a program's state and executes it: its integer and
+//!   floating-point registers and memory, its [`console::Console`], the
+//!   shadow [`call_stack`], and an undo log of every step. Exceptions go to
+//!   a handler in `.ktext`, such as the default one in [`exceptions`].
+//!   Any [`device::Device`], such as the [`console::MappedConsole`] or a
+//!   [`display::BitmapDisplay`], can be mapped over its [`memory`] and raise
+//!   interrupts.
+//! - [`observer`] lets tools watch a runtime's registers, memory and calls
+//!   without the runtime knowing about them.
+//! - [`debugger`] stops a runtime at breakpoints and watchpoints, and steps
+//!   it backwards through its undo log. [`repl`] gives it commands to type,
+//!   [`gdb`] serves it to gdb over the remote serial protocol, and [`dap`]
+//!   to editors over the Debug Adapter Protocol.
+//! - [`coverage`] records which lines and branches a runtime executes, and
+//!   writes them as LCOV.
+//! - [`profile`] counts the instructions and cycles spent on each line,
+//!   function and loop.
+//! - [`trace`] writes each instruction a runtime executes and what it
+//!   changed, as text or JSON Lines.
+//! - [`watchdog`] stops a runtime that runs too long or is stuck in a loop.
+//! - [`autotest`] runs a directory of programs, checking what each prints
+//!   and exits with.
+//!
+//! ```
+//! use micah::assembler::assemble;
+//! use micah::mips_parser::read_str_to_state;
+//! use micah::runtime::{Runtime, RegisterCodes};
+//!
+//! let (components, _) = read_str_to_state("main:\n\tli $t0, 7\n\tli $v0, 10\n\tsyscall\n", "exit.s");
+//! let program = assemble(&components).unwrap();
+//!
+//! let mut runtime = Runtime::new();
+//! runtime.load_program(program).unwrap();
+//! assert_eq!(runtime.run().unwrap(), 0);
+//! assert_eq!(runtime.get_register(&RegisterCodes::Rt0).unwrap(), 7);
+//! ```
+
+mod utils;
+
+pub mod assembler;
+pub mod autotest;
+pub mod call_stack;
+pub mod code;
+pub mod console;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod device;
+pub mod display;
+pub mod disassembler;
+pub mod elf;
+pub mod exceptions;
+pub mod gdb;
+pub mod linker;
+pub mod listing;
+pub mod memory;
+pub mod mips_parser;
+pub mod object;
+pub mod observer;
+pub mod profile;
+pub mod repl;
+pub mod runtime;
+pub mod trace;
+pub mod watchdog;
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::assembler::{startup_code, RelocationKind, DATA_BASE, TEXT_BASE};
+use super::elf::{Elf, Segment, PF_R, PF_W, PF_X};
+use super::memory::Endian;
+use super::object::{Object, ObjectSection, ObjectSymbol};
+
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    /// No object defines this symbol as a global.
+    Undefined(String),
+    /// More than one object defines this global.
+    Duplicate(String),
+    /// A `j` or `jal` to this symbol can't reach it.
+    OutOfRange(String),
+    /// The objects don't all have the same byte order.
+    MixedEndianness,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::Undefined(name) => write!(f, "undefined reference to {}", name),
+            LinkError::Duplicate(name) => write!(f, "{} is defined more than once", name),
+            LinkError::OutOfRange(name) => write!(f, "jump to {} is out of range", name),
+            LinkError::MixedEndianness => write!(f, "cannot link big- and little-endian objects together"),
+        }
+    }
+}
+
+/// Links objects into an executable. Text is laid out from `TEXT_BASE` and
+/// data from `DATA_BASE`, in the order the objects are given. If `main` is
+/// defined, the entry point is the startup code, placed after all the text.
+pub fn link(objects: &[Object]) -> Result<Elf, LinkError> {
+    let endian = match objects.first() {
+        Some(object) => object.endian,
+        None => Endian::Big
+    };
+    if objects.iter().any(|object| object.endian != endian) {
+        return Err(LinkError::MixedEndianness)
+    }
+
+    let mut text = Vec::new();
+    let mut data = Vec::new();
+    let mut bases = Vec::new();
+    for object in objects {
+        let align = object.data_alignment.max(1) as usize;
+        data.resize(data.len().div_ceil(align) * align, 0);
+        bases.push((TEXT_BASE + text.len() as u32, DATA_BASE + data.len() as u32));
+        text.extend_from_slice(&object.text);
+        data.extend_from_slice(&object.data);
+    }
+    let address = |object: usize, symbol: &ObjectSymbol| match symbol.section {
+        ObjectSection::Text => Some(bases[object].0 + symbol.offset),
+        ObjectSection::Data => Some(bases[object].1 + symbol.offset),
+        ObjectSection::Undefined => None,
+    };
+
+    let mut globals = HashMap::new();
+    for (i, object) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.global) {
+            if let Some(addr) = address(i, symbol) {
+                if globals.insert(symbol.name.clone(), addr).is_some() {
+                    return Err(LinkError::Duplicate(symbol.name.clone()))
+                }
+            }
+        }
+    }
+
+    for (i, object) in objects.iter().enumerate() {
+        for relocation in &object.relocations {
+            let symbol = &object.symbols[relocation.symbol];
+            let value = match address(i, symbol).or_else(|| globals.get(&symbol.name).cloned()) {
+                Some(addr) => (i64::from(addr) + relocation.addend) as u32,
+                None => return Err(LinkError::Undefined(symbol.name.clone()))
+            };
+            let (segment, base, offset) = match relocation.section {
+                ObjectSection::Data => (&mut data, DATA_BASE, bases[i].1 - DATA_BASE + relocation.offset),
+                _ => (&mut text, TEXT_BASE, bases[i].0 - TEXT_BASE + relocation.offset),
+            };
+            let offset = offset as usize;
+            let bytes = &mut segment[offset..offset + 4];
+            let word = endian.word_from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            let word = match relocation.kind {
+                RelocationKind::Word32 => value,
+                RelocationKind::Jump26 => {
+                    let next = base + offset as u32 + 4;
+                    if (next ^ value) & 0xf000_0000 != 0 || !value.is_multiple_of(4) {
+                        return Err(LinkError::OutOfRange(symbol.name.clone()))
+                    }
+                    word & 0xfc00_0000 | (value >> 2) & 0x03ff_ffff
+                }
+                RelocationKind::Hi16 => word & 0xffff_0000 | (value.wrapping_add(0x8000) >> 16),
+                RelocationKind::Lo16 => word & 0xffff_0000 | value & 0xffff,
+            };
+            bytes.copy_from_slice(&endian.word_to_bytes(word));
+        }
+    }
+
+    let mut symbols = HashMap::new();
+    for (i, object) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter().filter(|symbol| !symbol.global) {
+            if let Some(addr) = address(i, symbol) {
+                symbols.entry(symbol.name.clone()).or_insert(addr);
+            }
+        }
+    }
+    symbols.extend(globals.iter().map(|(name, addr)| (name.clone(), *addr)));
+
+    let mut entry = TEXT_BASE;
+    if let Some(main) = symbols.get("main") {
+        entry = TEXT_BASE + text.len() as u32;
+        for (i, instruction) in startup_code(*main).iter().enumerate() {
+            let word = instruction.encode(entry + 4 * i as u32).unwrap();
+            text.extend_from_slice(&endian.word_to_bytes(word));
+        }
+    }
+
+    let mut segments = vec![Segment {vaddr: TEXT_BASE, mem_size: text.len() as u32, data: text, flags: PF_R | PF_X}];
+    if !data.is_empty() {
+        segments.push(Segment {vaddr: DATA_BASE, mem_size: data.len() as u32, data, flags: PF_R | PF_W});
+    }
+    Ok(Elf {endian, entry, segments, symbols})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble_object;
+    use super::super::console::Console;
+    use super::super::elf::{parse_elf, write_executable};
+    use super::super::mips_parser::read_str_to_state;
+    use super::super::runtime::Runtime;
+
+    fn object(source: &str) -> Object {
+        let (components, _) = read_str_to_state(source, "test.s");
+        Object::from_elf(&Object::from_program(&assemble_object(&components).unwrap()).to_elf()).unwrap()
+    }
+
+    const MAIN: &str = "
+            .globl main
+            .data
+    prompt: .asciiz \"sum: \"
+            .text
+    main:   addi $sp, $sp, -4
+            sw $ra, 0($sp)
+            la $a0, prompt
+            li $v0, 4
+            syscall
+            lw $a0, values+4
+            lw $a1, values
+            jal add
+            move $a0, $v0
+            li $v0, 1
+            syscall
+            lw $ra, 0($sp)
+            addi $sp, $sp, 4
+            jr $ra
+    ";
+
+    const ADD: &str = "
+            .globl add
+            .globl values
+            .data
+            .align 2
+    values: .word 40, 2
+    local:  .word add
+            .text
+    add:    lw $t0, local
+            add $v0, $a0, $a1
+            jr $ra
+    ";
+
+    #[test]
+    fn test_link(){
+        let elf = link(&[object(MAIN), object(ADD)]).unwrap();
+        assert_eq!(elf.symbols["add"], elf.symbols["main"] + 4 * 17);
+        assert_eq!(elf.symbols["values"], DATA_BASE + 8);
+        assert_eq!(elf.entry, elf.symbols["add"] + 4 * 4);
+
+        let mut runtime = Runtime::new();
+        runtime.load_elf(&parse_elf(&write_executable(&elf)).unwrap()).unwrap();
+        runtime.set_console(Console::scripted(b""));
+        assert_eq!(runtime.read_word(DATA_BASE as usize + 16).unwrap(), elf.symbols["add"]);
+        assert_eq!(runtime.run().unwrap(), 0);
+        assert_eq!(runtime.console().captured_output(), Some(&b"sum: 42"[..]));
+    }
+
+    #[test]
+    fn test_link_errors(){
+        assert_eq!(link(&[object(MAIN)]), Err(LinkError::Undefined("values".to_string())));
+        assert_eq!(link(&[object(MAIN), object(ADD), object(ADD)]), Err(LinkError::Duplicate("add".to_string())));
+
+        // Only globals are visible to other objects.
+        let hidden = ".data\nvalues: .word 1, 2\n.text\nadd: jr $ra\n";
+        assert_eq!(link(&[object(MAIN), object(hidden)]), Err(LinkError::Undefined("values".to_string())));
+
+        let mut little = object(ADD);
+        little.endian = Endian::Little;
+        assert_eq!(link(&[object(MAIN), little]), Err(LinkError::MixedEndianness));
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use super::assembler::{Program, DATA_BASE, KDATA_BASE, KTEXT_BASE, TEXT_BASE};
+use super::disassembler::disassemble_word;
+
+/// Bytes of data shown on each row of a listing.
+const DATA_ROW_LEN: usize = 4;
+
+/// One row of output: an address and what was emitted there.
+struct Row {
+    addr: u32,
+    contents: String,
+    expansion: String,
+}
+
+/// Renders an assembly listing: each line of `source` with the address and
+/// machine code or data it produced, then the symbol table. Pseudo-instructions
+/// show every instruction they expand to.
+pub fn listing(program: &Program, source: &str) -> String {
+    let mut rows: HashMap<usize, Vec<Row>> = HashMap::new();
+    let mut startup = Vec::new();
+    let text = program.words.iter().zip(&program.locations)
+        .enumerate()
+        .map(|(i, word)| (TEXT_BASE + 4 * i as u32, word));
+    let kernel_text = program.kernel_words.iter().zip(&program.kernel_locations)
+        .enumerate()
+        .map(|(i, word)| (KTEXT_BASE + 4 * i as u32, word));
+    for (addr, (word, location)) in text.chain(kernel_text) {
+        let row = Row {
+            addr,
+            contents: format!("{:08x}", word),
+            expansion: disassemble_word(*word, addr, Some(program)),
+        };
+        match location {
+            Some(location) => rows.entry(location.line_num).or_default().push(row),
+            None => startup.push(row),
+        }
+    }
+    for span in &program.data_spans {
+        let (data, base) = if span.addr >= KDATA_BASE { (&program.kernel_data, KDATA_BASE) } else { (&program.data, DATA_BASE) };
+        let start = (span.addr - base) as usize;
+        let bytes = &data[start..start + span.len];
+        let line_rows = rows.entry(span.location.line_num).or_default();
+        for (j, chunk) in bytes.chunks(DATA_ROW_LEN).enumerate() {
+            let contents: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            line_rows.push(Row {
+                addr: span.addr + (j * DATA_ROW_LEN) as u32,
+                contents: contents.join(""),
+                expansion: String::new(),
+            });
+        }
+    }
+
+    let mut out = String::new();
+    for (line_num, line) in source.lines().enumerate() {
+        match rows.get(&line_num) {
+            Some(line_rows) => {
+                for (j, row) in line_rows.iter().enumerate() {
+                    let source_text = if j == 0 { line } else { "" };
+                    let number = if j == 0 { format!("{:5}", line_num + 1) } else { String::new() };
+                    out.push_str(&format_row(&number, Some(row), source_text));
+                }
+            }
+            None => out.push_str(&format_row(&format!("{:5}", line_num + 1), None, line)),
+        }
+    }
+    for (j, row) in startup.iter().enumerate() {
+        let source_text = if j == 0 { "# startup code: calls main, then exits" } else { "" };
+        out.push_str(&format_row("", Some(row), source_text));
+    }
+
+    let mut symbols: Vec<(&String, &u32)> = program.symbols.iter().collect();
+    symbols.sort_by_key(|(name, addr)| (**addr, name.as_str()));
+    let width = symbols.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    out.push_str("\nSymbols:\n");
+    for (name, addr) in symbols {
+        out.push_str(&format!("  {:width$}  0x{:08x}\n", name, addr, width = width));
+    }
+    out
+}
+
+fn format_row(number: &str, row: Option<&Row>, source_text: &str) -> String {
+    let (addr, contents, expansion) = match row {
+        Some(row) => (format!("0x{:08x}", row.addr), row.contents.as_str(), row.expansion.as_str()),
+        None => (String::new(), "", ""),
+    };
+    let line = format!("{:5}  {:10}  {:8}  {:24}  {}", number, addr, contents, expansion, source_text);
+    format!("{}\n", line.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+
+    #[test]
+    fn test_listing(){
+        let source = "\t.data\nmsg:\t.asciiz \"Hello\"\n\n\t.text\nmain:\tla $a0, msg\n\tjr $ra\n";
+        let (components, _) = read_str_to_state(source, "hello.s");
+        let program = assemble(&components).unwrap();
+        let lines: Vec<String> = listing(&program, source).lines().map(|line| line.to_string()).collect();
+        assert_eq!(lines, vec![
+            "    1                                                  \t.data",
+            "    2  0x10010000  48656c6c                            msg:\t.asciiz \"Hello\"",
+            "       0x10010004  6f00",
+            "    3",
+            "    4                                                  \t.text",
+            "    5  0x00400000  3c011001  lui $at, 0x1001           main:\tla $a0, msg",
+            "       0x00400004  24240000  addiu $a0, $at, 0",
+            "    6  0x00400008  03e00008  jr $ra                    \tjr $ra",
+            "       0x0040000c  0c100000  jal main                  # startup code: calls main, then exits",
+            "       0x00400010  2402000a  addiu $v0, $zero, 10",
+            "       0x00400014  0000000c  syscall",
+            "",
+            "Symbols:",
+            "  main  0x00400000",
+            "  msg   0x10010000",
+        ]);
+    }
+}
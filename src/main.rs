@@ -1,29 +1,783 @@
+use std::cell::RefCell;
 use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::mem;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::ops::Range;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::time::Duration;
 
-mod utils;
+use micah::assembler::{assemble, assemble_object, Program, TEXT_BASE};
+use micah::autotest;
+use micah::console::{Console, ConsoleInput, ConsoleOutput, MappedConsole, MAPPED_CONSOLE_LEN};
+use micah::coverage::Coverage;
+use micah::dap;
+use micah::debugger::Debugger;
+use micah::display::{BitmapDisplay, DISPLAY_BASE};
+use micah::elf::{is_elf, parse_elf, write_executable, Elf};
+use micah::exceptions::add_default_handler;
+use micah::gdb;
+use micah::linker::link;
+use micah::listing::listing;
+use micah::memory::MMIO_BASE;
+use micah::mips_parser::{read_str_to_state, MIPSComponent};
+use micah::object::Object;
+use micah::profile::{CostModel, Profiler};
+use micah::repl::{self, Reply};
+use micah::runtime::{Runtime, RuntimeError, StepResult, DEFAULT_UNDO_CAPACITY};
+use micah::trace::{TraceFormat, Tracer};
+use micah::watchdog::{Stop, Watchdog};
 
-mod mips_parser;
-mod memory;
-mod runtime;
+/// Exit statuses for a program the watchdog stopped, distinct from each
+/// other and from the 1 of any other error. A timeout's matches timeout(1).
+const EXIT_STALLED: i32 = 123;
+const EXIT_TIMEOUT: i32 = 124;
+const EXIT_STEP_LIMIT: i32 = 125;
 
-use mips_parser::*;
-use runtime::*;
-use memory::*;
+fn parse_file(file_name: &str) -> (Vec<MIPSComponent>, String) {
+    let source = match fs::read_to_string(file_name) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("micah: could not read {}: {}", file_name, error);
+            process::exit(1);
+        }
+    };
+    let (components, _) = read_str_to_state(&source, file_name);
+    (components, source)
+}
 
-fn main() {
-    println!("==================================================");
-    println!("                  [[ MICAH ]]");
-    println!("    [ MIPS Interpreted Controller And Helper ]");
-    println!("==================================================");
+/// Assembles a file, returning the program and the source it came from.
+/// With `default_handler`, the program gets SPIM's default exception handler
+/// if it doesn't have one of its own.
+fn assemble_file(file_name: &str, default_handler: bool) -> (Program, String) {
+    let (mut components, source) = parse_file(file_name);
+    if default_handler {
+        add_default_handler(&mut components);
+    }
+    match assemble(&components) {
+        Ok(program) => (program, source),
+        Err(error) => {
+            eprintln!("micah: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn read_file(file_name: &str) -> Vec<u8> {
+    match fs::read(file_name) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprintln!("micah: could not read {}: {}", file_name, error);
+            process::exit(1);
+        }
+    }
+}
+
+fn write_file(file_name: &str, contents: &[u8]) {
+    if let Err(error) = fs::write(file_name, contents) {
+        eprintln!("micah: could not write {}: {}", file_name, error);
+        process::exit(1);
+    }
+}
+
+fn is_elf_file(file_name: &str) -> bool {
+    let mut magic = [0; 4];
+    match fs::File::open(file_name) {
+        Ok(mut file) => file.read_exact(&mut magic).is_ok() && is_elf(&magic),
+        Err(_) => false
+    }
+}
+
+fn read_elf_file(file_name: &str) -> Elf {
+    match parse_elf(&read_file(file_name)) {
+        Ok(elf) => elf,
+        Err(error) => {
+            eprintln!("micah: {}: {}", file_name, error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Prints the machine code of a program's text segment alongside its
+/// disassembly.
+fn disasm(file_name: &str) {
+    let (program, _) = assemble_file(file_name, false);
+    let num_words = program.words.len();
+    let mut runtime = Runtime::with_undo_capacity(0);
+    if let Err(error) = runtime.load_program(program) {
+        eprintln!("micah: {}", error);
+        process::exit(1);
+    }
+    for word in runtime.disassemble(TEXT_BASE, num_words) {
+        if let Some(label) = &word.label {
+            println!("{}:", label);
+        }
+        println!("  {}", word);
+    }
+}
+
+/// Splits `-o <path>` out of a subcommand's arguments, returning the output
+/// path and the input files.
+fn output_and_inputs(command: &str, args: &[String], default: &str) -> (String, Vec<String>) {
+    let mut output = None;
+    let mut inputs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            match args.next() {
+                Some(path) => output = Some(path.clone()),
+                None => {
+                    eprintln!("micah: -o requires a file:\n./micah {} <file_name...> -o <out>", command);
+                    process::exit(1);
+                }
+            }
+        } else {
+            inputs.push(arg.clone());
+        }
+    }
+    if inputs.is_empty() {
+        eprintln!("micah: {} requires a file:\n./micah {} <file_name...> -o <out>", command, command);
+        process::exit(1);
+    }
+    (output.unwrap_or_else(|| default.to_string()), inputs)
+}
+
+/// Assembles one file into a relocatable object, `foo.o` for `foo.s` unless
+/// `-o` says otherwise.
+fn assemble_command(args: &[String]) {
+    let default = args.first().map(|file_name| match file_name.rfind('.') {
+        Some(dot) => format!("{}.o", &file_name[..dot]),
+        None => format!("{}.o", file_name),
+    });
+    let (output, inputs) = output_and_inputs("assemble", args, &default.unwrap_or_default());
+    if inputs.len() > 1 {
+        eprintln!("micah: assemble takes one file; use link to combine objects");
+        process::exit(1);
+    }
+    let (components, _) = parse_file(&inputs[0]);
+    match assemble_object(&components) {
+        Ok(program) => write_file(&output, &Object::from_program(&program).to_elf()),
+        Err(error) => {
+            eprintln!("micah: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Links objects into an executable, `a.out` unless `-o` says otherwise.
+fn link_command(args: &[String]) {
+    let (output, inputs) = output_and_inputs("link", args, "a.out");
+    let mut objects = Vec::new();
+    for file_name in &inputs {
+        match Object::from_elf(&read_file(file_name)) {
+            Ok(object) => objects.push(object),
+            Err(error) => {
+                eprintln!("micah: {}: {}", file_name, error);
+                process::exit(1);
+            }
+        }
+    }
+    match link(&objects) {
+        Ok(elf) => write_file(&output, &write_executable(&elf)),
+        Err(error) => {
+            eprintln!("micah: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs every test case in a directory, printing whether each passed, and
+/// writes summaries for `--junit <file>` and `--json <file>`. Exits with 1 if
+/// any failed.
+fn test_command(args: &[String]) {
+    let mut dir = None;
+    let mut junit_file = None;
+    let mut json_file = None;
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--junit" => junit_file = Some(flag_file("--junit", args.next())),
+            "--json" => json_file = Some(flag_file("--json", args.next())),
+            _ => dir = Some(arg),
+        }
+    }
+    let dir = match dir {
+        Some(dir) => dir,
+        None => {
+            eprintln!("micah: test requires a directory:\n./micah test <dir> [--junit <out.xml>] [--json <out.json>]");
+            process::exit(1);
+        }
+    };
+    let cases = match autotest::discover(Path::new(&dir)) {
+        Ok(cases) if cases.is_empty() => {
+            eprintln!("micah: no test cases in {}", dir);
+            process::exit(1);
+        }
+        Ok(cases) => cases,
+        Err(error) => {
+            eprintln!("micah: could not read the tests in {}: {}", dir, error);
+            process::exit(1);
+        }
+    };
+    let mut results = Vec::new();
+    for case in &cases {
+        let result = autotest::run_case(case);
+        if result.passed() {
+            println!("PASS {}", result.name);
+        } else {
+            println!("FAIL {}: {}", result.name, result.failures.join("; "));
+            print!("{}", result.diff.as_deref().unwrap_or_default());
+        }
+        results.push(result);
+    }
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    if let Some(junit_file) = junit_file {
+        write_file(&junit_file, autotest::junit(&results).as_bytes());
+    }
+    if let Some(json_file) = json_file {
+        write_file(&json_file, autotest::json(&results).as_bytes());
+    }
+    process::exit(if failed > 0 { 1 } else { 0 });
+}
+
+/// The file named after `flag`, or exits if there isn't one.
+fn flag_file(flag: &str, arg: Option<String>) -> String {
+    match arg {
+        Some(file) => file,
+        None => {
+            eprintln!("micah: {} requires a file:\n./micah {} <file> <file_name>", flag, flag);
+            process::exit(1);
+        }
+    }
+}
+
+/// Parses a decimal or `0x` hexadecimal number.
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok()
+    }
+}
+
+/// Parses a number of seconds, which may be fractional.
+fn parse_seconds(text: &str) -> Option<Duration> {
+    Duration::try_from_secs_f64(text.parse().ok()?).ok()
+}
+
+/// Parses `--trace-format <text|json>`.
+fn parse_trace_format(arg: Option<String>) -> TraceFormat {
+    match arg.as_deref() {
+        Some("text") => TraceFormat::Text,
+        Some("json") => TraceFormat::JsonLines,
+        _ => {
+            eprintln!("micah: --trace-format requires text or json:\n./micah --trace --trace-format <text|json> <file_name>");
+            process::exit(1);
+        }
+    }
+}
 
+/// Parses `--trace-range <from>..<to>` once the program is loaded, where
+/// each end is anything the debugger takes as an address.
+fn parse_trace_range(runtime: &Runtime, arg: &str) -> Range<u32> {
+    let range = arg.split_once("..").and_then(|(start, end)| {
+        Some(repl::parse_address(runtime, start)?..repl::parse_address(runtime, end)?)
+    });
+    match range {
+        Some(range) => range,
+        None => {
+            eprintln!("micah: --trace-range requires two labels or addresses:\n./micah --trace-range <from>..<to> <file_name>");
+            process::exit(1);
+        }
+    }
+}
+
+/// Parses `--display <width>x<height>[@<base>]`. The display must have
+/// pixels, and all of them must fit in the address space above the base.
+fn parse_display(arg: Option<String>) -> (usize, usize, usize) {
+    let parsed = arg.as_ref().and_then(|arg| {
+        let (size, base) = match arg.split_once('@') {
+            Some((size, base)) => (size, parse_number(base)?),
+            None => (arg.as_str(), DISPLAY_BASE)
+        };
+        let (width, height) = size.split_once('x')?;
+        Some((parse_number(width)?, parse_number(height)?, base))
+    });
+    let (width, height, base) = match parsed {
+        Some(display) => display,
+        None => {
+            eprintln!("micah: --display requires a size:\n./micah --display <width>x<height>[@<base>] <file_name>");
+            process::exit(1);
+        }
+    };
+    if width == 0 || height == 0 {
+        eprintln!("micah: --display needs a width and height of at least one pixel");
+        process::exit(1);
+    }
+    let end = width.checked_mul(height).and_then(|pixels| pixels.checked_mul(4)).and_then(|len| len.checked_add(base));
+    if end.is_none_or(|end| end > u32::MAX as usize + 1) {
+        eprintln!("micah: a {}x{} display doesn't fit in memory at {:#x}", width, height, base);
+        process::exit(1);
+    }
+    (width, height, base)
+}
+
+/// A snapshot of the bitmap display to take after `step` instructions, or
+/// when the program exits. It is written to a `.ppm` or `.png` file, or
+/// drawn in the terminal if the file is `-`.
+struct Snapshot {
+    step: Option<u64>,
+    file: String,
+}
+
+/// Parses `--snapshot <step>:<file>`, where the step may be `exit`.
+fn parse_snapshot(arg: Option<String>) -> Snapshot {
+    let parsed = arg.as_ref().and_then(|arg| {
+        let (step, file) = arg.split_once(':')?;
+        let step = match step {
+            "exit" => None,
+            step => Some(step.parse().ok()?)
+        };
+        let known = file == "-" || file.ends_with(".ppm") || file.ends_with(".png");
+        if known { Some(Snapshot { step, file: file.to_string() }) } else { None }
+    });
+    match parsed {
+        Some(snapshot) => snapshot,
+        None => {
+            eprintln!("micah: --snapshot requires a step and a .ppm, .png or - file:\n./micah --snapshot <step|exit>:<file> <file_name>");
+            process::exit(1);
+        }
+    }
+}
+
+fn take_snapshot(display: &BitmapDisplay, snapshot: &Snapshot) {
+    if snapshot.file == "-" {
+        print!("{}", display.to_ansi());
+    } else if snapshot.file.ends_with(".png") {
+        write_file(&snapshot.file, &display.to_png());
+    } else {
+        write_file(&snapshot.file, &display.to_ppm());
+    }
+}
+
+/// Runs a program, taking each of `snapshots` of `display` when it is due.
+fn run_with_snapshots(runtime: &mut Runtime, watchdog: &mut Watchdog, display: &RefCell<BitmapDisplay>, mut snapshots: Vec<Snapshot>) -> Result<i32, RuntimeError> {
+    loop {
+        let count = runtime.instruction_count();
+        // An exception retires no instruction, so drop each snapshot once
+        // taken rather than taking it again.
+        snapshots.retain(|snapshot| {
+            if snapshot.step != Some(count) {
+                return true
+            }
+            take_snapshot(&display.borrow(), snapshot);
+            false
+        });
+        if let StepResult::Exited(code) = runtime.step()? {
+            for snapshot in snapshots.iter().filter(|snapshot| snapshot.step.is_none()) {
+                take_snapshot(&display.borrow(), snapshot);
+            }
+            return Ok(code)
+        }
+        watchdog.check(runtime)?;
+    }
+}
+
+/// Runs the loaded program under the debugger's prompt until it is quit,
+/// returning its exit code, or 0 if it hadn't exited.
+fn debug(runtime: &mut Runtime) -> i32 {
+    let mut debugger = Debugger::new(mem::take(runtime));
+    println!("Type help for a list of commands.");
+    loop {
+        print!("(micah) ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        if !matches!(io::stdin().read_line(&mut line), Ok(1..)) {
+            break
+        }
+        match repl::execute(&mut debugger, &line) {
+            Reply::Output(output) => print!("{}", output),
+            Reply::Quit => break,
+        }
+    }
+    let code = debugger.exit_code().unwrap_or(0);
+    *runtime = debugger.into_runtime();
+    code
+}
+
+/// Where to wait for gdb to connect.
+enum GdbListen {
+    Port(u16),
+    Socket(String),
+}
+
+/// Waits for gdb to connect, then serves it the loaded program until it
+/// detaches, returning the program's exit code.
+fn serve_gdb(runtime: &mut Runtime, listen: &GdbListen) -> i32 {
+    let mut debugger = Debugger::new(mem::take(runtime));
+    let served = match listen {
+        // Anyone who can connect can read and write the program's memory,
+        // so only listen locally.
+        GdbListen::Port(port) => TcpListener::bind(("127.0.0.1", *port)).and_then(|listener| {
+            eprintln!("micah: waiting for gdb on 127.0.0.1:{}", port);
+            let (stream, _) = listener.accept()?;
+            gdb::serve(&mut debugger, stream.try_clone()?, stream)
+        }),
+        #[cfg(unix)]
+        GdbListen::Socket(path) => UnixListener::bind(path).and_then(|listener| {
+            eprintln!("micah: waiting for gdb on {}", path);
+            let served = listener.accept().and_then(|(stream, _)| {
+                gdb::serve(&mut debugger, stream.try_clone()?, stream)
+            });
+            let _ = fs::remove_file(path);
+            served
+        }),
+        #[cfg(not(unix))]
+        GdbListen::Socket(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets aren't supported here")),
+    };
+    if let Err(error) = served {
+        eprintln!("micah: could not serve gdb: {}", error);
+    }
+    let code = debugger.exit_code().unwrap_or(0);
+    *runtime = debugger.into_runtime();
+    code
+}
+
+/// Serves the loaded program to an editor over the Debug Adapter Protocol
+/// on stdin and stdout until it disconnects, returning the program's exit
+/// code.
+fn serve_dap(runtime: &mut Runtime) -> i32 {
+    let mut debugger = Debugger::new(mem::take(runtime));
+    if let Err(error) = dap::serve(&mut debugger, io::BufReader::new(io::stdin()), io::stdout()) {
+        eprintln!("micah: could not serve the debug adapter protocol: {}", error);
+    }
+    let code = debugger.exit_code().unwrap_or(0);
+    *runtime = debugger.into_runtime();
+    code
+}
+
+fn main() {
     let mut args: Vec<String> = env::args().collect();
     let num_args = args.len();
     if num_args < 2 {
-        panic!("MITCH requires files as an argument:\n./mitch <file_name> <file_name...>");
-    } 
+        eprintln!("micah: no file to run:\n./micah <file_name>");
+        process::exit(1);
+    }
     args.remove(0);
 
-    let component_list = read_file_to_state(&args[0]).unwrap(); 
+    if args[0] == "disasm" {
+        match args.get(1) {
+            Some(file_name) => disasm(file_name),
+            None => {
+                eprintln!("micah: disasm requires a file:\n./micah disasm <file_name>");
+                process::exit(1);
+            }
+        }
+        return
+    }
+    if args[0] == "assemble" {
+        return assemble_command(&args[1..])
+    }
+    if args[0] == "link" {
+        return link_command(&args[1..])
+    }
+    if args[0] == "test" {
+        return test_command(&args[1..])
+    }
 
-}
+    // Under --dap, stdout carries the protocol.
+    if !args.iter().any(|arg| arg == "--dap") {
+        println!("==================================================");
+        println!("                  [[ MICAH ]]");
+        println!("    [ MIPS Interpreted Controller And Helper ]");
+        println!("==================================================");
+    }
+
+    let mut file_name = None;
+    let mut listing_file = None;
+    let mut default_handler = false;
+    let mut mapped_io = false;
+    let mut display = None;
+    let mut snapshots = Vec::new();
+    let mut debugging = false;
+    let mut gdb = None;
+    let mut dap = false;
+    let mut undo_capacity = None;
+    let mut max_steps = None;
+    let mut timeout = None;
+    let mut detect_stalls = false;
+    let mut coverage_file = None;
+    let mut profiling = false;
+    let mut folded_file = None;
+    let mut tracing = false;
+    let mut trace_format = TraceFormat::Text;
+    let mut trace_ranges = Vec::new();
+    let mut trace_file = None;
+    let mut check_conventions = false;
+    let mut check_undefined = false;
+    let mut stdin_file = None;
+    let mut stdout_file = None;
+    let mut stderr_file = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--exceptions" => default_handler = true,
+            "--mapped-io" => mapped_io = true,
+            "--debug" => debugging = true,
+            "--dap" => dap = true,
+            "--gdb-port" => match args.next().and_then(|port| port.parse().ok()) {
+                Some(port) => gdb = Some(GdbListen::Port(port)),
+                None => {
+                    eprintln!("micah: --gdb-port requires a port number:\n./micah --gdb-port <port> <file_name>");
+                    process::exit(1);
+                }
+            },
+            "--gdb-socket" => gdb = Some(GdbListen::Socket(flag_file("--gdb-socket", args.next()))),
+            "--undo-capacity" => match args.next().as_deref().and_then(parse_number) {
+                Some(capacity) => undo_capacity = Some(capacity),
+                None => {
+                    eprintln!("micah: --undo-capacity requires a number of writes:\n./micah --debug --undo-capacity <writes> <file_name>");
+                    process::exit(1);
+                }
+            },
+            "--max-steps" => match args.next().as_deref().and_then(parse_number) {
+                Some(steps) => max_steps = Some(steps as u64),
+                None => {
+                    eprintln!("micah: --max-steps requires a number of instructions:\n./micah --max-steps <steps> <file_name>");
+                    process::exit(1);
+                }
+            },
+            "--timeout" => match args.next().as_deref().and_then(parse_seconds) {
+                Some(seconds) => timeout = Some(seconds),
+                None => {
+                    eprintln!("micah: --timeout requires a number of seconds:\n./micah --timeout <seconds> <file_name>");
+                    process::exit(1);
+                }
+            },
+            "--detect-stalls" => detect_stalls = true,
+            "--coverage" => coverage_file = Some(flag_file("--coverage", args.next())),
+            "--profile" => profiling = true,
+            "--profile-folded" => folded_file = Some(flag_file("--profile-folded", args.next())),
+            "--trace" => tracing = true,
+            "--trace-format" => trace_format = parse_trace_format(args.next()),
+            // Checked once the program is loaded, with the other errors.
+            "--trace-range" => trace_ranges.push(args.next().unwrap_or_default()),
+            "--trace-file" => trace_file = Some(flag_file("--trace-file", args.next())),
+            "--check-conventions" => check_conventions = true,
+            "--check-undefined" => check_undefined = true,
+            "--display" => display = Some(parse_display(args.next())),
+            "--snapshot" => snapshots.push(parse_snapshot(args.next())),
+            "--stdin" => stdin_file = Some(flag_file("--stdin", args.next())),
+            "--stdout" => stdout_file = Some(flag_file("--stdout", args.next())),
+            "--stderr" => stderr_file = Some(flag_file("--stderr", args.next())),
+            "--listing" => match args.next() {
+                Some(path) => listing_file = Some(path),
+                None => {
+                    eprintln!("micah: --listing requires a file:\n./micah --listing <out.lst> <file_name>");
+                    process::exit(1);
+                }
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("micah: unknown option {}:\n./micah [options] <file_name>", arg);
+                process::exit(1);
+            }
+            _ => match file_name {
+                Some(first) => {
+                    eprintln!("micah: can only run one file, not {} and {}:\n./micah [options] <file_name>", first, arg);
+                    process::exit(1);
+                }
+                None => file_name = Some(arg),
+            },
+        }
+    }
+    let file_name = match file_name {
+        Some(file_name) => file_name,
+        None => {
+            eprintln!("micah: no file to run");
+            process::exit(1);
+        }
+    };
+
+    if [debugging, gdb.is_some(), dap].iter().filter(|&&debugger| debugger).count() > 1 {
+        eprintln!("micah: only one of --debug, gdb and --dap can control the program");
+        process::exit(1);
+    }
+    let under_debugger = debugging || gdb.is_some() || dap;
+
+    if undo_capacity.is_some() && !under_debugger {
+        eprintln!("micah: --undo-capacity only applies with --debug, gdb or --dap");
+        process::exit(1);
+    }
 
+    if under_debugger && (max_steps.is_some() || timeout.is_some() || detect_stalls) {
+        eprintln!("micah: --max-steps, --timeout and --detect-stalls don't apply under a debugger");
+        process::exit(1);
+    }
+
+    if dap && mapped_io {
+        eprintln!("micah: --mapped-io can't share stdin and stdout with --dap");
+        process::exit(1);
+    }
+
+    // Nothing steps backwards outside a debugger, so don't pay for the undo log.
+    let undo_capacity = if under_debugger { undo_capacity.unwrap_or(DEFAULT_UNDO_CAPACITY) } else { 0 };
+    let mut runtime = Runtime::with_undo_capacity(undo_capacity);
+    runtime.set_convention_checks(check_conventions);
+    runtime.set_undefined_read_checks(check_undefined);
+    let mut watchdog = Watchdog::new();
+    if let Some(max_steps) = max_steps {
+        watchdog.set_max_steps(max_steps);
+    }
+    if detect_stalls {
+        watchdog.detect_stalls(&mut runtime);
+    }
+    let mut console = Console::new();
+    if mapped_io {
+        // Nothing else is mapped yet, so this can't overlap.
+        runtime.map_device(MMIO_BASE, MAPPED_CONSOLE_LEN, Box::new(MappedConsole::new())).unwrap();
+        // The keyboard reads stdin as it likes, so syscalls can't share it.
+        console.set_input(ConsoleInput::Disabled);
+    }
+    if let Some(stdin_file) = &stdin_file {
+        console.set_input(ConsoleInput::Scripted(read_file(stdin_file), 0));
+    }
+    if dap {
+        // The protocol owns stdin and stdout, so the program reads its
+        // --stdin file or nothing, and what it prints is sent as events.
+        if stdin_file.is_none() {
+            console.set_input(ConsoleInput::Scripted(Vec::new(), 0));
+        }
+        console.set_output(ConsoleOutput::Captured(Vec::new()));
+        console.set_error(ConsoleOutput::Captured(Vec::new()));
+    }
+    if stdout_file.is_some() {
+        console.set_output(ConsoleOutput::Captured(Vec::new()));
+    }
+    if stderr_file.is_some() {
+        console.set_error(ConsoleOutput::Captured(Vec::new()));
+    }
+    runtime.set_console(console);
+    let display = display.map(|(width, height, base)| {
+        let display = Rc::new(RefCell::new(BitmapDisplay::new(width, height)));
+        let len = display.borrow().len();
+        if runtime.map_device(base, len, Box::new(display.clone())).is_err() {
+            eprintln!("micah: the display cannot be mapped at {:#x}", base);
+            process::exit(1);
+        }
+        display
+    });
+    if display.is_some() && snapshots.is_empty() {
+        snapshots.push(Snapshot { step: None, file: "-".to_string() });
+    } else if display.is_none() && !snapshots.is_empty() {
+        eprintln!("micah: --snapshot needs a --display to take snapshots of");
+        process::exit(1);
+    }
+    let loaded = if is_elf_file(&file_name) {
+        if listing_file.is_some() {
+            eprintln!("micah: --listing needs an assembly file, not an executable");
+            process::exit(1);
+        }
+        if coverage_file.is_some() {
+            eprintln!("micah: --coverage needs an assembly file, not an executable");
+            process::exit(1);
+        }
+        runtime.load_elf(&read_elf_file(&file_name))
+    } else {
+        let (program, source) = assemble_file(&file_name, default_handler);
+        if let Some(listing_file) = listing_file {
+            write_file(&listing_file, listing(&program, &source).as_bytes());
+        }
+        runtime.load_program(program)
+    };
+    let coverage = coverage_file.as_ref().and_then(|_| {
+        let coverage = Rc::new(RefCell::new(Coverage::new(runtime.program()?)));
+        runtime.add_observer(Box::new(coverage.clone()));
+        Some(coverage)
+    });
+    let profiler = (profiling || folded_file.is_some()).then(|| {
+        let profiler = Rc::new(RefCell::new(Profiler::new(CostModel::default())));
+        runtime.add_observer(Box::new(profiler.clone()));
+        profiler
+    });
+    // The ranges may name labels, so wait until they have addresses.
+    let tracer = (tracing || !trace_ranges.is_empty() || trace_file.is_some()).then(|| {
+        let output: Box<dyn Write> = match &trace_file {
+            Some(trace_file) => match fs::File::create(trace_file) {
+                Ok(file) => Box::new(BufWriter::new(file)),
+                Err(error) => {
+                    eprintln!("micah: could not write {}: {}", trace_file, error);
+                    process::exit(1);
+                }
+            },
+            None => Box::new(BufWriter::new(io::stderr())),
+        };
+        let mut tracer = Tracer::new(output, trace_format);
+        for range in &trace_ranges {
+            tracer.add_range(parse_trace_range(&runtime, range));
+        }
+        let tracer = Rc::new(RefCell::new(tracer));
+        runtime.add_observer(Box::new(tracer.clone()));
+        tracer
+    });
+    if let Some(timeout) = timeout {
+        watchdog.set_timeout(timeout);
+    }
+    let result = loaded.and_then(|_| match (&gdb, &display) {
+        (Some(gdb), _) => Ok(serve_gdb(&mut runtime, gdb)),
+        _ if debugging => Ok(debug(&mut runtime)),
+        _ if dap => Ok(serve_dap(&mut runtime)),
+        (None, Some(display)) => run_with_snapshots(&mut runtime, &mut watchdog, display, snapshots),
+        (None, None) => watchdog.run(&mut runtime),
+    });
+    if let Some(tracer) = tracer {
+        if let Err(error) = tracer.borrow_mut().finish() {
+            eprintln!("micah: could not write the trace: {}", error);
+        }
+    }
+    // Report what ran even if the program failed.
+    if let (Some(coverage), Some(coverage_file)) = (coverage, coverage_file) {
+        write_file(&coverage_file, coverage.borrow().lcov().as_bytes());
+    }
+    if let Some(profiler) = profiler {
+        if profiling {
+            eprint!("{}", profiler.borrow().report());
+        }
+        if let Some(folded_file) = folded_file {
+            write_file(&folded_file, profiler.borrow().folded().as_bytes());
+        }
+    }
+    // Save what the program printed even if it failed, to see how far it got.
+    if let Some(stdout_file) = stdout_file {
+        write_file(&stdout_file, runtime.console().captured_output().unwrap_or_default());
+    }
+    if let Some(stderr_file) = stderr_file {
+        write_file(&stderr_file, runtime.console().captured_error().unwrap_or_default());
+    }
+    for violation in runtime.convention_violations() {
+        eprintln!("micah: warning: {}", violation);
+    }
+    for read in runtime.undefined_reads() {
+        eprintln!("micah: warning: {}", read);
+    }
+    match result {
+        Ok(code) => process::exit(code),
+        Err(error) => {
+            match runtime.current_location() {
+                Some(location) => eprintln!("micah: {} at {}", error, location),
+                None => eprintln!("micah: {}", error),
+            }
+            eprint!("{}", runtime.backtrace());
+            process::exit(match error {
+                RuntimeError::Stopped(Stop::Stalled(_)) => EXIT_STALLED,
+                RuntimeError::Stopped(Stop::Timeout(_)) => EXIT_TIMEOUT,
+                RuntimeError::Stopped(Stop::StepLimit(_)) => EXIT_STEP_LIMIT,
+                _ => 1,
+            });
+        }
+    }
+}
@@ -1,14 +1,117 @@
-use super::mips_parser::*;
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+use super::device::Device;
 
 const PAGE_SIZE: usize = 4000;
-const NUM_PAGES: usize = 500;
+/// Enough pages to cover the 32-bit address space.
+const NUM_PAGES: usize = u32::MAX as usize / PAGE_SIZE + 1;
+
+/// The value of every byte in a freshly allocated page.
+pub const UNINITIALISED_BYTE: u8 = 0b01100110;
+
+/// Words in a page's bitmap of written bytes.
+const WRITTEN_WORDS: usize = PAGE_SIZE.div_ceil(64);
+
+/// Where SPIM maps its I/O devices.
+pub const MMIO_BASE: usize = 0xffff0000;
+
+/// Hashes page indices with a single multiply. Every instruction fetch looks
+/// up a page, so the default hasher is too slow here.
+#[derive(Default)]
+struct PageHasher(u64);
+
+impl Hasher for PageHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8 | *byte as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+
+    fn write_usize(&mut self, index: usize) {
+        self.0 = (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+/// A page of memory, and a bit for each of its bytes saying whether it has
+/// ever been written.
+struct MemoryPage {
+    bytes: [u8; PAGE_SIZE],
+    written: [u64; WRITTEN_WORDS],
+}
+
+impl MemoryPage {
+    fn mark_written(&mut self, offset: usize, len: usize) {
+        for offset in offset..offset + len {
+            self.written[offset / 64] |= 1 << (offset % 64);
+        }
+    }
+
+    fn is_written(&self, offset: usize) -> bool {
+        self.written[offset / 64] & 1 << (offset % 64) != 0
+    }
+}
+
+type MemoryRepList = HashMap<usize, Box<MemoryPage>, BuildHasherDefault<PageHasher>>;
+
+/// The order the bytes of a half or word are stored in memory.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+impl Endian {
+    pub fn word_to_bytes(self, word: u32) -> [u8; 4] {
+        match self {
+            Endian::Big => word.to_be_bytes(),
+            Endian::Little => word.to_le_bytes(),
+        }
+    }
+
+    pub fn word_from_bytes(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Endian::Big => u32::from_be_bytes(bytes),
+            Endian::Little => u32::from_le_bytes(bytes),
+        }
+    }
 
-type MemoryPage = Box<[u8; PAGE_SIZE]>;
-type MemoryPageOpt = Option<MemoryPage>;
-type MemoryRepList = [MemoryPageOpt; NUM_PAGES];
+    pub fn half_to_bytes(self, half: u16) -> [u8; 2] {
+        match self {
+            Endian::Big => half.to_be_bytes(),
+            Endian::Little => half.to_le_bytes(),
+        }
+    }
+
+    pub fn half_from_bytes(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            Endian::Big => u16::from_be_bytes(bytes),
+            Endian::Little => u16::from_le_bytes(bytes),
+        }
+    }
+}
+
+struct MappedRegion {
+    base: usize,
+    len: usize,
+    device: Box<dyn Device>,
+}
 
+/// The program's memory, allocated a page at a time as it is written.
+/// Memory is big-endian unless told otherwise.
+///
+/// Devices can be mapped over ranges of addresses. Reading or writing there
+/// reads or writes the device's registers instead.
 pub struct MemoryRep {
     memory: MemoryRepList,
+    endian: Endian,
+    regions: Vec<MappedRegion>,
+    /// The lowest mapped address, so most accesses skip the regions.
+    mapped_start: usize,
 }
 
 #[derive(Debug)]
@@ -16,121 +119,244 @@ pub enum MemoryError {
     NULLAccess, 
     OverflowAccess,
     PageFault,
-    InvalidMem
+    InvalidMem,
+    AlreadyMapped
 }
 
 fn check_sane_index(index: usize) -> Result<(), MemoryError> {
     if index == 0 {
         return Err(MemoryError::NULLAccess)
-    } else if index >= PAGE_SIZE {
+    } else if index >= NUM_PAGES {
         return Err(MemoryError::OverflowAccess)
     }
     Ok(())
 }
 
 impl MemoryRep {
-    fn memory_field() -> MemoryRepList {
-        unsafe {
-            let mut arr: MemoryRepList = std::mem::uninitialized();
-            for item in &mut arr[..] {
-                std::ptr::write(item, None as MemoryPageOpt);
-            }
-            assert!(arr[20].is_none());
-            arr
+    pub fn new() -> MemoryRep {
+        MemoryRep {
+            memory: MemoryRep::memory_field(),
+            endian: Endian::Big,
+            regions: Vec::new(),
+            mapped_start: usize::MAX,
+        }
+    }
+
+    /// Maps `device` over the `len` bytes from `base`, which mustn't be empty,
+    /// overlap another device's or run past the end of the address space.
+    pub fn map(&mut self, base: usize, len: usize, device: Box<dyn Device>) -> Result<(), MemoryError> {
+        if len == 0 {
+            return Err(MemoryError::InvalidMem)
+        }
+        if base.checked_add(len).is_none_or(|end| end > u32::MAX as usize + 1) {
+            return Err(MemoryError::OverflowAccess)
         }
+        if self.regions.iter().any(|region| base < region.base + region.len && region.base < base + len) {
+            return Err(MemoryError::AlreadyMapped)
+        }
+        self.mapped_start = self.mapped_start.min(base);
+        self.regions.push(MappedRegion { base, len, device });
+        Ok(())
+    }
+
+    pub fn is_mapped(&self, addr: usize) -> bool {
+        addr >= self.mapped_start && self.regions.iter().any(|region| region.contains(addr))
+    }
+
+    pub fn has_devices(&self) -> bool {
+        !self.regions.is_empty()
+    }
+
+    /// Advances every mapped device by one cycle.
+    pub fn tick(&mut self) {
+        for region in &mut self.regions {
+            region.device.tick();
+        }
+    }
+
+    /// The Cause bits of every interrupt the devices are raising.
+    pub fn interrupts(&self) -> u32 {
+        self.regions.iter().fold(0, |interrupts, region| interrupts | region.device.interrupts())
+    }
+
+    fn mapped_region(&mut self, addr: usize) -> Option<(&mut Box<dyn Device>, u32)> {
+        if addr < self.mapped_start {
+            return None
+        }
+        self.regions.iter_mut()
+            .find(|region| region.contains(addr))
+            .map(|region| (&mut region.device, (addr - region.base) as u32))
+    }
 
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+    }
+
+    fn memory_field() -> MemoryRepList {
+        HashMap::default()
     }
 
     fn addr_exists(&self, addr: usize) -> Result<(), MemoryError> {
         let index: usize = addr / PAGE_SIZE;
-        let offset: usize = addr % PAGE_SIZE;
-       
+
         check_sane_index(index)?;
-        match &self.memory[index]{
+        match self.memory.get(&index) {
             Some(_) => {
                 // TODO: add memory checks
-                return Ok(())
+                Ok(())
             }
-            None => return Err(MemoryError::InvalidMem)
+            None => Err(MemoryError::InvalidMem)
         }
     }
 
-    fn init_page<'a>(&self) -> MemoryPage{
-        Box::new([0b01100110; PAGE_SIZE])
+    fn init_page(&self) -> Box<MemoryPage> {
+        Box::new(MemoryPage { bytes: [UNINITIALISED_BYTE; PAGE_SIZE], written: [0; WRITTEN_WORDS] })
     }
 
-    fn get_page<'a>(&mut self, addr: usize) -> Result<&mut MemoryPage, MemoryError> {
+    fn get_page(&mut self, addr: usize) -> Result<&mut Box<MemoryPage>, MemoryError> {
         let index: usize = addr / PAGE_SIZE;
 
         check_sane_index(index)?;
 
-        match &mut self.memory[index] {
+        match self.memory.get_mut(&index) {
             Some(memory_index) => {
-                return Ok(memory_index);
+                Ok(memory_index)
             }
             None => {
-                return Err(MemoryError::PageFault)
+                Err(MemoryError::PageFault)
             }
         }
     }
 
     pub fn store_byte(&mut self, addr: usize, byte: u8) -> Result<(), MemoryError>{
+        if let Some((device, offset)) = self.mapped_region(addr) {
+            device.write(offset, byte as u32);
+            return Ok(())
+        }
         let index: usize = addr / PAGE_SIZE;
         let offset: usize = addr % PAGE_SIZE;
         match self.get_page(addr) {
             Ok(page) => {
-                page[offset] = byte;
+                page.bytes[offset] = byte;
+                page.mark_written(offset, 1);
             }
             Err(MemoryError::PageFault) => {
                 let mut page = self.init_page();
-                page[offset] = byte;
-                self.memory[index] = Some(page);
+                page.bytes[offset] = byte;
+                page.mark_written(offset, 1);
+                self.memory.insert(index, page);
             }
-            Err(_) => {
+            Err(error) => return Err(error)
+        }
+        Ok(())
 
-            }
+    }
 
+    /// Whether the byte at `addr` has been written since the memory was
+    /// created. Device registers always count as written.
+    pub fn is_written(&self, addr: usize) -> bool {
+        if self.is_mapped(addr) {
+            return true
         }
-        Ok(())
+        match self.memory.get(&(addr / PAGE_SIZE)) {
+            Some(page) => page.is_written(addr % PAGE_SIZE),
+            None => false
+        }
+    }
+
+    /// The byte at `addr`, read without side effects for a debugger: device
+    /// registers and pages never written give `None`.
+    pub fn peek_byte(&self, addr: usize) -> Option<u8> {
+        if self.is_mapped(addr) {
+            return None
+        }
+        self.memory.get(&(addr / PAGE_SIZE)).map(|page| page.bytes[addr % PAGE_SIZE])
+    }
 
+    /// Puts the byte at `addr` back as it was before it was first written.
+    pub fn forget_byte(&mut self, addr: usize) {
+        if let Ok(page) = self.get_page(addr) {
+            let offset = addr % PAGE_SIZE;
+            page.bytes[offset] = UNINITIALISED_BYTE;
+            page.written[offset / 64] &= !(1 << (offset % 64));
+        }
     }
     
     pub fn store_word(&mut self, addr: usize, word: u32) -> Result<(), MemoryError>{
-        let bytes: [u8; 4] = unsafe { std::mem::transmute(word.to_be()) };
-        for i in 0..4 {
-            self.store_byte(addr+i, bytes[i]);
+        if let Some((device, offset)) = self.mapped_region(addr) {
+            device.write(offset, word);
+            return Ok(())
+        }
+        let bytes: [u8; 4] = self.endian.word_to_bytes(word);
+        let offset = addr % PAGE_SIZE;
+        if offset + 4 <= PAGE_SIZE {
+            // The whole word is on one page, so only look it up once.
+            if let Ok(page) = self.get_page(addr) {
+                page.bytes[offset..offset + 4].copy_from_slice(&bytes);
+                page.mark_written(offset, 4);
+                return Ok(())
+            }
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            self.store_byte(addr+i, *byte)?;
         }
         Ok(())
 
     }
    
     pub fn read_byte(&mut self, addr: usize) -> Result<u8, MemoryError> {
+        if let Some((device, offset)) = self.mapped_region(addr) {
+            return Ok(device.read(offset) as u8)
+        }
         self.addr_exists(addr)?;
 
-        Ok(self.get_page(addr)?[addr % PAGE_SIZE])
+        Ok(self.get_page(addr)?.bytes[addr % PAGE_SIZE])
 
     }
 
     pub fn read_word(&mut self, addr: usize) -> Result<u32, MemoryError> {
-        let mut return_word: u32 = 0;
-        for i in 0..4 {
-            return_word <<= 8;
-            return_word |= self.read_byte(addr + i)? as u32;
+        if let Some((device, offset)) = self.mapped_region(addr) {
+            return Ok(device.read(offset))
+        }
+        let offset = addr % PAGE_SIZE;
+        if offset + 4 <= PAGE_SIZE {
+            if let Ok(page) = self.get_page(addr) {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&page.bytes[offset..offset + 4]);
+                return Ok(self.endian.word_from_bytes(bytes))
+            }
         }
-        Ok(return_word)
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read_byte(addr + i)?;
+        }
+        Ok(self.endian.word_from_bytes(bytes))
         
     }
 }
 
+impl MappedRegion {
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.base && addr - self.base < self.len
+    }
+}
+
+impl Default for MemoryRep {
+    fn default() -> MemoryRep {
+        MemoryRep::new()
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
     fn get_empty_memory_rep() -> MemoryRep {
-        MemoryRep {
-            memory: MemoryRep::memory_field()
-        }
+        MemoryRep::new()
     }
 
     #[test]
@@ -145,6 +371,22 @@ mod tests {
 
     }
 
+    #[test]
+    fn written_bytes(){
+        let mut memory = get_empty_memory_rep();
+        let address = PAGE_SIZE + 100;
+        memory.store_word(address, 0x66666666).unwrap();
+        assert!(memory.is_written(address + 3));
+        assert!(!memory.is_written(address + 4));
+        assert_eq!(memory.read_byte(address + 4).unwrap(), UNINITIALISED_BYTE);
+        assert!(!memory.is_written(2 * PAGE_SIZE));
+
+        memory.store_byte(address + 4, 1).unwrap();
+        memory.forget_byte(address + 4);
+        assert!(!memory.is_written(address + 4));
+        assert_eq!(memory.read_byte(address + 4).unwrap(), UNINITIALISED_BYTE);
+    }
+
     #[test]
     fn simple_word_read_write(){
         let mut memory = get_empty_memory_rep();
@@ -169,15 +411,75 @@ mod tests {
         }
     }
     
+    #[test]
+    fn high_address_read_write(){
+        let mut memory = get_empty_memory_rep();
+        let address = 0x7ffffffc;
+        memory.store_word(address, 1234321).expect("Should not fail to store memory");
+        assert_eq!(memory.read_word(address).unwrap(), 1234321);
+        match memory.read_byte(u32::MAX as usize + PAGE_SIZE) {
+            Err(MemoryError::OverflowAccess) => (),
+            _ => panic!("read_byte past the address space should overflow")
+        }
+    }
+
+    #[test]
+    fn little_endian_words(){
+        let mut memory = get_empty_memory_rep();
+        memory.set_endian(Endian::Little);
+        let address = 2 * PAGE_SIZE - 2;
+        memory.store_word(address, 0x11223344).unwrap();
+        memory.store_word(address + 4, 0x55667788).unwrap();
+        assert_eq!(memory.read_byte(address).unwrap(), 0x44);
+        assert_eq!(memory.read_byte(address + 3).unwrap(), 0x11);
+        assert_eq!(memory.read_word(address).unwrap(), 0x11223344);
+        assert_eq!(memory.read_word(address + 4).unwrap(), 0x55667788);
+    }
+
     #[test]
     fn word_read_null_fails(){
         let mut memory = get_empty_memory_rep();
         let address = 100;
-        memory.store_word(address, 1234321);
+        let _ = memory.store_word(address, 1234321);
         match memory.read_word(address) {
-            Ok(byte) => panic!("read_byte should not return"),
+            Ok(_) => panic!("read_byte should not return"),
             Err(MemoryError::NULLAccess) => (),
             Err(_) => panic!("read_byte returned an unexpected error")
         }
     }
+
+    #[test]
+    fn mapped_console(){
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use super::super::console::{MappedConsole, RECEIVER_CONTROL, RECEIVER_DATA, TRANSMITTER_DATA, MAPPED_CONSOLE_LEN};
+
+        let mut memory = get_empty_memory_rep();
+        let console = Rc::new(RefCell::new(MappedConsole::scripted(b"hi")));
+        memory.map(MMIO_BASE, MAPPED_CONSOLE_LEN, Box::new(console.clone())).unwrap();
+        match memory.map(MMIO_BASE + 8, 4, Box::new(console.clone())) {
+            Err(MemoryError::AlreadyMapped) => (),
+            _ => panic!("overlapping devices should not be mapped")
+        }
+        match memory.map(u32::MAX as usize - 2, 4, Box::new(console.clone())) {
+            Err(MemoryError::OverflowAccess) => (),
+            _ => panic!("devices should not be mapped past the address space")
+        }
+        match memory.map(MMIO_BASE + 0x100, 0, Box::new(console.clone())) {
+            Err(MemoryError::InvalidMem) => (),
+            _ => panic!("devices should not be mapped over no memory")
+        }
+        assert!(memory.is_mapped(MMIO_BASE + 3));
+        assert!(!memory.is_mapped(MMIO_BASE + MAPPED_CONSOLE_LEN));
+        memory.store_word(MMIO_BASE - 4, 7).unwrap();
+        assert_eq!(memory.read_word(MMIO_BASE - 4).unwrap(), 7);
+
+        assert_eq!(memory.read_word(MMIO_BASE + RECEIVER_CONTROL as usize).unwrap(), 0);
+        memory.tick();
+        assert_eq!(memory.read_word(MMIO_BASE + RECEIVER_CONTROL as usize).unwrap(), 1);
+        assert_eq!(memory.read_byte(MMIO_BASE + RECEIVER_DATA as usize).unwrap(), b'h');
+        assert_eq!(memory.read_word(MMIO_BASE + RECEIVER_CONTROL as usize).unwrap(), 0);
+        memory.store_byte(MMIO_BASE + TRANSMITTER_DATA as usize, b'!').unwrap();
+        assert_eq!(console.borrow().captured_output(), Some(&b"!"[..]));
+    }
 }
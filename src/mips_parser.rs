@@ -2,39 +2,50 @@ use std::collections::HashMap;
 
 use super::utils::smart_split::SmartSplit;
 
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Result};
+use std::io::{Read, Result};
 
+/// Where in the source a component was written.
 #[derive(Debug, PartialEq, Clone)]
 pub struct MIPSLocation {
-    file: String,
-    line_num: usize,
-    line_text: String
+    pub file: String,
+    pub line_num: usize,
+    pub line_text: String
+}
+
+impl fmt::Display for MIPSLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // line_num counts from zero, editors count from one.
+        write!(f, "{}:{}: {}", self.file, self.line_num + 1, self.line_text.trim())
+    }
 }
 
 #[derive(Debug)]
 #[derive(PartialEq)]
 pub struct MIPSDirective {
-    directive_type: String,
-    directive_value: Vec<String>,
-    directive_location: MIPSLocation
+    pub directive_type: String,
+    pub directive_value: Vec<String>,
+    pub directive_location: MIPSLocation
 }
 
 #[derive(Debug)]
 #[derive(PartialEq)]
 pub struct MIPSLabel {
-    label: String,
+    pub label: String,
 } 
 
 #[derive(Debug)]
 #[derive(PartialEq)]
 pub struct MIPSInstruction {
-    instr_type: String,
-    instr_args: Vec<String>,
-    instr_location: MIPSLocation
+    pub instr_type: String,
+    pub instr_args: Vec<String>,
+    pub instr_location: MIPSLocation
 } 
 
 
+/// One parsed piece of a source line; a line can hold several labels
+/// followed by one directive or instruction.
 #[derive(Debug)]
 #[derive(PartialEq)]
 pub enum MIPSComponent {
@@ -43,14 +54,14 @@ pub enum MIPSComponent {
     Instruction(MIPSInstruction),
 }
 
-fn parse_line_to_parts<'a>(line: &'a String) -> impl Iterator<Item = &'a str> {
+fn parse_line_to_parts(line: &str) -> impl Iterator<Item = &str> {
     SmartSplit::new(line)
 }
 
 fn parse_parts_to_component<'a>(parts: &mut impl Iterator<Item =&'a str>, location: MIPSLocation) -> Option<Vec<MIPSComponent>> {
     let mut return_parts:Vec<MIPSComponent> = Vec::new();
     let possible_label = parts.next()?;
-    if possible_label.chars().last().unwrap() == ':' {
+    if possible_label.ends_with(':') {
         let new_label =  MIPSLabel {
             label: possible_label.to_owned()
         };
@@ -83,32 +94,53 @@ fn parse_parts_to_component<'a>(parts: &mut impl Iterator<Item =&'a str>, locati
     Some(return_parts)
 }
 
-fn parse_line_to_component(line: &String, location: MIPSLocation) -> Option<Vec<MIPSComponent>> {
+fn parse_line_to_component(line: &str, location: MIPSLocation) -> Option<Vec<MIPSComponent>> {
     let mut parts = parse_line_to_parts(line);
     parse_parts_to_component(&mut parts, location)
 
 }
 
-pub fn read_file_to_state<'a>(file_name: &String) -> Result<(Vec<MIPSComponent> , HashMap<String, &'a MIPSComponent>)> {
-    let component_list = Vec::new();
-    let label_map = HashMap::new();
+impl MIPSLabel {
+    /// The label's name, without the trailing colon.
+    pub fn name(&self) -> &str {
+        self.label.trim_end_matches(':')
+    }
+}
 
+/// Parses MIPS source into its components, and a map from each label's name
+/// to the index of that label in the component list.
+pub fn read_str_to_state(source: &str, file_name: &str) -> (Vec<MIPSComponent>, HashMap<String, usize>) {
+    let mut component_list = Vec::new();
+    let mut label_map = HashMap::new();
 
-    let file = File::open(file_name)?;
-    for (i, line) in BufReader::new(file).lines().enumerate() {
-        let mut line = line?.clone();
+    for (i, line) in source.lines().enumerate() {
         let location = MIPSLocation {
             file: file_name.to_owned(),
             line_num: i,
-            line_text: line.clone()
+            line_text: line.to_owned()
         };
-        parse_line_to_component(&mut line, location);
+        if let Some(components) = parse_line_to_component(line, location) {
+            for component in components {
+                if let MIPSComponent::Label(label) = &component {
+                    label_map.insert(label.name().to_owned(), component_list.len());
+                }
+                component_list.push(component);
+            }
+        }
     }
 
-    Ok((component_list, label_map))
+    (component_list, label_map)
+}
+
+pub fn read_file_to_state(file_name: &str) -> Result<(Vec<MIPSComponent>, HashMap<String, usize>)> {
+    let mut source = String::new();
+    File::open(file_name)?.read_to_string(&mut source)?;
+    Ok(read_str_to_state(&source, file_name))
 }
 
 #[cfg(test)]
+// The tests pass owned lines, as `read_file_to_state` does.
+#[allow(clippy::unnecessary_to_owned)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
@@ -250,6 +282,39 @@ mod tests {
         );
     }
     
+    #[test]
+    fn test_parse_line_to_parts_comment() {
+        let loc = MIPSLocation {file: "".to_string(), line_num: 0, line_text: "".to_string()};
+        assert_eq!(parse_line_to_component("# just a comment", loc.clone()), None);
+        compare_results(
+            parse_line_to_component("label: .byte '#', 2 # comment", loc.clone()).unwrap(),
+            vec![
+                MIPSComponent::Label(MIPSLabel {
+                    label: "label:".to_string()
+                }),
+                MIPSComponent::Directive(MIPSDirective {
+                    directive_type: "byte".to_string(),
+                    directive_value: vec!["'#'".to_string(), "2".to_string()],
+                    directive_location: loc.clone()
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_str_to_state() {
+        let (components, labels) = read_str_to_state("main:\n\tli $v0, 10 # exit\n\n\tsyscall\n", "test.s");
+        assert_eq!(components.len(), 3);
+        assert_eq!(labels.get("main"), Some(&0));
+        match &components[2] {
+            MIPSComponent::Instruction(instr) => {
+                assert_eq!(instr.instr_type, "syscall");
+                assert_eq!(instr.instr_location.line_num, 3);
+            }
+            other => panic!("Expected an instruction, found {:?}", other)
+        }
+    }
+
     #[test]
     fn test_parse_line_to_parts_label_assembly() {
         let loc = MIPSLocation {file: "".to_string(), line_num: 0, line_text: "".to_string()};
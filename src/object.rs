@@ -0,0 +1,315 @@
+use std::collections::HashSet;
+
+use super::assembler::{Program, RelocationKind, DATA_BASE, TEXT_BASE};
+use super::elf::*;
+use super::memory::Endian;
+
+const R_MIPS_32: u8 = 2;
+const R_MIPS_26: u8 = 4;
+const R_MIPS_HI16: u8 = 5;
+const R_MIPS_LO16: u8 = 6;
+
+/// Where a symbol is defined in an object.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ObjectSection {
+    Text,
+    Data,
+    /// Defined in another object.
+    Undefined,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub section: ObjectSection,
+    /// The offset into `section`.
+    pub offset: u32,
+    pub global: bool,
+}
+
+/// A place at `offset` into `section` that holds the address of
+/// `symbols[symbol]` plus `addend`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ObjectRelocation {
+    pub section: ObjectSection,
+    pub offset: u32,
+    pub kind: RelocationKind,
+    pub symbol: usize,
+    pub addend: i64,
+}
+
+/// A relocatable object: one file's text and data, not yet given addresses.
+#[derive(Debug, PartialEq)]
+pub struct Object {
+    pub endian: Endian,
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub data_alignment: u32,
+    pub symbols: Vec<ObjectSymbol>,
+    pub relocations: Vec<ObjectRelocation>,
+}
+
+impl Object {
+    /// Takes a program from `assemble_object` apart into sections. Labels it
+    /// uses but doesn't define become undefined globals.
+    pub fn from_program(program: &Program) -> Object {
+        let endian = Endian::Big;
+        let mut text = Vec::with_capacity(4 * program.words.len());
+        for word in &program.words {
+            text.extend_from_slice(&endian.word_to_bytes(*word));
+        }
+
+        let mut names: Vec<&String> = program.symbols.keys().collect();
+        names.sort_by_key(|name| (program.globals.contains(*name), program.symbols[*name], name.as_str()));
+        let mut symbols: Vec<ObjectSymbol> = names.into_iter()
+            .map(|name| {
+                let (section, offset) = section_offset(program.symbols[name]);
+                ObjectSymbol {name: name.clone(), section, offset, global: program.globals.contains(name)}
+            })
+            .collect();
+        let mut undefined = HashSet::new();
+        for relocation in &program.relocations {
+            if !program.symbols.contains_key(&relocation.symbol) && undefined.insert(&relocation.symbol) {
+                symbols.push(ObjectSymbol {
+                    name: relocation.symbol.clone(),
+                    section: ObjectSection::Undefined,
+                    offset: 0,
+                    global: true,
+                });
+            }
+        }
+
+        let mut relocations: Vec<ObjectRelocation> = program.relocations.iter()
+            .map(|relocation| {
+                let (section, offset) = section_offset(relocation.addr);
+                ObjectRelocation {
+                    section,
+                    offset,
+                    kind: relocation.kind,
+                    symbol: symbols.iter().position(|symbol| symbol.name == relocation.symbol).unwrap(),
+                    addend: relocation.addend,
+                }
+            })
+            .collect();
+        relocations.sort_by_key(|relocation| (relocation.section == ObjectSection::Data, relocation.offset));
+
+        Object {
+            endian,
+            text,
+            data: program.data.clone(),
+            data_alignment: program.data_alignment.max(1) as u32,
+            symbols,
+            relocations,
+        }
+    }
+
+    /// Writes the object as an ELF32 relocatable file, with `.text`, `.data`,
+    /// `.rela.text`, `.rela.data`, `.symtab` and `.strtab` sections.
+    pub fn to_elf(&self) -> Vec<u8> {
+        let mut writer = ElfWriter::new(self.endian);
+        let text = writer.add(Section {
+            name: ".text", sh_type: SHT_PROGBITS, flags: SHF_ALLOC | SHF_EXECINSTR, addr: 0,
+            data: self.text.clone(), link: 0, info: 0, align: 4, entsize: 0,
+        });
+        let data = writer.add(Section {
+            name: ".data", sh_type: SHT_PROGBITS, flags: SHF_ALLOC | SHF_WRITE, addr: 0,
+            data: self.data.clone(), link: 0, info: 0, align: self.data_alignment, entsize: 0,
+        });
+
+        // Local symbols have to come before global ones.
+        let mut order: Vec<usize> = (0..self.symbols.len()).collect();
+        order.sort_by_key(|i| self.symbols[*i].global);
+        let mut indices = vec![0; self.symbols.len()];
+        let mut symtab = vec![0; 16];
+        let mut strtab = vec![0];
+        for (position, i) in order.iter().enumerate() {
+            let symbol = &self.symbols[*i];
+            indices[*i] = position as u32 + 1;
+            let shndx = match symbol.section {
+                ObjectSection::Text => text,
+                ObjectSection::Data => data,
+                ObjectSection::Undefined => SHN_UNDEF,
+            };
+            let binding = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+            writer.symbol(&mut symtab, strtab.len() as u32, symbol.offset, binding, shndx);
+            strtab.extend_from_slice(symbol.name.as_bytes());
+            strtab.push(0);
+        }
+        let first_global = order.iter().filter(|i| !self.symbols[**i].global).count() as u32 + 1;
+
+        let symtab_index = u32::from(data) + 3;
+        for (name, section, index) in &[(".rela.text", ObjectSection::Text, text), (".rela.data", ObjectSection::Data, data)] {
+            let mut rela = Vec::new();
+            for relocation in self.relocations.iter().filter(|relocation| relocation.section == *section) {
+                let kind = match relocation.kind {
+                    RelocationKind::Word32 => R_MIPS_32,
+                    RelocationKind::Jump26 => R_MIPS_26,
+                    RelocationKind::Hi16 => R_MIPS_HI16,
+                    RelocationKind::Lo16 => R_MIPS_LO16,
+                };
+                writer.word(&mut rela, relocation.offset);
+                writer.word(&mut rela, indices[relocation.symbol] << 8 | u32::from(kind));
+                writer.word(&mut rela, relocation.addend as u32);
+            }
+            writer.add(Section {
+                name, sh_type: SHT_RELA, flags: 0, addr: 0, data: rela,
+                link: symtab_index, info: u32::from(*index), align: 4, entsize: 12,
+            });
+        }
+        writer.add(Section {
+            name: ".symtab", sh_type: SHT_SYMTAB, flags: 0, addr: 0, data: symtab,
+            link: symtab_index + 1, info: first_global, align: 4, entsize: 16,
+        });
+        writer.add(Section {
+            name: ".strtab", sh_type: SHT_STRTAB, flags: 0, addr: 0, data: strtab,
+            link: 0, info: 0, align: 1, entsize: 0,
+        });
+        writer.finish(ET_REL, 0, &[])
+    }
+
+    /// Reads an ELF32 relocatable file with `RELA` relocations, such as one
+    /// written by `to_elf`.
+    pub fn from_elf(bytes: &[u8]) -> Result<Object, ElfError> {
+        let (reader, elf_type) = read_header(bytes)?;
+        if elf_type != ET_REL {
+            return Err(ElfError::NotRelocatable)
+        }
+        let sections = section_headers(&reader)?;
+        let names = section_names(&reader, &sections)?;
+        let find = |name: &str| names.iter().position(|section| section == name);
+        let contents = |index: Option<usize>| match index {
+            Some(index) => reader.slice(sections[index].offset, sections[index].size, "section").map(|data| data.to_vec()),
+            None => Ok(Vec::new())
+        };
+        let text_index = find(".text");
+        let data_index = find(".data");
+        let section_of = |index: usize| {
+            if Some(index) == text_index {
+                Ok(ObjectSection::Text)
+            } else if Some(index) == data_index {
+                Ok(ObjectSection::Data)
+            } else {
+                Err(ElfError::UnsupportedSection(names.get(index).cloned().unwrap_or_default()))
+            }
+        };
+
+        let mut symbols = Vec::new();
+        for symbol in read_symbols(&reader, &sections)?.into_iter().skip(1) {
+            let section = match symbol.shndx {
+                SHN_UNDEF => ObjectSection::Undefined,
+                shndx => section_of(shndx as usize)?,
+            };
+            symbols.push(ObjectSymbol {
+                name: symbol.name,
+                section,
+                offset: symbol.value,
+                global: symbol.binding != STB_LOCAL,
+            });
+        }
+
+        let mut relocations = Vec::new();
+        for (i, header) in sections.iter().enumerate() {
+            if header.sh_type == SHT_REL {
+                return Err(ElfError::UnsupportedSection(names[i].clone()))
+            }
+            if header.sh_type != SHT_RELA {
+                continue
+            }
+            let section = section_of(header.info)?;
+            let entsize = header.entsize.max(12);
+            for j in 0..header.size / entsize {
+                let entry = header.offset + j * entsize;
+                let info = reader.word(entry + 4, "relocation")?;
+                let kind = match info as u8 {
+                    R_MIPS_32 => RelocationKind::Word32,
+                    R_MIPS_26 => RelocationKind::Jump26,
+                    R_MIPS_HI16 => RelocationKind::Hi16,
+                    R_MIPS_LO16 => RelocationKind::Lo16,
+                    kind => return Err(ElfError::UnsupportedRelocation(kind))
+                };
+                // The symbol table's null entry isn't in `symbols`.
+                let symbol = (info >> 8) as usize;
+                if symbol == 0 || symbol > symbols.len() {
+                    return Err(ElfError::Truncated("symbol table"))
+                }
+                relocations.push(ObjectRelocation {
+                    section,
+                    offset: reader.word(entry, "relocation")?,
+                    kind,
+                    symbol: symbol - 1,
+                    addend: i64::from(reader.word(entry + 8, "relocation")? as i32),
+                });
+            }
+        }
+
+        Ok(Object {
+            endian: reader.endian,
+            text: contents(text_index)?,
+            data: contents(data_index)?,
+            data_alignment: match data_index {
+                Some(index) => sections[index].align.max(1),
+                None => 1
+            },
+            symbols,
+            relocations,
+        })
+    }
+}
+
+fn section_offset(addr: u32) -> (ObjectSection, u32) {
+    if addr >= DATA_BASE {
+        (ObjectSection::Data, addr - DATA_BASE)
+    } else {
+        (ObjectSection::Text, addr - TEXT_BASE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble_object;
+    use super::super::mips_parser::read_str_to_state;
+
+    #[test]
+    fn test_object_round_trip(){
+        let source = "
+                .globl main
+                .data
+                .align 3
+        count:  .word 0
+        table:  .word count, helper+8
+                .text
+        main:   la $a0, table
+                lw $t0, count
+                jal helper
+                jr $ra
+        ";
+        let (components, _) = read_str_to_state(source, "main.s");
+        let object = Object::from_program(&assemble_object(&components).unwrap());
+        assert_eq!(object.text.len(), 4 * 6);
+        assert_eq!(object.data_alignment, 8);
+        let helper = object.symbols.iter().find(|symbol| symbol.name == "helper").unwrap();
+        assert_eq!(helper.section, ObjectSection::Undefined);
+        let main = object.symbols.iter().find(|symbol| symbol.name == "main").unwrap();
+        assert!(main.global);
+        let count = object.symbols.iter().find(|symbol| symbol.name == "count").unwrap();
+        assert_eq!((count.section, count.offset, count.global), (ObjectSection::Data, 0, false));
+        let kinds: Vec<RelocationKind> = object.relocations.iter().map(|relocation| relocation.kind).collect();
+        assert_eq!(kinds, vec![
+            RelocationKind::Hi16, RelocationKind::Lo16, RelocationKind::Hi16, RelocationKind::Lo16,
+            RelocationKind::Jump26, RelocationKind::Word32, RelocationKind::Word32,
+        ]);
+        assert_eq!(object.relocations[6].addend, 8);
+
+        let bytes = object.to_elf();
+        assert_eq!(Object::from_elf(&bytes), Ok(object));
+        assert_eq!(parse_elf(&bytes), Err(ElfError::NotExecutable));
+    }
+
+    #[test]
+    fn test_bad_object(){
+        let executable = fixtures::executable(Endian::Big, 0, &[(TEXT_BASE, vec![0; 4], 4)], &[]);
+        assert_eq!(Object::from_elf(&executable), Err(ElfError::NotRelocatable));
+        assert_eq!(Object::from_elf(b"\x7fELF"), Err(ElfError::Truncated("header")));
+    }
+}
@@ -0,0 +1,190 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::call_stack::CallFrame;
+use super::mips_parser::MIPSLocation;
+use super::runtime::{ExceptionCode, FloatRegister, RegisterCodes};
+
+/// Receives events from a `Runtime` as the program runs.
+///
+/// Every method does nothing by default, so an observer only implements the
+/// events it cares about. Memory events carry the access size in bytes, and
+/// for words the value as the program sees it.
+pub trait Observer {
+    fn instruction_retired(&mut self, _addr: u32, _location: Option<&MIPSLocation>) {}
+    fn register_written(&mut self, _reg: RegisterCodes, _old_val: u32, _new_val: u32) {}
+    /// Values are the register's raw bits; a double writes both halves.
+    fn float_register_written(&mut self, _reg: FloatRegister, _old_val: u32, _new_val: u32) {}
+    /// Values are `(hi, lo)`.
+    fn hi_lo_written(&mut self, _old_val: (u32, u32), _new_val: (u32, u32)) {}
+    fn cp0_register_written(&mut self, _reg: u8, _old_val: u32, _new_val: u32) {}
+    /// Values hold condition flag `i` in bit `i`.
+    fn float_conditions_written(&mut self, _old_val: u8, _new_val: u8) {}
+    fn memory_read(&mut self, _addr: usize, _size: usize, _val: u32) {}
+    fn memory_written(&mut self, _addr: usize, _size: usize, _val: u32) {}
+    fn branch(&mut self, _addr: u32, _target: u32, _taken: bool) {}
+    fn called(&mut self, _frame: &CallFrame) {}
+    /// `frames` are the frames popped by the return, newest first.
+    fn returned(&mut self, _frames: &[CallFrame]) {}
+    /// A `syscall` with this code in `$v0` is about to run.
+    fn syscall(&mut self, _code: u32) {}
+    /// The handler was entered for an exception or interrupt, and will
+    /// return to `epc`.
+    fn exception(&mut self, _code: ExceptionCode, _epc: u32) {}
+}
+
+/// Lets a caller keep a handle on an observer after giving it to a runtime,
+/// to read back what it collected.
+impl<T: Observer> Observer for Rc<RefCell<T>> {
+    fn instruction_retired(&mut self, addr: u32, location: Option<&MIPSLocation>) {
+        self.borrow_mut().instruction_retired(addr, location)
+    }
+    fn register_written(&mut self, reg: RegisterCodes, old_val: u32, new_val: u32) {
+        self.borrow_mut().register_written(reg, old_val, new_val)
+    }
+    fn float_register_written(&mut self, reg: FloatRegister, old_val: u32, new_val: u32) {
+        self.borrow_mut().float_register_written(reg, old_val, new_val)
+    }
+    fn hi_lo_written(&mut self, old_val: (u32, u32), new_val: (u32, u32)) {
+        self.borrow_mut().hi_lo_written(old_val, new_val)
+    }
+    fn cp0_register_written(&mut self, reg: u8, old_val: u32, new_val: u32) {
+        self.borrow_mut().cp0_register_written(reg, old_val, new_val)
+    }
+    fn float_conditions_written(&mut self, old_val: u8, new_val: u8) {
+        self.borrow_mut().float_conditions_written(old_val, new_val)
+    }
+    fn memory_read(&mut self, addr: usize, size: usize, val: u32) {
+        self.borrow_mut().memory_read(addr, size, val)
+    }
+    fn memory_written(&mut self, addr: usize, size: usize, val: u32) {
+        self.borrow_mut().memory_written(addr, size, val)
+    }
+    fn branch(&mut self, addr: u32, target: u32, taken: bool) {
+        self.borrow_mut().branch(addr, target, taken)
+    }
+    fn called(&mut self, frame: &CallFrame) {
+        self.borrow_mut().called(frame)
+    }
+    fn returned(&mut self, frames: &[CallFrame]) {
+        self.borrow_mut().returned(frames)
+    }
+    fn syscall(&mut self, code: u32) {
+        self.borrow_mut().syscall(code)
+    }
+    fn exception(&mut self, code: ExceptionCode, epc: u32) {
+        self.borrow_mut().exception(code, epc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+    use super::super::runtime::Runtime;
+    use super::super::runtime::RegisterCodes::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl Observer for Recorder {
+        fn register_written(&mut self, reg: RegisterCodes, old_val: u32, new_val: u32) {
+            self.events.push(format!("{:?} {} -> {}", reg, old_val, new_val));
+        }
+        fn memory_read(&mut self, addr: usize, size: usize, val: u32) {
+            self.events.push(format!("read {} {} {}", addr, size, val));
+        }
+        fn memory_written(&mut self, addr: usize, size: usize, val: u32) {
+            self.events.push(format!("write {} {} {}", addr, size, val));
+        }
+        fn called(&mut self, frame: &CallFrame) {
+            self.events.push(format!("call {:?}", frame.function));
+        }
+        fn returned(&mut self, frames: &[CallFrame]) {
+            self.events.push(format!("return {}", frames.len()));
+        }
+    }
+
+    /// Records the events that don't come from the runtime's public methods.
+    #[derive(Default)]
+    struct MachineRecorder {
+        events: Vec<String>,
+    }
+
+    impl Observer for MachineRecorder {
+        fn hi_lo_written(&mut self, old_val: (u32, u32), new_val: (u32, u32)) {
+            self.events.push(format!("hi/lo {:?} -> {:?}", old_val, new_val));
+        }
+        fn cp0_register_written(&mut self, reg: u8, old_val: u32, new_val: u32) {
+            self.events.push(format!("cp0 {} {:#x} -> {:#x}", reg, old_val, new_val));
+        }
+        fn float_conditions_written(&mut self, old_val: u8, new_val: u8) {
+            self.events.push(format!("fcc {} -> {}", old_val, new_val));
+        }
+        fn exception(&mut self, code: ExceptionCode, epc: u32) {
+            self.events.push(format!("{:?} at {:#x}", code, epc));
+        }
+    }
+
+    #[test]
+    fn test_machine_events(){
+        let source = "
+        main:   li $t0, 3
+                mult $t0, $t0
+                c.eq.s 2, $f0, $f0
+                break
+                jr $ra
+                .ktext
+                mfc0 $k0, $14
+                addiu $k0, $k0, 4
+                mtc0 $k0, $14
+                eret
+        ";
+        let (components, _) = read_str_to_state(source, "test.s");
+        let mut runtime = Runtime::new();
+        runtime.load_program(assemble(&components).unwrap()).unwrap();
+        let recorder = Rc::new(RefCell::new(MachineRecorder::default()));
+        runtime.add_observer(Box::new(recorder.clone()));
+        assert_eq!(runtime.run().unwrap(), 0);
+
+        assert_eq!(recorder.borrow().events, vec![
+            "hi/lo (0, 0) -> (0, 9)",
+            "fcc 0 -> 4",
+            "cp0 13 0x0 -> 0x24",
+            "cp0 14 0x0 -> 0x40000c",
+            "cp0 12 0x3000ff10 -> 0x3000ff12",
+            "Breakpoint at 0x40000c",
+            "cp0 14 0x40000c -> 0x400010",
+            "cp0 12 0x3000ff12 -> 0x3000ff10",
+        ]);
+    }
+
+    #[test]
+    fn test_observer_events(){
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        let mut runtime = Runtime::new();
+        runtime.add_observer(Box::new(recorder.clone()));
+
+        runtime.set_register(&Rt0, 5).unwrap();
+        runtime.store_word(5000, 7).unwrap();
+        runtime.read_word(5000).unwrap();
+        runtime.store_byte(5000, 1).unwrap();
+        runtime.call(CallFrame {
+            function: Some("f".to_string()), target: 0x40, return_addr: 0x10, call_site: None, saved_registers: None
+        });
+        runtime.return_to(0x10);
+        runtime.return_to(0x10);
+
+        assert_eq!(recorder.borrow().events, vec![
+            "Rt0 0 -> 5",
+            "write 5000 4 7",
+            "read 5000 4 7",
+            "write 5000 1 1",
+            "call Some(\"f\")",
+            "return 1",
+        ]);
+    }
+}
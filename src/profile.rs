@@ -0,0 +1,357 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use super::assembler::KTEXT_BASE;
+use super::call_stack::CallFrame;
+use super::mips_parser::MIPSLocation;
+use super::observer::Observer;
+use super::runtime::ExceptionCode;
+
+/// What the instructions before `main` is called are counted under.
+const STARTUP: &str = "(startup)";
+
+/// How many loops the report lists.
+const REPORTED_LOOPS: usize = 10;
+
+/// A simple cost model for counting cycles: every instruction costs
+/// `instruction`, plus the extra cycles for what it does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CostModel {
+    pub instruction: u64,
+    /// Per load or store, including a syscall's.
+    pub memory_access: u64,
+    /// Per branch taken, for the fetches it throws away.
+    pub taken_branch: u64,
+    /// Per instruction writing hi and lo, which multiplies and divides do.
+    pub multiply_divide: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> CostModel {
+        CostModel {
+            instruction: 1,
+            memory_access: 1,
+            taken_branch: 1,
+            multiply_divide: 4,
+        }
+    }
+}
+
+/// Instructions executed and the cycles they cost.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Count {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl Count {
+    fn add(&mut self, other: Count) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+}
+
+/// The instructions executed from one source line.
+#[derive(Debug, PartialEq)]
+pub struct LineProfile {
+    pub location: MIPSLocation,
+    pub count: Count,
+}
+
+/// The instructions executed in one function: `own` in the function
+/// itself and `total` in it and everything it called.
+#[derive(Debug, PartialEq)]
+pub struct FunctionProfile {
+    pub function: String,
+    pub own: Count,
+    pub total: Count,
+}
+
+/// A loop from `head` back from `tail`, with how many times it went back
+/// and what was executed between the two.
+#[derive(Debug, PartialEq)]
+pub struct LoopProfile {
+    pub head: u32,
+    pub tail: u32,
+    pub iterations: u64,
+    pub count: Count,
+}
+
+/// Counts the instructions and cycles a runtime spends on each address,
+/// source line, function and loop.
+///
+/// Functions come from the shadow call stack, so a function is the label a
+/// `jal` went to. A loop is a jump backwards that wasn't a call or return.
+pub struct Profiler {
+    model: CostModel,
+    addresses: HashMap<u32, (Count, Option<MIPSLocation>)>,
+    /// Each call stack seen, as the functions in it joined by `;`, and the
+    /// instructions executed with the top function at the top.
+    stacks: Vec<(String, Count)>,
+    stack_ids: HashMap<String, usize>,
+    /// The function at each depth of the stack, and the stack up to it.
+    frames: Vec<(String, usize)>,
+    /// The stack the instruction being executed started in, so a `jal`
+    /// counts towards its caller and a `jr $ra` towards its callee.
+    executing: usize,
+    loops: HashMap<(u32, u32), u64>,
+    /// Extra cycles the instruction being executed has cost so far.
+    extra_cycles: u64,
+    last_addr: Option<u32>,
+    /// Whether the instruction being executed, or the last one, called,
+    /// returned or took an exception, so it isn't a loop.
+    transferring: bool,
+    transferred: bool,
+}
+
+impl Profiler {
+    pub fn new(model: CostModel) -> Profiler {
+        Profiler {
+            model,
+            addresses: HashMap::new(),
+            stacks: vec![(STARTUP.to_string(), Count::default())],
+            stack_ids: HashMap::from([(STARTUP.to_string(), 0)]),
+            frames: Vec::new(),
+            executing: 0,
+            loops: HashMap::new(),
+            extra_cycles: 0,
+            last_addr: None,
+            transferring: false,
+            transferred: false,
+        }
+    }
+
+    /// Everything executed.
+    pub fn total(&self) -> Count {
+        let mut total = Count::default();
+        for (count, _) in self.addresses.values() {
+            total.add(*count);
+        }
+        total
+    }
+
+    /// The source lines executed, most cycles first.
+    pub fn lines(&self) -> Vec<LineProfile> {
+        let mut lines: BTreeMap<(&str, usize), LineProfile> = BTreeMap::new();
+        for (count, location) in self.addresses.values() {
+            if let Some(location) = location {
+                lines.entry((&location.file, location.line_num))
+                    .or_insert_with(|| LineProfile { location: location.clone(), count: Count::default() })
+                    .count.add(*count);
+            }
+        }
+        let mut lines: Vec<LineProfile> = lines.into_values().collect();
+        lines.sort_by_key(|line| Reverse(line.count.cycles));
+        lines
+    }
+
+    /// The functions executed, most cycles in total first.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions: BTreeMap<&str, FunctionProfile> = BTreeMap::new();
+        for (stack, count) in &self.stacks {
+            let mut names: Vec<&str> = stack.split(';').collect();
+            if let Some(top) = names.last() {
+                functions.entry(top).or_insert_with(|| profile(top)).own.add(*count);
+            }
+            // A recursive function's time is only its own once.
+            names.sort_unstable();
+            names.dedup();
+            for name in names {
+                functions.entry(name).or_insert_with(|| profile(name)).total.add(*count);
+            }
+        }
+        let mut functions: Vec<FunctionProfile> = functions.into_values()
+            .filter(|function| function.total.instructions > 0)
+            .collect();
+        functions.sort_by_key(|function| Reverse(function.total.cycles));
+        functions
+    }
+
+    /// The loops executed, most cycles first.
+    pub fn loops(&self) -> Vec<LoopProfile> {
+        let mut loops: Vec<LoopProfile> = self.loops.iter().map(|(&(head, tail), &iterations)| {
+            let mut count = Count::default();
+            for (addr, (addr_count, _)) in &self.addresses {
+                if (head..=tail).contains(addr) {
+                    count.add(*addr_count);
+                }
+            }
+            LoopProfile { head, tail, iterations, count }
+        }).collect();
+        loops.sort_by(|a, b| b.count.cycles.cmp(&a.count.cycles).then(a.head.cmp(&b.head)));
+        loops
+    }
+
+    /// A table of the lines, functions and hottest loops.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let total = self.total();
+        writeln!(report, "{} instructions, {} cycles", total.instructions, total.cycles).unwrap();
+        writeln!(report, "\n{:>12} {:>12}  line", "instructions", "cycles").unwrap();
+        for line in self.lines() {
+            writeln!(report, "{:>12} {:>12}  {}", line.count.instructions, line.count.cycles, line.location).unwrap();
+        }
+        writeln!(report, "\n{:>12} {:>12} {:>12}  function", "instructions", "cycles", "total cycles").unwrap();
+        for function in self.functions() {
+            writeln!(report, "{:>12} {:>12} {:>12}  {}", function.own.instructions, function.own.cycles,
+                function.total.cycles, function.function).unwrap();
+        }
+        writeln!(report, "\n{:>12} {:>12}  hottest loops", "iterations", "cycles").unwrap();
+        for found in self.loops().iter().take(REPORTED_LOOPS) {
+            writeln!(report, "{:>12} {:>12}  {}", found.iterations, found.count.cycles, self.describe_loop(found)).unwrap();
+        }
+        report
+    }
+
+    fn describe_loop(&self, found: &LoopProfile) -> String {
+        let location = |addr| self.addresses.get(&addr).and_then(|(_, location)| location.as_ref());
+        match (location(found.head), location(found.tail)) {
+            (Some(head), Some(tail)) if head.file == tail.file => {
+                format!("{}:{}-{}: {}", head.file, head.line_num + 1, tail.line_num + 1, head.line_text.trim())
+            }
+            _ => format!("0x{:08x}-0x{:08x}", found.head, found.tail)
+        }
+    }
+
+    /// Cycles per call stack in the folded format flamegraph tools read:
+    /// one `caller;callee cycles` line per stack.
+    pub fn folded(&self) -> String {
+        let mut folded = String::new();
+        let mut stacks: Vec<&(String, Count)> = self.stacks.iter().filter(|(_, count)| count.cycles > 0).collect();
+        stacks.sort_by(|a, b| a.0.cmp(&b.0));
+        for (stack, count) in stacks {
+            writeln!(folded, "{} {}", stack, count.cycles).unwrap();
+        }
+        folded
+    }
+
+    fn stack_id(&self) -> usize {
+        self.frames.last().map_or(0, |(_, id)| *id)
+    }
+}
+
+fn profile(function: &str) -> FunctionProfile {
+    FunctionProfile { function: function.to_string(), own: Count::default(), total: Count::default() }
+}
+
+impl Observer for Profiler {
+    fn instruction_retired(&mut self, addr: u32, location: Option<&MIPSLocation>) {
+        let count = Count { instructions: 1, cycles: self.model.instruction + self.extra_cycles };
+        self.extra_cycles = 0;
+        self.stacks[self.executing].1.add(count);
+        let (addr_count, _) = self.addresses.entry(addr).or_insert_with(|| (Count::default(), location.cloned()));
+        addr_count.add(count);
+
+        if let Some(last) = self.last_addr {
+            // Returning from the exception handler isn't a loop either.
+            let same_mode = (addr < KTEXT_BASE) == (last < KTEXT_BASE);
+            if addr <= last && !self.transferred && same_mode {
+                *self.loops.entry((addr, last)).or_insert(0) += 1;
+            }
+        }
+        self.last_addr = Some(addr);
+        self.executing = self.stack_id();
+        self.transferred = self.transferring;
+        self.transferring = false;
+    }
+    fn hi_lo_written(&mut self, _old_val: (u32, u32), _new_val: (u32, u32)) {
+        self.extra_cycles += self.model.multiply_divide;
+    }
+    fn memory_read(&mut self, _addr: usize, _size: usize, _val: u32) {
+        self.extra_cycles += self.model.memory_access;
+    }
+    fn memory_written(&mut self, _addr: usize, _size: usize, _val: u32) {
+        self.extra_cycles += self.model.memory_access;
+    }
+    fn branch(&mut self, _addr: u32, _target: u32, taken: bool) {
+        if taken {
+            self.extra_cycles += self.model.taken_branch;
+        }
+    }
+    fn called(&mut self, frame: &CallFrame) {
+        let name = match &frame.function {
+            Some(function) => function.clone(),
+            None => format!("0x{:08x}", frame.target),
+        };
+        let stack = match self.frames.last() {
+            Some((_, id)) => format!("{};{}", self.stacks[*id].0, name),
+            None => name.clone(),
+        };
+        let id = match self.stack_ids.get(&stack) {
+            Some(id) => *id,
+            None => {
+                self.stacks.push((stack.clone(), Count::default()));
+                self.stack_ids.insert(stack, self.stacks.len() - 1);
+                self.stacks.len() - 1
+            }
+        };
+        self.frames.push((name, id));
+        self.transferring = true;
+    }
+    fn returned(&mut self, frames: &[CallFrame]) {
+        let depth = self.frames.len().saturating_sub(frames.len());
+        self.frames.truncate(depth);
+        self.transferring = true;
+    }
+    fn exception(&mut self, _code: ExceptionCode, _epc: u32) {
+        self.transferring = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+    use super::super::runtime::Runtime;
+
+    #[test]
+    fn test_profile() {
+        let source = "
+        main:   li $a0, 3
+                jal sum
+                li $v0, 10
+                syscall
+        sum:    li $v0, 0
+        loop:   add $v0, $v0, $a0
+                addi $a0, $a0, -1
+                bne $a0, $zero, loop
+                sw $v0, 0($sp)
+                jr $ra
+        ";
+        let (components, _) = read_str_to_state(source, "test.s");
+        let mut runtime = Runtime::new();
+        runtime.load_program(assemble(&components).unwrap()).unwrap();
+        let profiler = Rc::new(RefCell::new(Profiler::new(CostModel::default())));
+        runtime.add_observer(Box::new(profiler.clone()));
+        runtime.run().unwrap();
+        let profiler = profiler.borrow();
+
+        // The startup code's jal, then 4 in main, 1 + 3 * 3 + 2 in sum.
+        assert_eq!(profiler.total().instructions, 17);
+
+        let lines = profiler.lines();
+        let add = lines.iter().find(|line| line.location.line_num == 6).unwrap();
+        assert_eq!(add.count, Count { instructions: 3, cycles: 3 });
+        let bne = lines.iter().find(|line| line.location.line_num == 8).unwrap();
+        // Taken twice.
+        assert_eq!(bne.count, Count { instructions: 3, cycles: 5 });
+
+        let functions = profiler.functions();
+        assert_eq!(functions.iter().map(|f| f.function.as_str()).collect::<Vec<_>>(), vec!["main", "sum", STARTUP]);
+        assert_eq!(functions[0].own.instructions, 4);
+        assert_eq!(functions[0].total.instructions, 16);
+        assert_eq!(functions[1].own, Count { instructions: 12, cycles: 15 });
+
+        let loops = profiler.loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!((loops[0].iterations, loops[0].count.instructions), (2, 9));
+        assert_eq!(profiler.describe_loop(&loops[0]), "test.s:7-9: loop:   add $v0, $v0, $a0");
+
+        assert_eq!(profiler.folded(), "(startup) 1\nmain 4\nmain;sum 15\n");
+    }
+}
@@ -0,0 +1,269 @@
+use std::fmt::Write;
+
+use super::debugger::{Debugger, Stop};
+use super::runtime::{RegisterCodes, Runtime};
+
+/// What a command asks of the prompt.
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    /// Text to print, which is empty or ends in a newline.
+    Output(String),
+    Quit,
+}
+
+pub const HELP: &str = "\
+break <where>              stop before running the instruction at <where>
+delete <where>             remove the breakpoint at <where>
+watch <where> [bytes]      stop when the bytes at <where> change (default 4)
+unwatch <where>            remove the watchpoint at <where>
+step [n], s                run n instructions (default 1)
+continue, c                run until a breakpoint, watchpoint or the exit
+reverse-step [n], rs       undo n instructions (default 1)
+reverse-continue, rc       run backwards until a breakpoint or watchpoint
+registers, regs            show the registers
+x <where> [words]          show memory as words (default 1)
+x/i <where> [n]            disassemble n instructions (default 1)
+backtrace, bt              show the call stack
+quit, q                    stop debugging
+<where> is a label, a 0x address, a line number or file:line.
+";
+
+/// Runs one line typed at `micah --debug`'s prompt.
+pub fn execute(debugger: &mut Debugger, line: &str) -> Reply {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (command, args) = match words.split_first() {
+        Some((command, args)) => (*command, args),
+        None => return Reply::Output(String::new())
+    };
+    let output = match command {
+        "break" | "b" => with_address(debugger, args, |debugger, addr| {
+            debugger.add_breakpoint(addr);
+            format!("breakpoint at {}\n", describe(debugger.runtime_mut(), addr))
+        }),
+        "delete" | "d" => with_address(debugger, args, |debugger, addr| {
+            if debugger.remove_breakpoint(addr) {
+                format!("deleted the breakpoint at 0x{:08x}\n", addr)
+            } else {
+                format!("no breakpoint at 0x{:08x}\n", addr)
+            }
+        }),
+        "watch" | "w" => match count(args.get(1), 4) {
+            Some(len) => with_address(debugger, args, |debugger, addr| {
+                debugger.add_watchpoint(addr, len as u32);
+                format!("watching {} bytes at 0x{:08x}\n", len, addr)
+            }),
+            None => "watch needs a number of bytes\n".to_string()
+        },
+        "unwatch" => with_address(debugger, args, |debugger, addr| {
+            if debugger.remove_watchpoint(addr) {
+                format!("deleted the watchpoint at 0x{:08x}\n", addr)
+            } else {
+                format!("no watchpoint at 0x{:08x}\n", addr)
+            }
+        }),
+        "step" | "s" => repeat(debugger, args, Debugger::step),
+        "reverse-step" | "rs" => repeat(debugger, args, Debugger::reverse_step),
+        "continue" | "c" => {
+            let stop = debugger.resume();
+            report(debugger, stop)
+        }
+        "reverse-continue" | "rc" => {
+            let stop = debugger.reverse_resume();
+            report(debugger, stop)
+        }
+        "registers" | "regs" => registers(debugger.runtime()),
+        "x" => match count(args.get(1), 1) {
+            Some(words) => with_address(debugger, args, |debugger, addr| memory(debugger.runtime(), addr, words)),
+            None => "x needs a number of words\n".to_string()
+        },
+        "x/i" => match count(args.get(1), 1) {
+            Some(n) => with_address(debugger, args, |debugger, addr| instructions(debugger.runtime_mut(), addr, n)),
+            None => "x/i needs a number of instructions\n".to_string()
+        },
+        "backtrace" | "bt" => debugger.runtime().backtrace(),
+        "help" | "h" => HELP.to_string(),
+        "quit" | "q" => return Reply::Quit,
+        _ => format!("unknown command {}; try help\n", command)
+    };
+    Reply::Output(output)
+}
+
+/// Parses `<where>`, the first argument, and runs `command` at it.
+fn with_address(debugger: &mut Debugger, args: &[&str], command: impl FnOnce(&mut Debugger, u32) -> String) -> String {
+    match args.first() {
+        Some(text) => match parse_address(debugger.runtime(), text) {
+            Some(addr) => command(debugger, addr),
+            None => format!("no such place as {}\n", text)
+        },
+        None => "that command needs a label, address or line\n".to_string()
+    }
+}
+
+/// A label, a `0x` address, a line number, or a line in a file.
+pub fn parse_address(runtime: &Runtime, text: &str) -> Option<u32> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        return u32::from_str_radix(hex, 16).ok()
+    }
+    let program = runtime.program()?;
+    if let Ok(line) = text.parse() {
+        return program.address_of_line(None, line)
+    }
+    if let Some((file, line)) = text.rsplit_once(':') {
+        if let Ok(line) = line.parse() {
+            return program.address_of_line(Some(file), line)
+        }
+    }
+    program.symbols.get(text).cloned()
+}
+
+fn count(arg: Option<&&str>, default: usize) -> Option<usize> {
+    match arg {
+        Some(arg) => arg.parse().ok(),
+        None => Some(default)
+    }
+}
+
+/// Steps `n` times in one direction, stopping early for anything but a
+/// plain step.
+fn repeat(debugger: &mut Debugger, args: &[&str], step: fn(&mut Debugger) -> Stop) -> String {
+    let n = match count(args.first(), 1) {
+        Some(n) => n,
+        None => return "step needs a number of instructions\n".to_string()
+    };
+    let mut stop = Stop::Stepped;
+    for _ in 0..n {
+        stop = step(debugger);
+        if !matches!(stop, Stop::Stepped) {
+            break
+        }
+    }
+    report(debugger, stop)
+}
+
+/// Says why the program stopped and where it is now.
+fn report(debugger: &mut Debugger, stop: Stop) -> String {
+    let reason = match stop {
+        Stop::Stepped => String::new(),
+        Stop::Breakpoint(_) => "breakpoint\n".to_string(),
+        Stop::Watchpoint(addr) => format!("watchpoint at 0x{:08x} changed\n", addr),
+        Stop::Exited(code) => return format!("the program exited with code {}\n", code),
+        Stop::Error(error) => format!("error: {}\n", error),
+        Stop::HistoryStart => "reached the start of the undo log\n".to_string(),
+        Stop::Paused => "paused\n".to_string(),
+    };
+    let pc = debugger.runtime().pc();
+    format!("{}at {}\n", reason, describe(debugger.runtime_mut(), pc))
+}
+
+/// An address, with the source or disassembly of the instruction there.
+fn describe(runtime: &mut Runtime, addr: u32) -> String {
+    match runtime.program().and_then(|program| program.location_at(addr)) {
+        Some(location) => format!("0x{:08x} {}", addr, location),
+        None => match runtime.disassemble(addr, 1).first() {
+            Some(word) => format!("0x{:08x} {}", addr, word.text),
+            None => format!("0x{:08x}", addr)
+        }
+    }
+}
+
+fn registers(runtime: &Runtime) -> String {
+    let mut output = String::new();
+    for number in 0..32 {
+        let reg = RegisterCodes::from_number(number);
+        let val = runtime.get_register(&reg).unwrap_or(0);
+        write!(output, "{:>5} 0x{:08x}", reg.to_string(), val).unwrap();
+        output.push(if number % 4 == 3 { '\n' } else { ' ' });
+    }
+    writeln!(output, "{:>5} 0x{:08x} {:>5} 0x{:08x} {:>5} 0x{:08x}", "pc", runtime.pc(), "hi", runtime.hi(), "lo", runtime.lo()).unwrap();
+    output
+}
+
+/// `words` words from `addr`, four to a line. Bytes that can't be read
+/// without side effects show as `??`.
+fn memory(runtime: &Runtime, addr: u32, words: usize) -> String {
+    let endian = runtime.endian();
+    let mut output = String::new();
+    for i in 0..words {
+        let word_addr = addr.wrapping_add(4 * i as u32);
+        if i % 4 == 0 {
+            write!(output, "0x{:08x}:", word_addr).unwrap();
+        }
+        let bytes: Option<Vec<u8>> = (0..4).map(|byte| runtime.peek_byte(word_addr.wrapping_add(byte) as usize)).collect();
+        match bytes {
+            Some(bytes) => write!(output, " 0x{:08x}", endian.word_from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).unwrap(),
+            None => output.push_str(" ??????????"),
+        }
+        if i % 4 == 3 || i + 1 == words {
+            output.push('\n');
+        }
+    }
+    output
+}
+
+/// `n` instructions from `addr`, each under any label that names it.
+fn instructions(runtime: &mut Runtime, addr: u32, n: usize) -> String {
+    let words = runtime.disassemble(addr, n);
+    if words.is_empty() {
+        return format!("nothing to disassemble at 0x{:08x}\n", addr)
+    }
+    let mut output = String::new();
+    for word in words {
+        if let Some(label) = &word.label {
+            writeln!(output, "{}:", label).unwrap();
+        }
+        writeln!(output, "  {}", word).unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+
+    #[test]
+    fn test_commands(){
+        let source = "
+                .data
+        total:  .word 0
+                .text
+        main:   li $t0, 2
+        loop:   lw $t1, total
+                add $t1, $t1, $t0
+                sw $t1, total
+                addi $t0, $t0, -1
+                bgtz $t0, loop
+                jr $ra
+        ";
+        let (components, _) = read_str_to_state(source, "sum.s");
+        let mut runtime = Runtime::new();
+        runtime.load_program(assemble(&components).unwrap()).unwrap();
+        let mut debugger = Debugger::new(runtime);
+        let mut run = |line: &str| match execute(&mut debugger, line) {
+            Reply::Output(output) => output,
+            Reply::Quit => "quit".to_string(),
+        };
+
+        assert_eq!(run("break loop"), "breakpoint at 0x00400004 sum.s:6: loop:   lw $t1, total\n");
+        assert_eq!(run("c"), "breakpoint\nat 0x00400004 sum.s:6: loop:   lw $t1, total\n");
+        assert!(run("regs").contains("  $t0 0x00000002   $t1 0x00000000"));
+        assert_eq!(run("watch total"), "watching 4 bytes at 0x10010000\n");
+        assert_eq!(run("delete sum.s:6"), "deleted the breakpoint at 0x00400004\n");
+        assert_eq!(run("c"), "watchpoint at 0x10010000 changed\nat 0x00400018 sum.s:9: addi $t0, $t0, -1\n");
+        assert_eq!(run("c"), "watchpoint at 0x10010000 changed\nat 0x00400018 sum.s:9: addi $t0, $t0, -1\n");
+        assert_eq!(run("x/i sum.s:5 2"), "main:\n  0x00400000  24080002  addiu $t0, $zero, 2\nloop:\n  0x00400004  3c011001  lui $at, 0x1001\n");
+        assert_eq!(run("x/i 0x20000000"), "nothing to disassemble at 0x20000000\n");
+        assert_eq!(run("x total 2"), "0x10010000: 0x00000003 0x66666666\n");
+        // Back to the store that wrote 3, and from there to before the add.
+        assert_eq!(run("rc"), "watchpoint at 0x10010000 changed\nat 0x00400014 sum.s:8: sw $t1, total\n");
+        assert_eq!(run("x 0x10010000"), "0x10010000: 0x00000002\n");
+        assert_eq!(run("rs 2"), "at 0x0040000c sum.s:7: add $t1, $t1, $t0\n");
+        assert_eq!(run("unwatch total"), "deleted the watchpoint at 0x10010000\n");
+        assert_eq!(run("c"), "the program exited with code 0\n");
+        assert_eq!(run("break nowhere"), "no such place as nowhere\n");
+        assert_eq!(run("jump"), "unknown command jump; try help\n");
+        assert_eq!(run(""), "");
+        assert_eq!(run("q"), "quit");
+    }
+}
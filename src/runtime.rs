@@ -1,3 +1,21 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::iter;
+
+use super::assembler::{Program, DATA_BASE, GLOBAL_POINTER, KDATA_BASE, KTEXT_BASE, STACK_TOP, TEXT_BASE};
+use super::call_stack::{CallFrame, CallStack, ConventionViolation, CALLEE_SAVED};
+use super::code::{FloatFormat, Instruction};
+use super::console::{format_double, format_float, Console, ConsoleError};
+use super::disassembler;
+use super::disassembler::DisassembledWord;
+use super::elf::{Elf, PF_X};
+use super::memory;
+use super::device::Device;
+use super::memory::MemoryRep;
+use super::mips_parser::MIPSLocation;
+use super::observer::Observer;
+use super::watchdog;
+
 #[derive(PartialEq, Debug)]
 pub enum MemoryError {
     WriteToZero,
@@ -5,7 +23,7 @@ pub enum MemoryError {
 }
 
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum RegisterCodes {
     Rzero,
     Rat,
@@ -21,7 +39,44 @@ pub enum RegisterCodes {
 }
 
 use RegisterCodes::*;
-const RegisterCodeID: [RegisterCodes; 32] = [
+
+impl RegisterCodes {
+    /// The register's number, as used in machine code.
+    pub fn number(&self) -> u32 {
+        *self as u32
+    }
+
+    /// The register with the given number, ignoring all but the low 5 bits.
+    pub fn from_number(number: u32) -> RegisterCodes {
+        REGISTER_CODE_ID[(number & 31) as usize]
+    }
+
+    /// The register's conventional name, without the `$`.
+    pub fn name(&self) -> &'static str {
+        REGISTER_NAMES[*self as usize]
+    }
+}
+
+impl fmt::Display for RegisterCodes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}", self.name())
+    }
+}
+
+const REGISTER_NAMES: [&str; 32] = [
+    "zero",
+    "at",
+    "v0", "v1",
+    "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3",
+    "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3",
+    "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1",
+    "gp", "sp", "fp", "ra"
+];
+
+const REGISTER_CODE_ID: [RegisterCodes; 32] = [
     Rzero,
     Rat,
     Rv0, Rv1,
@@ -35,14 +90,60 @@ const RegisterCodeID: [RegisterCodes; 32] = [
 
 ];
 
+/// One of the floating-point coprocessor's 32 registers, `$f0`-`$f31`.
+/// A double occupies an even register and the odd one after it.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct FloatRegister(pub u8);
+
+impl FloatRegister {
+    /// The register with the given number, ignoring all but the low 5 bits.
+    pub fn from_number(number: u32) -> FloatRegister {
+        FloatRegister((number & 31) as u8)
+    }
+
+    pub fn number(&self) -> u32 {
+        self.0 as u32
+    }
+
+    /// Parses a register name such as `f12`, without the `$`.
+    pub fn from_name(name: &str) -> Option<FloatRegister> {
+        let number: u8 = name.strip_prefix('f').or_else(|| name.strip_prefix('F'))?.parse().ok()?;
+        if number < 32 { Some(FloatRegister(number)) } else { None }
+    }
+}
+
+impl fmt::Display for FloatRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "$f{}", self.0)
+    }
+}
+
+/// Registers that hold a meaningful value before the program writes to them.
+const INITIALLY_DEFINED: [RegisterCodes; 5] = [Rzero, Rk0, Rk1, Rgp, Rsp];
+
+/// Registers the ABI lets a callee overwrite, so they are undefined after `jal`.
+/// The argument registers are left alone, since the callee reads them.
+const CALL_CLOBBERED: [RegisterCodes; 13] = [
+    Rat, Rv0, Rv1,
+    Rt0, Rt1, Rt2, Rt3, Rt4, Rt5, Rt6, Rt7, Rt8, Rt9
+];
+
+fn register_mask(regs: &[RegisterCodes]) -> u32 {
+    regs.iter().fold(0, |mask, reg| mask | 1 << Registers::register_to_index(reg))
+}
+
+/// The 32 general purpose registers.
 pub struct Registers {
-    registers: [u32; 32]
+    registers: [u32; 32],
+    /// Bit `i` is set once register `i` holds a value the program put there.
+    defined: u32,
 }
 
 impl Registers {
     pub fn new() -> Registers {
         Registers {
             registers: [0; 32],
+            defined: register_mask(&INITIALLY_DEFINED),
         }
     }
     pub fn code_to_register(code: &str) -> Option<RegisterCodes> {
@@ -63,12 +164,16 @@ impl Registers {
             "s4" => Some(Rs4), "s5" => Some(Rs5), "s6" => Some(Rs6), "s7" => Some(Rs7),
 
             "t8" => Some(Rt8), "t9" => Some(Rt9), "k0" => Some(Rk0), "k1" => Some(Rk1),
-            _ => None
+
+            "gp" => Some(Rgp), "sp" => Some(Rsp), "fp" => Some(Rfp), "s8" => Some(Rfp),
+            "ra" => Some(Rra),
+            _ => code.parse::<usize>().ok().and_then(|index| REGISTER_CODE_ID.get(index).cloned())
         }
         
     }
     fn register_to_index(reg: &RegisterCodes) -> usize {
-        RegisterCodeID.iter().position(|s| *s == *reg).expect("Provided register not in RegisterCodeID")
+        // RegisterCodes is declared in the same order as REGISTER_CODE_ID.
+        *reg as usize
     }
 
     pub fn get_register(&self, reg: &RegisterCodes) -> Result<u32, MemoryError>{
@@ -79,34 +184,2167 @@ impl Registers {
     }
     
     pub fn set_register(&mut self, reg: &RegisterCodes, val: u32) -> Result<(), MemoryError> {
-        if [Rk0, Rk1, Rzero].contains(&reg) {
+        if [Rk0, Rk1].contains(reg) {
             return Err(MemoryError::WriteNotIntended)
         }
-        self.registers[Registers::register_to_index(reg)] = val;
+        self.set_kernel_register(reg, val)
+    }
+
+    /// Sets a register as an exception handler may, including `$k0` and `$k1`.
+    fn set_kernel_register(&mut self, reg: &RegisterCodes, val: u32) -> Result<(), MemoryError> {
+        if *reg == Rzero {
+            return Err(MemoryError::WriteNotIntended)
+        }
+        let index = Registers::register_to_index(reg);
+        self.registers[index] = val;
+        self.defined |= 1 << index;
         Ok(())
     }
+
+    pub fn is_defined(&self, reg: &RegisterCodes) -> bool {
+        self.defined & 1 << Registers::register_to_index(reg) != 0
+    }
+
+    /// Marks the registers a callee may clobber as undefined.
+    fn clobber_for_call(&mut self) {
+        self.defined &= !register_mask(&CALL_CLOBBERED);
+    }
+}
+
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers::new()
+    }
+}
+
+/// A source operand read from a register the program never wrote.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UndefinedRegisterRead {
+    pub register: RegisterCodes,
+    pub location: Option<MIPSLocation>,
+}
+
+impl fmt::Display for UndefinedRegisterRead {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "read of undefined register {}", self.register)?;
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+/// Coprocessor 0 registers, by number.
+pub const BAD_VADDR: u8 = 8;
+pub const STATUS: u8 = 12;
+pub const CAUSE: u8 = 13;
+pub const EPC: u8 = 14;
+
+/// Status bits: interrupts enabled, and the exception level, which is set
+/// while a handler runs.
+pub const STATUS_IE: u32 = 1 << 0;
+pub const STATUS_EXL: u32 = 1 << 1;
+/// Status at startup, as in SPIM: coprocessors usable, every interrupt
+/// unmasked but interrupts disabled, user mode.
+const INITIAL_STATUS: u32 = 0x3000_ff10;
+/// The interrupt bits of Cause, and the mask bits of Status that match them.
+pub const INTERRUPT_MASK: u32 = 0xff00;
+/// The interrupt bits of Cause driven by devices; the low two are software's.
+const HARDWARE_INTERRUPTS: u32 = 0xfc00;
+
+/// The `ExcCode` field of Cause, with SPIM's numbering.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExceptionCode {
+    Interrupt = 0,
+    /// An address error on a load or instruction fetch.
+    AddressLoad = 4,
+    AddressStore = 5,
+    BadInstructionAddress = 6,
+    BadDataAddress = 7,
+    Syscall = 8,
+    Breakpoint = 9,
+    ReservedInstruction = 10,
+    Overflow = 12,
+}
+
+/// Number of writes the undo log keeps by default before forgetting the oldest steps.
+pub const DEFAULT_UNDO_CAPACITY: usize = 1 << 16;
+
+#[derive(PartialEq, Debug, Clone)]
+enum UndoEntry {
+    Register(RegisterCodes, u32),
+    Defined(u32),
+    HiLo(u32, u32),
+    FloatRegister(FloatRegister, u32),
+    FloatConditions(u8),
+    Coprocessor0(u8, u32),
+    /// A byte's old value, or `None` if it had never been written.
+    Memory(usize, Option<u8>),
+    Call,
+    Return(Vec<CallFrame>),
+}
+
+/// A bounded log of the values overwritten by each step, newest last.
+///
+/// `capacity` counts individual register and byte writes. Once it is exceeded,
+/// whole steps are dropped from the front; the newest step is always kept, so
+/// the log can exceed its capacity by at most one step.
+pub struct UndoLog {
+    entries: VecDeque<UndoEntry>,
+    steps: VecDeque<UndoStep>,
+    capacity: usize,
+}
+
+/// Where a step started, if known, and how many entries it recorded.
+struct UndoStep {
+    /// The pc and the number of instructions executed before the step.
+    start: Option<(u32, u64)>,
+    num_entries: usize,
+}
+
+impl UndoLog {
+    pub fn new(capacity: usize) -> UndoLog {
+        UndoLog {
+            entries: VecDeque::new(),
+            steps: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn begin_step(&mut self, pc: u32, instruction_count: u64) {
+        if self.capacity > 0 {
+            self.steps.push_back(UndoStep { start: Some((pc, instruction_count)), num_entries: 0 });
+        }
+    }
+
+    fn is_recording(&self) -> bool {
+        self.capacity > 0
+    }
+
+    fn record(&mut self, entry: UndoEntry) {
+        if self.capacity == 0 {
+            return
+        }
+        match self.steps.back_mut() {
+            Some(step) => step.num_entries += 1,
+            None => self.steps.push_back(UndoStep { start: None, num_entries: 1 }),
+        }
+        self.entries.push_back(entry);
+        while self.entries.len() > self.capacity && self.steps.len() > 1 {
+            if let Some(step) = self.steps.pop_front() {
+                self.entries.drain(..step.num_entries);
+            }
+        }
+    }
+
+    fn pop_step(&mut self) -> Option<(UndoStep, VecDeque<UndoEntry>)> {
+        let step = self.steps.pop_back()?;
+        let entries = self.entries.split_off(self.entries.len() - step.num_entries);
+        Some((step, entries))
+    }
+
+    /// The number of steps that can currently be undone.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+/// What the runtime should do after a syscall.
+#[derive(Debug, PartialEq)]
+pub enum SyscallResult {
+    Continue,
+    Exit(i32),
+}
+
+#[derive(Debug)]
+pub enum SyscallError {
+    Console(ConsoleError),
+    Register(MemoryError),
+    Memory(memory::MemoryError),
+    UnknownSyscall(u32),
+}
+
+impl From<ConsoleError> for SyscallError {
+    fn from(error: ConsoleError) -> SyscallError {
+        SyscallError::Console(error)
+    }
+}
+
+impl From<MemoryError> for SyscallError {
+    fn from(error: MemoryError) -> SyscallError {
+        SyscallError::Register(error)
+    }
+}
+
+impl From<memory::MemoryError> for SyscallError {
+    fn from(error: memory::MemoryError) -> SyscallError {
+        SyscallError::Memory(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    Register(MemoryError),
+    Memory(memory::MemoryError),
+    Syscall(SyscallError),
+    /// The pc does not point at an instruction.
+    NoInstruction(u32),
+    /// The word at the pc (the first value) is not an instruction micah can run.
+    InvalidInstruction(u32, u32),
+    ArithmeticOverflow,
+    UnalignedAccess(u32),
+    /// A `break` instruction, with its code.
+    Break(u32),
+    /// A watchdog stopped the program before it finished.
+    Stopped(watchdog::Stop),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::Register(error) => write!(f, "register error: {:?}", error),
+            RuntimeError::Memory(error) => write!(f, "memory error: {:?}", error),
+            RuntimeError::Syscall(SyscallError::UnknownSyscall(code)) => write!(f, "unknown syscall {}", code),
+            RuntimeError::Syscall(SyscallError::Console(ConsoleError::EndOfInput)) => {
+                write!(f, "tried to read past the end of input")
+            }
+            RuntimeError::Syscall(SyscallError::Console(ConsoleError::InputDisabled)) => {
+                write!(f, "syscalls can't read input while the console is memory-mapped")
+            }
+            RuntimeError::Syscall(error) => write!(f, "syscall error: {:?}", error),
+            RuntimeError::NoInstruction(pc) => write!(f, "no instruction at 0x{:08x}", pc),
+            RuntimeError::InvalidInstruction(pc, word) => {
+                write!(f, "invalid instruction 0x{:08x} at 0x{:08x}", word, pc)
+            }
+            RuntimeError::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+            RuntimeError::UnalignedAccess(addr) => write!(f, "unaligned memory access at 0x{:08x}", addr),
+            RuntimeError::Break(code) => write!(f, "break {}", code),
+            RuntimeError::Stopped(stop) => write!(f, "{}", stop),
+        }
+    }
+}
+
+impl From<MemoryError> for RuntimeError {
+    fn from(error: MemoryError) -> RuntimeError {
+        RuntimeError::Register(error)
+    }
+}
+
+impl From<memory::MemoryError> for RuntimeError {
+    fn from(error: memory::MemoryError) -> RuntimeError {
+        RuntimeError::Memory(error)
+    }
+}
+
+impl From<watchdog::Stop> for RuntimeError {
+    fn from(stop: watchdog::Stop) -> RuntimeError {
+        RuntimeError::Stopped(stop)
+    }
+}
+
+impl From<SyscallError> for RuntimeError {
+    fn from(error: SyscallError) -> RuntimeError {
+        RuntimeError::Syscall(error)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StepResult {
+    Running,
+    Exited(i32),
 }
 
+/// The instructions of a text segment starting at `base`, decoded ahead of
+/// time so most steps needn't fetch and decode. A store into the segment
+/// empties the slots it overwrites, and they are decoded from memory again
+/// when next fetched.
+struct DecodedText {
+    base: u32,
+    instructions: Vec<Option<Instruction>>,
+}
+
+impl DecodedText {
+    fn index(&self, addr: u32) -> Option<usize> {
+        let index = (addr.checked_sub(self.base)? / 4) as usize;
+        if index < self.instructions.len() { Some(index) } else { None }
+    }
+}
+
+/// Everything a running program can observe or change.
 pub struct Runtime {
-    registers: Registers
+    registers: Registers,
+    pc: u32,
+    hi: u32,
+    lo: u32,
+    float_registers: [u32; 32],
+    /// The FPU's eight condition flags, flag `i` in bit `i`.
+    float_conditions: u8,
+    coprocessor0: [u32; 32],
+    memory: MemoryRep,
+    program: Option<Program>,
+    decoded: Vec<DecodedText>,
+    instruction_count: u64,
+    undo_log: UndoLog,
+    call_stack: CallStack,
+    check_conventions: bool,
+    check_undefined_reads: bool,
+    location: Option<MIPSLocation>,
+    register_writers: Vec<Option<MIPSLocation>>,
+    convention_violations: Vec<ConventionViolation>,
+    undefined_reads: Vec<UndefinedRegisterRead>,
+    console: Console,
+    observers: Vec<Box<dyn Observer>>,
 }
 
+impl Runtime {
+    pub fn new() -> Runtime {
+        Runtime::with_undo_capacity(DEFAULT_UNDO_CAPACITY)
+    }
+
+    /// Creates a runtime whose undo log remembers at most `capacity` writes.
+    /// A capacity of zero disables the undo log.
+    pub fn with_undo_capacity(capacity: usize) -> Runtime {
+        Runtime {
+            registers: Registers::new(),
+            pc: 0,
+            hi: 0,
+            lo: 0,
+            float_registers: [0; 32],
+            float_conditions: 0,
+            coprocessor0: initial_coprocessor0(),
+            memory: MemoryRep::new(),
+            program: None,
+            decoded: Vec::new(),
+            instruction_count: 0,
+            undo_log: UndoLog::new(capacity),
+            call_stack: CallStack::new(),
+            check_conventions: false,
+            check_undefined_reads: false,
+            location: None,
+            register_writers: vec![None; 32],
+            convention_violations: Vec::new(),
+            undefined_reads: Vec::new(),
+            console: Console::new(),
+            observers: Vec::new(),
+        }
+    }
+
+    pub fn undo_log(&self) -> &UndoLog {
+        &self.undo_log
+    }
+
+    /// Marks the start of a new instruction; every write until the next call
+    /// is undone together by `step_back`.
+    pub fn begin_step(&mut self) {
+        self.undo_log.begin_step(self.pc, self.instruction_count);
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    /// Enables checking that `$s0`-`$s7` and `$sp` survive every call, and
+    /// that each function returns through the `$ra` it was called with.
+    pub fn set_convention_checks(&mut self, enabled: bool) {
+        self.check_conventions = enabled;
+    }
+
+    /// Enables warnings for source operands read through `read_source_register`
+    /// that were never written, or were clobbered by a call.
+    pub fn set_undefined_read_checks(&mut self, enabled: bool) {
+        self.check_undefined_reads = enabled;
+    }
+
+    /// Sets the source location of the instruction being run, which the
+    /// checkers use to report where a problem came from.
+    pub fn set_location(&mut self, location: &MIPSLocation) {
+        if self.check_conventions || self.check_undefined_reads {
+            self.location = Some(location.clone());
+        }
+    }
+
+    pub fn get_register(&self, reg: &RegisterCodes) -> Result<u32, MemoryError> {
+        self.registers.get_register(reg)
+    }
+
+    /// Reads a register as an instruction's source operand. An undefined
+    /// read is recorded once for each register and location.
+    pub fn read_source_register(&mut self, reg: &RegisterCodes) -> Result<u32, MemoryError> {
+        if self.check_undefined_reads && !self.registers.is_defined(reg) {
+            let read = UndefinedRegisterRead {
+                register: *reg,
+                location: self.location.clone(),
+            };
+            if !self.undefined_reads.contains(&read) {
+                self.undefined_reads.push(read);
+            }
+        }
+        self.registers.get_register(reg)
+    }
+
+    pub fn undefined_reads(&self) -> &[UndefinedRegisterRead] {
+        &self.undefined_reads
+    }
+
+    pub fn set_register(&mut self, reg: &RegisterCodes, val: u32) -> Result<(), MemoryError> {
+        self.write_register(reg, val, false)
+    }
+
+    fn write_register(&mut self, reg: &RegisterCodes, val: u32, kernel: bool) -> Result<(), MemoryError> {
+        let old_val = self.registers.get_register(reg)?;
+        let old_defined = self.registers.defined;
+        if kernel {
+            self.registers.set_kernel_register(reg, val)?;
+        } else {
+            self.registers.set_register(reg, val)?;
+        }
+        self.undo_log.record(UndoEntry::Register(*reg, old_val));
+        for observer in &mut self.observers {
+            observer.register_written(*reg, old_val, val);
+        }
+        if self.registers.defined != old_defined {
+            self.undo_log.record(UndoEntry::Defined(old_defined));
+        }
+        if self.check_conventions {
+            self.register_writers[Registers::register_to_index(reg)] = self.location.clone();
+        }
+        Ok(())
+    }
+
+    /// The raw bits of a floating-point register.
+    pub fn get_float_register(&self, reg: &FloatRegister) -> u32 {
+        self.float_registers[reg.0 as usize]
+    }
+
+    pub fn set_float_register(&mut self, reg: &FloatRegister, val: u32) {
+        let old_val = self.float_registers[reg.0 as usize];
+        self.float_registers[reg.0 as usize] = val;
+        self.undo_log.record(UndoEntry::FloatRegister(*reg, old_val));
+        for observer in &mut self.observers {
+            observer.float_register_written(*reg, old_val, val);
+        }
+    }
+
+    pub fn get_single(&self, reg: &FloatRegister) -> f32 {
+        f32::from_bits(self.get_float_register(reg))
+    }
+
+    pub fn set_single(&mut self, reg: &FloatRegister, val: f32) {
+        self.set_float_register(reg, val.to_bits());
+    }
+
+    /// Reads the double in the even register `reg`, whose low word is in
+    /// `reg` and high word in the register after it.
+    pub fn get_double(&self, reg: &FloatRegister) -> f64 {
+        let high = self.get_float_register(&FloatRegister(reg.0 | 1));
+        f64::from_bits((high as u64) << 32 | self.get_float_register(reg) as u64)
+    }
+
+    pub fn set_double(&mut self, reg: &FloatRegister, val: f64) {
+        let bits = val.to_bits();
+        self.set_float_register(reg, bits as u32);
+        self.set_float_register(&FloatRegister(reg.0 | 1), (bits >> 32) as u32);
+    }
+
+    /// Whether FPU condition flag `cc` (0 to 7) is set.
+    pub fn float_condition(&self, cc: u8) -> bool {
+        self.float_conditions & 1 << (cc & 7) != 0
+    }
+
+    fn set_float_condition(&mut self, cc: u8, val: bool) {
+        let old_conditions = self.float_conditions;
+        self.undo_log.record(UndoEntry::FloatConditions(old_conditions));
+        let mask = 1 << (cc & 7);
+        self.float_conditions = if val { old_conditions | mask } else { old_conditions & !mask };
+        for observer in &mut self.observers {
+            observer.float_conditions_written(old_conditions, self.float_conditions);
+        }
+    }
+
+    /// The FPU control and status register. Only the condition flags are
+    /// modelled: flag 0 is bit 23, and flags 1-7 are bits 25-31.
+    pub fn fcsr(&self) -> u32 {
+        let conditions = self.float_conditions as u32;
+        (conditions & 1) << 23 | (conditions >> 1) << 25
+    }
+
+    /// A coprocessor 0 register, such as `STATUS` or `EPC`. Registers micah
+    /// doesn't model read as zero unless written.
+    pub fn get_cp0_register(&self, reg: u8) -> u32 {
+        self.coprocessor0[reg as usize & 31]
+    }
+
+    pub fn set_cp0_register(&mut self, reg: u8, val: u32) {
+        let reg = reg & 31;
+        let old_val = self.coprocessor0[reg as usize];
+        self.undo_log.record(UndoEntry::Coprocessor0(reg, old_val));
+        self.coprocessor0[reg as usize] = val;
+        for observer in &mut self.observers {
+            observer.cp0_register_written(reg, old_val, val);
+        }
+    }
+
+    /// Reads a byte for a debugger, without telling observers or touching
+    /// devices. Device registers and unallocated memory give `None`.
+    pub fn peek_byte(&self, addr: usize) -> Option<u8> {
+        self.memory.peek_byte(addr)
+    }
+
+    /// Whether `addr` belongs to a mapped device rather than to memory.
+    pub fn is_mapped(&self, addr: usize) -> bool {
+        self.memory.is_mapped(addr)
+    }
+
+    pub fn read_byte(&mut self, addr: usize) -> Result<u8, memory::MemoryError> {
+        let byte = self.memory.read_byte(addr)?;
+        for observer in &mut self.observers {
+            observer.memory_read(addr, 1, byte as u32);
+        }
+        Ok(byte)
+    }
+
+    pub fn read_word(&mut self, addr: usize) -> Result<u32, memory::MemoryError> {
+        let word = self.memory.read_word(addr)?;
+        for observer in &mut self.observers {
+            observer.memory_read(addr, 4, word);
+        }
+        Ok(word)
+    }
+
+    /// Writes a byte, recording it in the undo log. Device registers can't
+    /// be undone, and reading them back may have side effects, so writes to
+    /// them aren't recorded.
+    fn write_byte(&mut self, addr: usize, byte: u8) -> Result<(), memory::MemoryError> {
+        self.forget_decoded(addr, 1);
+        if !self.undo_log.is_recording() || self.memory.is_mapped(addr) {
+            return self.memory.store_byte(addr, byte)
+        }
+        let old_byte = if self.memory.is_written(addr) { self.memory.read_byte(addr).ok() } else { None };
+        self.memory.store_byte(addr, byte)?;
+        self.undo_log.record(UndoEntry::Memory(addr, old_byte));
+        Ok(())
+    }
+
+    /// Empties the decoded instruction slots overlapping the `len` bytes
+    /// from `addr`, so code that rewrites itself runs what it wrote.
+    fn forget_decoded(&mut self, addr: usize, len: usize) {
+        for text in &mut self.decoded {
+            for addr in [addr, addr + len - 1] {
+                if let Some(index) = text.index(addr as u32) {
+                    text.instructions[index] = None;
+                }
+            }
+        }
+    }
+
+    pub fn store_byte(&mut self, addr: usize, byte: u8) -> Result<(), memory::MemoryError> {
+        self.write_byte(addr, byte)?;
+        for observer in &mut self.observers {
+            observer.memory_written(addr, 1, byte as u32);
+        }
+        Ok(())
+    }
+
+    pub fn store_word(&mut self, addr: usize, word: u32) -> Result<(), memory::MemoryError> {
+        if self.undo_log.is_recording() && !self.memory.is_mapped(addr) {
+            for (i, byte) in self.memory.endian().word_to_bytes(word).iter().enumerate() {
+                self.write_byte(addr + i, *byte)?;
+            }
+        } else {
+            self.forget_decoded(addr, 4);
+            self.memory.store_word(addr, word)?;
+        }
+        for observer in &mut self.observers {
+            observer.memory_written(addr, 4, word);
+        }
+        Ok(())
+    }
+
+    /// Records a `jal`/`jalr` on the shadow call stack.
+    pub fn call(&mut self, mut frame: CallFrame) {
+        if self.check_conventions {
+            let mut saved = [0; 9];
+            for (value, reg) in saved.iter_mut().zip(CALLEE_SAVED.iter()) {
+                *value = self.registers.registers[Registers::register_to_index(reg)];
+            }
+            frame.saved_registers = Some(saved);
+        }
+        for observer in &mut self.observers {
+            observer.called(&frame);
+        }
+        self.call_stack.push(frame);
+        self.undo_log.record(UndoEntry::Call);
+        let old_defined = self.registers.defined;
+        self.registers.clobber_for_call();
+        if self.registers.defined != old_defined {
+            self.undo_log.record(UndoEntry::Defined(old_defined));
+        }
+    }
+
+    /// Records a `jr $ra` to `addr`, returning the frames it popped.
+    /// If conventions are checked, the frame being returned from is compared
+    /// against the registers it saved, and a return to no caller at all is
+    /// blamed on the newest frame.
+    pub fn return_to(&mut self, addr: u32) -> Vec<CallFrame> {
+        let popped = self.call_stack.pop_to(addr);
+        match popped.last() {
+            Some(frame) => {
+                self.check_callee_saved(frame);
+                self.undo_log.record(UndoEntry::Return(popped.clone()));
+                for observer in &mut self.observers {
+                    observer.returned(&popped);
+                }
+            }
+            None if self.check_conventions => self.check_return_address(addr),
+            None => ()
+        }
+        popped
+    }
+
+    /// Reports the newest function for losing its `$ra`, once for each
+    /// wrong address it returns to.
+    fn check_return_address(&mut self, addr: u32) {
+        let frame = match self.call_stack.frames().last() {
+            Some(frame) => frame,
+            None => return
+        };
+        let violation = ConventionViolation {
+            function: frame.function.clone(),
+            register: Rra,
+            expected: frame.return_addr,
+            found: addr,
+            clobbered_at: self.register_writers[Registers::register_to_index(&Rra)].clone(),
+        };
+        if !self.convention_violations.contains(&violation) {
+            self.convention_violations.push(violation);
+        }
+    }
+
+    fn check_callee_saved(&mut self, frame: &CallFrame) {
+        let saved = match &frame.saved_registers {
+            Some(saved) => saved,
+            None => return
+        };
+        for (expected, reg) in saved.iter().zip(CALLEE_SAVED.iter()) {
+            let index = Registers::register_to_index(reg);
+            let found = self.registers.registers[index];
+            if found != *expected {
+                self.convention_violations.push(ConventionViolation {
+                    function: frame.function.clone(),
+                    register: *reg,
+                    expected: *expected,
+                    found,
+                    clobbered_at: self.register_writers[index].clone(),
+                });
+            }
+        }
+    }
+
+    pub fn convention_violations(&self) -> &[ConventionViolation] {
+        &self.convention_violations
+    }
+
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    /// A backtrace of the shadow call stack, to be printed alongside any runtime error.
+    pub fn backtrace(&self) -> String {
+        self.call_stack.backtrace()
+    }
+
+    /// Replaces the console syscalls read from and write to, e.g. with
+    /// `Console::scripted` for automated marking.
+    pub fn set_console(&mut self, console: Console) {
+        self.console = console;
+    }
+
+    pub fn console(&self) -> &Console {
+        &self.console
+    }
+
+    /// Maps `device` over the `len` bytes from `base`. It is ticked after
+    /// every instruction, and its interrupts are taken by the `.ktext`
+    /// handler when Status enables them.
+    pub fn map_device(&mut self, base: usize, len: usize, device: Box<dyn Device>) -> Result<(), memory::MemoryError> {
+        self.memory.map(base, len, device)
+    }
+
+    /// Runs the syscall selected by `$v0`, with SPIM's numbering.
+    pub fn syscall(&mut self) -> Result<SyscallResult, SyscallError> {
+        let code = self.read_source_register(&Rv0)?;
+        for observer in &mut self.observers {
+            observer.syscall(code);
+        }
+        match code {
+            1 => {
+                let arg = self.read_source_register(&Ra0)?;
+                self.console.write((arg as i32).to_string().as_bytes())?;
+            }
+            2 => {
+                let arg = self.get_single(&FloatRegister(12));
+                self.console.write(format_float(arg).as_bytes())?;
+            }
+            3 => {
+                let arg = self.get_double(&FloatRegister(12));
+                self.console.write(format_double(arg).as_bytes())?;
+            }
+            4 => {
+                let mut addr = self.read_source_register(&Ra0)? as usize;
+                let mut string = Vec::new();
+                loop {
+                    let byte = self.read_byte(addr)?;
+                    if byte == 0 {
+                        break
+                    }
+                    string.push(byte);
+                    addr += 1;
+                }
+                self.console.write(&string)?;
+            }
+            5 => {
+                let value = self.console.read_int()?;
+                self.set_register(&Rv0, value as u32)?;
+            }
+            6 => {
+                let value = self.console.read_double()? as f32;
+                self.set_single(&FloatRegister(0), value);
+            }
+            7 => {
+                let value = self.console.read_double()?;
+                self.set_double(&FloatRegister(0), value);
+            }
+            8 => {
+                let addr = self.read_source_register(&Ra0)? as usize;
+                let max_len = self.read_source_register(&Ra1)? as usize;
+                if max_len > 0 {
+                    let line = self.console.read_line(max_len)?;
+                    for (i, byte) in line.iter().chain([0].iter()).enumerate() {
+                        self.store_byte(addr + i, *byte)?;
+                    }
+                }
+            }
+            10 => return Ok(SyscallResult::Exit(0)),
+            11 => {
+                let arg = self.read_source_register(&Ra0)?;
+                self.console.write(&[arg as u8])?;
+            }
+            12 => {
+                let byte = self.console.read_char()?;
+                self.set_register(&Rv0, byte as u32)?;
+            }
+            17 => {
+                let arg = self.read_source_register(&Ra0)?;
+                return Ok(SyscallResult::Exit(arg as i32))
+            }
+            code => return Err(SyscallError::UnknownSyscall(code))
+        }
+        Ok(SyscallResult::Continue)
+    }
+
+    /// Reverts every write made by the most recent step.
+    /// Returns false if there is nothing left in the undo log.
+    pub fn step_back(&mut self) -> bool {
+        let (UndoStep { start, .. }, step) = match self.undo_log.pop_step() {
+            Some(step) => step,
+            None => return false
+        };
+        if let Some((pc, instruction_count)) = start {
+            self.pc = pc;
+            self.instruction_count = instruction_count;
+        }
+        for entry in step.into_iter().rev() {
+            match entry {
+                UndoEntry::Register(reg, val) => {
+                    self.registers.registers[Registers::register_to_index(&reg)] = val;
+                }
+                UndoEntry::Defined(defined) => {
+                    self.registers.defined = defined;
+                }
+                UndoEntry::HiLo(hi, lo) => {
+                    self.hi = hi;
+                    self.lo = lo;
+                }
+                UndoEntry::FloatRegister(reg, val) => {
+                    self.float_registers[reg.0 as usize] = val;
+                }
+                UndoEntry::FloatConditions(conditions) => {
+                    self.float_conditions = conditions;
+                }
+                UndoEntry::Coprocessor0(reg, val) => {
+                    self.coprocessor0[reg as usize] = val;
+                }
+                UndoEntry::Memory(addr, byte) => {
+                    self.forget_decoded(addr, 1);
+                    match byte {
+                        Some(byte) => self.memory.store_byte(addr, byte).expect("Undo log recorded an unwritable address"),
+                        None => self.memory.forget_byte(addr),
+                    }
+                }
+                UndoEntry::Call => {
+                    self.call_stack.pop();
+                }
+                UndoEntry::Return(frames) => {
+                    for frame in frames.into_iter().rev() {
+                        self.call_stack.push(frame);
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Runtime {
+    /// Loads an assembled program's text and data, points the pc at its
+    /// entry and sets up `$sp` and `$gp` as SPIM does.
+    pub fn load_program(&mut self, program: Program) -> Result<(), RuntimeError> {
+        for (i, word) in program.words.iter().enumerate() {
+            self.memory.store_word(TEXT_BASE as usize + 4 * i, *word)?;
+        }
+        for (i, byte) in program.data.iter().enumerate() {
+            self.memory.store_byte(DATA_BASE as usize + i, *byte)?;
+        }
+        for (i, word) in program.kernel_words.iter().enumerate() {
+            self.memory.store_word(KTEXT_BASE as usize + 4 * i, *word)?;
+        }
+        for (i, byte) in program.kernel_data.iter().enumerate() {
+            self.memory.store_byte(KDATA_BASE as usize + i, *byte)?;
+        }
+        self.registers.set_register(&Rsp, STACK_TOP)?;
+        self.registers.set_register(&Rgp, GLOBAL_POINTER)?;
+        self.pc = program.entry;
+        self.decoded = vec![
+            DecodedText { base: TEXT_BASE, instructions: program.text.iter().cloned().map(Some).collect() },
+            DecodedText { base: KTEXT_BASE, instructions: program.kernel_text.iter().cloned().map(Some).collect() },
+        ];
+        self.program = Some(program);
+        Ok(())
+    }
+
+    /// Loads an ELF executable's segments in its byte order and points the
+    /// pc at its entry. `$gp` is set from the `_gp` symbol if there is one.
+    pub fn load_elf(&mut self, elf: &Elf) -> Result<(), RuntimeError> {
+        self.memory.set_endian(elf.endian);
+        for segment in &elf.segments {
+            let bytes = segment.data.iter().cloned().chain(iter::repeat(0));
+            let size = (segment.mem_size as usize).max(segment.data.len());
+            for (i, byte) in bytes.take(size).enumerate() {
+                self.memory.store_byte(segment.vaddr as usize + i, byte)?;
+            }
+            // Executables hold no decoded instructions, so decode each one
+            // the first time it runs.
+            if segment.flags & PF_X != 0 {
+                self.decoded.push(DecodedText { base: segment.vaddr, instructions: vec![None; size / 4] });
+            }
+        }
+        self.registers.set_register(&Rsp, STACK_TOP)?;
+        let gp = elf.symbols.get("_gp").cloned().unwrap_or(GLOBAL_POINTER);
+        self.registers.set_register(&Rgp, gp)?;
+        self.pc = elf.entry;
+        self.program = Some(elf.program());
+        Ok(())
+    }
+
+    /// Passes `args` to a loaded program as SPIM does. The strings go at the
+    /// top of the stack with a null-terminated array of pointers to them
+    /// below, and `$sp` points at the count just below that. `$a0` holds the
+    /// count and `$a1` the array.
+    pub fn set_arguments(&mut self, args: &[String]) -> Result<(), RuntimeError> {
+        let mut addr = STACK_TOP;
+        let mut pointers = Vec::with_capacity(args.len());
+        for arg in args.iter().rev() {
+            addr -= arg.len() as u32 + 1;
+            for (i, byte) in arg.bytes().chain([0]).enumerate() {
+                self.memory.store_byte(addr as usize + i, byte)?;
+            }
+            pointers.push(addr);
+        }
+        let argv = (addr & !3) - 4 * (args.len() as u32 + 1);
+        for (i, pointer) in pointers.iter().rev().chain([0].iter()).enumerate() {
+            self.memory.store_word(argv as usize + 4 * i, *pointer)?;
+        }
+        self.memory.store_word(argv as usize - 4, args.len() as u32)?;
+        self.registers.set_register(&Rsp, argv - 4)?;
+        self.registers.set_register(&Ra0, args.len() as u32)?;
+        self.registers.set_register(&Ra1, argv)?;
+        Ok(())
+    }
+
+    pub fn program(&self) -> Option<&Program> {
+        self.program.as_ref()
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    /// Moves the pc, e.g. for a debugger jumping elsewhere. Stepping back
+    /// past the next step returns it to where the step started.
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+    }
+
+    /// The byte order of memory, big-endian unless an executable said otherwise.
+    pub fn endian(&self) -> memory::Endian {
+        self.memory.endian()
+    }
+
+    pub fn hi(&self) -> u32 {
+        self.hi
+    }
+
+    pub fn lo(&self) -> u32 {
+        self.lo
+    }
+
+    /// Disassembles `count` words of memory from `addr`, naming targets with
+    /// the loaded program's labels.
+    pub fn disassemble(&mut self, addr: u32, count: usize) -> Vec<DisassembledWord> {
+        disassembler::disassemble(&mut self.memory, self.program.as_ref(), addr, count)
+    }
+
+    /// The number of instructions executed so far.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /// The source location of the instruction at the pc.
+    pub fn current_location(&self) -> Option<&MIPSLocation> {
+        self.program.as_ref().and_then(|program| program.location_at(self.pc))
+    }
+
+    /// Executes the instruction at the pc. If it raises an exception and an
+    /// exception handler is loaded, the pc moves to the handler; otherwise
+    /// the error is returned and the pc is left pointing at the instruction
+    /// that failed. Mapped devices then advance a cycle, and may interrupt.
+    pub fn step(&mut self) -> Result<StepResult, RuntimeError> {
+        let result = self.step_instruction();
+        if let Ok(StepResult::Running) = result {
+            if self.memory.has_devices() {
+                self.tick_devices();
+            }
+        }
+        result
+    }
+
+    fn step_instruction(&mut self) -> Result<StepResult, RuntimeError> {
+        let pc = self.pc;
+        let instruction = match self.fetch(pc) {
+            Ok(instruction) => instruction,
+            Err(error) => return self.raise(error, pc, None)
+        };
+        self.begin_step();
+        if self.check_conventions || self.check_undefined_reads {
+            self.location = self.program.as_ref().and_then(|program| program.location_at(pc)).cloned();
+        }
+        let mut next_pc = pc.wrapping_add(4);
+        let result = match self.execute(instruction, pc, &mut next_pc) {
+            Ok(result) => result,
+            Err(error) => return self.raise(error, pc, Some(instruction))
+        };
+        self.pc = next_pc;
+        self.instruction_count += 1;
+        self.retire_instruction(pc);
+        Ok(result)
+    }
+
+    /// Tells observers the instruction at `addr` has finished.
+    fn retire_instruction(&mut self, addr: u32) {
+        if self.observers.is_empty() {
+            return
+        }
+        let location = self.program.as_ref().and_then(|program| program.location_at(addr));
+        for observer in &mut self.observers {
+            observer.instruction_retired(addr, location);
+        }
+    }
+
+    /// Ticks the mapped devices and copies their interrupts into Cause. An
+    /// interrupt that is enabled and unmasked is taken before the next
+    /// instruction, which the handler returns to.
+    fn tick_devices(&mut self) {
+        self.memory.tick();
+        let old_cause = self.coprocessor0[CAUSE as usize];
+        let cause = old_cause & !HARDWARE_INTERRUPTS | self.memory.interrupts();
+        if cause != old_cause {
+            self.set_cp0_register(CAUSE, cause);
+        }
+        let status = self.coprocessor0[STATUS as usize];
+        let enabled = status & STATUS_IE != 0 && status & STATUS_EXL == 0;
+        if enabled && status & cause & INTERRUPT_MASK != 0 && self.has_exception_handler() {
+            self.enter_exception(ExceptionCode::Interrupt, self.pc, None);
+        }
+    }
+
+    /// Whether there is code at the exception vector to handle exceptions.
+    pub fn has_exception_handler(&mut self) -> bool {
+        self.memory.is_written(KTEXT_BASE as usize)
+    }
+
+    /// Turns `error`, raised by `instruction` at `pc` (or by fetching it, if
+    /// `None`), into an exception if it is one and there's a handler to take
+    /// it. An exception inside the handler is returned as an error, since
+    /// handling it would overwrite the EPC.
+    fn raise(&mut self, error: RuntimeError, pc: u32, instruction: Option<Instruction>) -> Result<StepResult, RuntimeError> {
+        let (code, bad_addr) = match (&error, instruction) {
+            (RuntimeError::NoInstruction(_), None) | (RuntimeError::UnalignedAccess(_), None) => {
+                (ExceptionCode::BadInstructionAddress, Some(pc))
+            }
+            (RuntimeError::InvalidInstruction(..), _) => (ExceptionCode::ReservedInstruction, None),
+            (RuntimeError::UnalignedAccess(addr), Some(instruction)) if is_store(&instruction) => {
+                (ExceptionCode::AddressStore, Some(*addr))
+            }
+            (RuntimeError::UnalignedAccess(addr), _) => (ExceptionCode::AddressLoad, Some(*addr)),
+            (RuntimeError::Memory(_), Some(instruction)) => match self.access_address(&instruction) {
+                Some(addr) if is_store(&instruction) => (ExceptionCode::AddressStore, Some(addr)),
+                Some(addr) => (ExceptionCode::AddressLoad, Some(addr)),
+                None => (ExceptionCode::BadDataAddress, None),
+            },
+            (RuntimeError::ArithmeticOverflow, _) => (ExceptionCode::Overflow, None),
+            (RuntimeError::Syscall(SyscallError::UnknownSyscall(_)), _) => (ExceptionCode::Syscall, None),
+            (RuntimeError::Break(_), _) => (ExceptionCode::Breakpoint, None),
+            _ => return Err(error)
+        };
+        if self.coprocessor0[STATUS as usize] & STATUS_EXL != 0 || !self.has_exception_handler() {
+            return Err(error)
+        }
+        if instruction.is_none() {
+            self.begin_step();
+        }
+        self.enter_exception(code, pc, bad_addr);
+        Ok(StepResult::Running)
+    }
+
+    /// The address a load or store accesses. A faulting access writes no
+    /// registers, so its base register still holds what it did.
+    fn access_address(&self, instruction: &Instruction) -> Option<u32> {
+        use Instruction as I;
+        match *instruction {
+            I::Lb {base, offset, ..} | I::Lbu {base, offset, ..} | I::Lh {base, offset, ..}
+            | I::Lhu {base, offset, ..} | I::Lw {base, offset, ..} | I::Sb {base, offset, ..}
+            | I::Sh {base, offset, ..} | I::Sw {base, offset, ..} | I::Lwc1 {base, offset, ..}
+            | I::Ldc1 {base, offset, ..} | I::Swc1 {base, offset, ..} | I::Sdc1 {base, offset, ..} => {
+                Some(self.get_register(&base).ok()?.wrapping_add(offset as i32 as u32))
+            }
+            _ => None
+        }
+    }
+
+    /// Records an exception in coprocessor 0 as the hardware does and jumps
+    /// to the handler, which returns to `epc` with `eret`.
+    fn enter_exception(&mut self, code: ExceptionCode, epc: u32, bad_addr: Option<u32>) {
+        if let Some(addr) = bad_addr {
+            self.set_cp0_register(BAD_VADDR, addr);
+        }
+        // Keep the pending interrupt bits.
+        let cause = self.coprocessor0[CAUSE as usize] & 0xff00 | (code as u32) << 2;
+        self.set_cp0_register(CAUSE, cause);
+        self.set_cp0_register(EPC, epc);
+        let status = self.coprocessor0[STATUS as usize];
+        self.set_cp0_register(STATUS, status | STATUS_EXL);
+        self.pc = KTEXT_BASE;
+        for observer in &mut self.observers {
+            observer.exception(code, epc);
+        }
+    }
+
+    /// The instruction at `pc`, decoded ahead of time if it can be and
+    /// otherwise read and decoded from memory. Fetches are not reported to
+    /// observers as memory reads.
+    fn fetch(&mut self, pc: u32) -> Result<Instruction, RuntimeError> {
+        if !pc.is_multiple_of(4) {
+            return Err(RuntimeError::UnalignedAccess(pc))
+        }
+        let slot = self.decoded.iter().enumerate()
+            .find_map(|(segment, text)| Some((segment, text.index(pc)?)));
+        if let Some((segment, index)) = slot {
+            if let Some(instruction) = self.decoded[segment].instructions[index] {
+                return Ok(instruction)
+            }
+        }
+        if !(0..4).all(|i| self.memory.is_written(pc as usize + i)) {
+            return Err(RuntimeError::NoInstruction(pc))
+        }
+        let word = self.memory.read_word(pc as usize).map_err(|_| RuntimeError::NoInstruction(pc))?;
+        let instruction = Instruction::decode(word, pc).ok_or(RuntimeError::InvalidInstruction(pc, word))?;
+        if let Some((segment, index)) = slot {
+            self.decoded[segment].instructions[index] = Some(instruction);
+        }
+        Ok(instruction)
+    }
+
+    /// Steps until the program exits, returning its exit code.
+    pub fn run(&mut self) -> Result<i32, RuntimeError> {
+        loop {
+            if let StepResult::Exited(code) = self.step()? {
+                return Ok(code)
+            }
+        }
+    }
+
+    fn read(&mut self, reg: RegisterCodes) -> Result<u32, RuntimeError> {
+        Ok(self.read_source_register(&reg)?)
+    }
+
+    /// Writes a destination register, discarding writes to `$zero`. Only an
+    /// exception handler may write `$k0` and `$k1`.
+    fn write(&mut self, reg: RegisterCodes, val: u32) -> Result<(), RuntimeError> {
+        if reg != Rzero {
+            let kernel = self.coprocessor0[STATUS as usize] & STATUS_EXL != 0;
+            self.write_register(&reg, val, kernel)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_hi_lo(&mut self, hi: u32, lo: u32) {
+        let old_val = (self.hi, self.lo);
+        self.undo_log.record(UndoEntry::HiLo(self.hi, self.lo));
+        self.hi = hi;
+        self.lo = lo;
+        for observer in &mut self.observers {
+            observer.hi_lo_written(old_val, (hi, lo));
+        }
+    }
+
+    fn effective_address(&mut self, base: RegisterCodes, offset: i16, alignment: u32) -> Result<u32, RuntimeError> {
+        let addr = self.read(base)?.wrapping_add(offset as i32 as u32);
+        if !addr.is_multiple_of(alignment) {
+            return Err(RuntimeError::UnalignedAccess(addr))
+        }
+        Ok(addr)
+    }
+
+    fn read_half(&mut self, addr: usize) -> Result<u16, memory::MemoryError> {
+        let half = if self.memory.is_mapped(addr) {
+            self.memory.read_word(addr)? as u16
+        } else {
+            let bytes = [self.memory.read_byte(addr)?, self.memory.read_byte(addr + 1)?];
+            self.memory.endian().half_from_bytes(bytes)
+        };
+        for observer in &mut self.observers {
+            observer.memory_read(addr, 2, half as u32);
+        }
+        Ok(half)
+    }
+
+    fn store_half(&mut self, addr: usize, half: u16) -> Result<(), memory::MemoryError> {
+        if self.memory.is_mapped(addr) {
+            self.memory.store_word(addr, half as u32)?;
+        } else {
+            for (i, byte) in self.memory.endian().half_to_bytes(half).iter().enumerate() {
+                self.write_byte(addr + i, *byte)?;
+            }
+        }
+        for observer in &mut self.observers {
+            observer.memory_written(addr, 2, half as u32);
+        }
+        Ok(())
+    }
+
+    /// Reads a doubleword as two words in the memory's byte order.
+    fn read_doubleword(&mut self, addr: usize) -> Result<u64, memory::MemoryError> {
+        let (first, second) = (self.read_word(addr)? as u64, self.read_word(addr + 4)? as u64);
+        Ok(match self.memory.endian() {
+            memory::Endian::Big => first << 32 | second,
+            memory::Endian::Little => second << 32 | first,
+        })
+    }
+
+    fn store_doubleword(&mut self, addr: usize, val: u64) -> Result<(), memory::MemoryError> {
+        let (first, second) = match self.memory.endian() {
+            memory::Endian::Big => (val >> 32, val),
+            memory::Endian::Little => (val, val >> 32),
+        };
+        self.store_word(addr, first as u32)?;
+        self.store_word(addr + 4, second as u32)
+    }
+
+    /// Reads `reg` in format `fmt`, widened to a double. Words are read as
+    /// signed integers.
+    fn read_float(&self, fmt: FloatFormat, reg: FloatRegister) -> f64 {
+        match fmt {
+            FloatFormat::Single => self.get_single(&reg) as f64,
+            FloatFormat::Double => self.get_double(&reg),
+            FloatFormat::Word => self.get_float_register(&reg) as i32 as f64,
+        }
+    }
+
+    /// Writes `val` to `reg` in format `fmt`, rounding it to a single if
+    /// need be.
+    fn write_float(&mut self, fmt: FloatFormat, reg: FloatRegister, val: f64) {
+        match fmt {
+            FloatFormat::Single => self.set_single(&reg, val as f32),
+            FloatFormat::Double => self.set_double(&reg, val),
+            // The default rounding mode is to the nearest, ties to even.
+            FloatFormat::Word => self.set_float_register(&reg, float_to_word(val.round_ties_even()) as u32),
+        }
+    }
+
+    /// Applies `op` to `fs` and `ft` in format `fmt`. Singles are computed
+    /// in single precision, so rounding matches real hardware.
+    fn float_arithmetic(
+        &mut self, fmt: FloatFormat, fd: FloatRegister, fs: FloatRegister, ft: FloatRegister,
+        single: fn(f32, f32) -> f32, double: fn(f64, f64) -> f64
+    ) {
+        match fmt {
+            FloatFormat::Single => {
+                let val = single(self.get_single(&fs), self.get_single(&ft));
+                self.set_single(&fd, val);
+            }
+            _ => {
+                let val = double(self.get_double(&fs), self.get_double(&ft));
+                self.set_double(&fd, val);
+            }
+        }
+    }
+
+    /// Flips or clears the sign bit of `fs`, as `neg` and `abs` do.
+    fn float_sign(&mut self, fmt: FloatFormat, fd: FloatRegister, fs: FloatRegister, op: fn(u32) -> u32) {
+        let high = if fmt == FloatFormat::Double { FloatRegister(fs.0 | 1) } else { fs };
+        let high = op(self.get_float_register(&high));
+        if fmt == FloatFormat::Double {
+            let low = self.get_float_register(&fs);
+            self.set_float_register(&fd, low);
+            self.set_float_register(&FloatRegister(fd.0 | 1), high);
+        } else {
+            self.set_float_register(&fd, high);
+        }
+    }
+
+    fn round_to_word(&mut self, fmt: FloatFormat, fd: FloatRegister, fs: FloatRegister, round: fn(f64) -> f64) {
+        let val = round(self.read_float(fmt, fs));
+        self.set_float_register(&fd, float_to_word(val) as u32);
+    }
+
+    /// Takes the branch at `pc` to `target` if `taken`, and tells observers
+    /// whether it was.
+    fn branch(&mut self, pc: u32, target: u32, taken: bool, next_pc: &mut u32) {
+        if taken {
+            *next_pc = target;
+        }
+        for observer in &mut self.observers {
+            observer.branch(pc, target, taken);
+        }
+    }
+
+    fn call_to(&mut self, pc: u32, target: u32) {
+        let (function, call_site) = match &self.program {
+            Some(program) => (program.label_at(target).map(|label| label.to_owned()), program.location_at(pc).cloned()),
+            None => (None, None)
+        };
+        self.call(CallFrame {
+            function,
+            target,
+            return_addr: pc.wrapping_add(4),
+            call_site,
+            saved_registers: None,
+        });
+    }
+
+    fn execute(&mut self, instruction: Instruction, pc: u32, next_pc: &mut u32) -> Result<StepResult, RuntimeError> {
+        use Instruction as I;
+        match instruction {
+            I::Add {rd, rs, rt} => {
+                let val = (self.read(rs)? as i32).checked_add(self.read(rt)? as i32);
+                self.write(rd, val.ok_or(RuntimeError::ArithmeticOverflow)? as u32)?;
+            }
+            I::Addu {rd, rs, rt} => {
+                let val = self.read(rs)?.wrapping_add(self.read(rt)?);
+                self.write(rd, val)?;
+            }
+            I::Sub {rd, rs, rt} => {
+                let val = (self.read(rs)? as i32).checked_sub(self.read(rt)? as i32);
+                self.write(rd, val.ok_or(RuntimeError::ArithmeticOverflow)? as u32)?;
+            }
+            I::Subu {rd, rs, rt} => {
+                let val = self.read(rs)?.wrapping_sub(self.read(rt)?);
+                self.write(rd, val)?;
+            }
+            I::And {rd, rs, rt} => {
+                let val = self.read(rs)? & self.read(rt)?;
+                self.write(rd, val)?;
+            }
+            I::Or {rd, rs, rt} => {
+                let val = self.read(rs)? | self.read(rt)?;
+                self.write(rd, val)?;
+            }
+            I::Xor {rd, rs, rt} => {
+                let val = self.read(rs)? ^ self.read(rt)?;
+                self.write(rd, val)?;
+            }
+            I::Nor {rd, rs, rt} => {
+                let val = !(self.read(rs)? | self.read(rt)?);
+                self.write(rd, val)?;
+            }
+            I::Slt {rd, rs, rt} => {
+                let val = (self.read(rs)? as i32) < (self.read(rt)? as i32);
+                self.write(rd, val as u32)?;
+            }
+            I::Sltu {rd, rs, rt} => {
+                let val = self.read(rs)? < self.read(rt)?;
+                self.write(rd, val as u32)?;
+            }
+            I::Sllv {rd, rt, rs} => {
+                let val = self.read(rt)? << (self.read(rs)? & 31);
+                self.write(rd, val)?;
+            }
+            I::Srlv {rd, rt, rs} => {
+                let val = self.read(rt)? >> (self.read(rs)? & 31);
+                self.write(rd, val)?;
+            }
+            I::Srav {rd, rt, rs} => {
+                let val = (self.read(rt)? as i32) >> (self.read(rs)? & 31);
+                self.write(rd, val as u32)?;
+            }
+            I::Sll {rd, rt, shamt} => {
+                let val = self.read(rt)? << shamt;
+                self.write(rd, val)?;
+            }
+            I::Srl {rd, rt, shamt} => {
+                let val = self.read(rt)? >> shamt;
+                self.write(rd, val)?;
+            }
+            I::Sra {rd, rt, shamt} => {
+                let val = (self.read(rt)? as i32) >> shamt;
+                self.write(rd, val as u32)?;
+            }
+            I::Mul {rd, rs, rt} => {
+                let val = (self.read(rs)? as i32).wrapping_mul(self.read(rt)? as i32);
+                self.write(rd, val as u32)?;
+            }
+            I::Mult {rs, rt} => {
+                let product = (self.read(rs)? as i32 as i64) * (self.read(rt)? as i32 as i64);
+                self.set_hi_lo((product >> 32) as u32, product as u32);
+            }
+            I::Multu {rs, rt} => {
+                let product = (self.read(rs)? as u64) * (self.read(rt)? as u64);
+                self.set_hi_lo((product >> 32) as u32, product as u32);
+            }
+            I::Div {rs, rt} => {
+                let dividend = self.read(rs)? as i32;
+                let divisor = self.read(rt)? as i32;
+                // Dividing by zero leaves hi and lo unpredictable; we leave them alone.
+                if divisor != 0 {
+                    self.set_hi_lo(dividend.wrapping_rem(divisor) as u32, dividend.wrapping_div(divisor) as u32);
+                }
+            }
+            I::Divu {rs, rt} => {
+                let dividend = self.read(rs)?;
+                let divisor = self.read(rt)?;
+                if divisor != 0 {
+                    self.set_hi_lo(dividend % divisor, dividend / divisor);
+                }
+            }
+            I::Mfhi {rd} => {
+                let val = self.hi;
+                self.write(rd, val)?;
+            }
+            I::Mflo {rd} => {
+                let val = self.lo;
+                self.write(rd, val)?;
+            }
+            I::Mthi {rs} => {
+                let val = self.read(rs)?;
+                let lo = self.lo;
+                self.set_hi_lo(val, lo);
+            }
+            I::Mtlo {rs} => {
+                let val = self.read(rs)?;
+                let hi = self.hi;
+                self.set_hi_lo(hi, val);
+            }
+            I::Jr {rs} => {
+                let target = self.read(rs)?;
+                if rs == Rra {
+                    self.return_to(target);
+                }
+                *next_pc = target;
+            }
+            I::Jalr {rd, rs} => {
+                let target = self.read(rs)?;
+                self.write(rd, pc.wrapping_add(4))?;
+                self.call_to(pc, target);
+                *next_pc = target;
+            }
+            I::Syscall => {
+                if let SyscallResult::Exit(code) = self.syscall()? {
+                    return Ok(StepResult::Exited(code))
+                }
+            }
+            I::Break {code} => return Err(RuntimeError::Break(code)),
+
+            I::Addi {rt, rs, imm} => {
+                let val = (self.read(rs)? as i32).checked_add(imm as i32);
+                self.write(rt, val.ok_or(RuntimeError::ArithmeticOverflow)? as u32)?;
+            }
+            I::Addiu {rt, rs, imm} => {
+                let val = self.read(rs)?.wrapping_add(imm as i32 as u32);
+                self.write(rt, val)?;
+            }
+            I::Slti {rt, rs, imm} => {
+                let val = (self.read(rs)? as i32) < imm as i32;
+                self.write(rt, val as u32)?;
+            }
+            I::Sltiu {rt, rs, imm} => {
+                let val = self.read(rs)? < imm as i32 as u32;
+                self.write(rt, val as u32)?;
+            }
+            I::Andi {rt, rs, imm} => {
+                let val = self.read(rs)? & imm as u32;
+                self.write(rt, val)?;
+            }
+            I::Ori {rt, rs, imm} => {
+                let val = self.read(rs)? | imm as u32;
+                self.write(rt, val)?;
+            }
+            I::Xori {rt, rs, imm} => {
+                let val = self.read(rs)? ^ imm as u32;
+                self.write(rt, val)?;
+            }
+            I::Lui {rt, imm} => {
+                self.write(rt, (imm as u32) << 16)?;
+            }
+            I::Lb {rt, base, offset} => {
+                let addr = self.effective_address(base, offset, 1)?;
+                let val = self.read_byte(addr as usize)? as i8 as i32 as u32;
+                self.write(rt, val)?;
+            }
+            I::Lbu {rt, base, offset} => {
+                let addr = self.effective_address(base, offset, 1)?;
+                let val = self.read_byte(addr as usize)? as u32;
+                self.write(rt, val)?;
+            }
+            I::Lh {rt, base, offset} => {
+                let addr = self.effective_address(base, offset, 2)?;
+                let val = self.read_half(addr as usize)? as i16 as i32 as u32;
+                self.write(rt, val)?;
+            }
+            I::Lhu {rt, base, offset} => {
+                let addr = self.effective_address(base, offset, 2)?;
+                let val = self.read_half(addr as usize)? as u32;
+                self.write(rt, val)?;
+            }
+            I::Lw {rt, base, offset} => {
+                let addr = self.effective_address(base, offset, 4)?;
+                let val = self.read_word(addr as usize)?;
+                self.write(rt, val)?;
+            }
+            I::Sb {rt, base, offset} => {
+                let addr = self.effective_address(base, offset, 1)?;
+                let val = self.read(rt)?;
+                self.store_byte(addr as usize, val as u8)?;
+            }
+            I::Sh {rt, base, offset} => {
+                let addr = self.effective_address(base, offset, 2)?;
+                let val = self.read(rt)?;
+                self.store_half(addr as usize, val as u16)?;
+            }
+            I::Sw {rt, base, offset} => {
+                let addr = self.effective_address(base, offset, 4)?;
+                let val = self.read(rt)?;
+                self.store_word(addr as usize, val)?;
+            }
+            I::Beq {rs, rt, target} => {
+                let taken = self.read(rs)? == self.read(rt)?;
+                self.branch(pc, target, taken, next_pc);
+            }
+            I::Bne {rs, rt, target} => {
+                let taken = self.read(rs)? != self.read(rt)?;
+                self.branch(pc, target, taken, next_pc);
+            }
+            I::Blez {rs, target} => {
+                let taken = self.read(rs)? as i32 <= 0;
+                self.branch(pc, target, taken, next_pc);
+            }
+            I::Bgtz {rs, target} => {
+                let taken = self.read(rs)? as i32 > 0;
+                self.branch(pc, target, taken, next_pc);
+            }
+            I::Bltz {rs, target} => {
+                let taken = (self.read(rs)? as i32) < 0;
+                self.branch(pc, target, taken, next_pc);
+            }
+            I::Bgez {rs, target} => {
+                let taken = self.read(rs)? as i32 >= 0;
+                self.branch(pc, target, taken, next_pc);
+            }
+
+            I::J {target} => {
+                *next_pc = target;
+            }
+            I::Jal {target} => {
+                self.write(Rra, pc.wrapping_add(4))?;
+                self.call_to(pc, target);
+                *next_pc = target;
+            }
+
+            I::AddFmt {fmt, fd, fs, ft} => self.float_arithmetic(fmt, fd, fs, ft, |a, b| a + b, |a, b| a + b),
+            I::SubFmt {fmt, fd, fs, ft} => self.float_arithmetic(fmt, fd, fs, ft, |a, b| a - b, |a, b| a - b),
+            I::MulFmt {fmt, fd, fs, ft} => self.float_arithmetic(fmt, fd, fs, ft, |a, b| a * b, |a, b| a * b),
+            I::DivFmt {fmt, fd, fs, ft} => self.float_arithmetic(fmt, fd, fs, ft, |a, b| a / b, |a, b| a / b),
+            I::SqrtFmt {fmt, fd, fs} => {
+                match fmt {
+                    FloatFormat::Single => {
+                        let val = self.get_single(&fs).sqrt();
+                        self.set_single(&fd, val);
+                    }
+                    _ => {
+                        let val = self.get_double(&fs).sqrt();
+                        self.set_double(&fd, val);
+                    }
+                }
+            }
+            I::AbsFmt {fmt, fd, fs} => self.float_sign(fmt, fd, fs, |high| high & 0x7fff_ffff),
+            I::NegFmt {fmt, fd, fs} => self.float_sign(fmt, fd, fs, |high| high ^ 0x8000_0000),
+            I::MovFmt {fmt, fd, fs} => self.float_sign(fmt, fd, fs, |high| high),
+            I::Cvt {to, from, fd, fs} => {
+                let val = self.read_float(from, fs);
+                self.write_float(to, fd, val);
+            }
+            I::RoundW {fmt, fd, fs} => self.round_to_word(fmt, fd, fs, f64::round_ties_even),
+            I::TruncW {fmt, fd, fs} => self.round_to_word(fmt, fd, fs, f64::trunc),
+            I::CeilW {fmt, fd, fs} => self.round_to_word(fmt, fd, fs, f64::ceil),
+            I::FloorW {fmt, fd, fs} => self.round_to_word(fmt, fd, fs, f64::floor),
+            I::CompareFmt {cond, fmt, cc, fs, ft} => {
+                // Singles widen to doubles exactly, so comparing as doubles is safe.
+                let (a, b) = (self.read_float(fmt, fs), self.read_float(fmt, ft));
+                let unordered = a.is_nan() || b.is_nan();
+                let val = (cond & 1 != 0 && unordered) || (cond & 2 != 0 && a == b) || (cond & 4 != 0 && a < b);
+                self.set_float_condition(cc, val);
+            }
+            I::Bc1t {cc, target} => {
+                let taken = self.float_condition(cc);
+                self.branch(pc, target, taken, next_pc);
+            }
+            I::Bc1f {cc, target} => {
+                let taken = !self.float_condition(cc);
+                self.branch(pc, target, taken, next_pc);
+            }
+            I::Mfc1 {rt, fs} => {
+                let val = self.get_float_register(&fs);
+                self.write(rt, val)?;
+            }
+            I::Mtc1 {rt, fs} => {
+                let val = self.read(rt)?;
+                self.set_float_register(&fs, val);
+            }
+            I::Lwc1 {ft, base, offset} => {
+                let addr = self.effective_address(base, offset, 4)?;
+                let val = self.read_word(addr as usize)?;
+                self.set_float_register(&ft, val);
+            }
+            I::Ldc1 {ft, base, offset} => {
+                let addr = self.effective_address(base, offset, 8)?;
+                let val = self.read_doubleword(addr as usize)?;
+                self.set_double(&ft, f64::from_bits(val));
+            }
+            I::Swc1 {ft, base, offset} => {
+                let addr = self.effective_address(base, offset, 4)?;
+                let val = self.get_float_register(&ft);
+                self.store_word(addr as usize, val)?;
+            }
+            I::Sdc1 {ft, base, offset} => {
+                let addr = self.effective_address(base, offset, 8)?;
+                let val = self.get_double(&ft).to_bits();
+                self.store_doubleword(addr as usize, val)?;
+            }
+            I::Mfc0 {rt, rd} => {
+                let val = self.get_cp0_register(rd);
+                self.write(rt, val)?;
+            }
+            I::Mtc0 {rt, rd} => {
+                let val = self.read(rt)?;
+                self.set_cp0_register(rd, val);
+            }
+            I::Eret => {
+                let status = self.coprocessor0[STATUS as usize];
+                self.set_cp0_register(STATUS, status & !STATUS_EXL);
+                *next_pc = self.coprocessor0[EPC as usize];
+            }
+        }
+        Ok(StepResult::Running)
+    }
+}
+
+fn initial_coprocessor0() -> [u32; 32] {
+    let mut registers = [0; 32];
+    registers[STATUS as usize] = INITIAL_STATUS;
+    registers
+}
+
+fn is_store(instruction: &Instruction) -> bool {
+    use Instruction as I;
+    matches!(instruction, I::Sb {..} | I::Sh {..} | I::Sw {..} | I::Swc1 {..} | I::Sdc1 {..})
+}
+
+/// Converts to a 32-bit integer as the FPU does when the invalid operation
+/// exception is disabled: NaNs and values out of range give `i32::MAX`.
+fn float_to_word(val: f64) -> i32 {
+    if val.is_nan() || val < i32::MIN as f64 || val > i32::MAX as f64 {
+        i32::MAX
+    } else {
+        val as i32
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Runtime {
+        Runtime::new()
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use super::super::console::{MappedConsole, MAPPED_CONSOLE_LEN, RECEIVER_INTERRUPT};
+    use super::super::memory::MMIO_BASE;
+
+    fn load_str(source: &str) -> Runtime {
+        let (components, _) = read_str_to_state(source, "test.s");
+        let mut runtime = Runtime::new();
+        runtime.load_program(assemble(&components).unwrap()).unwrap();
+        runtime
+    }
+
+    const FACTORIAL: &str = "
+            .data
+    prompt: .asciiz \"n: \"
+            .text
+    main:   addi $sp, $sp, -4
+            sw $ra, 0($sp)
+            la $a0, prompt
+            li $v0, 4
+            syscall
+            li $v0, 5
+            syscall
+            move $a0, $v0
+            jal fact
+            move $a0, $v0
+            li $v0, 1
+            syscall
+            lw $ra, 0($sp)
+            addi $sp, $sp, 4
+            li $v0, 0
+            jr $ra
+
+    fact:   addi $sp, $sp, -8
+            sw $ra, 4($sp)
+            sw $s0, 0($sp)
+            move $s0, $a0
+            li $v0, 1
+            ble $s0, 1, fact_end
+            addi $a0, $s0, -1
+            jal fact
+            mul $v0, $v0, $s0
+    fact_end:
+            lw $s0, 0($sp)
+            lw $ra, 4($sp)
+            addi $sp, $sp, 8
+            jr $ra
+    ";
     
     #[test]
     fn test_get_set_register(){
-        let mut runtime = Runtime {registers: Registers::new()};
+        let mut runtime = Runtime::new();
         let register = Registers::code_to_register("a1").unwrap();
         assert_eq!(register, Ra1);
-        runtime.registers.set_register(&register, 57);
-        assert_eq!(runtime.registers.get_register(&register).unwrap(), 57)
+        runtime.set_register(&register, 57).unwrap();
+        assert_eq!(runtime.get_register(&register).unwrap(), 57)
     }
     #[test]
     fn test_write_not_intended(){
-        let mut runtime = Runtime {registers: Registers::new()};
+        let mut runtime = Runtime::new();
         let register = Registers::code_to_register("zero").unwrap();
         assert_eq!(register, Rzero);
-        assert_eq!(runtime.registers.set_register(&register, 57), Err(MemoryError::WriteNotIntended));
+        assert_eq!(runtime.set_register(&register, 57), Err(MemoryError::WriteNotIntended));
+    }
+
+    #[test]
+    fn test_step_back(){
+        let mut runtime = Runtime::new();
+        let address = 5000;
+        runtime.begin_step();
+        runtime.set_register(&Rt0, 1).unwrap();
+        runtime.store_word(address, 1234321).unwrap();
+        runtime.begin_step();
+        runtime.set_register(&Rt0, 2).unwrap();
+        runtime.set_register(&Rt0, 3).unwrap();
+        runtime.store_word(address, 7).unwrap();
+
+        assert!(runtime.step_back());
+        assert_eq!(runtime.get_register(&Rt0).unwrap(), 1);
+        assert_eq!(runtime.read_word(address).unwrap(), 1234321);
+
+        assert!(runtime.step_back());
+        assert_eq!(runtime.get_register(&Rt0).unwrap(), 0);
+        assert_eq!(runtime.read_byte(address).unwrap(), memory::UNINITIALISED_BYTE);
+
+        assert!(!runtime.step_back());
+    }
+
+    #[test]
+    fn test_step_back_call_stack(){
+        let mut runtime = Runtime::new();
+        let frame = CallFrame {
+            function: Some("f".to_string()), target: 0x40, return_addr: 0x10, call_site: None, saved_registers: None
+        };
+        runtime.begin_step();
+        runtime.call(frame.clone());
+        runtime.begin_step();
+        assert_eq!(runtime.return_to(0x10), vec![frame.clone()]);
+        assert_eq!(runtime.call_stack().depth(), 0);
+
+        assert!(runtime.step_back());
+        assert_eq!(runtime.call_stack().frames(), &[frame]);
+        assert!(runtime.step_back());
+        assert_eq!(runtime.call_stack().depth(), 0);
+    }
+
+    #[test]
+    fn test_convention_violation(){
+        let mut runtime = Runtime::new();
+        runtime.set_convention_checks(true);
+        let location = MIPSLocation {file: "test.s".to_string(), line_num: 3, line_text: "li $s1, 4".to_string()};
+        let frame = CallFrame {
+            function: Some("f".to_string()), target: 0x40, return_addr: 0x10, call_site: None, saved_registers: None
+        };
+        runtime.set_register(&Rs1, 1).unwrap();
+        runtime.call(frame);
+        runtime.set_register(&Rs0, 7).unwrap();
+        runtime.set_register(&Rs0, 0).unwrap();
+        runtime.set_location(&location);
+        runtime.set_register(&Rs1, 4).unwrap();
+        runtime.return_to(0x10);
+        assert_eq!(runtime.convention_violations(), &[ConventionViolation {
+            function: Some("f".to_string()),
+            register: Rs1,
+            expected: 1,
+            found: 4,
+            clobbered_at: Some(location),
+        }]);
+
+        // `g` calls `h` without saving `$ra`, so returns into itself.
+        let mut runtime = load_str("
+        main:   jal g
+                jr $ra
+        g:      jal h
+        back:   jr $ra
+        h:      jr $ra
+        ");
+        runtime.set_convention_checks(true);
+        let back = runtime.program().unwrap().symbols["back"];
+        for _ in 0..8 {
+            runtime.step().unwrap();
+        }
+        let violations = runtime.convention_violations();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].function.as_deref(), Some("g"));
+        assert_eq!((violations[0].register, violations[0].found), (Rra, back));
+        assert!(violations[0].clobbered_at.as_ref().unwrap().line_text.contains("jal h"));
+    }
+
+    #[test]
+    fn test_undefined_reads(){
+        let mut runtime = Runtime::new();
+        runtime.set_undefined_read_checks(true);
+        let frame = CallFrame {
+            function: Some("f".to_string()), target: 0x40, return_addr: 0x10, call_site: None, saved_registers: None
+        };
+        runtime.set_register(&Rt0, 1).unwrap();
+        runtime.set_register(&Rs0, 1).unwrap();
+        runtime.read_source_register(&Rt0).unwrap();
+        runtime.read_source_register(&Rsp).unwrap();
+        assert!(runtime.undefined_reads().is_empty());
+
+        runtime.begin_step();
+        runtime.call(frame);
+        runtime.read_source_register(&Rs0).unwrap();
+        runtime.read_source_register(&Rt0).unwrap();
+        runtime.read_source_register(&Rt0).unwrap();
+        assert_eq!(runtime.undefined_reads(), &[UndefinedRegisterRead {register: Rt0, location: None}]);
+
+        assert!(runtime.step_back());
+        assert!(runtime.registers.is_defined(&Rt0));
+        assert!(!runtime.registers.is_defined(&Rt1));
+    }
+
+    #[test]
+    fn test_scripted_syscalls(){
+        let mut runtime = Runtime::new();
+        runtime.set_console(Console::scripted(b"12\nhi\n"));
+        let address = 5000;
+
+        runtime.set_register(&Rv0, 5).unwrap();
+        assert_eq!(runtime.syscall().unwrap(), SyscallResult::Continue);
+        assert_eq!(runtime.get_register(&Rv0).unwrap(), 12);
+
+        runtime.set_register(&Rv0, 8).unwrap();
+        runtime.set_register(&Ra0, address as u32).unwrap();
+        runtime.set_register(&Ra1, 10).unwrap();
+        runtime.syscall().unwrap();
+
+        runtime.set_register(&Rv0, 4).unwrap();
+        runtime.syscall().unwrap();
+        runtime.set_register(&Rv0, 1).unwrap();
+        runtime.set_register(&Ra0, -3i32 as u32).unwrap();
+        runtime.syscall().unwrap();
+        assert_eq!(runtime.console().captured_output(), Some(&b"hi\n-3"[..]));
+
+        runtime.set_register(&Rv0, 12).unwrap();
+        match runtime.syscall() {
+            Err(SyscallError::Console(ConsoleError::EndOfInput)) => (),
+            other => panic!("Reading past the end of input returned {:?}", other)
+        }
+
+        runtime.set_register(&Rv0, 17).unwrap();
+        assert_eq!(runtime.syscall().unwrap(), SyscallResult::Exit(-3));
+    }
+
+    #[test]
+    fn test_run_program(){
+        let mut runtime = load_str(FACTORIAL);
+        runtime.set_console(Console::scripted(b"5\n"));
+        runtime.set_convention_checks(true);
+        assert_eq!(runtime.run().unwrap(), 0);
+        assert_eq!(runtime.console().captured_output(), Some(&b"n: 120"[..]));
+        assert_eq!(runtime.call_stack().depth(), 0);
+        assert!(runtime.convention_violations().is_empty());
+        assert_eq!(runtime.get_register(&Rsp).unwrap(), STACK_TOP);
+    }
+
+    #[test]
+    fn test_arguments(){
+        // Prints argc, then the last argument.
+        let mut runtime = load_str("
+        main:   lw $t0, 0($sp)
+                li $v0, 1
+                syscall
+                sll $t0, $t0, 2
+                addu $t0, $a1, $t0
+                lw $a0, -4($t0)
+                li $v0, 4
+                syscall
+                jr $ra
+        ");
+        runtime.set_console(Console::scripted(b""));
+        runtime.set_arguments(&["prog".to_string(), "-v".to_string(), "last".to_string()]).unwrap();
+        assert_eq!(runtime.get_register(&Ra0).unwrap(), 3);
+        let argv = runtime.get_register(&Ra1).unwrap();
+        assert_eq!(argv % 4, 0);
+        assert_eq!(runtime.get_register(&Rsp).unwrap(), argv - 4);
+        assert_eq!(runtime.run().unwrap(), 0);
+        assert_eq!(runtime.console().captured_output(), Some(&b"3last"[..]));
+    }
+
+    #[test]
+    fn test_step_back_program(){
+        let mut runtime = load_str(FACTORIAL);
+        runtime.set_console(Console::scripted(b"3\n"));
+        let start = runtime.pc();
+        for _ in 0..20 {
+            runtime.step().unwrap();
+        }
+        let depth = runtime.call_stack().depth();
+        assert!(depth > 1);
+        assert_eq!(runtime.instruction_count(), 20);
+        assert!(runtime.step_back());
+        assert_eq!(runtime.instruction_count(), 19);
+        while runtime.step_back() {}
+        assert_eq!(runtime.pc(), start);
+        assert_eq!(runtime.instruction_count(), 0);
+        assert_eq!(runtime.call_stack().depth(), 0);
+        assert_eq!(runtime.get_register(&Rsp).unwrap(), STACK_TOP);
+    }
+
+    #[test]
+    fn test_floating_point(){
+        let mut runtime = load_str("
+                .data
+        half:   .float 0.5
+                .text
+        main:   li $v0, 6
+                syscall
+                l.s $f1, half
+                mul.s $f12, $f0, $f1
+                li $v0, 2
+                syscall
+                cvt.d.s $f2, $f1
+                li $v0, 7
+                syscall
+                div.d $f12, $f0, $f2
+                li $v0, 3
+                syscall
+                c.lt.d 3, $f2, $f12
+                bc1t 3, done
+                round.w.d $f4, $f2
+                trunc.w.d $f5, $f12
+                mfc1 $t0, $f5
+                li $v0, 0
+        done:   jr $ra
+        ");
+        runtime.set_console(Console::scripted(b"3\n0.1\n"));
+        assert_eq!(runtime.run().unwrap(), 0);
+        assert_eq!(runtime.console().captured_output(), Some(&b"1.500000000.200000000000000011"[..]));
+        assert_eq!(runtime.get_double(&FloatRegister(12)), 0.2);
+        assert!(!runtime.float_condition(3));
+        assert_eq!(runtime.get_float_register(&FloatRegister(4)), 0);
+        assert_eq!(runtime.get_register(&Rt0).unwrap(), 0);
+
+        runtime.set_double(&FloatRegister(2), -0.0);
+        assert_eq!(runtime.get_float_register(&FloatRegister(3)), 0x8000_0000);
+        assert!(runtime.step_back());
+        assert_eq!(runtime.get_double(&FloatRegister(2)), 0.5);
+    }
+
+    #[test]
+    fn test_exception_handler(){
+        // Counts exceptions in $s0 and skips the instruction that raised them.
+        let mut runtime = load_str("
+                .text
+        main:   li $t0, 0x7fffffff
+                addi $t1, $t0, 1
+                lw $t2, 2($sp)
+                sw $t0, 0($zero)
+                lw $t2, 4($zero)
+                break 7
+                li $v0, 10
+                syscall
+                .ktext
+                mfc0 $k0, $13
+                mfc0 $k1, $14
+                addiu $k1, $k1, 4
+                mtc0 $k1, $14
+                addi $s0, $s0, 1
+                eret
+        ");
+        runtime.set_register(&Rs0, 0).unwrap();
+        // The startup code's jal, then li's two instructions.
+        for _ in 0..4 {
+            runtime.step().unwrap();
+        }
+        assert_eq!(runtime.pc(), KTEXT_BASE);
+        assert_eq!(runtime.get_cp0_register(EPC), TEXT_BASE + 8);
+        assert_eq!(runtime.get_cp0_register(CAUSE), (ExceptionCode::Overflow as u32) << 2);
+        assert_ne!(runtime.get_cp0_register(STATUS) & STATUS_EXL, 0);
+        assert!(runtime.step_back());
+        assert_eq!(runtime.pc(), TEXT_BASE + 8);
+        assert_eq!(runtime.get_cp0_register(STATUS), INITIAL_STATUS);
+
+        // Stores and loads that fault raise different exceptions.
+        while runtime.pc() != TEXT_BASE + 16 {
+            runtime.step().unwrap();
+        }
+        assert_eq!(runtime.get_cp0_register(BAD_VADDR), STACK_TOP + 2);
+        runtime.step().unwrap();
+        assert_eq!(runtime.get_cp0_register(CAUSE), (ExceptionCode::AddressStore as u32) << 2);
+        assert_eq!(runtime.get_cp0_register(BAD_VADDR), 0);
+        while runtime.pc() != TEXT_BASE + 20 {
+            runtime.step().unwrap();
+        }
+        runtime.step().unwrap();
+        assert_eq!(runtime.get_cp0_register(CAUSE), (ExceptionCode::AddressLoad as u32) << 2);
+        assert_eq!(runtime.get_cp0_register(BAD_VADDR), 4);
+
+        assert_eq!(runtime.run().unwrap(), 0);
+        assert_eq!(runtime.get_register(&Rs0).unwrap(), 5);
+        assert_eq!(runtime.get_register(&Rk0).unwrap(), (ExceptionCode::Breakpoint as u32) << 2);
+        assert_eq!(runtime.get_cp0_register(STATUS) & STATUS_EXL, 0);
+        assert_eq!(runtime.get_register(&Rt1), Ok(0));
+
+        // An exception inside the handler can't be handled.
+        let mut runtime = load_str("main: break\n.ktext\nbreak 1");
+        runtime.step().unwrap();
+        match runtime.run() {
+            Err(RuntimeError::Break(1)) => (),
+            other => panic!("A break in the handler returned {:?}", other)
+        }
+        assert_eq!(runtime.set_register(&Rk0, 1), Err(MemoryError::WriteNotIntended));
+    }
+
+    #[test]
+    fn test_mapped_console(){
+        // Echoes a line by polling the ready bits.
+        let mut runtime = load_str("
+        main:   li $t0, 0xffff0000
+        read:   lw $t1, 0($t0)
+                andi $t1, $t1, 1
+                beqz $t1, read
+                lw $a0, 4($t0)
+                beq $a0, 10, done
+        write:  lw $t1, 8($t0)
+                andi $t1, $t1, 1
+                beqz $t1, write
+                sb $a0, 12($t0)
+                j read
+        done:   jr $ra
+        ");
+        let console = Rc::new(RefCell::new(MappedConsole::scripted(b"echo\n")));
+        runtime.map_device(MMIO_BASE, MAPPED_CONSOLE_LEN, Box::new(console.clone())).unwrap();
+        assert_eq!(runtime.run().unwrap(), 0);
+        assert_eq!(console.borrow().captured_output(), Some(&b"echo"[..]));
+
+        // Collects three keys in $s1 from receiver interrupts.
+        let mut runtime = load_str("
+        main:   li $t0, 0xffff0000
+                li $t1, 2
+                sw $t1, 0($t0)
+                mfc0 $t1, $12
+                ori $t1, $t1, 1
+                mtc0 $t1, $12
+        wait:   blt $s0, 3, wait
+                jr $ra
+                .ktext
+                mfc0 $s2, $13
+                lui $k0, 0xffff
+                lw $k0, 4($k0)
+                sll $s1, $s1, 8
+                or $s1, $s1, $k0
+                addi $s0, $s0, 1
+                eret
+        ");
+        runtime.map_device(MMIO_BASE, MAPPED_CONSOLE_LEN, Box::new(MappedConsole::scripted(b"xyz"))).unwrap();
+        assert_eq!(runtime.run().unwrap(), 0);
+        assert_eq!(runtime.get_register(&Rs1).unwrap(), 0x78797a);
+        assert_eq!(runtime.get_register(&Rs2).unwrap(), RECEIVER_INTERRUPT | (ExceptionCode::Interrupt as u32) << 2);
+    }
+
+    #[test]
+    fn test_runtime_errors(){
+        let mut runtime = load_str("main: li $t0, 0x7fffffff\n addi $t0, $t0, 1");
+        match runtime.run() {
+            Err(RuntimeError::ArithmeticOverflow) => (),
+            other => panic!("Expected an overflow, found {:?}", other)
+        }
+        assert_eq!(runtime.current_location().unwrap().line_num, 1);
+        assert_eq!(runtime.backtrace(), "#0 main\n");
+
+        let mut runtime = load_str("main: li $t0, 3\n lw $t1, 0($t0)");
+        match runtime.run() {
+            Err(RuntimeError::UnalignedAccess(3)) => (),
+            other => panic!("Expected an unaligned access, found {:?}", other)
+        }
+
+        let mut runtime = load_str("nop");
+        match runtime.run() {
+            Err(RuntimeError::NoInstruction(pc)) => assert_eq!(pc, TEXT_BASE + 4),
+            other => panic!("Expected to run off the end of the program, found {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_code_in_memory(){
+        // Copies `li $v0, 17` over the `li $v0, 10` below it, reads its own
+        // first instruction, and jumps through a computed address.
+        let mut runtime = load_str("
+        main:   lw $t0, patch
+                la $t1, target
+                sw $t0, 0($t1)
+                lw $a0, main
+                la $t2, target
+                jalr $t2
+        patch:  li $v0, 17
+        target: li $v0, 10
+                syscall
+        ");
+        let program = runtime.program().unwrap();
+        let main_word = program.words[0];
+        assert_eq!(runtime.read_word(TEXT_BASE as usize).unwrap(), main_word);
+        let target = runtime.program().unwrap().symbols["target"];
+        assert_eq!(runtime.disassemble(target, 1)[0].text, "addiu $v0, $zero, 10");
+        runtime.run().unwrap();
+        assert_eq!(runtime.disassemble(target, 1)[0].text, "addiu $v0, $zero, 17");
+        assert_eq!(runtime.get_register(&Rv0).unwrap(), 17);
+        assert_eq!(runtime.get_register(&Ra0).unwrap(), main_word);
+
+        let mut runtime = load_str("main: li $t0, -1\n sw $t0, main\n j main");
+        match runtime.run() {
+            Err(RuntimeError::InvalidInstruction(TEXT_BASE, 0xffff_ffff)) => (),
+            other => panic!("Expected an invalid instruction, found {:?}", other)
+        }
+
+        // A word that happens to match fresh memory is still an instruction.
+        let mut runtime = load_str("main: li $t0, 0x66666666\n sw $t0, main\n j main");
+        match runtime.run() {
+            Err(RuntimeError::InvalidInstruction(TEXT_BASE, 0x6666_6666)) => (),
+            other => panic!("Expected an invalid instruction, found {:?}", other)
+        }
+
+        // Stepping back over a patch runs the original instruction again.
+        let mut runtime = load_str("
+        main:   li $a0, 3
+                la $t1, target
+                lw $t0, patch
+                sw $t0, 0($t1)
+        target: li $v0, 10
+                syscall
+        patch:  li $v0, 17
+        ");
+        assert_eq!(runtime.run().unwrap(), 3);
+        for _ in 0..3 {
+            assert!(runtime.step_back());
+        }
+        runtime.set_register(&Rt1, DATA_BASE).unwrap();
+        assert_eq!(runtime.run().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_load_elf(){
+        use super::super::elf::{fixtures, parse_elf};
+        use super::super::memory::Endian;
+
+        let (components, _) = read_str_to_state(FACTORIAL, "test.s");
+        let program = assemble(&components).unwrap();
+        for endian in [Endian::Big, Endian::Little].iter() {
+            let text: Vec<u8> = program.words.iter().flat_map(|word| endian.word_to_bytes(*word).to_vec()).collect();
+            let text_size = text.len() as u32;
+            let bytes = fixtures::executable(
+                *endian, program.entry,
+                &[(TEXT_BASE, text, text_size), (DATA_BASE, program.data.clone(), 0x100)],
+                &[("main", program.symbols["main"]), ("fact", program.symbols["fact"]), ("_gp", 0x1001_8000)]
+            );
+            let mut runtime = Runtime::new();
+            runtime.load_elf(&parse_elf(&bytes).unwrap()).unwrap();
+            assert_eq!(runtime.get_register(&Rgp).unwrap(), 0x1001_8000);
+            assert_eq!(runtime.read_byte(DATA_BASE as usize + 0xff).unwrap(), 0);
+            runtime.set_console(Console::scripted(b"4\n"));
+            for _ in 0..30 {
+                runtime.step().unwrap();
+            }
+            assert!(runtime.backtrace().contains("fact"));
+            assert_eq!(runtime.run().unwrap(), 0);
+            assert_eq!(runtime.console().captured_output(), Some(&b"n: 24"[..]));
+        }
+    }
+
+    #[test]
+    fn test_undo_capacity(){
+        let mut runtime = Runtime::with_undo_capacity(2);
+        for i in 1..=3 {
+            runtime.begin_step();
+            runtime.set_register(&Rt0, i).unwrap();
+        }
+        assert_eq!(runtime.undo_log().len(), 2);
+        assert!(runtime.step_back());
+        assert!(runtime.step_back());
+        assert!(!runtime.step_back());
+        assert_eq!(runtime.get_register(&Rt0).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_undo_disabled(){
+        let mut runtime = Runtime::with_undo_capacity(0);
+        runtime.begin_step();
+        runtime.set_register(&Rt0, 1).unwrap();
+        assert!(runtime.undo_log().is_empty());
+        assert!(!runtime.step_back());
     }
 }
@@ -0,0 +1,240 @@
+use std::io::{self, Write};
+use std::ops::Range;
+
+use super::mips_parser::MIPSLocation;
+use super::observer::Observer;
+use super::runtime::{ExceptionCode, FloatRegister, RegisterCodes};
+use super::utils::json::json_string;
+
+/// How a `Tracer` writes each instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    /// One line of text per instruction, for people.
+    Text,
+    /// One JSON object per line per instruction, for tools.
+    JsonLines,
+}
+
+/// A write made by the instruction being traced.
+enum Change {
+    /// A register's name, and its old and new values.
+    Register(String, u32, u32),
+    /// An address, the size of the write in bytes and the value written.
+    Memory(usize, usize, u32),
+}
+
+/// Writes a trace of every instruction a runtime executes: its address, the
+/// source it came from and what it changed. Only instructions in the
+/// traced ranges are written, or every instruction if there are none.
+pub struct Tracer {
+    output: Box<dyn Write>,
+    format: TraceFormat,
+    ranges: Vec<Range<u32>>,
+    changes: Vec<Change>,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>, format: TraceFormat) -> Tracer {
+        Tracer {
+            output,
+            format,
+            ranges: Vec::new(),
+            changes: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Traces the instructions from `range.start` up to `range.end`.
+    pub fn add_range(&mut self, range: Range<u32>) {
+        self.ranges.push(range);
+    }
+
+    /// Flushes the trace, returning the first error writing it if there
+    /// was one. Nothing more is written after an error.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.output.flush(),
+        }
+    }
+
+    fn is_traced(&self, addr: u32) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&addr))
+    }
+
+    fn change(&mut self, change: Change) {
+        self.changes.push(change);
+    }
+
+    fn register(&mut self, name: String, old_val: u32, new_val: u32) {
+        self.change(Change::Register(name, old_val, new_val));
+    }
+
+    /// Writes one line of the trace with the changes since the last one.
+    fn write_line(&mut self, addr: u32, event: Event) {
+        let changes = std::mem::take(&mut self.changes);
+        if self.error.is_some() || !self.is_traced(addr) {
+            return
+        }
+        let line = match self.format {
+            TraceFormat::Text => text_line(addr, &event, &changes),
+            TraceFormat::JsonLines => json_line(addr, &event, &changes),
+        };
+        if let Err(error) = writeln!(self.output, "{}", line) {
+            self.error = Some(error);
+        }
+    }
+}
+
+/// What a line of the trace is about.
+enum Event<'a> {
+    Instruction(Option<&'a MIPSLocation>),
+    Exception(ExceptionCode),
+}
+
+fn text_line(addr: u32, event: &Event, changes: &[Change]) -> String {
+    let mut line = match event {
+        Event::Instruction(Some(location)) => format!("0x{:08x} {}", addr, location),
+        Event::Instruction(None) => format!("0x{:08x}", addr),
+        Event::Exception(code) => format!("0x{:08x} exception {:?}", addr, code),
+    };
+    for (i, change) in changes.iter().enumerate() {
+        line.push_str(if i == 0 { "  ; " } else { ", " });
+        match change {
+            Change::Register(name, old_val, new_val) => {
+                line.push_str(&format!("{} 0x{:08x} -> 0x{:08x}", name, old_val, new_val));
+            }
+            Change::Memory(addr, size, val) => {
+                line.push_str(&format!("[0x{:08x}] = 0x{:0width$x}", addr, val, width = size * 2));
+            }
+        }
+    }
+    line
+}
+
+fn json_line(addr: u32, event: &Event, changes: &[Change]) -> String {
+    let mut line = format!("{{\"addr\":{}", addr);
+    match event {
+        Event::Instruction(Some(location)) => {
+            line.push_str(&format!(",\"file\":{},\"line\":{},\"source\":{}", json_string(&location.file),
+                location.line_num + 1, json_string(location.line_text.trim())));
+        }
+        Event::Instruction(None) => {}
+        Event::Exception(code) => line.push_str(&format!(",\"exception\":{}", json_string(&format!("{:?}", code)))),
+    }
+    let registers: Vec<String> = changes.iter().filter_map(|change| match change {
+        Change::Register(name, old_val, new_val) => {
+            Some(format!("{{\"name\":{},\"old\":{},\"new\":{}}}", json_string(name), old_val, new_val))
+        }
+        _ => None
+    }).collect();
+    let memory: Vec<String> = changes.iter().filter_map(|change| match change {
+        Change::Memory(addr, size, val) => Some(format!("{{\"addr\":{},\"size\":{},\"value\":{}}}", addr, size, val)),
+        _ => None
+    }).collect();
+    line.push_str(&format!(",\"registers\":[{}],\"memory\":[{}]}}", registers.join(","), memory.join(",")));
+    line
+}
+
+impl Observer for Tracer {
+    fn instruction_retired(&mut self, addr: u32, location: Option<&MIPSLocation>) {
+        self.write_line(addr, Event::Instruction(location));
+    }
+    fn register_written(&mut self, reg: RegisterCodes, old_val: u32, new_val: u32) {
+        self.register(reg.to_string(), old_val, new_val);
+    }
+    fn float_register_written(&mut self, reg: FloatRegister, old_val: u32, new_val: u32) {
+        self.register(reg.to_string(), old_val, new_val);
+    }
+    fn hi_lo_written(&mut self, (old_hi, old_lo): (u32, u32), (new_hi, new_lo): (u32, u32)) {
+        if old_hi != new_hi {
+            self.register("hi".to_string(), old_hi, new_hi);
+        }
+        if old_lo != new_lo {
+            self.register("lo".to_string(), old_lo, new_lo);
+        }
+    }
+    fn cp0_register_written(&mut self, reg: u8, old_val: u32, new_val: u32) {
+        self.register(format!("cp0 ${}", reg), old_val, new_val);
+    }
+    fn float_conditions_written(&mut self, old_val: u8, new_val: u8) {
+        self.register("fcc".to_string(), old_val as u32, new_val as u32);
+    }
+    fn memory_written(&mut self, addr: usize, size: usize, val: u32) {
+        self.change(Change::Memory(addr, size, val));
+    }
+    /// An instruction that raises an exception doesn't retire, so what it
+    /// and the hardware changed is traced at the instruction's address.
+    fn exception(&mut self, code: ExceptionCode, epc: u32) {
+        self.write_line(epc, Event::Exception(code));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::mips_parser::read_str_to_state;
+    use super::super::runtime::Runtime;
+
+    /// A writer a test can read back after handing it to a tracer.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(source: &str, format: TraceFormat, range: Option<(&str, &str)>) -> Vec<String> {
+        let (components, _) = read_str_to_state(source, "test.s");
+        let program = assemble(&components).unwrap();
+        let output = Shared::default();
+        let mut tracer = Tracer::new(Box::new(output.clone()), format);
+        if let Some((start, end)) = range {
+            tracer.add_range(program.symbols[start]..program.symbols[end]);
+        }
+        let mut runtime = Runtime::new();
+        runtime.load_program(program).unwrap();
+        runtime.add_observer(Box::new(tracer));
+        runtime.run().unwrap();
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        text.lines().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_trace() {
+        let source = "
+        main:   li $t0, 5
+                sw $t0, 0($sp)
+                jal double
+                li $v0, 10
+                syscall
+        double: add $t0, $t0, $t0
+        done:   jr $ra
+        ";
+        let lines = trace(source, TraceFormat::Text, None);
+        // The startup stub calls main.
+        assert_eq!(lines[0], "0x0040001c  ; $ra 0x00000000 -> 0x00400020");
+        assert_eq!(lines[1], "0x00400000 test.s:2: main:   li $t0, 5  ; $t0 0x00000000 -> 0x00000005");
+        assert_eq!(lines[2], "0x00400004 test.s:3: sw $t0, 0($sp)  ; [0x7ffffffc] = 0x00000005");
+
+        let lines = trace(source, TraceFormat::Text, Some(("double", "done")));
+        assert_eq!(lines, vec!["0x00400014 test.s:7: double: add $t0, $t0, $t0  ; $t0 0x00000005 -> 0x0000000a"]);
+
+        let lines = trace(source, TraceFormat::JsonLines, Some(("double", "done")));
+        assert_eq!(lines, vec![concat!(
+            "{\"addr\":4194324,\"file\":\"test.s\",\"line\":7,\"source\":\"double: add $t0, $t0, $t0\",",
+            "\"registers\":[{\"name\":\"$t0\",\"old\":5,\"new\":10}],\"memory\":[]}"
+        )]);
+    }
+}
@@ -1 +1,3 @@
+pub mod json;
 pub mod smart_split;
+pub mod xml;
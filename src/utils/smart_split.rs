@@ -5,8 +5,8 @@ pub struct SmartSplit<'a> {
 }
 
 impl<'a> SmartSplit<'a> {
-    pub fn new(string: &str) -> SmartSplit{
-        SmartSplit {string: string, cur_pos: 0}
+    pub fn new(string: &str) -> SmartSplit<'_>{
+        SmartSplit {string, cur_pos: 0}
     }
 }
 
@@ -24,6 +24,10 @@ impl<'a> Iterator for SmartSplit<'a> {
                 break
             }
         }
+        // An unquoted '#' starts a comment running to the end of the line.
+        if self.string[start_pos..].starts_with('#') {
+            return None
+        }
 
         let mut end_pos = start_pos;
         let mut escaped = false;
@@ -43,7 +47,7 @@ impl<'a> Iterator for SmartSplit<'a> {
                 escaped = true;
             } else if c == '\'' || c == '"' {
                 quote_char = c;
-            } else if c.is_whitespace() || c == ',' {
+            } else if c.is_whitespace() || c == ',' || c == '#' {
                 break
             } 
             
@@ -84,6 +88,9 @@ mod tests {
         compare_iterator_to_vec("a '\\'b c'", vec!["a", "'\\'b c'"]);
         compare_iterator_to_vec("'a b\\'' c", vec!["'a b\\''", "c"]);
         compare_iterator_to_vec("'\"a\" ' b c", vec!["'\"a\" '", "b", "c"]);
+        compare_iterator_to_vec("a b# c", vec!["a", "b"]);
+        compare_iterator_to_vec("'#' # c", vec!["'#'"]);
+        assert_eq!(SmartSplit::new("a # b").count(), 1);
     }
 
 }
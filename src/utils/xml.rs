@@ -0,0 +1,28 @@
+/// `text` escaped to go in XML text or an attribute value. Control
+/// characters XML can't hold at all are dropped.
+pub fn xml_escape(text: &str) -> String {
+    let mut xml = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => xml.push_str("&amp;"),
+            '<' => xml.push_str("&lt;"),
+            '>' => xml.push_str("&gt;"),
+            '"' => xml.push_str("&quot;"),
+            '\'' => xml.push_str("&apos;"),
+            '\n' | '\r' | '\t' => xml.push(c),
+            c if (c as u32) < 0x20 => {}
+            c => xml.push(c),
+        }
+    }
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xml_escape() {
+        assert_eq!(xml_escape("a < b && \"c\"\u{1}\n"), "a &lt; b &amp;&amp; &quot;c&quot;\n");
+    }
+}
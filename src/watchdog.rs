@@ -0,0 +1,305 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::mips_parser::MIPSLocation;
+use super::observer::Observer;
+use super::runtime::{ExceptionCode, FloatRegister, RegisterCodes, Runtime, RuntimeError, StepResult};
+
+/// How many steps to run between looks at the clock, which costs about as
+/// much as a step does.
+const CLOCK_INTERVAL: u32 = 1024;
+
+/// Why a watchdog stopped a program that hadn't finished.
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    /// It ran this many instructions.
+    StepLimit(u64),
+    /// It ran for longer than this.
+    Timeout(Duration),
+    /// It went round the loop at this address twice without changing
+    /// anything, so it would go round forever.
+    Stalled(u32),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::StepLimit(steps) => write!(f, "stopped after the step limit of {} instructions", steps),
+            Stop::Timeout(timeout) => write!(f, "stopped after the timeout of {:?}", timeout),
+            Stop::Stalled(addr) => write!(f, "stuck in a loop starting at 0x{:08x} that changes nothing", addr),
+        }
+    }
+}
+
+/// Something the program wrote, keyed so that writing it again replaces the
+/// first write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Written {
+    Register(u32),
+    FloatRegister(u32),
+    HiLo,
+    Cp0(u8),
+    FloatConditions,
+    Memory(usize, usize),
+}
+
+/// Watches for a loop that goes round without changing anything.
+///
+/// A loop's head is an instruction reached by going backwards. Each time
+/// round, the detector notes the last value of everything written. If one
+/// time round writes the same values as the time before, the machine is
+/// in the same state as it was then, and will do the same again forever.
+/// Input can change what happens next, so a time round that made a syscall,
+/// took an exception or read a device isn't compared.
+#[derive(Default)]
+struct StallDetector {
+    last_addr: Option<u32>,
+    head: Option<u32>,
+    writes: HashMap<Written, u64>,
+    previous: Option<HashMap<Written, u64>>,
+    disturbed: bool,
+    /// Addresses read since the watchdog last looked, to check for devices.
+    reads: Vec<usize>,
+    stalled: Option<u32>,
+}
+
+impl StallDetector {
+    fn write(&mut self, written: Written, val: u64) {
+        self.writes.insert(written, val);
+    }
+
+    /// Starts another time round the loop at `head`.
+    fn go_round(&mut self, head: u32) {
+        if self.head != Some(head) || self.disturbed {
+            self.head = Some(head);
+            self.previous = None;
+        } else if self.previous.as_ref() == Some(&self.writes) {
+            self.stalled = Some(head);
+        } else {
+            // Reuse the old map rather than allocating one every time round.
+            let mut writes = self.previous.take().unwrap_or_default();
+            mem::swap(&mut writes, &mut self.writes);
+            self.previous = Some(writes);
+        }
+        self.writes.clear();
+        self.disturbed = false;
+    }
+}
+
+impl Observer for StallDetector {
+    fn instruction_retired(&mut self, addr: u32, _location: Option<&MIPSLocation>) {
+        if self.last_addr.is_some_and(|last| addr <= last) {
+            self.go_round(addr);
+        }
+        self.last_addr = Some(addr);
+    }
+    fn register_written(&mut self, reg: RegisterCodes, _old_val: u32, new_val: u32) {
+        self.write(Written::Register(reg.number()), new_val as u64);
+    }
+    fn float_register_written(&mut self, reg: FloatRegister, _old_val: u32, new_val: u32) {
+        self.write(Written::FloatRegister(reg.number()), new_val as u64);
+    }
+    fn hi_lo_written(&mut self, _old_val: (u32, u32), (hi, lo): (u32, u32)) {
+        self.write(Written::HiLo, (hi as u64) << 32 | lo as u64);
+    }
+    fn cp0_register_written(&mut self, reg: u8, _old_val: u32, new_val: u32) {
+        self.write(Written::Cp0(reg), new_val as u64);
+    }
+    fn float_conditions_written(&mut self, _old_val: u8, new_val: u8) {
+        self.write(Written::FloatConditions, new_val as u64);
+    }
+    fn memory_read(&mut self, addr: usize, _size: usize, _val: u32) {
+        self.reads.push(addr);
+    }
+    fn memory_written(&mut self, addr: usize, size: usize, val: u32) {
+        self.write(Written::Memory(addr, size), val as u64);
+    }
+    fn syscall(&mut self, _code: u32) {
+        self.disturbed = true;
+    }
+    fn exception(&mut self, _code: ExceptionCode, _epc: u32) {
+        self.disturbed = true;
+    }
+}
+
+/// Stops a program that runs for too many steps or too long, or that is
+/// stuck in a loop, so a marking script isn't held up by it.
+pub struct Watchdog {
+    max_steps: Option<u64>,
+    timeout: Option<(Duration, Instant)>,
+    until_clock: u32,
+    stalls: Option<Rc<RefCell<StallDetector>>>,
+}
+
+impl Watchdog {
+    /// A watchdog that lets a program run for as long as it likes.
+    pub fn new() -> Watchdog {
+        Watchdog {
+            max_steps: None,
+            timeout: None,
+            until_clock: CLOCK_INTERVAL,
+            stalls: None,
+        }
+    }
+
+    /// Stops the program once it has run `steps` instructions in all.
+    pub fn set_max_steps(&mut self, steps: u64) {
+        self.max_steps = Some(steps);
+    }
+
+    /// Stops the program once `timeout` has passed, starting now.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some((timeout, Instant::now()));
+    }
+
+    /// Watches `runtime` for loops that go round without changing anything.
+    pub fn detect_stalls(&mut self, runtime: &mut Runtime) {
+        let stalls = Rc::new(RefCell::new(StallDetector::default()));
+        runtime.add_observer(Box::new(stalls.clone()));
+        self.stalls = Some(stalls);
+    }
+
+    /// Checks `runtime` after a step, returning why it should stop if it
+    /// should.
+    pub fn check(&mut self, runtime: &Runtime) -> Result<(), Stop> {
+        if let Some(max_steps) = self.max_steps {
+            if runtime.instruction_count() >= max_steps {
+                return Err(Stop::StepLimit(max_steps))
+            }
+        }
+        if let Some((timeout, start)) = self.timeout {
+            self.until_clock -= 1;
+            if self.until_clock == 0 {
+                self.until_clock = CLOCK_INTERVAL;
+                if start.elapsed() >= timeout {
+                    return Err(Stop::Timeout(timeout))
+                }
+            }
+        }
+        if let Some(stalls) = &self.stalls {
+            let mut stalls = stalls.borrow_mut();
+            if stalls.reads.drain(..).any(|addr| runtime.is_mapped(addr)) {
+                stalls.disturbed = true;
+            }
+            if let Some(head) = stalls.stalled {
+                return Err(Stop::Stalled(head))
+            }
+        }
+        Ok(())
+    }
+
+    /// Steps until the program exits or the watchdog stops it, returning its
+    /// exit code.
+    pub fn run(&mut self, runtime: &mut Runtime) -> Result<i32, RuntimeError> {
+        loop {
+            if let StepResult::Exited(code) = runtime.step()? {
+                return Ok(code)
+            }
+            self.check(runtime)?;
+        }
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Watchdog {
+        Watchdog::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::assembler::assemble;
+    use super::super::console::{Console, ConsoleInput};
+    use super::super::mips_parser::read_str_to_state;
+
+    fn load(source: &str) -> Runtime {
+        let (components, _) = read_str_to_state(source, "test.s");
+        let mut runtime = Runtime::new();
+        runtime.load_program(assemble(&components).unwrap()).unwrap();
+        runtime
+    }
+
+    fn stop(runtime: &mut Runtime, watchdog: &mut Watchdog) -> Stop {
+        match watchdog.run(runtime) {
+            Err(RuntimeError::Stopped(stop)) => stop,
+            result => panic!("expected the watchdog to stop the program, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let source = "
+        main:   li $t0, 0
+        loop:   addi $t0, $t0, 1
+                j loop
+        ";
+        let mut runtime = load(source);
+        let mut watchdog = Watchdog::new();
+        watchdog.set_max_steps(100);
+        watchdog.detect_stalls(&mut runtime);
+        assert_eq!(stop(&mut runtime, &mut watchdog), Stop::StepLimit(100));
+        assert_eq!(runtime.instruction_count(), 100);
+
+        let mut runtime = load(source);
+        let mut watchdog = Watchdog::new();
+        watchdog.set_timeout(Duration::from_millis(10));
+        assert_eq!(stop(&mut runtime, &mut watchdog), Stop::Timeout(Duration::from_millis(10)));
+
+        // A program that finishes in time isn't stopped.
+        let mut runtime = load("main: li $t0, 1\njr $ra\n");
+        let mut watchdog = Watchdog::new();
+        watchdog.set_max_steps(100);
+        watchdog.set_timeout(Duration::from_secs(60));
+        assert_eq!(watchdog.run(&mut runtime).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_stalls() {
+        // The store and the count change nothing after the first time round.
+        let source = "
+        main:   li $t1, 5
+        wait:   li $t0, 1
+                sw $t0, 0($sp)
+                bne $t0, $zero, wait
+        ";
+        let mut runtime = load(source);
+        let wait = runtime.program().unwrap().address_of_line(None, 3).unwrap();
+        let mut watchdog = Watchdog::new();
+        watchdog.detect_stalls(&mut runtime);
+        assert_eq!(stop(&mut runtime, &mut watchdog), Stop::Stalled(wait));
+        assert!(runtime.instruction_count() < 20);
+
+        // A loop that counts is making progress, even a long way round.
+        let source = "
+        main:   li $t0, 100
+        loop:   addi $t0, $t0, -1
+                bne $t0, $zero, loop
+                jr $ra
+        ";
+        let mut runtime = load(source);
+        let mut watchdog = Watchdog::new();
+        watchdog.detect_stalls(&mut runtime);
+        assert_eq!(watchdog.run(&mut runtime).unwrap(), 0);
+
+        // Reading the same input twice isn't a stall, since the next read
+        // may differ.
+        let source = "
+        main:   li $v0, 5
+                syscall
+                bne $v0, $zero, main
+                jr $ra
+        ";
+        let mut runtime = load(source);
+        let mut console = Console::new();
+        console.set_input(ConsoleInput::Scripted(b"7\n7\n7\n0\n".to_vec(), 0));
+        runtime.set_console(console);
+        let mut watchdog = Watchdog::new();
+        watchdog.detect_stalls(&mut runtime);
+        assert_eq!(watchdog.run(&mut runtime).unwrap(), 0);
+    }
+}